@@ -0,0 +1,51 @@
+-- +goose Up
+-- +goose StatementBegin
+CREATE TYPE "OrganizationRole" AS ENUM (
+  'Admin',
+  'Member'
+);
+
+CREATE TABLE organizations (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  name TEXT NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE TABLE organization_members (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  organization_id UUID NOT NULL,
+  user_id UUID NOT NULL,
+  role "OrganizationRole" NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  UNIQUE (organization_id, user_id)
+);
+
+-- Every existing user gets a personal organization they administer.
+INSERT INTO organizations (id, name)
+  SELECT id, email FROM users;
+INSERT INTO organization_members (organization_id, user_id, role)
+  SELECT id, id, 'Admin' FROM users;
+
+CREATE TABLE audit_events (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  organization_id UUID,
+  actor_id UUID,
+  action TEXT NOT NULL,
+  target_type TEXT NOT NULL,
+  target_id UUID,
+  ip TEXT,
+  request_id TEXT NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX audit_events_organization_id_created_at_idx
+  ON audit_events (organization_id, created_at DESC);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP TABLE audit_events;
+DROP TABLE organization_members;
+DROP TABLE organizations;
+DROP TYPE "OrganizationRole";
+-- +goose StatementEnd
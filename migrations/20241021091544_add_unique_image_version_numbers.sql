@@ -0,0 +1,11 @@
+-- +goose Up
+-- +goose StatementBegin
+ALTER TABLE image_versions
+  ADD CONSTRAINT image_versions_image_id_version_number_key UNIQUE (image_id, version_number);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+ALTER TABLE image_versions
+  DROP CONSTRAINT image_versions_image_id_version_number_key;
+-- +goose StatementEnd
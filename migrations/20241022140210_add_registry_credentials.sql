@@ -0,0 +1,22 @@
+-- +goose Up
+-- +goose StatementBegin
+CREATE TABLE registry_credentials (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id UUID NOT NULL,
+  image_id UUID,
+  organization_id UUID,
+  registry TEXT NOT NULL,
+  username TEXT NOT NULL,
+  encrypted_token TEXT NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  CHECK ((image_id IS NULL) <> (organization_id IS NULL))
+);
+
+CREATE INDEX registry_credentials_image_id_idx ON registry_credentials (image_id);
+CREATE INDEX registry_credentials_organization_id_idx ON registry_credentials (organization_id);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP TABLE registry_credentials;
+-- +goose StatementEnd
@@ -0,0 +1,16 @@
+-- +goose Up
+-- +goose StatementBegin
+CREATE TABLE image_webhooks (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  image_id UUID NOT NULL UNIQUE,
+  encrypted_secret TEXT NOT NULL,
+  auto_launch_cpu TEXT,
+  auto_launch_gpu TEXT,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP TABLE image_webhooks;
+-- +goose StatementEnd
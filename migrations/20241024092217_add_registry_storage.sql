@@ -0,0 +1,27 @@
+-- +goose Up
+-- +goose StatementBegin
+CREATE TABLE registry_blobs (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  image_id UUID NOT NULL,
+  digest TEXT NOT NULL,
+  size BIGINT NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  UNIQUE (image_id, digest)
+);
+
+CREATE TABLE registry_manifests (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  image_id UUID NOT NULL,
+  digest TEXT NOT NULL,
+  media_type TEXT NOT NULL,
+  size BIGINT NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  UNIQUE (image_id, digest)
+);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP TABLE registry_manifests;
+DROP TABLE registry_blobs;
+-- +goose StatementEnd
@@ -0,0 +1,18 @@
+-- +goose Up
+-- +goose StatementBegin
+ALTER TABLE images ADD COLUMN config JSONB NOT NULL DEFAULT '{}';
+ALTER TABLE image_versions ADD COLUMN config JSONB NOT NULL DEFAULT '{}';
+
+ALTER TABLE image_webhooks ADD COLUMN auto_launch BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE image_webhooks ADD COLUMN auto_launch_preset TEXT;
+UPDATE image_webhooks SET auto_launch = true
+  WHERE auto_launch_cpu IS NOT NULL OR auto_launch_gpu IS NOT NULL;
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+ALTER TABLE image_webhooks DROP COLUMN auto_launch_preset;
+ALTER TABLE image_webhooks DROP COLUMN auto_launch;
+ALTER TABLE image_versions DROP COLUMN config;
+ALTER TABLE images DROP COLUMN config;
+-- +goose StatementEnd
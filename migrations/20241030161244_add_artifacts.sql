@@ -0,0 +1,18 @@
+-- +goose Up
+-- +goose StatementBegin
+CREATE TABLE artifacts (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  job_id UUID NOT NULL,
+  name TEXT NOT NULL,
+  key TEXT NOT NULL,
+  size_bytes BIGINT NOT NULL,
+  etag TEXT NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  UNIQUE (job_id, name)
+);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP TABLE artifacts;
+-- +goose StatementEnd
@@ -0,0 +1,25 @@
+-- +goose Up
+-- +goose StatementBegin
+ALTER TYPE "JobStatus" ADD VALUE 'TimedOut';
+
+ALTER TABLE users ADD COLUMN plan TEXT NOT NULL DEFAULT 'free';
+
+ALTER TABLE jobs
+  ADD COLUMN machine_id TEXT,
+  ADD COLUMN started_at TIMESTAMPTZ,
+  ADD COLUMN max_runtime_seconds BIGINT;
+
+ALTER TABLE image_webhooks ADD COLUMN auto_launch_max_runtime_seconds BIGINT;
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+ALTER TABLE image_webhooks DROP COLUMN auto_launch_max_runtime_seconds;
+
+ALTER TABLE jobs
+  DROP COLUMN machine_id,
+  DROP COLUMN started_at,
+  DROP COLUMN max_runtime_seconds;
+
+ALTER TABLE users DROP COLUMN plan;
+-- +goose StatementEnd
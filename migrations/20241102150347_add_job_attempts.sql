@@ -0,0 +1,54 @@
+-- +goose Up
+-- +goose StatementBegin
+CREATE TYPE "AttemptStatus" AS ENUM (
+  'Running',
+  'Succeeded',
+  'Failed'
+);
+
+CREATE TYPE "FailureClass" AS ENUM (
+  'Capacity',
+  'NonZeroExit',
+  'Lost',
+  'Timeout'
+);
+
+CREATE TABLE attempts (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  job_id UUID NOT NULL,
+  number INTEGER NOT NULL,
+  status "AttemptStatus" NOT NULL,
+  machine_id TEXT,
+  failure "FailureClass",
+  exit_code INTEGER,
+  error TEXT,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  finished_at TIMESTAMPTZ,
+  UNIQUE (job_id, number)
+);
+
+CREATE INDEX attempts_status_idx ON attempts (status);
+
+ALTER TABLE jobs ADD COLUMN spec JSONB NOT NULL DEFAULT '{}';
+
+-- Jobs waiting for their next attempt to start.
+CREATE TABLE job_queue (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  job_id UUID NOT NULL UNIQUE,
+  run_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX job_queue_run_at_idx ON job_queue (run_at);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP TABLE job_queue;
+
+ALTER TABLE jobs DROP COLUMN spec;
+
+DROP TABLE attempts;
+DROP TYPE "FailureClass";
+DROP TYPE "AttemptStatus";
+-- +goose StatementEnd
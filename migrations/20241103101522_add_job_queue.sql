@@ -0,0 +1,15 @@
+-- +goose Up
+-- +goose StatementBegin
+ALTER TYPE "JobStatus" ADD VALUE 'Queued';
+
+ALTER TABLE job_queue
+  ADD COLUMN failures INTEGER NOT NULL DEFAULT 0,
+  ADD COLUMN last_error TEXT;
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+ALTER TABLE job_queue
+  DROP COLUMN failures,
+  DROP COLUMN last_error;
+-- +goose StatementEnd
@@ -0,0 +1,34 @@
+-- +goose Up
+-- +goose StatementBegin
+CREATE TABLE schedules (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id UUID NOT NULL,
+  name TEXT NOT NULL,
+  cron TEXT NOT NULL,
+  timezone TEXT NOT NULL DEFAULT 'UTC',
+  image_version_id UUID NOT NULL,
+  enabled BOOLEAN NOT NULL DEFAULT TRUE,
+  next_run_at TIMESTAMPTZ,
+  template JSONB NOT NULL DEFAULT '{}',
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX schedules_user_id_idx ON schedules (user_id);
+CREATE INDEX schedules_next_run_at_idx ON schedules (next_run_at) WHERE enabled;
+
+CREATE TABLE schedule_runs (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  schedule_id UUID NOT NULL,
+  scheduled_for TIMESTAMPTZ NOT NULL,
+  job_id UUID,
+  error TEXT,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  UNIQUE (schedule_id, scheduled_for)
+);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP TABLE schedule_runs;
+DROP TABLE schedules;
+-- +goose StatementEnd
@@ -0,0 +1,40 @@
+-- +goose Up
+-- +goose StatementBegin
+ALTER TYPE "JobStatus" ADD VALUE 'Blocked';
+ALTER TYPE "JobStatus" ADD VALUE 'Cancelled';
+
+CREATE TABLE pipelines (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id UUID NOT NULL,
+  name TEXT NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX pipelines_user_id_idx ON pipelines (user_id);
+
+CREATE TABLE pipeline_jobs (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  pipeline_id UUID NOT NULL,
+  job_id UUID NOT NULL UNIQUE,
+  key TEXT NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  UNIQUE (pipeline_id, key)
+);
+
+CREATE TABLE job_dependencies (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  job_id UUID NOT NULL,
+  depends_on_job_id UUID NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  UNIQUE (job_id, depends_on_job_id)
+);
+
+CREATE INDEX job_dependencies_depends_on_job_id_idx ON job_dependencies (depends_on_job_id);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP TABLE job_dependencies;
+DROP TABLE pipeline_jobs;
+DROP TABLE pipelines;
+-- +goose StatementEnd
@@ -0,0 +1,29 @@
+-- +goose Up
+-- +goose StatementBegin
+CREATE TABLE job_batches (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id UUID NOT NULL,
+  name TEXT NOT NULL,
+  max_parallelism INTEGER,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX job_batches_user_id_idx ON job_batches (user_id);
+
+ALTER TABLE jobs
+  ADD COLUMN batch_id UUID,
+  ADD COLUMN batch_index INTEGER;
+
+CREATE INDEX jobs_batch_id_idx ON jobs (batch_id, batch_index);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP INDEX jobs_batch_id_idx;
+
+ALTER TABLE jobs
+  DROP COLUMN batch_id,
+  DROP COLUMN batch_index;
+
+DROP TABLE job_batches;
+-- +goose StatementEnd
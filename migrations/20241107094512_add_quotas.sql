@@ -0,0 +1,27 @@
+-- +goose Up
+-- +goose StatementBegin
+ALTER TABLE jobs
+  ADD COLUMN gpus INTEGER NOT NULL DEFAULT 0;
+
+-- Overrides of the plan's limits for a user, or limits shared by every
+-- member of an organization. Unset limits fall back to the plan's for users
+-- and don't apply to organizations.
+CREATE TABLE quotas (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id UUID UNIQUE,
+  organization_id UUID UNIQUE,
+  max_concurrent_jobs BIGINT,
+  max_gpus BIGINT,
+  max_monthly_gpu_hours DOUBLE PRECISION,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  CHECK ((user_id IS NULL) <> (organization_id IS NULL))
+);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP TABLE quotas;
+
+ALTER TABLE jobs
+  DROP COLUMN gpus;
+-- +goose StatementEnd
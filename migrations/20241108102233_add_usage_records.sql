@@ -0,0 +1,38 @@
+-- +goose Up
+-- +goose StatementBegin
+ALTER TABLE attempts
+  ADD COLUMN resources JSONB,
+  ADD COLUMN machine_started_at TIMESTAMPTZ,
+  ADD COLUMN machine_stopped_at TIMESTAMPTZ;
+
+-- What each attempt's machine ran on and for how long, written once the
+-- attempt finishes.
+CREATE TABLE usage_records (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id UUID NOT NULL,
+  job_id UUID NOT NULL,
+  attempt_id UUID NOT NULL UNIQUE,
+  cpu_kind TEXT NOT NULL,
+  cpus INTEGER NOT NULL,
+  memory_mb INTEGER NOT NULL,
+  gpu_kind TEXT,
+  gpus INTEGER NOT NULL,
+  started_at TIMESTAMPTZ NOT NULL,
+  stopped_at TIMESTAMPTZ NOT NULL,
+  seconds BIGINT NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX usage_records_user_id_stopped_at_idx ON usage_records (user_id, stopped_at);
+CREATE INDEX usage_records_job_id_idx ON usage_records (job_id);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP TABLE usage_records;
+
+ALTER TABLE attempts
+  DROP COLUMN resources,
+  DROP COLUMN machine_started_at,
+  DROP COLUMN machine_stopped_at;
+-- +goose StatementEnd
@@ -0,0 +1,43 @@
+-- +goose Up
+-- +goose StatementBegin
+CREATE TYPE "InvoiceStatus" AS ENUM (
+  'Open',
+  'Paid',
+  'Failed'
+);
+
+-- Users' customers at the payment provider. Accounts with unpaid invoices
+-- are suspended and can't submit jobs.
+CREATE TABLE billing_accounts (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id UUID NOT NULL UNIQUE,
+  customer_id TEXT NOT NULL,
+  suspended BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE TABLE invoices (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id UUID NOT NULL,
+  period_start TIMESTAMPTZ NOT NULL,
+  period_end TIMESTAMPTZ NOT NULL,
+  plan TEXT NOT NULL,
+  amount_cents BIGINT NOT NULL,
+  currency TEXT NOT NULL,
+  status "InvoiceStatus" NOT NULL,
+  lines JSONB NOT NULL DEFAULT '[]',
+  provider_invoice_id TEXT,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  paid_at TIMESTAMPTZ,
+  UNIQUE (user_id, period_start)
+);
+
+CREATE INDEX invoices_status_idx ON invoices (status);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP TABLE invoices;
+DROP TABLE billing_accounts;
+DROP TYPE "InvoiceStatus";
+-- +goose StatementEnd
@@ -0,0 +1,52 @@
+-- +goose Up
+-- +goose StatementBegin
+ALTER TYPE "FailureClass" ADD VALUE 'OutOfCredits';
+
+CREATE TYPE "LedgerEntryKind" AS ENUM (
+  'Purchase',
+  'Usage'
+);
+
+-- Prepaid credit of a user, or shared by every member of an organization.
+-- Usage of accounts with credit is debited from it instead of invoiced.
+CREATE TABLE credit_accounts (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  user_id UUID UNIQUE,
+  organization_id UUID UNIQUE,
+  balance_cents BIGINT NOT NULL DEFAULT 0,
+  spend_limit_cents BIGINT,
+  stop_jobs_at_zero BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  CHECK ((user_id IS NULL) <> (organization_id IS NULL))
+);
+
+CREATE TABLE credit_ledger_entries (
+  id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+  credit_account_id UUID NOT NULL,
+  kind "LedgerEntryKind" NOT NULL,
+  amount_cents BIGINT NOT NULL,
+  balance_cents BIGINT NOT NULL,
+  description TEXT NOT NULL,
+  usage_record_id UUID UNIQUE,
+  invoice_id UUID UNIQUE,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX credit_ledger_entries_credit_account_id_idx
+  ON credit_ledger_entries (credit_account_id, created_at);
+
+-- Invoices paid for by adding their amount to a credit account.
+CREATE TABLE credit_purchases (
+  invoice_id UUID PRIMARY KEY,
+  credit_account_id UUID NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+DROP TABLE credit_purchases;
+DROP TABLE credit_ledger_entries;
+DROP TABLE credit_accounts;
+DROP TYPE "LedgerEntryKind";
+-- +goose StatementEnd
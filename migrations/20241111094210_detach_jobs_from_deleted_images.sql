@@ -0,0 +1,18 @@
+-- +goose Up
+-- +goose StatementBegin
+-- Finished jobs outlive the images they ran, deleting an image unsets it.
+ALTER TABLE jobs ALTER COLUMN image_version_id DROP NOT NULL;
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+-- Refuse rather than throw away the history of jobs whose image is gone.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM jobs WHERE image_version_id IS NULL) THEN
+        RAISE EXCEPTION 'jobs with deleted images exist, remove them before rolling back';
+    END IF;
+END
+$$;
+ALTER TABLE jobs ALTER COLUMN image_version_id SET NOT NULL;
+-- +goose StatementEnd
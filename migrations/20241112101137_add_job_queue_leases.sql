@@ -0,0 +1,11 @@
+-- +goose Up
+-- +goose StatementBegin
+-- Workers lease entries instead of holding a transaction open while they
+-- start the job.
+ALTER TABLE job_queue ADD COLUMN locked_until TIMESTAMPTZ;
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+ALTER TABLE job_queue DROP COLUMN locked_until;
+-- +goose StatementEnd
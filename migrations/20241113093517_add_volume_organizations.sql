@@ -0,0 +1,11 @@
+-- +goose Up
+-- +goose StatementBegin
+-- Volumes shared with an organization can be managed by any of its admins.
+ALTER TABLE volumes ADD COLUMN organization_id UUID;
+CREATE INDEX volumes_organization_id_idx ON volumes (organization_id);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+ALTER TABLE volumes DROP COLUMN organization_id;
+-- +goose StatementEnd
@@ -0,0 +1,11 @@
+-- +goose Up
+-- +goose StatementBegin
+-- The reconciler leases attempts instead of holding a row lock while it
+-- checks on their machines.
+ALTER TABLE attempts ADD COLUMN reconcile_locked_until TIMESTAMPTZ;
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+ALTER TABLE attempts DROP COLUMN reconcile_locked_until;
+-- +goose StatementEnd
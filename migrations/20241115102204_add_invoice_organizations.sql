@@ -0,0 +1,12 @@
+-- +goose Up
+-- +goose StatementBegin
+-- Credit bought for an organization's account is invoiced to the admin who
+-- paid, on behalf of the organization.
+ALTER TABLE invoices ADD COLUMN organization_id UUID;
+CREATE INDEX invoices_organization_id_idx ON invoices (organization_id);
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+ALTER TABLE invoices DROP COLUMN organization_id;
+-- +goose StatementEnd
@@ -1,33 +1,98 @@
-use std::{env, sync::atomic::AtomicUsize, time::Instant};
+use std::{
+    env,
+    sync::{atomic::AtomicUsize, Arc},
+    time::Instant,
+};
 
 use deadpool_postgres::{Config, Pool, Runtime};
 use fly::apis::configuration::Configuration as FlyClient;
 use http::method::Method;
 use redis::Client as RedisClient;
 use thruster::{
-    context::typed_hyper_context::TypedHyperContext, context_state, m, middleware_fn, App, Context,
-    HyperRequest, MiddlewareNext, MiddlewareResult,
+    context::{context_ext::ContextExt, typed_hyper_context::TypedHyperContext},
+    context_state, m, middleware_fn, App, Context, HyperRequest, MiddlewareNext, MiddlewareResult,
 };
 use tokio_postgres::NoTls;
 use tracing::info;
+use uuid::Uuid;
 
 use crate::{
     controllers::{
-        images::{create_image, get_image_versions, get_images},
-        jobs::{create_job, get_jobs},
+        audit_events::get_audit_events,
+        batches::{create_job_batch, get_job_batch, get_job_batch_jobs, get_job_batches},
+        billing::{get_invoice, get_invoices, get_prices, receive_payment_webhook},
+        credits::{create_credit_purchase, get_credit_ledger, get_credits, update_credits},
+        idempotency::idempotent,
+        images::{
+            create_image, create_image_version, delete_image, delete_image_version,
+            get_image_config, get_image_versions, get_images, update_image, update_image_config,
+        },
+        jobs::{
+            create_job, download_job_artifact, get_job, get_job_artifacts, get_job_attempts,
+            get_job_usage, get_jobs,
+        },
+        oci_registry::oci_registry,
+        pipelines::{create_pipeline, get_pipeline, get_pipelines},
+        quotas::get_quotas,
+        registry_credentials::{
+            create_registry_credential, delete_registry_credential, get_registry_credentials,
+        },
+        schedules::{
+            create_schedule, delete_schedule, get_schedule, get_schedule_runs, get_schedules,
+            update_schedule,
+        },
+        secrets::{create_secret, delete_secret, get_secrets, update_secret},
         sessions::{authenticate, create_session},
+        uploads::{create_upload, get_uploads},
+        usage::get_usage,
         users::{create_user, get_user},
+        volumes::{
+            create_volume, create_volume_snapshot, delete_volume, extend_volume,
+            get_volume_snapshots, get_volumes,
+        },
+        webhooks::{configure_image_webhook, receive_registry_webhook},
     },
     models::User,
+    services::{
+        blob_store::BlobStore,
+        crypto::Encryptor,
+        object_storage::S3Client,
+        payments::{PaymentProvider, StripeClient},
+        registry::RegistryClient,
+    },
 };
 
+#[cfg(test)]
+const TEST_ENCRYPTION_KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+#[cfg(test)]
+pub(crate) const TEST_PAYMENTS_WEBHOOK_SECRET: &str = "whsec_test";
+
 #[context_state]
-pub struct State(RequestCounter, Pool, RedisClient, Option<User>, FlyClient);
+pub struct State(
+    RequestCounter,
+    Pool,
+    RedisClient,
+    Option<User>,
+    FlyClient,
+    RequestId,
+    RegistryClient,
+    Encryptor,
+    BlobStore,
+    Option<S3Client>,
+    Option<Arc<dyn PaymentProvider>>,
+);
 
 pub struct ServerConfig {
-    db: Pool,
-    cache: RedisClient,
-    fly: FlyClient,
+    pub(crate) db: Pool,
+    pub(crate) cache: RedisClient,
+    pub(crate) fly: FlyClient,
+    pub(crate) registry: RegistryClient,
+    pub(crate) encryptor: Encryptor,
+    pub(crate) blob_store: BlobStore,
+    /// Where jobs upload their artifacts, if configured.
+    pub(crate) object_storage: Option<S3Client>,
+    /// Where invoices are sent to be paid, if configured.
+    pub(crate) payments: Option<Arc<dyn PaymentProvider>>,
 }
 
 pub type Ctx = TypedHyperContext<State>;
@@ -35,6 +100,23 @@ pub type Ctx = TypedHyperContext<State>;
 #[derive(Default)]
 pub struct RequestCounter(AtomicUsize);
 
+#[derive(Clone, Debug, Default)]
+pub struct RequestId(pub String);
+
+/// The longest `X-Request-Id` a client may pick.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Whether a client's `X-Request-Id` is safe to log and record: a UUID, or a
+/// short token of letters, digits, `-`, `_` and `.`.
+pub(crate) fn is_valid_request_id(id: &str) -> bool {
+    Uuid::parse_str(id).is_ok()
+        || (!id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LENGTH
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+}
+
 pub(crate) trait ClonableCtx {
     fn clone_ctx(&self) -> Self;
 }
@@ -44,12 +126,23 @@ impl ClonableCtx for Ctx {
         let pool: &Pool = self.extra.get();
         let cache: &RedisClient = self.extra.get();
         let fly: &FlyClient = self.extra.get();
+        let registry: &RegistryClient = self.extra.get();
+        let encryptor: &Encryptor = self.extra.get();
+        let blob_store: &BlobStore = self.extra.get();
+        let object_storage: &Option<S3Client> = self.extra.get();
+        let payments: &Option<Arc<dyn PaymentProvider>> = self.extra.get();
         Ctx::new_without_request(State(
             RequestCounter::default(),
             pool.clone(),
             cache.clone(),
             None,
             fly.clone(),
+            RequestId::default(),
+            registry.clone(),
+            encryptor.clone(),
+            blob_store.clone(),
+            object_storage.clone(),
+            payments.clone(),
         ))
     }
 }
@@ -63,6 +156,12 @@ fn generate_context(request: HyperRequest, state: &ServerConfig, _path: &str) ->
             state.cache.clone(),
             None,
             state.fly.clone(),
+            RequestId::default(),
+            state.registry.clone(),
+            state.encryptor.clone(),
+            state.blob_store.clone(),
+            state.object_storage.clone(),
+            state.payments.clone(),
         ),
     )
 }
@@ -116,6 +215,21 @@ async fn count(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx>
     next(context).await
 }
 
+#[middleware_fn]
+async fn request_id(mut context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let id = context
+        .req_header("X-Request-Id")
+        .filter(|id| is_valid_request_id(id))
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    context.set("X-Request-Id", &id);
+    let request_id: &mut RequestId = context.extra.get_mut();
+    *request_id = RequestId(id);
+
+    next(context).await
+}
+
 #[middleware_fn]
 async fn cors(mut context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
     context.set("Access-Control-Allow-Origin", "*");
@@ -178,7 +292,54 @@ pub async fn generate_default_server_config() -> ServerConfig {
     fly.bearer_access_token = env::var("FLY_API_TOKEN").ok();
     info!("Running fly with configuration: {fly:#?}");
 
-    ServerConfig { db, cache, fly }
+    #[cfg(test)]
+    let encryption_key =
+        env::var("ENCRYPTION_KEY").unwrap_or_else(|_| TEST_ENCRYPTION_KEY.to_string());
+    #[cfg(not(test))]
+    let encryption_key = env::var("ENCRYPTION_KEY").expect("ENCRYPTION_KEY must be set");
+    let encryptor = Encryptor::new(&encryption_key).expect("Could not parse ENCRYPTION_KEY");
+
+    #[cfg(test)]
+    let blob_store = BlobStore::Filesystem(std::env::temp_dir().join("lim-test-blobs"));
+    #[cfg(not(test))]
+    let blob_store = BlobStore::from_env();
+
+    #[cfg(test)]
+    let object_storage = Some(S3Client::new(
+        "http://localhost:9000",
+        "lim-test",
+        "us-east-1",
+        "test",
+        "test",
+    ));
+    #[cfg(not(test))]
+    let object_storage = S3Client::from_env();
+
+    #[cfg(test)]
+    let payments: Option<Arc<dyn PaymentProvider>> = Some(Arc::new(StripeClient::new(
+        "http://localhost:12111",
+        "sk_test",
+        TEST_PAYMENTS_WEBHOOK_SECRET,
+    )));
+    #[cfg(not(test))]
+    let payments =
+        StripeClient::from_env().map(|client| Arc::new(client) as Arc<dyn PaymentProvider>);
+
+    #[cfg(test)]
+    let registry = RegistryClient::allowing_private_hosts();
+    #[cfg(not(test))]
+    let registry = RegistryClient::from_env();
+
+    ServerConfig {
+        db,
+        cache,
+        fly,
+        registry,
+        encryptor,
+        blob_store,
+        object_storage,
+        payments,
+    }
 }
 
 pub async fn init() -> App<HyperRequest, Ctx, ServerConfig> {
@@ -189,15 +350,111 @@ pub async fn init() -> App<HyperRequest, Ctx, ServerConfig> {
 
 pub async fn init_with_config(server_config: ServerConfig) -> App<HyperRequest, Ctx, ServerConfig> {
     App::<HyperRequest, Ctx, ServerConfig>::create(generate_context, server_config)
-        .middleware("/", m![profiling, count, cors])
+        .middleware("/", m![profiling, count, request_id, cors, oci_registry])
         .get("/ping", m![ping])
         .post("/users", m![create_user])
         .get("/users", m![authenticate, get_user])
         .post("/sessions", m![create_session])
-        .post("/images", m![authenticate, create_image])
+        .post("/images", m![authenticate, idempotent, create_image])
         .get("/images", m![authenticate, get_images])
+        .patch("/images/:id", m![authenticate, update_image])
+        .delete("/images/:id", m![authenticate, delete_image])
+        .get("/images/:id/config", m![authenticate, get_image_config])
+        .put("/images/:id/config", m![authenticate, update_image_config])
         .get("/images/:id/versions", m![authenticate, get_image_versions])
-        .post("/jobs", m![authenticate, create_job])
+        .post(
+            "/images/:id/versions",
+            m![authenticate, create_image_version],
+        )
+        .delete(
+            "/images/:id/versions/:version_id",
+            m![authenticate, delete_image_version],
+        )
+        .get(
+            "/images/:id/versions/:version_id/config",
+            m![authenticate, get_image_config],
+        )
+        .put(
+            "/images/:id/versions/:version_id/config",
+            m![authenticate, update_image_config],
+        )
+        .put(
+            "/images/:id/webhook",
+            m![authenticate, configure_image_webhook],
+        )
+        .post("/webhooks/registry/:image_id", m![receive_registry_webhook])
+        .post("/webhooks/payments", m![receive_payment_webhook])
+        .post("/jobs", m![authenticate, idempotent, create_job])
         .get("/jobs", m![authenticate, get_jobs])
+        .get("/jobs/:id", m![authenticate, get_job])
+        .get("/jobs/:id/attempts", m![authenticate, get_job_attempts])
+        .get("/jobs/:id/usage", m![authenticate, get_job_usage])
+        .get("/jobs/:id/artifacts", m![authenticate, get_job_artifacts])
+        .get(
+            "/jobs/:id/artifacts/:artifact_id",
+            m![authenticate, download_job_artifact],
+        )
+        .post(
+            "/job-batches",
+            m![authenticate, idempotent, create_job_batch],
+        )
+        .get("/job-batches", m![authenticate, get_job_batches])
+        .get("/job-batches/:id", m![authenticate, get_job_batch])
+        .get(
+            "/job-batches/:id/jobs",
+            m![authenticate, get_job_batch_jobs],
+        )
+        .post("/pipelines", m![authenticate, idempotent, create_pipeline])
+        .get("/pipelines", m![authenticate, get_pipelines])
+        .get("/pipelines/:id", m![authenticate, get_pipeline])
+        .post("/schedules", m![authenticate, create_schedule])
+        .get("/schedules", m![authenticate, get_schedules])
+        .get("/schedules/:id", m![authenticate, get_schedule])
+        .patch("/schedules/:id", m![authenticate, update_schedule])
+        .delete("/schedules/:id", m![authenticate, delete_schedule])
+        .get("/schedules/:id/runs", m![authenticate, get_schedule_runs])
+        .get("/quotas", m![authenticate, get_quotas])
+        .get("/usage", m![authenticate, get_usage])
+        .get("/prices", m![authenticate, get_prices])
+        .get("/invoices", m![authenticate, get_invoices])
+        .get("/invoices/:id", m![authenticate, get_invoice])
+        .get("/credits", m![authenticate, get_credits])
+        .put("/credits", m![authenticate, update_credits])
+        .post(
+            "/credits/purchases",
+            m![authenticate, idempotent, create_credit_purchase],
+        )
+        .get("/credits/ledger", m![authenticate, get_credit_ledger])
+        .get("/audit-events", m![authenticate, get_audit_events])
+        .post("/secrets", m![authenticate, create_secret])
+        .get("/secrets", m![authenticate, get_secrets])
+        .put("/secrets/:id", m![authenticate, update_secret])
+        .delete("/secrets/:id", m![authenticate, delete_secret])
+        .post("/uploads", m![authenticate, create_upload])
+        .get("/uploads", m![authenticate, get_uploads])
+        .post("/volumes", m![authenticate, create_volume])
+        .get("/volumes", m![authenticate, get_volumes])
+        .delete("/volumes/:id", m![authenticate, delete_volume])
+        .post("/volumes/:id/extend", m![authenticate, extend_volume])
+        .post(
+            "/volumes/:id/snapshots",
+            m![authenticate, create_volume_snapshot],
+        )
+        .get(
+            "/volumes/:id/snapshots",
+            m![authenticate, get_volume_snapshots],
+        )
+        .post(
+            "/registry-credentials",
+            m![authenticate, create_registry_credential],
+        )
+        .get(
+            "/registry-credentials",
+            m![authenticate, get_registry_credentials],
+        )
+        .delete(
+            "/registry-credentials/:id",
+            m![authenticate, delete_registry_credential],
+        )
         .set404(m![identity])
 }
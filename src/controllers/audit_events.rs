@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::Error,
+    models::{AuditEvent, User},
+    services::{
+        audit::{self, AuditEventFilter, MAX_PAGE_SIZE},
+        organizations::administered_organization_ids,
+    },
+    thruster_extensions::{parse_param, RequestExt},
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct AuditEventPage {
+    pub(crate) events: Vec<AuditEvent>,
+    pub(crate) next_offset: Option<i64>,
+}
+
+fn audit_event_filter(
+    params: &HashMap<String, String>,
+    administered: &[Uuid],
+) -> Result<AuditEventFilter, String> {
+    let organization_ids = match parse_param::<Uuid>(params, "organization_id")? {
+        Some(organization_id) => vec![organization_id],
+        None => administered.to_vec(),
+    };
+
+    Ok(AuditEventFilter {
+        organization_ids,
+        actor_id: parse_param(params, "actor_id")?,
+        action: params.get("action").cloned(),
+        target_type: params.get("target_type").cloned(),
+        target_id: parse_param(params, "target_id")?,
+        from: parse_param::<DateTime<Utc>>(params, "from")?,
+        to: parse_param::<DateTime<Utc>>(params, "to")?,
+        limit: parse_param(params, "limit")?
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE),
+        offset: parse_param::<i64>(params, "offset")?.unwrap_or(0).max(0),
+    })
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_audit_events(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+
+    let administered = administered_organization_ids(&db, &user.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching organizations: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    let filter = match audit_event_filter(&context.query_params(), &administered) {
+        Ok(filter) => filter,
+        Err(message) => return Err(Error::UnprocessableEntity(context, message).into()),
+    };
+
+    if filter
+        .organization_ids
+        .iter()
+        .any(|id| !administered.contains(id))
+    {
+        return Err(ThrusterError::unauthorized_error(context));
+    }
+
+    let events = audit::list(&db, &filter).await.map_err(|e| {
+        tracing::error!("An error occurred while fetching audit events: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let next_offset = (events.len() as i64 == filter.limit).then(|| filter.offset + filter.limit);
+
+    context
+        .json(&AuditEventPage {
+            events,
+            next_offset,
+        })
+        .map_err(|_e| {
+            Error::GenericError(
+                context.clone_ctx(),
+                "Serialization error".to_string(),
+                serde_json::Value::default(),
+            )
+            .into()
+        })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            images::tests::create_image_helper, sessions::tests::create_user_and_session_helper,
+        },
+        thruster_extensions::TestResponseExt,
+    };
+    use thruster::Testable;
+
+    pub(crate) async fn get_audit_events_helper(
+        app: &impl Testable,
+        session_token: &str,
+        query: &str,
+    ) -> AuditEventPage {
+        app.get(
+            &format!("/audit-events{query}"),
+            vec![(
+                "Authorization".to_string(),
+                format!("Bearer {session_token}"),
+            )],
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(200, "It should have an OK status")
+        .json::<AuditEventPage>()
+    }
+
+    #[tokio::test]
+    async fn get_audit_events_should_record_user_actions() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let page = get_audit_events_helper(&test_app, &session.token, "").await;
+        let actions = page
+            .events
+            .iter()
+            .map(|e| e.action.as_str())
+            .collect::<Vec<_>>();
+
+        assert!(actions.contains(&"user.create"), "It should record sign up");
+        assert!(
+            actions.contains(&"session.create"),
+            "It should record login"
+        );
+        assert!(
+            page.events
+                .iter()
+                .any(|e| e.action == "image.create" && e.target_id == Some(image.id)),
+            "It should record the image creation"
+        );
+        assert!(
+            page.events.iter().all(|e| e.actor_id == Some(test_user.id)),
+            "It should only contain the user's own events"
+        );
+    }
+
+    #[tokio::test]
+    async fn request_ids_should_only_be_taken_from_clients_when_well_formed() {
+        let test_app = crate::app::init().await.commit();
+
+        let too_long = "x".repeat(500);
+        for (sent, kept) in [
+            ("ci-run.42", true),
+            ("7d3c1a2e-5b0f-4c7a-9a51-0b6d2f1e9c11", true),
+            ("a\"b<script>", false),
+            (too_long.as_str(), false),
+        ] {
+            let response = (&test_app as &dyn Testable)
+                .get(
+                    "/ping",
+                    vec![("X-Request-Id".to_string(), sent.to_string())],
+                )
+                .await
+                .expect("Should correctly resolve");
+            let echoed = response
+                .headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("X-Request-Id"))
+                .map(|(_, value)| value.clone())
+                .unwrap();
+
+            assert_eq!(echoed == sent, kept, "{sent}");
+            assert!(crate::app::is_valid_request_id(&echoed));
+        }
+    }
+
+    #[tokio::test]
+    async fn get_audit_events_should_filter_and_paginate() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let _ = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let page =
+            get_audit_events_helper(&test_app, &session.token, "?action=image.create&limit=1")
+                .await;
+        assert_eq!(page.events.len(), 1, "It should respect the limit");
+        assert_eq!(
+            page.next_offset,
+            Some(1),
+            "It should point at the next page"
+        );
+
+        let page = get_audit_events_helper(
+            &test_app,
+            &session.token,
+            "?action=image.create&limit=1&offset=1",
+        )
+        .await;
+        assert_eq!(page.events.len(), 1, "It should return the second page");
+        assert_eq!(page.events[0].action, "image.create");
+    }
+
+    #[tokio::test]
+    async fn get_audit_events_should_require_organization_admin() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = (&test_app as &dyn Testable)
+            .get(
+                &format!("/audit-events?organization_id={}", Uuid::new_v4()),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+    }
+
+    #[tokio::test]
+    async fn get_audit_events_should_reject_malformed_filters() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        for query in ["?actor_id=me", "?from=yesterday", "?limit=many"] {
+            let _ = (&test_app as &dyn Testable)
+                .get(
+                    &format!("/audit-events{query}"),
+                    vec![(
+                        "Authorization".to_string(),
+                        format!("Bearer {}", session.token),
+                    )],
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(422, "It should have an unprocessable entity status");
+        }
+    }
+}
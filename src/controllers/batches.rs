@@ -0,0 +1,704 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use chrono::Utc;
+use deadpool_postgres::{GenericClient, Pool};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::{submission_error, Error},
+    models::{Image, ImageVersion, Job, JobBatch, JobStatus, User},
+    services::{
+        audit,
+        batches::{
+            add_to_batch, batch_job_ids, batch_progress, expand_grid, merge_params, release_batch,
+            BatchProgress, MAX_BATCH_JOBS,
+        },
+        billing::check_billing,
+        credits::check_credits,
+        image_config::effective_config,
+        jobs::{record_job, validate_job, ValidJob},
+        quotas::check_quota,
+        schedules::JobTemplate,
+    },
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct CreateJobBatch {
+    pub(crate) name: String,
+    pub(crate) image_id: Uuid,
+    pub(crate) image_version_id: Uuid,
+    /// How many of the batch's jobs may be queued or running at once,
+    /// unlimited if unset.
+    pub(crate) max_parallelism: Option<i32>,
+    /// One job per parameter set, each layered over the template's params.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) parameter_sets: Vec<Value>,
+    /// One job per combination of the grid's values, instead of
+    /// `parameter_sets`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) parameter_grid: Option<BTreeMap<String, Vec<Value>>>,
+    #[serde(flatten)]
+    pub(crate) template: JobTemplate,
+}
+
+/// A batch along with how far along its jobs are.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct JobBatchDetails {
+    #[serde(flatten)]
+    pub(crate) batch: JobBatch,
+    pub(crate) progress: BatchProgress,
+}
+
+/// Submits one job per parameter set. Jobs are recorded as `Blocked` and
+/// queued in order as the batch's `max_parallelism` allows.
+#[thruster::json_request]
+pub(crate) async fn create_job_batch(
+    create_job_batch: CreateJobBatch,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let CreateJobBatch {
+        name,
+        image_id,
+        image_version_id,
+        max_parallelism,
+        parameter_sets,
+        parameter_grid,
+        template,
+    } = create_job_batch;
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let mut db = db.get().await.unwrap();
+    let db = db.transaction().await.unwrap();
+
+    if name.trim().is_empty() {
+        return Err(Error::UnprocessableEntity(context, "Batches need a name".to_string()).into());
+    }
+    if max_parallelism.is_some_and(|max_parallelism| max_parallelism < 1) {
+        return Err(Error::UnprocessableEntity(
+            context,
+            "max_parallelism must be at least 1".to_string(),
+        )
+        .into());
+    }
+    // Volumes are only attached to one running job at a time.
+    if template.spec.volume.is_some() && max_parallelism != Some(1) {
+        return Err(Error::UnprocessableEntity(
+            context,
+            "Batches that mount a volume need a max_parallelism of 1".to_string(),
+        )
+        .into());
+    }
+
+    let parameter_sets = match (parameter_sets.is_empty(), parameter_grid) {
+        (false, None) => parameter_sets,
+        (true, Some(grid)) => match expand_grid(&grid) {
+            Ok(parameter_sets) => parameter_sets,
+            Err(message) => return Err(Error::UnprocessableEntity(context, message).into()),
+        },
+        _ => {
+            return Err(Error::UnprocessableEntity(
+                context,
+                "Batches need either parameter_sets or a parameter_grid".to_string(),
+            )
+            .into())
+        }
+    };
+    if parameter_sets.is_empty() || parameter_sets.len() > MAX_BATCH_JOBS {
+        return Err(Error::UnprocessableEntity(
+            context,
+            format!("Batches need between 1 and {MAX_BATCH_JOBS} jobs"),
+        )
+        .into());
+    }
+
+    let mut inputs = vec![];
+    for (index, parameter_set) in parameter_sets.iter().enumerate() {
+        let params = match merge_params(template.input.params.as_ref(), parameter_set) {
+            Ok(params) => params,
+            Err(message) => {
+                return Err(Error::UnprocessableEntity(
+                    context,
+                    format!("Parameter set {index}: {message}"),
+                )
+                .into())
+            }
+        };
+        let mut input = template.input.clone();
+        input.params = Some(params);
+        inputs.push(input);
+    }
+
+    let image = Image::read(&db, &image_id).await.map_err(|e| {
+        tracing::error!("An error occurred while fetching an image: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    if image.user_id != user.id {
+        tracing::error!("User does not own image");
+        return Err(ThrusterError::unauthorized_error(context));
+    }
+
+    let image_version = ImageVersion::read_where_image_id(&db, &image_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching an image: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?
+        .into_iter()
+        .find(|v| v.id == image_version_id)
+        .ok_or_else(|| ThrusterError::not_found_error(context.clone_ctx()))?;
+
+    // The template is checked in full once, the params of every set.
+    let ValidJob { resources, .. } = validate_job(
+        &db,
+        &user.id,
+        &image,
+        &image_version,
+        &template.spec,
+        &inputs[0],
+    )
+    .await
+    .map_err(|e| submission_error(&context, e))?;
+    let config = effective_config(&db, &image.id, &image_version.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching an image config: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+    for (index, input) in inputs.iter().enumerate() {
+        if let Err(message) = input.validate(&config) {
+            return Err(Error::UnprocessableEntity(
+                context,
+                format!("Parameter set {index}: {message}"),
+            )
+            .into());
+        }
+    }
+
+    check_billing(&db, &user.id)
+        .await
+        .map_err(|e| submission_error(&context, e))?;
+    check_credits(&db, &user.id, Utc::now())
+        .await
+        .map_err(|e| submission_error(&context, e))?;
+
+    // The jobs the batch queues straight away are held to the user's quotas,
+    // the rest take the slots they free up.
+    let queued = max_parallelism.map_or(inputs.len(), |max_parallelism| {
+        inputs.len().min(max_parallelism as usize)
+    }) as i64;
+    check_quota(
+        &db,
+        &user.id,
+        queued,
+        queued * i64::from(resources.gpus),
+        Utc::now(),
+    )
+    .await
+    .map_err(|e| submission_error(&context, e))?;
+
+    let batch = JobBatch::create(&db, user.id, name, max_parallelism)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while creating a job batch: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    for (index, input) in inputs.iter().enumerate() {
+        let job = record_job(
+            &db,
+            &user.id,
+            &image,
+            &image_version,
+            &template.spec,
+            input,
+            JobStatus::Blocked,
+        )
+        .await
+        .map_err(|e| submission_error(&context, e))?;
+        add_to_batch(&db, &job.id, &batch.id, index as i32)
+            .await
+            .map_err(|e| {
+                tracing::error!("An error occurred while adding a job to a batch: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?;
+    }
+    release_batch(&db, &batch.id, Utc::now())
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while queueing a batch's jobs: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "job_batch.create",
+        "job_batch",
+        Some(batch.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let progress = batch_progress(&db, &batch.id).await.map_err(|e| {
+        tracing::error!("An error occurred while counting a batch's jobs: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    db.commit().await.unwrap();
+
+    context
+        .json(&JobBatchDetails { batch, progress })
+        .map_err(|_e| {
+            Error::GenericError(
+                context.clone_ctx(),
+                "Serialization error".to_string(),
+                serde_json::Value::default(),
+            )
+            .into()
+        })?;
+
+    context.status(201);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_job_batches(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let batches = JobBatch::read_where_user_id(&db, &user.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching job batches: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    let mut details = vec![];
+    for batch in batches {
+        let progress = batch_progress(&db, &batch.id).await.map_err(|e| {
+            tracing::error!("An error occurred while counting a batch's jobs: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+        details.push(JobBatchDetails { batch, progress });
+    }
+
+    context.json(&details).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+async fn owned_batch(
+    context: &Ctx,
+    db: &impl GenericClient,
+    user_id: &Uuid,
+) -> Result<JobBatch, ThrusterError<Ctx>> {
+    let batch_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid job batch id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    JobBatch::read(db, &batch_id)
+        .await
+        .ok()
+        .filter(|batch| batch.user_id == *user_id)
+        .ok_or_else(|| ThrusterError::not_found_error(context.clone_ctx()))
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_job_batch(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let batch = owned_batch(&context, &db, &user.id).await?;
+    let progress = batch_progress(&db, &batch.id).await.map_err(|e| {
+        tracing::error!("An error occurred while counting a batch's jobs: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context
+        .json(&JobBatchDetails { batch, progress })
+        .map_err(|_e| {
+            Error::GenericError(
+                context.clone_ctx(),
+                "Serialization error".to_string(),
+                serde_json::Value::default(),
+            )
+            .into()
+        })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+/// Lists a batch's jobs in the order of their parameter sets.
+#[thruster::middleware]
+pub(crate) async fn get_job_batch_jobs(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let batch = owned_batch(&context, &db, &user.id).await?;
+    let job_ids = batch_job_ids(&db, &batch.id).await.map_err(|e| {
+        tracing::error!("An error occurred while fetching a batch's jobs: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let mut jobs = vec![];
+    for job_id in job_ids {
+        jobs.push(Job::read(&db, &job_id).await.map_err(|e| {
+            tracing::error!("An error occurred while fetching a job: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?);
+    }
+
+    context.json(&jobs).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            images::tests::{create_image_helper, create_image_version_helper},
+            pipelines::tests::start_job,
+            sessions::tests::create_user_and_session_helper,
+        },
+        models::BillingAccount,
+        services::jobs::{complete_attempt, read_job_input, JobInput, JobSpec},
+        thruster_extensions::TestResponseExt,
+    };
+    use serde_json::json;
+    use thruster::Testable;
+
+    fn create_batch_body(
+        image: &Image,
+        image_version: &ImageVersion,
+        parameter_sets: Vec<Value>,
+        parameter_grid: Option<BTreeMap<String, Vec<Value>>>,
+    ) -> Vec<u8> {
+        serde_json::to_vec(&CreateJobBatch {
+            name: "sweep".to_string(),
+            image_id: image.id,
+            image_version_id: image_version.id,
+            max_parallelism: Some(2),
+            parameter_sets,
+            parameter_grid,
+            template: JobTemplate {
+                spec: JobSpec {
+                    preset: Some("cpu-small".to_string()),
+                    ..Default::default()
+                },
+                input: JobInput {
+                    params: Some(json!({ "epochs": 10 })),
+                    ..Default::default()
+                },
+            },
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn create_job_batch_should_respect_max_parallelism() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+
+        let details = (&test_app as &dyn Testable)
+            .post(
+                "/job-batches",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                create_batch_body(
+                    &image,
+                    &image_version,
+                    vec![],
+                    Some(BTreeMap::from([
+                        ("lr".to_string(), vec![json!(0.1), json!(0.01)]),
+                        ("batch_size".to_string(), vec![json!(32), json!(64)]),
+                    ])),
+                ),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status")
+            .json::<JobBatchDetails>();
+        assert_eq!(
+            details.progress,
+            BatchProgress {
+                total: 4,
+                waiting: 2,
+                queued: 2,
+                ..Default::default()
+            }
+        );
+
+        let jobs = (&test_app as &dyn Testable)
+            .get(
+                &format!("/job-batches/{}/jobs", details.batch.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an ok status")
+            .json::<Vec<Job>>();
+        assert_eq!(jobs.len(), 4);
+        assert_eq!(
+            read_job_input(&db.get().await.unwrap(), &jobs[0].id)
+                .await
+                .unwrap()
+                .params,
+            Some(json!({ "batch_size": 32, "epochs": 10, "lr": 0.1 }))
+        );
+
+        let attempt = start_job(&jobs[0].id).await;
+        complete_attempt(&db.get().await.unwrap(), &attempt, Utc::now())
+            .await
+            .unwrap();
+
+        let details = (&test_app as &dyn Testable)
+            .get(
+                &format!("/job-batches/{}", details.batch.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an ok status")
+            .json::<JobBatchDetails>();
+        assert_eq!(
+            details.progress,
+            BatchProgress {
+                total: 4,
+                waiting: 1,
+                queued: 2,
+                completed: 1,
+                ..Default::default()
+            },
+            "A finished job should free its slot"
+        );
+    }
+
+    #[tokio::test]
+    async fn release_batch_should_hold_jobs_back_past_the_quota() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+
+        let details = (&test_app as &dyn Testable)
+            .post(
+                "/job-batches",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                create_batch_body(
+                    &image,
+                    &image_version,
+                    vec![json!({}), json!({}), json!({}), json!({})],
+                    None,
+                ),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status")
+            .json::<JobBatchDetails>();
+        db.get()
+            .await
+            .unwrap()
+            .execute(
+                "INSERT INTO quotas (user_id, max_concurrent_jobs) VALUES ($1, 1)",
+                &[&test_user.id],
+            )
+            .await
+            .unwrap();
+
+        let get_progress = || async {
+            (&test_app as &dyn Testable)
+                .get(
+                    &format!("/job-batches/{}", details.batch.id),
+                    vec![(
+                        "Authorization".to_string(),
+                        format!("Bearer {}", session.token),
+                    )],
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(200, "It should have an ok status")
+                .json::<JobBatchDetails>()
+                .progress
+        };
+        let jobs = batch_job_ids(&db.get().await.unwrap(), &details.batch.id)
+            .await
+            .unwrap();
+
+        let attempt = start_job(&jobs[0]).await;
+        complete_attempt(&db.get().await.unwrap(), &attempt, Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(
+            get_progress().await,
+            BatchProgress {
+                total: 4,
+                waiting: 2,
+                queued: 1,
+                completed: 1,
+                ..Default::default()
+            },
+            "It should keep the freed slot while over the quota"
+        );
+
+        let attempt = start_job(&jobs[1]).await;
+        complete_attempt(&db.get().await.unwrap(), &attempt, Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(
+            get_progress().await,
+            BatchProgress {
+                total: 4,
+                waiting: 1,
+                queued: 1,
+                completed: 2,
+                ..Default::default()
+            },
+            "It should release the job once there's room"
+        );
+    }
+
+    #[tokio::test]
+    async fn create_job_batch_should_refuse_suspended_accounts() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+        BillingAccount::create(
+            &db.get().await.unwrap(),
+            test_user.id,
+            "cus_test".to_string(),
+            true,
+        )
+        .await
+        .unwrap();
+
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                "/job-batches",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                create_batch_body(&image, &image_version, vec![json!({})], None),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(402, "It should suspend batch submission");
+
+        assert!(
+            JobBatch::read_where_user_id(&db.get().await.unwrap(), &test_user.id)
+                .await
+                .unwrap()
+                .is_empty(),
+            "It should not create the batch"
+        );
+    }
+
+    #[tokio::test]
+    async fn create_job_batch_should_need_one_kind_of_parameters() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+
+        for (parameter_sets, parameter_grid) in [
+            (vec![], None),
+            (
+                vec![json!({ "lr": 0.1 })],
+                Some(BTreeMap::from([("lr".to_string(), vec![json!(0.1)])])),
+            ),
+            (vec![json!([0.1])], None),
+        ] {
+            let _ = (&test_app as &dyn Testable)
+                .post(
+                    "/job-batches",
+                    vec![(
+                        "Authorization".to_string(),
+                        format!("Bearer {}", session.token),
+                    )],
+                    create_batch_body(&image, &image_version, parameter_sets, parameter_grid),
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(422, "It should have an unprocessable entity status");
+        }
+    }
+}
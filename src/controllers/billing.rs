@@ -0,0 +1,311 @@
+use std::{str::FromStr, sync::Arc};
+
+use chrono::Utc;
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::Error,
+    models::{Invoice, User},
+    services::{
+        billing::{handle_payment_event, read_invoice_lines, InvoiceLine, CURRENCY},
+        payments::{PaymentError, PaymentProvider, STRIPE_SIGNATURE_HEADER},
+        plans::{Plan, PLANS},
+        presets::{ResourcePreset, RESOURCE_PRESETS},
+    },
+    thruster_extensions::RequestExt,
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct InvoiceDetails {
+    #[serde(flatten)]
+    pub(crate) invoice: Invoice,
+    pub(crate) lines: Vec<InvoiceLine>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Prices {
+    currency: &'static str,
+    plans: &'static [Plan],
+    /// Machine time is billed at the price of the preset with the same
+    /// GPU, or CPU for machines without one.
+    presets: &'static [ResourcePreset],
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_prices(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    context
+        .json(&Prices {
+            currency: CURRENCY,
+            plans: PLANS,
+            presets: RESOURCE_PRESETS,
+        })
+        .map_err(|_e| {
+            Error::GenericError(
+                context.clone_ctx(),
+                "Serialization error".to_string(),
+                serde_json::Value::default(),
+            )
+            .into()
+        })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_invoices(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let mut invoices = Invoice::read_where_user_id(&db, &user.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching invoices: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+    invoices.sort_by_key(|invoice| std::cmp::Reverse(invoice.period_start));
+
+    context.json(&invoices).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_invoice(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let invoice_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid invoice id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let invoice = Invoice::read(&db, &invoice_id)
+        .await
+        .ok()
+        .filter(|invoice| invoice.user_id == user.id)
+        .ok_or_else(|| ThrusterError::not_found_error(context.clone_ctx()))?;
+
+    let lines = read_invoice_lines(&db, &invoice.id).await.map_err(|e| {
+        tracing::error!("An error occurred while fetching invoice lines: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context
+        .json(&InvoiceDetails { invoice, lines })
+        .map_err(|_e| {
+            Error::GenericError(
+                context.clone_ctx(),
+                "Serialization error".to_string(),
+                serde_json::Value::default(),
+            )
+            .into()
+        })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+/// Receives the payment provider's events about invoices. The request is
+/// authenticated by its signature rather than a session.
+#[thruster::middleware]
+pub(crate) async fn receive_payment_webhook(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let payments: &Option<Arc<dyn PaymentProvider>> = context.extra.get();
+    let Some(payments) = payments.clone() else {
+        return Err(ThrusterError::not_found_error(context));
+    };
+    let signature = context
+        .req_header(STRIPE_SIGNATURE_HEADER)
+        .map(|v| v.to_string())
+        .unwrap_or_default();
+
+    let payload = context.take_body().await.map_err(|e| {
+        tracing::error!("Could not read webhook payload: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let event = match payments.parse_webhook(&payload, &signature, Utc::now()) {
+        Ok(event) => event,
+        Err(PaymentError::InvalidSignature) => {
+            return Err(ThrusterError::unauthorized_error(context))
+        }
+        Err(e) => return Err(Error::UnprocessableEntity(context, e.to_string()).into()),
+    };
+
+    if let Some(event) = event {
+        let db: &Pool = context.extra.get();
+        let mut db = db.get().await.unwrap();
+        let db = db.transaction().await.unwrap();
+
+        handle_payment_event(&db, event, Utc::now())
+            .await
+            .map_err(|e| {
+                tracing::error!("An error occurred while handling a payment event: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?;
+
+        db.commit().await.map_err(|e| {
+            tracing::error!("An error occurred while committing a payment event: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+    }
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app::TEST_PAYMENTS_WEBHOOK_SECRET,
+        controllers::{
+            images::tests::create_image_helper, quotas::tests::submit_job,
+            sessions::tests::create_user_and_session_helper,
+        },
+        models::{BillingAccount, InvoiceStatus},
+        services::payments::stripe_signature,
+        thruster_extensions::TestResponseExt,
+    };
+    use chrono::TimeZone;
+    use thruster::{testing::TestResponse, Testable};
+
+    fn invoice_event(event_type: &str, invoice_id: &Uuid) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "type": event_type,
+            "data": {
+                "object": {
+                    "id": "in_test",
+                    "metadata": { "invoice_id": invoice_id.to_string() }
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    async fn send_event(app: &impl Testable, secret: &str, payload: Vec<u8>) -> TestResponse {
+        let signature = stripe_signature(secret, &payload, Utc::now().timestamp());
+
+        app.post(
+            "/webhooks/payments",
+            vec![(STRIPE_SIGNATURE_HEADER.to_string(), signature)],
+            payload,
+        )
+        .await
+        .expect("Should correctly resolve")
+    }
+
+    #[tokio::test]
+    async fn receive_payment_webhook_should_suspend_unpaid_accounts() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        BillingAccount::create(&db, test_user.id, "cus_test".to_string(), false)
+            .await
+            .unwrap();
+        let invoice = Invoice::create(
+            &db,
+            test_user.id,
+            None,
+            Utc.with_ymd_and_hms(2024, 10, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 11, 1, 0, 0, 0).unwrap(),
+            "pro".to_string(),
+            4_900,
+            CURRENCY.to_string(),
+            InvoiceStatus::Open,
+            Some("in_test".to_string()),
+        )
+        .await
+        .unwrap();
+
+        send_event(
+            &test_app,
+            TEST_PAYMENTS_WEBHOOK_SECRET,
+            invoice_event("invoice.payment_failed", &invoice.id),
+        )
+        .await
+        .expect_status(200, "It should have an ok status");
+        submit_job(&test_app, &session.token, &image.id, "cpu-small")
+            .await
+            .expect_status(402, "It should suspend job submission");
+
+        send_event(
+            &test_app,
+            TEST_PAYMENTS_WEBHOOK_SECRET,
+            invoice_event("invoice.paid", &invoice.id),
+        )
+        .await
+        .expect_status(200, "It should have an ok status");
+        submit_job(&test_app, &session.token, &image.id, "cpu-small")
+            .await
+            .expect_status(201, "It should reinstate the account once paid");
+
+        let details = (&test_app as &dyn Testable)
+            .get(
+                &format!("/invoices/{}", invoice.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an ok status")
+            .json::<InvoiceDetails>();
+
+        assert_eq!(details.invoice.status, InvoiceStatus::Paid);
+        assert!(details.invoice.paid_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn receive_payment_webhook_should_reject_bad_signatures() {
+        let test_app = crate::app::init().await.commit();
+
+        send_event(
+            &test_app,
+            "wrong",
+            invoice_event("invoice.paid", &Uuid::new_v4()),
+        )
+        .await
+        .expect_status(401, "It should have an unauthorized status");
+    }
+}
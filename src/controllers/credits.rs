@@ -0,0 +1,539 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use deadpool_postgres::{GenericClient, Pool};
+use serde::{Deserialize, Serialize};
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::Error,
+    models::{CreditLedgerEntry, User},
+    services::{
+        audit,
+        billing::submit_invoice,
+        credits::{configure_credit_account, credit_status, find_credit_account, purchase_credits},
+        organizations::administered_organization_ids,
+        payments::PaymentProvider,
+    },
+    thruster_extensions::{parse_param, RequestExt},
+};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct ConfigureCreditAccount {
+    /// Configures the account shared by an organization's members instead.
+    #[serde(default)]
+    pub(crate) organization_id: Option<Uuid>,
+    #[serde(default)]
+    pub(crate) spend_limit_cents: Option<i64>,
+    #[serde(default)]
+    pub(crate) stop_jobs_at_zero: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct PurchaseCredits {
+    #[serde(default)]
+    pub(crate) organization_id: Option<Uuid>,
+    pub(crate) amount_cents: i64,
+}
+
+/// Whether the user may manage the credit of the organization, or their own
+/// when unset.
+async fn may_manage(
+    context: &Ctx,
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    organization_id: Option<Uuid>,
+) -> Result<bool, ThrusterError<Ctx>> {
+    let Some(organization_id) = organization_id else {
+        return Ok(true);
+    };
+    let administered = administered_organization_ids(db, user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching organizations: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    Ok(administered.contains(&organization_id))
+}
+
+/// Lists the user's credit account and those of the organizations they
+/// administer.
+#[thruster::middleware]
+pub(crate) async fn get_credits(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+    let now = Utc::now();
+
+    let administered = administered_organization_ids(&db, &user.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching organizations: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    let mut statuses = vec![];
+    for organization_id in std::iter::once(None).chain(administered.into_iter().map(Some)) {
+        let account = find_credit_account(&db, &user.id, organization_id)
+            .await
+            .map_err(|e| {
+                tracing::error!("An error occurred while fetching a credit account: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?;
+        if let Some(account) = account {
+            statuses.push(credit_status(&db, &account.id, now).await.map_err(|e| {
+                tracing::error!("An error occurred while fetching a credit balance: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?);
+        }
+    }
+
+    context.json(&statuses).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+/// Creates or updates a credit account. Once a user's jobs are charged to
+/// one they're no longer invoiced for machine time.
+#[thruster::json_request]
+pub(crate) async fn update_credits(
+    configure_credit_account: ConfigureCreditAccount,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let ConfigureCreditAccount {
+        organization_id,
+        spend_limit_cents,
+        stop_jobs_at_zero,
+    } = configure_credit_account;
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    if !may_manage(&context, &db, &user.id, organization_id).await? {
+        return Err(ThrusterError::unauthorized_error(context));
+    }
+    if spend_limit_cents.is_some_and(|limit| limit < 0) {
+        return Err(Error::UnprocessableEntity(
+            context,
+            "spend_limit_cents can't be negative".to_string(),
+        )
+        .into());
+    }
+
+    let account = configure_credit_account(
+        &db,
+        &user.id,
+        organization_id,
+        spend_limit_cents,
+        stop_jobs_at_zero,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while configuring a credit account: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "credit_account.update",
+        "credit_account",
+        Some(account.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+    let status = credit_status(&db, &account.id, Utc::now())
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching a credit balance: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    context.json(&status).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+/// Invoices the user for credit, which is added to the account when the
+/// payment provider reports the invoice paid.
+#[thruster::json_request]
+pub(crate) async fn create_credit_purchase(
+    purchase: PurchaseCredits,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let PurchaseCredits {
+        organization_id,
+        amount_cents,
+    } = purchase;
+    let payments: &Option<Arc<dyn PaymentProvider>> = context.extra.get();
+    let Some(payments) = payments.clone() else {
+        return Err(ThrusterError::not_found_error(context));
+    };
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let mut db = db.get().await.unwrap();
+
+    if !may_manage(&context, &db, &user.id, organization_id).await? {
+        return Err(ThrusterError::unauthorized_error(context));
+    }
+    if amount_cents <= 0 {
+        return Err(Error::UnprocessableEntity(
+            context,
+            "amount_cents must be positive".to_string(),
+        )
+        .into());
+    }
+
+    let transaction = db.transaction().await.unwrap();
+    let account = find_credit_account(&transaction, &user.id, organization_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching a credit account: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+    let Some(account) = account else {
+        return Err(Error::UnprocessableEntity(
+            context,
+            "There's no credit account to add to, create one with PUT /credits".to_string(),
+        )
+        .into());
+    };
+    let invoice = purchase_credits(
+        &transaction,
+        &user.id,
+        &account.id,
+        amount_cents,
+        Utc::now(),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while purchasing credit: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+    audit::record(
+        &transaction,
+        &context,
+        Some(user.id),
+        "credit_purchase.create",
+        "invoice",
+        Some(invoice.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+    transaction.commit().await.map_err(|e| {
+        tracing::error!("An error occurred while committing a credit purchase: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    // Invoices that can't be sent now are sent with the next billing run.
+    if let Err(e) = submit_invoice(&db, &payments, &invoice).await {
+        tracing::error!("Could not send invoice {}: {e:#?}", invoice.id);
+    }
+
+    context.json(&invoice).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(201);
+
+    Ok(context)
+}
+
+/// Lists the changes to the user's credit balance, newest first, or with
+/// `organization_id` those of an organization they administer.
+#[thruster::middleware]
+pub(crate) async fn get_credit_ledger(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let organization_id = match parse_param(&context.query_params(), "organization_id") {
+        Ok(organization_id) => organization_id,
+        Err(message) => return Err(Error::UnprocessableEntity(context, message).into()),
+    };
+    if !may_manage(&context, &db, &user.id, organization_id).await? {
+        return Err(ThrusterError::unauthorized_error(context));
+    }
+
+    let account = find_credit_account(&db, &user.id, organization_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching a credit account: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+    let mut entries = match account {
+        Some(account) => CreditLedgerEntry::read_where_credit_account_id(&db, &account.id)
+            .await
+            .map_err(|e| {
+                tracing::error!("An error occurred while fetching ledger entries: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?,
+        None => vec![],
+    };
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.created_at));
+
+    context.json(&entries).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            audit_events::tests::get_audit_events_helper, images::tests::create_image_helper,
+            quotas::tests::submit_job, sessions::tests::create_user_and_session_helper,
+        },
+        models::{BillingAccount, Invoice, LedgerEntryKind, OrganizationMember},
+        services::{billing::handle_payment_event, credits::CreditStatus, payments::PaymentEvent},
+        thruster_extensions::TestResponseExt,
+    };
+    use thruster::{testing::TestResponse, Testable};
+
+    async fn update_credits_helper(
+        app: &impl Testable,
+        token: &str,
+        configure: ConfigureCreditAccount,
+    ) -> TestResponse {
+        app.put(
+            "/credits",
+            vec![("Authorization".to_string(), format!("Bearer {token}"))],
+            serde_json::to_vec(&configure).unwrap(),
+        )
+        .await
+        .expect("Should correctly resolve")
+    }
+
+    #[tokio::test]
+    async fn create_job_should_require_credit_once_an_account_exists() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let status =
+            update_credits_helper(&test_app, &session.token, ConfigureCreditAccount::default())
+                .await
+                .expect_status(200, "It should have an ok status")
+                .json::<CreditStatus>();
+        assert_eq!(status.available_cents, 0);
+
+        submit_job(&test_app, &session.token, &image.id, "cpu-small")
+            .await
+            .expect_status(402, "It should require credit");
+
+        let invoice = purchase_credits(&db, &test_user.id, &status.account.id, 1_000, Utc::now())
+            .await
+            .unwrap();
+        handle_payment_event(&db, PaymentEvent::InvoicePaid(invoice.id), Utc::now())
+            .await
+            .unwrap();
+        submit_job(&test_app, &session.token, &image.id, "cpu-small")
+            .await
+            .expect_status(201, "It should have a created status");
+
+        let entries = (&test_app as &dyn Testable)
+            .get(
+                "/credits/ledger",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an ok status")
+            .json::<Vec<CreditLedgerEntry>>();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].kind, LedgerEntryKind::Purchase);
+        assert_eq!(entries[0].amount_cents, 1_000);
+        assert_eq!(entries[0].balance_cents, 1_000);
+
+        update_credits_helper(
+            &test_app,
+            &session.token,
+            ConfigureCreditAccount {
+                spend_limit_cents: Some(0),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect_status(200, "It should have an ok status");
+        submit_job(&test_app, &session.token, &image.id, "cpu-small")
+            .await
+            .expect_status(402, "It should respect the spend limit");
+    }
+
+    #[tokio::test]
+    async fn update_credits_should_require_organization_admins() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        let (admin, _) = create_user_and_session_helper(&test_app).await;
+        let (member, member_session) = create_user_and_session_helper(&test_app).await;
+        let organization_id = OrganizationMember::read_where_user_id(&db, &admin.id)
+            .await
+            .unwrap()[0]
+            .organization_id;
+        OrganizationMember::create(
+            &db,
+            organization_id,
+            member.id,
+            crate::models::OrganizationRole::Member,
+        )
+        .await
+        .unwrap();
+
+        update_credits_helper(
+            &test_app,
+            &member_session.token,
+            ConfigureCreditAccount {
+                organization_id: Some(organization_id),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect_status(401, "Only admins should manage an organization's credit");
+    }
+
+    #[tokio::test]
+    async fn create_credit_purchase_should_invoice_organizations_without_suspending_the_buyer() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        let (admin, session) = create_user_and_session_helper(&test_app).await;
+        let organization_id = OrganizationMember::read_where_user_id(&db, &admin.id)
+            .await
+            .unwrap()[0]
+            .organization_id;
+        BillingAccount::create(&db, admin.id, "cus_test".to_string(), false)
+            .await
+            .unwrap();
+        update_credits_helper(
+            &test_app,
+            &session.token,
+            ConfigureCreditAccount {
+                organization_id: Some(organization_id),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect_status(200, "It should have an ok status");
+
+        let invoice = (&test_app as &dyn Testable)
+            .post(
+                "/credits/purchases",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&PurchaseCredits {
+                    organization_id: Some(organization_id),
+                    amount_cents: 1_000,
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status")
+            .json::<Invoice>();
+        assert_eq!(invoice.user_id, admin.id);
+        assert_eq!(
+            invoice.organization_id,
+            Some(organization_id),
+            "It should invoice the credit on the organization's behalf"
+        );
+
+        handle_payment_event(&db, PaymentEvent::InvoiceFailed(invoice.id), Utc::now())
+            .await
+            .unwrap();
+        let suspended: bool = db
+            .query_one(
+                "SELECT suspended FROM billing_accounts WHERE user_id = $1",
+                &[&admin.id],
+            )
+            .await
+            .unwrap()
+            .get("suspended");
+        assert!(
+            !suspended,
+            "A failed credit purchase shouldn't suspend the buyer"
+        );
+
+        let page = get_audit_events_helper(&test_app, &session.token, "").await;
+        for (action, target_type) in [
+            ("credit_account.update", "credit_account"),
+            ("credit_purchase.create", "invoice"),
+        ] {
+            assert!(
+                page.events
+                    .iter()
+                    .any(|e| e.action == action && e.target_type == target_type),
+                "It should record {action}"
+            );
+        }
+        assert!(page.events.iter().any(|e| e.target_id == Some(invoice.id)));
+    }
+}
@@ -0,0 +1,249 @@
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::Error,
+    models::User,
+    services::idempotency::{
+        hold_during, release, request_hash, reserve, store_response, Reservation, StoredResponse,
+        IDEMPOTENCY_KEY_HEADER, MAX_KEY_LENGTH, REPLAYED_HEADER,
+    },
+    thruster_extensions::RequestExt,
+};
+
+/// Makes the endpoint after it safe to retry: requests sent with an
+/// `Idempotency-Key` are only handled once per user and key, later ones get
+/// the first response back. Must run after `authenticate`.
+#[thruster::middleware]
+pub(crate) async fn idempotent(
+    mut context: Ctx,
+    next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let Some(key) = context
+        .req_header(IDEMPOTENCY_KEY_HEADER)
+        .map(|v| v.to_string())
+    else {
+        return next(context).await;
+    };
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(Error::UnprocessableEntity(
+            context,
+            format!("{IDEMPOTENCY_KEY_HEADER} must be 1 to {MAX_KEY_LENGTH} characters"),
+        )
+        .into());
+    }
+
+    let user: &Option<User> = context.extra.get();
+    let user_id = user.as_ref().unwrap().id;
+    let (method, path) = context
+        .hyper_request
+        .as_ref()
+        .map(|r| {
+            (
+                r.request.method().to_string(),
+                r.request.uri().path().to_string(),
+            )
+        })
+        .unwrap_or_default();
+    let body = context.take_body().await.map_err(|e| {
+        tracing::error!("Could not read request body: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+    let hash = request_hash(&method, &path, &body);
+    context.restore_body(body);
+
+    let redis: &redis::Client = context.extra.get();
+    let mut conn = redis
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e| {
+            tracing::error!("Could not connect to redis: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    let reservation = reserve(&mut conn, &user_id, &key, &hash)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while reserving an idempotency key: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+    let claim = match reservation {
+        Reservation::Reserved(claim) => claim,
+        Reservation::InProgress => {
+            return Err(Error::Conflict(
+                context,
+                format!("A request with this {IDEMPOTENCY_KEY_HEADER} is in progress"),
+            )
+            .into())
+        }
+        Reservation::Mismatch => {
+            return Err(Error::UnprocessableEntity(
+                context,
+                format!("{IDEMPOTENCY_KEY_HEADER} was already used for a different request"),
+            )
+            .into())
+        }
+        Reservation::Replay(response) => {
+            context.status(response.status.into());
+            context.set("Content-Type", "application/json");
+            context.set(REPLAYED_HEADER, "true");
+            context.set_body_bytes(response.body.into_bytes());
+
+            return Ok(context);
+        }
+    };
+
+    match hold_during(&mut conn, &claim, next(context)).await {
+        Ok(mut context) if context.status < 500 => {
+            let body = hyper::body::to_bytes(std::mem::take(&mut context.body))
+                .await
+                .map_err(|e| {
+                    tracing::error!("Could not read response body: {e:#?}");
+                    ThrusterError::generic_error(context.clone_ctx())
+                })?
+                .to_vec();
+            let response = StoredResponse {
+                status: context.status,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            };
+            context.set_body_bytes(body);
+
+            match store_response(&mut conn, &claim, response).await {
+                Ok(true) => {}
+                Ok(false) => tracing::warn!("Idempotency key {key} was no longer reserved"),
+                Err(e) => {
+                    tracing::error!(
+                        "An error occurred while storing an idempotent response: {e:#?}"
+                    );
+                }
+            }
+
+            Ok(context)
+        }
+        result => {
+            if let Err(e) = release(&mut conn, &claim).await {
+                tracing::error!("An error occurred while releasing an idempotency key: {e:#?}");
+            }
+
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            images::tests::{create_image_helper, create_image_version_helper},
+            sessions::tests::create_user_and_session_helper,
+        },
+        models::{Image, Job},
+        services::jobs::JobSpec,
+        thruster_extensions::TestResponseExt,
+    };
+    use thruster::Testable;
+
+    fn headers(token: &str, key: &str) -> Vec<(String, String)> {
+        vec![
+            ("Authorization".to_string(), format!("Bearer {token}")),
+            (IDEMPOTENCY_KEY_HEADER.to_string(), key.to_string()),
+        ]
+    }
+
+    fn replayed(response: &thruster::testing::TestResponse) -> bool {
+        response
+            .headers
+            .iter()
+            .any(|(name, value)| name.eq_ignore_ascii_case(REPLAYED_HEADER) && value == "true")
+    }
+
+    #[tokio::test]
+    async fn create_image_should_replay_requests_with_the_same_key() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let body = serde_json::json!({
+            "nickname": "trainer",
+            "image_url": format!("ghcr.io/lionfish/{}", uuid::Uuid::new_v4()),
+            "verify": false,
+        })
+        .to_string()
+        .into_bytes();
+
+        let first = (&test_app as &dyn Testable)
+            .post("/images", headers(&session.token, "ci-42"), body.clone())
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status");
+        let second = (&test_app as &dyn Testable)
+            .post("/images", headers(&session.token, "ci-42"), body)
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should replay the created status");
+
+        assert!(!replayed(&first));
+        assert!(replayed(&second));
+        assert_eq!(first.json::<Image>().id, second.json::<Image>().id);
+    }
+
+    #[tokio::test]
+    async fn create_job_should_only_launch_once_per_key() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+        let body = |preset: &str| {
+            serde_json::to_vec(&crate::controllers::jobs::CreateJob {
+                image_id: image.id,
+                image_version_id: image_version.id,
+                spec: JobSpec {
+                    preset: Some(preset.to_string()),
+                    ..Default::default()
+                },
+                input: Default::default(),
+            })
+            .unwrap()
+        };
+
+        let first = (&test_app as &dyn Testable)
+            .post("/jobs", headers(&session.token, "ci-7"), body("cpu-small"))
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status")
+            .json::<Job>();
+        let second = (&test_app as &dyn Testable)
+            .post("/jobs", headers(&session.token, "ci-7"), body("cpu-small"))
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should replay the created status")
+            .json::<Job>();
+        assert_eq!(first.id, second.id);
+
+        let _ = (&test_app as &dyn Testable)
+            .post("/jobs", headers(&session.token, "ci-7"), body("cpu-large"))
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(422, "It should reject a reused key");
+
+        let jobs = (&test_app as &dyn Testable)
+            .get(
+                "/jobs",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an ok status")
+            .json::<Vec<Job>>();
+        assert_eq!(jobs.len(), 1, "It should not launch the job twice");
+    }
+}
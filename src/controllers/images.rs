@@ -1,6 +1,6 @@
 use std::str::FromStr;
 
-use deadpool_postgres::Pool;
+use deadpool_postgres::{GenericClient, Pool};
 use serde::{Deserialize, Serialize};
 use thruster::{
     context::context_ext::ContextExt,
@@ -13,12 +13,70 @@ use crate::{
     app::{ClonableCtx, Ctx},
     errors::Error,
     models::{Image, ImageVersion, User},
+    reference::{is_valid_digest, is_valid_tag, Reference},
+    services::{
+        audit,
+        crypto::Encryptor,
+        image_config::{
+            read_image_config, read_version_config, write_image_config, write_version_config,
+            ImageConfig,
+        },
+        images::{create_version, delete_image_rows, detach_jobs},
+        jobs::unfinished_job_count,
+        oci_registry::is_registry_reference,
+        registry::RegistryClient,
+        registry_credentials::find_registry_auth,
+        schedules::schedule_count,
+    },
 };
 
-#[derive(Debug, Deserialize, Serialize)]
+const REGISTRY_IMAGE_MESSAGE: &str =
+    "Images in the built-in registry are created by pushing to them";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub(crate) struct CreateImage {
     nickname: String,
     image_url: String,
+    /// Whether to check the registry for the manifest before accepting the
+    /// image, defaults to true.
+    verify: Option<bool>,
+}
+
+/// Pins `tag` to the manifest digest the registry currently serves for it,
+/// using any credentials the user has attached for the registry.
+async fn resolve_digest(
+    context: &Ctx,
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    image_id: Option<&Uuid>,
+    reference: &Reference,
+    tag: &str,
+) -> Result<String, ThrusterError<Ctx>> {
+    let registry: &RegistryClient = context.extra.get();
+    let encryptor: &Encryptor = context.extra.get();
+
+    let auth = find_registry_auth(db, encryptor, user_id, image_id, &reference.registry)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while loading registry credentials: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    registry
+        .resolve_digest(
+            reference.api_host(),
+            &reference.repository,
+            tag,
+            auth.as_ref(),
+        )
+        .await
+        .map_err(|e| {
+            Error::UnprocessableEntity(
+                context.clone_ctx(),
+                format!("Could not resolve {}:{tag}: {e}", reference.name()),
+            )
+            .into()
+        })
 }
 
 #[thruster::json_request]
@@ -30,24 +88,68 @@ pub(crate) async fn create_image(
     let CreateImage {
         nickname,
         image_url,
+        verify,
     } = create_image;
     let user: &Option<User> = context.extra.get();
     let user = user.as_ref().unwrap();
     let db: &Pool = context.extra.get();
     let db = db.get().await.unwrap();
-    let image = Image::create(&db, user.id, nickname, image_url)
+
+    let reference = match Reference::parse(&image_url) {
+        Ok(reference) if is_registry_reference(&reference) => {
+            return Err(
+                Error::UnprocessableEntity(context, REGISTRY_IMAGE_MESSAGE.to_string()).into(),
+            )
+        }
+        Ok(reference) => reference,
+        Err(e) => {
+            return Err(Error::UnprocessableEntity(
+                context,
+                format!("Invalid image url {image_url}: {e}"),
+            )
+            .into())
+        }
+    };
+    let tag = reference
+        .tag
+        .clone()
+        .unwrap_or_else(|| "latest".to_string());
+
+    let digest = match &reference.digest {
+        Some(digest) => digest.clone(),
+        None if verify.unwrap_or(true) => {
+            resolve_digest(&context, &db, &user.id, None, &reference, &tag).await?
+        }
+        None => String::new(),
+    };
+
+    let image = Image::create(&db, user.id, nickname, reference.name())
         .await
         .map_err(|e| {
             tracing::error!("An error occurred while creating an image: {e:#?}");
             ThrusterError::generic_error(context.clone_ctx())
         })?;
-    let _image_version = ImageVersion::create(&db, image.id, "".to_string(), "latest".to_string())
+    let _image_version = ImageVersion::create(&db, image.id, digest, tag)
         .await
         .map_err(|e| {
             tracing::error!("An error occurred while creating an image version: {e:#?}");
             ThrusterError::generic_error(context.clone_ctx())
         })?;
 
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "image.create",
+        "image",
+        Some(image.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
     context.json(&image).map_err(|_e| {
         Error::GenericError(
             context.clone(),
@@ -134,183 +236,1371 @@ pub(crate) async fn get_image_versions(
     Ok(context)
 }
 
-#[cfg(test)]
-pub(crate) mod tests {
-    use super::*;
-    use crate::{
-        controllers::sessions::tests::create_user_and_session_helper,
-        thruster_extensions::TestResponseExt,
-    };
-    use rand::distributions::DistString;
-    use thruster::Testable;
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct UpdateImage {
+    nickname: Option<String>,
+    image_url: Option<String>,
+}
 
-    pub(crate) async fn create_image_helper(
-        app: &impl Testable,
-        _user_id: &Uuid,
-        session_token: &str,
-    ) -> Image {
-        let nickname = format!(
-            "test-{}",
-            rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
-        );
-        let image_url = format!(
-            "https://registry.lionfi.sh/images/test-{}",
-            rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
-        );
+#[thruster::json_request]
+pub(crate) async fn update_image(
+    update_image: UpdateImage,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let UpdateImage {
+        nickname,
+        image_url,
+    } = update_image;
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
 
-        app.post(
-            "/images",
-            vec![(
-                "Authorization".to_string(),
-                format!("Bearer {session_token}"),
-            )],
-            serde_json::to_vec(&CreateImage {
-                nickname,
-                image_url,
-            })
-            .unwrap(),
-        )
-        .await
-        .expect("Should correctly resolve")
-        .expect_status(201, "It should have a created status")
-        .json::<Image>()
-    }
+    let image_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid image id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
 
-    #[tokio::test]
-    async fn create_image_should_work() {
-        let test_app = crate::app::init().await.commit();
+    let mut image = Image::read(&db, &image_id).await.map_err(|e| {
+        tracing::error!("Could not load image: {e:#?}");
+        ThrusterError::not_found_error(context.clone_ctx())
+    })?;
 
-        let (test_user, session) = create_user_and_session_helper(&test_app).await;
-        let _ = create_image_helper(&test_app, &test_user.id, &session.token).await;
+    if image.user_id != user.id {
+        return Err(ThrusterError::unauthorized_error(context));
     }
 
-    #[tokio::test]
-    async fn get_images_should_work() {
-        let test_app = crate::app::init().await.commit();
+    if let Some(nickname) = nickname {
+        image.nickname = nickname;
+    }
+    if let Some(image_url) = image_url {
+        match Reference::parse(&image_url) {
+            Ok(reference) if is_registry_reference(&reference) => {
+                return Err(
+                    Error::UnprocessableEntity(context, REGISTRY_IMAGE_MESSAGE.to_string()).into(),
+                )
+            }
+            Ok(reference) if reference.tag.is_none() && reference.digest.is_none() => {
+                image.image_url = reference.name();
+            }
+            Ok(_) => {
+                return Err(Error::UnprocessableEntity(
+                    context,
+                    "Image url must not include a tag or digest, create a version instead"
+                        .to_string(),
+                )
+                .into())
+            }
+            Err(e) => {
+                return Err(Error::UnprocessableEntity(
+                    context,
+                    format!("Invalid image url {image_url}: {e}"),
+                )
+                .into())
+            }
+        }
+    }
 
-        let (test_user, session) = create_user_and_session_helper(&test_app).await;
-        let _ = create_image_helper(&test_app, &test_user.id, &session.token).await;
+    let image = image.update(&db).await.map_err(|e| {
+        tracing::error!("An error occurred while updating an image: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
 
-        let images = (&test_app as &dyn Testable)
-            .get(
-                "/images",
-                vec![(
-                    "Authorization".to_string(),
-                    format!("Bearer {}", session.token),
-                )],
-            )
-            .await
-            .expect("Should correctly resolve")
-            .expect_status(200, "It should have an OK status")
-            .json::<Vec<Image>>();
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "image.update",
+        "image",
+        Some(image.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
 
-        assert_eq!(images.len(), 1, "It should have a image");
-    }
+    context.json(&image).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
 
-    #[tokio::test]
-    async fn get_images_should_retrieve_for_a_single_user() {
-        let test_app = crate::app::init().await.commit();
+    context.status(200);
 
-        // First user
-        let (test_user, session) = create_user_and_session_helper(&test_app).await;
-        let _ = create_image_helper(&test_app, &test_user.id, &session.token).await;
+    Ok(context)
+}
 
-        // Second user
-        let (test_user, session) = create_user_and_session_helper(&test_app).await;
-        let _ = create_image_helper(&test_app, &test_user.id, &session.token).await;
+#[thruster::middleware]
+pub(crate) async fn delete_image(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let mut db = db.get().await.unwrap();
+    let db = db.transaction().await.unwrap();
 
-        let images = (&test_app as &dyn Testable)
-            .get(
-                "/images",
-                vec![(
-                    "Authorization".to_string(),
-                    format!("Bearer {}", session.token),
-                )],
-            )
-            .await
-            .expect("Should correctly resolve")
-            .expect_status(200, "It should have an OK status")
-            .json::<Vec<Image>>();
+    let image_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid image id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
 
-        assert_eq!(images.len(), 1, "It should have a single image");
+    let image = Image::read(&db, &image_id).await.map_err(|e| {
+        tracing::error!("Could not load image: {e:#?}");
+        ThrusterError::not_found_error(context.clone_ctx())
+    })?;
+
+    if image.user_id != user.id {
+        return Err(ThrusterError::unauthorized_error(context));
     }
 
-    #[tokio::test]
-    async fn get_images_should_require_a_valid_session() {
-        let test_app = crate::app::init().await.commit();
+    let image_versions = ImageVersion::read_where_image_id(&db, &image_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching image versions: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+    let image_version_ids = image_versions.iter().map(|v| v.id).collect::<Vec<_>>();
 
-        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
-        let _ = (&test_app as &dyn Testable)
-            .get(
-                "/images",
-                vec![(
-                    "Authorization".to_string(),
-                    format!("Bearer {}z", session.token),
-                )],
-            )
+    check_unreferenced(&context, &db, &image_version_ids, "Image").await?;
+
+    detach_jobs(&db, &image_version_ids).await.map_err(|e| {
+        tracing::error!("An error occurred while detaching jobs: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+    delete_image_rows(&db, &image_id).await.map_err(|e| {
+        tracing::error!("An error occurred while deleting an image's rows: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    for image_version_id in &image_version_ids {
+        ImageVersion::destroy(&db, image_version_id)
             .await
-            .expect("Should correctly resolve")
-            .expect_status(401, "It should have an unauthorized status");
+            .map_err(|e| {
+                tracing::error!("An error occurred while deleting an image version: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?;
     }
 
-    #[tokio::test]
-    async fn get_image_version_should_work() {
-        let test_app = crate::app::init().await.commit();
+    Image::destroy(&db, &image_id).await.map_err(|e| {
+        tracing::error!("An error occurred while deleting an image: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
 
-        let (test_user, session) = create_user_and_session_helper(&test_app).await;
-        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "image.delete",
+        "image",
+        Some(image_id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
 
-        let image_versions = (&test_app as &dyn Testable)
-            .get(
-                &format!("/images/{}/versions", image.id),
-                vec![(
-                    "Authorization".to_string(),
-                    format!("Bearer {}", session.token),
-                )],
-            )
-            .await
-            .expect("Should correctly resolve")
-            .expect_status(200, "It should have an OK status")
-            .json::<Vec<ImageVersion>>();
+    db.commit().await.unwrap();
 
-        assert_eq!(image_versions.len(), 1, "It should have an image version");
-    }
+    context.status(204);
 
-    #[tokio::test]
-    async fn get_image_versions_should_require_a_valid_session() {
-        let test_app = crate::app::init().await.commit();
+    Ok(context)
+}
 
-        let (test_user, session) = create_user_and_session_helper(&test_app).await;
-        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+/// Refuses to delete versions that unfinished jobs or schedules still use.
+async fn check_unreferenced(
+    context: &Ctx,
+    db: &impl GenericClient,
+    image_version_ids: &[Uuid],
+    what: &str,
+) -> Result<(), ThrusterError<Ctx>> {
+    let unfinished_jobs = unfinished_job_count(db, image_version_ids)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while counting unfinished jobs: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+    if unfinished_jobs > 0 {
+        return Err(Error::Conflict(
+            context.clone_ctx(),
+            format!("{what} has {unfinished_jobs} job(s) still running or waiting to run"),
+        )
+        .into());
+    }
 
-        let _ = (&test_app as &dyn Testable)
-            .get(
-                &format!("/images/{}/versions", image.id),
-                vec![(
-                    "Authorization".to_string(),
-                    format!("Bearer {}z", session.token),
-                )],
-            )
-            .await
-            .expect("Should correctly resolve")
-            .expect_status(401, "It should have an unauthorized status");
+    let schedules = schedule_count(db, image_version_ids).await.map_err(|e| {
+        tracing::error!("An error occurred while counting schedules: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+    if schedules > 0 {
+        return Err(Error::Conflict(
+            context.clone_ctx(),
+            format!("{what} is launched by {schedules} schedule(s)"),
+        )
+        .into());
     }
 
-    #[tokio::test]
-    async fn get_image_versions_should_require_a_valid_session_from_the_owning_user() {
-        let test_app = crate::app::init().await.commit();
+    Ok(())
+}
 
-        let (test_user, session) = create_user_and_session_helper(&test_app).await;
-        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
-        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct CreateImageVersion {
+    tag: String,
+    digest: Option<String>,
+    /// Whether to resolve the tag's digest from the registry when no digest
+    /// is given, defaults to true.
+    verify: Option<bool>,
+}
 
-        let _ = (&test_app as &dyn Testable)
-            .get(
-                &format!("/images/{}/versions", image.id),
-                vec![(
-                    "Authorization".to_string(),
-                    format!("Bearer {}", session.token),
+#[thruster::json_request]
+pub(crate) async fn create_image_version(
+    create_image_version: CreateImageVersion,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let CreateImageVersion {
+        tag,
+        digest,
+        verify,
+    } = create_image_version;
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let image_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid image id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let image = Image::read(&db, &image_id).await.map_err(|e| {
+        tracing::error!("Could not load image: {e:#?}");
+        ThrusterError::not_found_error(context.clone_ctx())
+    })?;
+
+    if image.user_id != user.id {
+        return Err(ThrusterError::unauthorized_error(context));
+    }
+
+    if !is_valid_tag(&tag) {
+        return Err(Error::UnprocessableEntity(context, format!("Invalid tag: {tag}")).into());
+    }
+
+    if let Some(digest) = digest.as_ref().filter(|d| !is_valid_digest(d)) {
+        return Err(
+            Error::UnprocessableEntity(context, format!("Invalid digest: {digest}")).into(),
+        );
+    }
+
+    let existing = ImageVersion::read_where_image_id(&db, &image_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching image versions: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    if existing.iter().any(|v| v.version_number == tag) {
+        return Err(Error::Conflict(context, format!("Version {tag} already exists")).into());
+    }
+
+    let digest = match digest {
+        Some(digest) => digest,
+        None if verify.unwrap_or(true) => match Reference::parse(&image.image_url) {
+            Ok(reference) => {
+                resolve_digest(&context, &db, &user.id, Some(&image.id), &reference, &tag).await?
+            }
+            Err(e) => {
+                return Err(Error::UnprocessableEntity(
+                    context,
+                    format!("Image url {} is invalid: {e}", image.image_url),
+                )
+                .into())
+            }
+        },
+        None => String::new(),
+    };
+
+    let image_version = create_version(&db, &image_id, digest, tag.clone())
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while creating an image version: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+    let Some(image_version) = image_version else {
+        return Err(Error::Conflict(context, format!("Version {tag} already exists")).into());
+    };
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "image_version.create",
+        "image_version",
+        Some(image_version.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.json(&image_version).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(201);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn delete_image_version(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let mut db = db.get().await.unwrap();
+    let db = db.transaction().await.unwrap();
+
+    let image_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid image id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+    let image_version_id = Uuid::from_str(&context.params().get("version_id").unwrap().param)
+        .map_err(|e| {
+            tracing::error!("Invalid image version id format: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    let image = Image::read(&db, &image_id).await.map_err(|e| {
+        tracing::error!("Could not load image: {e:#?}");
+        ThrusterError::not_found_error(context.clone_ctx())
+    })?;
+
+    if image.user_id != user.id {
+        return Err(ThrusterError::unauthorized_error(context));
+    }
+
+    let image_version = ImageVersion::read(&db, &image_version_id)
+        .await
+        .ok()
+        .filter(|v| v.image_id == image_id)
+        .ok_or_else(|| ThrusterError::not_found_error(context.clone_ctx()))?;
+
+    check_unreferenced(&context, &db, &[image_version.id], "Image version").await?;
+
+    detach_jobs(&db, &[image_version.id]).await.map_err(|e| {
+        tracing::error!("An error occurred while detaching jobs: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    ImageVersion::destroy(&db, &image_version.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while deleting an image version: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "image_version.delete",
+        "image_version",
+        Some(image_version.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    db.commit().await.unwrap();
+
+    context.status(204);
+
+    Ok(context)
+}
+
+/// Loads the image (and version, on `/versions/:version_id` routes) a config
+/// request targets, checking that the user owns it.
+async fn config_target(
+    context: &Ctx,
+    db: &impl GenericClient,
+    user_id: &Uuid,
+) -> Result<(Image, Option<ImageVersion>), ThrusterError<Ctx>> {
+    let image_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid image id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let image = Image::read(db, &image_id).await.map_err(|e| {
+        tracing::error!("Could not load image: {e:#?}");
+        ThrusterError::not_found_error(context.clone_ctx())
+    })?;
+
+    if image.user_id != *user_id {
+        return Err(ThrusterError::unauthorized_error(context.clone_ctx()));
+    }
+
+    let image_version = match context.params().get("version_id") {
+        Some(param) => {
+            let image_version_id = Uuid::from_str(&param.param).map_err(|e| {
+                tracing::error!("Invalid image version id format: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?;
+
+            Some(
+                ImageVersion::read(db, &image_version_id)
+                    .await
+                    .ok()
+                    .filter(|v| v.image_id == image_id)
+                    .ok_or_else(|| ThrusterError::not_found_error(context.clone_ctx()))?,
+            )
+        }
+        None => None,
+    };
+
+    Ok((image, image_version))
+}
+
+/// Returns an image's run config, or a version's overrides of it.
+#[thruster::middleware]
+pub(crate) async fn get_image_config(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let (image, image_version) = config_target(&context, &db, &user.id).await?;
+
+    let config = match &image_version {
+        Some(image_version) => read_version_config(&db, &image_version.id).await,
+        None => read_image_config(&db, &image.id).await,
+    }
+    .map_err(|e| {
+        tracing::error!("An error occurred while fetching an image config: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.json(&config).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[thruster::json_request]
+pub(crate) async fn update_image_config(
+    config: ImageConfig,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let (image, image_version) = config_target(&context, &db, &user.id).await?;
+
+    if let Err(message) = config.validate() {
+        return Err(Error::UnprocessableEntity(context, message).into());
+    }
+
+    match &image_version {
+        Some(image_version) => write_version_config(&db, &image_version.id, &config).await,
+        None => write_image_config(&db, &image.id, &config).await,
+    }
+    .map_err(|e| {
+        tracing::error!("An error occurred while updating an image config: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let (target_type, target_id) = match &image_version {
+        Some(image_version) => ("image_version", image_version.id),
+        None => ("image", image.id),
+    };
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        &format!("{target_type}.configure"),
+        target_type,
+        Some(target_id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.json(&config).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            jobs::tests::create_job_helper, schedules::tests::create_schedule_helper,
+            sessions::tests::create_user_and_session_helper,
+        },
+        models::{Job, JobStatus},
+        testing::{StubResponse, StubServer},
+        thruster_extensions::TestResponseExt,
+    };
+    use rand::distributions::DistString;
+    use sha2::{Digest, Sha256};
+    use thruster::Testable;
+
+    pub(crate) async fn create_image_helper(
+        app: &impl Testable,
+        _user_id: &Uuid,
+        session_token: &str,
+    ) -> Image {
+        let nickname = format!(
+            "test-{}",
+            rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
+        );
+        let image_url = format!(
+            "ghcr.io/lionfish/test-{}",
+            rand::distributions::Alphanumeric
+                .sample_string(&mut rand::thread_rng(), 16)
+                .to_lowercase()
+        );
+
+        app.post(
+            "/images",
+            vec![(
+                "Authorization".to_string(),
+                format!("Bearer {session_token}"),
+            )],
+            serde_json::to_vec(&CreateImage {
+                nickname,
+                image_url,
+                verify: Some(false),
+            })
+            .unwrap(),
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(201, "It should have a created status")
+        .json::<Image>()
+    }
+
+    #[tokio::test]
+    async fn create_image_should_work() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = create_image_helper(&test_app, &test_user.id, &session.token).await;
+    }
+
+    #[tokio::test]
+    async fn get_images_should_work() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let images = (&test_app as &dyn Testable)
+            .get(
+                "/images",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<Vec<Image>>();
+
+        assert_eq!(images.len(), 1, "It should have a image");
+    }
+
+    #[tokio::test]
+    async fn get_images_should_retrieve_for_a_single_user() {
+        let test_app = crate::app::init().await.commit();
+
+        // First user
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        // Second user
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let images = (&test_app as &dyn Testable)
+            .get(
+                "/images",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<Vec<Image>>();
+
+        assert_eq!(images.len(), 1, "It should have a single image");
+    }
+
+    #[tokio::test]
+    async fn get_images_should_require_a_valid_session() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = (&test_app as &dyn Testable)
+            .get(
+                "/images",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}z", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+    }
+
+    #[tokio::test]
+    async fn get_image_version_should_work() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let image_versions = (&test_app as &dyn Testable)
+            .get(
+                &format!("/images/{}/versions", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<Vec<ImageVersion>>();
+
+        assert_eq!(image_versions.len(), 1, "It should have an image version");
+    }
+
+    #[tokio::test]
+    async fn get_image_versions_should_require_a_valid_session() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .get(
+                &format!("/images/{}/versions", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}z", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+    }
+
+    #[tokio::test]
+    async fn get_image_versions_should_require_a_valid_session_from_the_owning_user() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .get(
+                &format!("/images/{}/versions", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+    }
+
+    #[tokio::test]
+    async fn update_image_should_work() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let updated = (&test_app as &dyn Testable)
+            .patch(
+                &format!("/images/{}", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&UpdateImage {
+                    nickname: Some("renamed".to_string()),
+                    ..Default::default()
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<Image>();
+
+        assert_eq!(updated.nickname, "renamed", "It should update the nickname");
+        assert_eq!(
+            updated.image_url, image.image_url,
+            "It should leave the image url untouched"
+        );
+    }
+
+    #[tokio::test]
+    async fn update_image_should_require_the_owning_user() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .patch(
+                &format!("/images/{}", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&UpdateImage::default()).unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+    }
+
+    #[tokio::test]
+    async fn delete_image_should_remove_the_image_and_its_versions() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .delete(
+                &format!("/images/{}", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(204, "It should have a no content status");
+
+        let images = (&test_app as &dyn Testable)
+            .get(
+                "/images",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<Vec<Image>>();
+        assert!(images.is_empty(), "It should have removed the image");
+
+        let db = crate::app::generate_default_server_config().await.db;
+        let versions = ImageVersion::read_where_image_id(&db.get().await.unwrap(), &image.id)
+            .await
+            .unwrap();
+        assert!(versions.is_empty(), "It should have removed the versions");
+    }
+
+    #[tokio::test]
+    async fn delete_image_should_conflict_with_running_jobs() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let job = create_job_helper(&test_app, &test_user.id, &session.token).await;
+
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+        let image_version = ImageVersion::read(&db, &job.image_version_id.unwrap())
+            .await
+            .unwrap();
+
+        let _ = (&test_app as &dyn Testable)
+            .delete(
+                &format!("/images/{}", image_version.image_id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(409, "It should have a conflict status");
+    }
+
+    #[tokio::test]
+    async fn delete_image_should_conflict_with_schedules() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let details = create_schedule_helper(&test_app, &test_user.id, &session.token).await;
+
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+        let image_version = ImageVersion::read(&db, &details.schedule.image_version_id)
+            .await
+            .unwrap();
+
+        let _ = (&test_app as &dyn Testable)
+            .delete(
+                &format!("/images/{}", image_version.image_id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(409, "It should have a conflict status");
+    }
+
+    #[tokio::test]
+    async fn delete_image_should_detach_finished_jobs() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let job = create_job_helper(&test_app, &test_user.id, &session.token).await;
+
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+        let image_version = ImageVersion::read(&db, &job.image_version_id.unwrap())
+            .await
+            .unwrap();
+        db.execute(
+            "UPDATE jobs SET status = $1 WHERE id = $2",
+            &[&JobStatus::Completed, &job.id],
+        )
+        .await
+        .unwrap();
+
+        let _ = (&test_app as &dyn Testable)
+            .delete(
+                &format!("/images/{}", image_version.image_id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(204, "It should have a no content status");
+
+        let job = Job::read(&db, &job.id).await.unwrap();
+        assert_eq!(
+            job.image_version_id, None,
+            "It should keep the job's history"
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_image_should_require_the_owning_user() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .delete(
+                &format!("/images/{}", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+    }
+
+    pub(crate) async fn create_image_version_helper(
+        app: &impl Testable,
+        image_id: &Uuid,
+        session_token: &str,
+        tag: &str,
+    ) -> ImageVersion {
+        app.post(
+            &format!("/images/{image_id}/versions"),
+            vec![(
+                "Authorization".to_string(),
+                format!("Bearer {session_token}"),
+            )],
+            serde_json::to_vec(&CreateImageVersion {
+                tag: tag.to_string(),
+                verify: Some(false),
+                ..Default::default()
+            })
+            .unwrap(),
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(201, "It should have a created status")
+        .json::<ImageVersion>()
+    }
+
+    #[tokio::test]
+    async fn create_image_version_should_work() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1.2.0").await;
+
+        assert_eq!(image_version.version_number, "v1.2.0");
+
+        let image_versions = (&test_app as &dyn Testable)
+            .get(
+                &format!("/images/{}/versions", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<Vec<ImageVersion>>();
+
+        assert_eq!(image_versions.len(), 2, "It should have both versions");
+    }
+
+    #[tokio::test]
+    async fn create_image_version_should_reject_duplicate_tags() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                &format!("/images/{}/versions", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&CreateImageVersion {
+                    tag: "latest".to_string(),
+                    verify: Some(false),
+                    ..Default::default()
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(409, "It should have a conflict status");
+    }
+
+    #[tokio::test]
+    async fn create_image_version_should_reject_malformed_digests() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                &format!("/images/{}/versions", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&CreateImageVersion {
+                    tag: "v1".to_string(),
+                    digest: Some("sha256:nothex".to_string()),
+                    ..Default::default()
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(422, "It should have an unprocessable entity status");
+    }
+
+    #[tokio::test]
+    async fn delete_image_version_should_work() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+
+        let _ = (&test_app as &dyn Testable)
+            .delete(
+                &format!("/images/{}/versions/{}", image.id, image_version.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(204, "It should have a no content status");
+    }
+
+    #[tokio::test]
+    async fn delete_image_version_should_require_the_owning_user() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .delete(
+                &format!("/images/{}/versions/{}", image.id, image_version.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+    }
+
+    #[tokio::test]
+    async fn create_image_should_reject_malformed_image_urls() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        for image_url in [
+            "https://registry.lionfi.sh/images/model",
+            "registry.lionfi.sh/Images/model",
+            "registry.lionfi.sh/images/model:-latest",
+        ] {
+            let _ = (&test_app as &dyn Testable)
+                .post(
+                    "/images",
+                    vec![(
+                        "Authorization".to_string(),
+                        format!("Bearer {}", session.token),
+                    )],
+                    serde_json::to_vec(&CreateImage {
+                        nickname: "malformed".to_string(),
+                        image_url: image_url.to_string(),
+                        verify: Some(false),
+                    })
+                    .unwrap(),
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(422, "It should have an unprocessable entity status");
+        }
+    }
+
+    #[tokio::test]
+    async fn create_image_should_refuse_built_in_registry_repositories() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                "/images",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&CreateImage {
+                    nickname: "squatter".to_string(),
+                    image_url: "registry.lionfi.sh/team/model".to_string(),
+                    verify: Some(false),
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(422, "It should have an unprocessable entity status");
+
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let _ = (&test_app as &dyn Testable)
+            .patch(
+                &format!("/images/{}", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&UpdateImage {
+                    image_url: Some("registry.lionfi.sh/team/model".to_string()),
+                    ..Default::default()
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(422, "It should have an unprocessable entity status");
+    }
+
+    #[tokio::test]
+    async fn create_image_should_pin_the_resolved_digest() {
+        let test_app = crate::app::init().await.commit();
+        let manifest = r#"{"schemaVersion":2}"#;
+        let digest = format!("sha256:{:x}", Sha256::digest(manifest.as_bytes()));
+        let header = digest.clone();
+        let registry = StubServer::start(move |request| match request.path.as_str() {
+            "/v2/team/model/manifests/v2" => StubResponse::new(200)
+                .header("Docker-Content-Digest", &header)
+                .body(manifest),
+            _ => StubResponse::new(404),
+        })
+        .await;
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = (&test_app as &dyn Testable)
+            .post(
+                "/images",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&CreateImage {
+                    nickname: "pinned".to_string(),
+                    image_url: format!("{}/team/model:v2", registry.host()),
+                    verify: None,
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status")
+            .json::<Image>();
+
+        assert_eq!(image.image_url, format!("{}/team/model", registry.host()));
+
+        let image_versions = (&test_app as &dyn Testable)
+            .get(
+                &format!("/images/{}/versions", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<Vec<ImageVersion>>();
+
+        assert_eq!(image_versions[0].version_number, "v2");
+        assert_eq!(image_versions[0].hash, digest);
+    }
+
+    #[tokio::test]
+    async fn create_image_should_reject_tags_missing_from_the_registry() {
+        let test_app = crate::app::init().await.commit();
+        let registry = StubServer::start(|_request| StubResponse::new(404)).await;
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                "/images",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&CreateImage {
+                    nickname: "missing".to_string(),
+                    image_url: format!("{}/team/model", registry.host()),
+                    verify: None,
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(422, "It should have an unprocessable entity status");
+    }
+
+    #[tokio::test]
+    async fn update_image_config_should_roundtrip() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let config = ImageConfig {
+            cmd: Some(vec!["serve".to_string()]),
+            env: [("MODEL".to_string(), "small".to_string())].into(),
+            health_check_path: Some("/healthz".to_string()),
+            resource_preset: Some("a10".to_string()),
+            ..Default::default()
+        };
+
+        let _ = (&test_app as &dyn Testable)
+            .put(
+                &format!("/images/{}/config", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&config).unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status");
+
+        let stored = (&test_app as &dyn Testable)
+            .get(
+                &format!("/images/{}/config", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<ImageConfig>();
+
+        assert_eq!(stored, config);
+    }
+
+    #[tokio::test]
+    async fn update_image_config_should_reject_invalid_configs() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .put(
+                &format!("/images/{}/config", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&ImageConfig {
+                    resource_preset: Some("tpu".to_string()),
+                    ..Default::default()
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(422, "It should have an unprocessable entity status");
+    }
+
+    #[tokio::test]
+    async fn update_image_version_config_should_override_the_image_config() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        write_image_config(
+            &db,
+            &image.id,
+            &ImageConfig {
+                cmd: Some(vec!["serve".to_string()]),
+                resource_preset: Some("cpu-small".to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let _ = (&test_app as &dyn Testable)
+            .put(
+                &format!("/images/{}/versions/{}/config", image.id, image_version.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&ImageConfig {
+                    resource_preset: Some("a10".to_string()),
+                    ..Default::default()
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status");
+
+        let config =
+            crate::services::image_config::effective_config(&db, &image.id, &image_version.id)
+                .await
+                .unwrap();
+
+        assert_eq!(config.cmd, Some(vec!["serve".to_string()]));
+        assert_eq!(config.resource_preset.as_deref(), Some("a10"));
+    }
+
+    #[tokio::test]
+    async fn update_image_config_should_require_the_owning_user() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let (_other_user, other_session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .put(
+                &format!("/images/{}/config", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", other_session.token),
                 )],
+                serde_json::to_vec(&ImageConfig::default()).unwrap(),
             )
             .await
             .expect("Should correctly resolve")
@@ -1,11 +1,8 @@
-use deadpool_postgres::Pool;
-use fly::{
-    apis::configuration::Configuration as FlyClient,
-    models::{
-        FlyPeriodMachineConfig, FlyPeriodMachineGuest, FlyPeriodMachineMount, FlyPeriodMachinePort,
-        FlyPeriodMachineService,
-    },
-};
+use std::str::FromStr;
+
+use chrono::Utc;
+use deadpool_postgres::{GenericClient, Pool};
+use reqwest::Method;
 use serde::{Deserialize, Serialize};
 use thruster::{
     context::context_ext::ContextExt,
@@ -16,17 +13,36 @@ use uuid::Uuid;
 
 use crate::{
     app::{ClonableCtx, Ctx},
-    errors::Error,
-    models::{Image, ImageVersion, Job, JobStatus, User},
-    services::fly::create_machine,
+    errors::{submission_error, Error},
+    models::{Artifact, Attempt, Image, ImageVersion, Job, UsageRecord, User},
+    services::{
+        artifacts::DOWNLOAD_EXPIRY_SECONDS,
+        audit,
+        jobs::{
+            launch_job, read_job_input, read_max_runtime, validate_job, JobInput, JobSpec, ValidJob,
+        },
+        object_storage::S3Client,
+    },
 };
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct CreateJob {
-    image_id: Uuid,
-    image_version_id: Uuid,
-    cpu: String,
-    gpu: String,
+    pub(crate) image_id: Uuid,
+    pub(crate) image_version_id: Uuid,
+    #[serde(flatten)]
+    pub(crate) spec: JobSpec,
+    #[serde(flatten)]
+    pub(crate) input: JobInput,
+}
+
+/// A job along with the input it was submitted with.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct JobDetails {
+    #[serde(flatten)]
+    job: Job,
+    #[serde(flatten)]
+    input: JobInput,
+    max_runtime_seconds: Option<i64>,
 }
 
 #[thruster::json_request]
@@ -38,8 +54,8 @@ pub(crate) async fn create_job(
     let CreateJob {
         image_id,
         image_version_id,
-        cpu,
-        gpu,
+        spec,
+        input,
     } = create_job;
     let user: &Option<User> = context.extra.get();
     let user = user.as_ref().unwrap();
@@ -67,43 +83,282 @@ pub(crate) async fn create_job(
         .find(|v| v.id == image_version_id)
         .ok_or_else(|| ThrusterError::not_found_error(context.clone_ctx()))?;
 
-    let job = Job::create(&db, user.id, JobStatus::Pending, image_version.id)
+    let ValidJob {
+        max_runtime_seconds,
+        ..
+    } = validate_job(&db, &user.id, &image, &image_version, &spec, &input)
+        .await
+        .map_err(|e| submission_error(&context, e))?;
+
+    let job = launch_job(&db, &user.id, &image, &image_version, &spec, &input)
+        .await
+        .map_err(|e| submission_error(&context, e))?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "job.create",
+        "job",
+        Some(job.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    db.commit().await.unwrap();
+
+    context
+        .json(&JobDetails {
+            job,
+            input,
+            max_runtime_seconds: Some(max_runtime_seconds),
+        })
+        .map_err(|_e| {
+            Error::GenericError(
+                context.clone(),
+                "Serialization error".to_string(),
+                serde_json::Value::default(),
+            )
+            .into()
+        })?;
+
+    context.status(201);
+
+    Ok(context)
+}
+
+/// Loads the job in the `:id` param. Other users' jobs are reported as
+/// missing.
+async fn owned_job(
+    context: &Ctx,
+    db: &impl GenericClient,
+    user_id: &Uuid,
+) -> Result<Job, ThrusterError<Ctx>> {
+    let job_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid job id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    Job::read(db, &job_id)
+        .await
+        .ok()
+        .filter(|job| job.user_id == *user_id)
+        .ok_or_else(|| ThrusterError::not_found_error(context.clone_ctx()))
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_job(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let job = owned_job(&context, &db, &user.id).await?;
+
+    let input = read_job_input(&db, &job.id).await.map_err(|e| {
+        tracing::error!("An error occurred while fetching a job's input: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+    let max_runtime_seconds = read_max_runtime(&db, &job.id).await.map_err(|e| {
+        tracing::error!("An error occurred while fetching a job's runtime limit: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context
+        .json(&JobDetails {
+            job,
+            input,
+            max_runtime_seconds,
+        })
+        .map_err(|_e| {
+            Error::GenericError(
+                context.clone_ctx(),
+                "Serialization error".to_string(),
+                serde_json::Value::default(),
+            )
+            .into()
+        })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ArtifactLink {
+    #[serde(flatten)]
+    artifact: Artifact,
+    download_url: String,
+}
+
+/// Lists the machines started for a job, oldest first.
+#[thruster::middleware]
+pub(crate) async fn get_job_attempts(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let job = owned_job(&context, &db, &user.id).await?;
+
+    let mut attempts = Attempt::read_where_job_id(&db, &job.id)
         .await
         .map_err(|e| {
-            tracing::error!("An error occurred while creating a job: {e:#?}");
+            tracing::error!("An error occurred while fetching attempts: {e:#?}");
             ThrusterError::generic_error(context.clone_ctx())
         })?;
+    attempts.sort_by_key(|attempt| attempt.number);
 
-    #[cfg(not(test))]
-    {
-        let fly: &FlyClient = context.extra.get();
-        create_machine(
-            fly,
-            &user.id.to_string(),
-            &cpu,
-            &gpu,
-            &image,
-            &image_version,
+    context.json(&attempts).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
         )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_job_usage(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let job = owned_job(&context, &db, &user.id).await?;
+
+    let mut records = UsageRecord::read_where_job_id(&db, &job.id)
         .await
         .map_err(|e| {
-            tracing::error!("An error occurred while calling fly.io to create a machine: {e:#?}");
+            tracing::error!("An error occurred while fetching usage records: {e:#?}");
             ThrusterError::generic_error(context.clone_ctx())
         })?;
-    }
+    records.sort_by_key(|record| record.started_at);
 
-    db.commit().await.unwrap();
+    context.json(&records).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+/// Lists what a job has uploaded, with short lived download links.
+#[thruster::middleware]
+pub(crate) async fn get_job_artifacts(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let job = owned_job(&context, &db, &user.id).await?;
+
+    let object_storage: &Option<S3Client> = context.extra.get();
+    let artifacts = match object_storage {
+        Some(s3) => {
+            #[cfg(not(test))]
+            crate::services::artifacts::sync_artifacts(&db, s3, &job.id)
+                .await
+                .map_err(|e| {
+                    tracing::error!("An error occurred while syncing artifacts: {e:#?}");
+                    ThrusterError::generic_error(context.clone_ctx())
+                })?;
+
+            Artifact::read_where_job_id(&db, &job.id)
+                .await
+                .map_err(|e| {
+                    tracing::error!("An error occurred while fetching artifacts: {e:#?}");
+                    ThrusterError::generic_error(context.clone_ctx())
+                })?
+                .into_iter()
+                .map(|artifact| ArtifactLink {
+                    download_url: s3.presign(
+                        Method::GET,
+                        &artifact.key,
+                        DOWNLOAD_EXPIRY_SECONDS,
+                        Utc::now(),
+                    ),
+                    artifact,
+                })
+                .collect()
+        }
+        None => vec![],
+    };
 
-    context.json(&job).map_err(|_e| {
+    context.json(&artifacts).map_err(|_e| {
         Error::GenericError(
-            context.clone(),
+            context.clone_ctx(),
             "Serialization error".to_string(),
             serde_json::Value::default(),
         )
         .into()
     })?;
 
-    context.status(201);
+    context.status(200);
+
+    Ok(context)
+}
+
+/// Redirects to a short lived download link for one of a job's artifacts.
+#[thruster::middleware]
+pub(crate) async fn download_job_artifact(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let job = owned_job(&context, &db, &user.id).await?;
+
+    let artifact_id =
+        Uuid::from_str(&context.params().get("artifact_id").unwrap().param).map_err(|e| {
+            tracing::error!("Invalid artifact id format: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    let object_storage: &Option<S3Client> = context.extra.get();
+    let download_url = Artifact::read(&db, &artifact_id)
+        .await
+        .ok()
+        .filter(|artifact| artifact.job_id == job.id)
+        .zip(object_storage.as_ref())
+        .map(|(artifact, s3)| {
+            s3.presign(
+                Method::GET,
+                &artifact.key,
+                DOWNLOAD_EXPIRY_SECONDS,
+                Utc::now(),
+            )
+        })
+        .ok_or_else(|| ThrusterError::not_found_error(context.clone_ctx()))?;
+
+    context.set("Location", &download_url);
+    context.status(302);
 
     Ok(context)
 }
@@ -141,7 +396,18 @@ pub(crate) mod tests {
     use super::*;
     use crate::{
         controllers::{
-            images::tests::create_image_helper, sessions::tests::create_user_and_session_helper,
+            images::tests::{create_image_helper, create_image_version_helper},
+            secrets::tests::create_secret_helper,
+            sessions::tests::create_user_and_session_helper,
+            uploads::tests::create_upload_helper,
+        },
+        models::JobStatus,
+        services::{
+            artifacts::record_artifact,
+            image_config::{write_image_config, ImageConfig},
+            job_queue::tests::{launcher, work_job},
+            retries::RetryPolicy,
+            uploads::JobFile,
         },
         thruster_extensions::TestResponseExt,
     };
@@ -176,6 +442,12 @@ pub(crate) mod tests {
             serde_json::to_vec(&CreateJob {
                 image_id: image.id,
                 image_version_id: image_versions.get(0).unwrap().id,
+                spec: JobSpec {
+                    cpu: Some("performance".to_string()),
+                    gpu: Some("a100-pcie-40gb".to_string()),
+                    ..Default::default()
+                },
+                input: JobInput::default(),
             })
             .unwrap(),
         )
@@ -261,4 +533,475 @@ pub(crate) mod tests {
             .expect("Should correctly resolve")
             .expect_status(401, "It should have an unauthorized status");
     }
+
+    async fn create_job_with_input(
+        app: &impl Testable,
+        image_id: Uuid,
+        image_version_id: Uuid,
+        session_token: &str,
+        input: JobInput,
+    ) -> thruster::testing::TestResponse {
+        app.post(
+            "/jobs",
+            vec![(
+                "Authorization".to_string(),
+                format!("Bearer {session_token}"),
+            )],
+            serde_json::to_vec(&CreateJob {
+                image_id,
+                image_version_id,
+                spec: JobSpec {
+                    preset: Some("cpu-small".to_string()),
+                    ..Default::default()
+                },
+                input,
+            })
+            .unwrap(),
+        )
+        .await
+        .expect("Should correctly resolve")
+    }
+
+    #[tokio::test]
+    async fn create_job_should_persist_its_input() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+        let input = JobInput {
+            env: [("MODEL".to_string(), "large".to_string())].into(),
+            command: Some(vec!["python".to_string(), "train.py".to_string()]),
+            args: vec!["--epochs".to_string(), "3".to_string()],
+            params: Some(serde_json::json!({ "lr": 0.1 })),
+            ..Default::default()
+        };
+
+        let created = create_job_with_input(
+            &test_app,
+            image.id,
+            image_version.id,
+            &session.token,
+            input.clone(),
+        )
+        .await
+        .expect_status(201, "It should have a created status")
+        .json::<JobDetails>();
+
+        let job = (&test_app as &dyn Testable)
+            .get(
+                &format!("/jobs/{}", created.job.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<JobDetails>();
+
+        assert_eq!(job.input, input);
+    }
+
+    #[tokio::test]
+    async fn create_job_should_validate_params_against_the_image_schema() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+        write_image_config(
+            &db,
+            &image.id,
+            &ImageConfig {
+                params_schema: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": { "lr": { "type": "number" } },
+                    "required": ["lr"],
+                })),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let _ = create_job_with_input(
+            &test_app,
+            image.id,
+            image_version.id,
+            &session.token,
+            JobInput {
+                params: Some(serde_json::json!({ "lr": "fast" })),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect_status(422, "It should have an unprocessable entity status");
+
+        let _ = create_job_with_input(
+            &test_app,
+            image.id,
+            image_version.id,
+            &session.token,
+            JobInput {
+                params: Some(serde_json::json!({ "lr": 0.1 })),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect_status(201, "It should have a created status");
+    }
+
+    #[tokio::test]
+    async fn get_job_should_require_the_owning_user() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let job = create_job_helper(&test_app, &test_user.id, &session.token).await;
+        let (_other_user, other_session) = create_user_and_session_helper(&test_app).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .get(
+                &format!("/jobs/{}", job.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", other_session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(404, "It should have a not found status");
+    }
+
+    #[tokio::test]
+    async fn create_job_should_reject_unknown_secrets() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+        let _ = create_secret_helper(&test_app, &session.token, "HF_TOKEN", "hunter2").await;
+
+        let _ = create_job_with_input(
+            &test_app,
+            image.id,
+            image_version.id,
+            &session.token,
+            JobInput {
+                secrets: vec!["HF_TOKEN".to_string(), "S3_KEY".to_string()],
+                ..Default::default()
+            },
+        )
+        .await
+        .expect_status(422, "It should have an unprocessable entity status");
+
+        let job = create_job_with_input(
+            &test_app,
+            image.id,
+            image_version.id,
+            &session.token,
+            JobInput {
+                secrets: vec!["HF_TOKEN".to_string()],
+                ..Default::default()
+            },
+        )
+        .await
+        .expect_status(201, "It should have a created status");
+
+        let body = String::from_utf8(job.body.clone()).unwrap();
+        assert!(
+            !body.contains("hunter2"),
+            "It should only persist secret names"
+        );
+    }
+
+    #[tokio::test]
+    async fn create_job_should_cap_max_runtime_by_plan() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+
+        let create = |max_runtime_seconds: Option<i64>| {
+            (&test_app as &dyn Testable).post(
+                "/jobs",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&CreateJob {
+                    image_id: image.id,
+                    image_version_id: image_version.id,
+                    spec: JobSpec {
+                        preset: Some("cpu-small".to_string()),
+                        max_runtime_seconds,
+                        ..Default::default()
+                    },
+                    input: JobInput::default(),
+                })
+                .unwrap(),
+            )
+        };
+
+        let _ = create(Some(7 * 24 * 60 * 60))
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(422, "It should have an unprocessable entity status");
+
+        let job = create(None)
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status")
+            .json::<JobDetails>();
+        assert_eq!(job.max_runtime_seconds, Some(60 * 60));
+
+        let job = create(Some(600))
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status")
+            .json::<JobDetails>();
+        assert_eq!(job.max_runtime_seconds, Some(600));
+    }
+
+    #[tokio::test]
+    async fn create_job_should_queue_the_job() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+
+        let create = |retry: RetryPolicy| {
+            (&test_app as &dyn Testable).post(
+                "/jobs",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&CreateJob {
+                    image_id: image.id,
+                    image_version_id: image_version.id,
+                    spec: JobSpec {
+                        preset: Some("cpu-small".to_string()),
+                        retry: Some(retry),
+                        ..Default::default()
+                    },
+                    input: JobInput::default(),
+                })
+                .unwrap(),
+            )
+        };
+
+        let _ = create(RetryPolicy {
+            max_attempts: 0,
+            ..Default::default()
+        })
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(422, "It should have an unprocessable entity status");
+
+        let job = create(RetryPolicy {
+            max_attempts: 3,
+            ..Default::default()
+        })
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(201, "It should have a created status")
+        .json::<Job>();
+        assert!(matches!(job.status, JobStatus::Queued));
+
+        let get_attempts = || {
+            test_app.get(
+                &format!("/jobs/{}/attempts", job.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+        };
+        let attempts = get_attempts()
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an ok status")
+            .json::<Vec<Attempt>>();
+        assert!(
+            attempts.is_empty(),
+            "It should leave starting it to a worker"
+        );
+
+        let config = crate::app::generate_default_server_config().await;
+        assert!(work_job(&config.db, &launcher(&config), &job.id, Utc::now()).await);
+
+        let attempts = get_attempts()
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an ok status")
+            .json::<Vec<Attempt>>();
+        assert_eq!(attempts.len(), 1);
+        assert_eq!(attempts[0].number, 1);
+    }
+
+    #[tokio::test]
+    async fn create_job_should_only_accept_the_users_uploads() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let (_other_user, other_session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+        let upload = create_upload_helper(&test_app, &session.token, "train.csv", b"a,b").await;
+        let other_upload =
+            create_upload_helper(&test_app, &other_session.token, "train.csv", b"a,b").await;
+
+        let input = |upload_id| JobInput {
+            command: Some(vec!["python".to_string(), "train.py".to_string()]),
+            files: vec![JobFile {
+                upload_id,
+                path: "/data/train.csv".to_string(),
+            }],
+            ..Default::default()
+        };
+
+        let _ = create_job_with_input(
+            &test_app,
+            image.id,
+            image_version.id,
+            &session.token,
+            input(other_upload.id),
+        )
+        .await
+        .expect_status(422, "It should have an unprocessable entity status");
+
+        let job = create_job_with_input(
+            &test_app,
+            image.id,
+            image_version.id,
+            &session.token,
+            input(upload.id),
+        )
+        .await
+        .expect_status(201, "It should have a created status")
+        .json::<JobDetails>();
+
+        assert_eq!(job.input.files, input(upload.id).files);
+    }
+
+    #[tokio::test]
+    async fn create_job_should_require_a_command_for_files() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+        let upload = create_upload_helper(&test_app, &session.token, "train.csv", b"a,b").await;
+
+        let _ = create_job_with_input(
+            &test_app,
+            image.id,
+            image_version.id,
+            &session.token,
+            JobInput {
+                files: vec![JobFile {
+                    upload_id: upload.id,
+                    path: "/data/train.csv".to_string(),
+                }],
+                ..Default::default()
+            },
+        )
+        .await
+        .expect_status(422, "It should have an unprocessable entity status");
+    }
+
+    #[tokio::test]
+    async fn get_job_artifacts_should_return_download_links() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let job = create_job_helper(&test_app, &test_user.id, &session.token).await;
+
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+        record_artifact(
+            &db,
+            &job.id,
+            &format!("artifacts/{}/model.bin", job.id),
+            2048,
+            "abc",
+        )
+        .await
+        .unwrap();
+
+        let artifacts = (&test_app as &dyn Testable)
+            .get(
+                &format!("/jobs/{}/artifacts", job.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<Vec<ArtifactLink>>();
+
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(artifacts[0].artifact.name, "model.bin");
+        assert!(artifacts[0].download_url.starts_with(&format!(
+            "http://localhost:9000/lim-test/artifacts/{}/model.bin?",
+            job.id
+        )));
+
+        let response = (&test_app as &dyn Testable)
+            .get(
+                &format!("/jobs/{}/artifacts/{}", job.id, artifacts[0].artifact.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(302, "It should redirect to the download link");
+
+        let location = response
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("location"))
+            .map(|(_, v)| v.clone())
+            .expect("It should return the download link");
+        assert!(location.contains("X-Amz-Signature="));
+    }
+
+    #[tokio::test]
+    async fn get_job_artifacts_should_require_the_owning_user() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let job = create_job_helper(&test_app, &test_user.id, &session.token).await;
+        let (_other_user, other_session) = create_user_and_session_helper(&test_app).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .get(
+                &format!("/jobs/{}/artifacts", job.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", other_session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(404, "It should have a not found status");
+    }
 }
@@ -1,4 +1,19 @@
+pub(crate) mod audit_events;
+pub(crate) mod batches;
+pub(crate) mod billing;
+pub(crate) mod credits;
+pub(crate) mod idempotency;
 pub(crate) mod images;
 pub(crate) mod jobs;
+pub(crate) mod oci_registry;
+pub(crate) mod pipelines;
+pub(crate) mod quotas;
+pub(crate) mod registry_credentials;
+pub(crate) mod schedules;
+pub(crate) mod secrets;
 pub(crate) mod sessions;
+pub(crate) mod uploads;
+pub(crate) mod usage;
 pub(crate) mod users;
+pub(crate) mod volumes;
+pub(crate) mod webhooks;
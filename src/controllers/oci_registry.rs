@@ -0,0 +1,868 @@
+use std::str::FromStr;
+
+use base64::{engine::general_purpose, Engine};
+use deadpool_postgres::{GenericClient, Pool};
+use thruster::{
+    context::context_ext::ContextExt, errors::ThrusterError, middleware_fn, Context, ContextState,
+    MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::Ctx,
+    controllers::sessions::session_user,
+    models::{Image, User},
+    services::{
+        audit,
+        blob_store::BlobStore,
+        crypto::Encryptor,
+        oci_registry::{
+            append_upload, blob_size, cancel_upload, finish_upload, is_mirror_repository,
+            list_tags, pull_image, push_image, put_manifest, read_blob, read_manifest,
+            read_mirrored_blob, read_mirrored_manifest, registry_host, split_pull_token,
+            start_upload, upload_monolithic, upload_size, BlobContent, OciError, RegistryAccess,
+        },
+        registry::RegistryClient,
+    },
+    thruster_extensions::RequestExt,
+};
+
+/// The OCI Distribution endpoints, relative to `/v2/`. Repository names may
+/// contain slashes, so routes are matched from the end of the path.
+#[derive(Debug, PartialEq)]
+enum RegistryRoute {
+    Base,
+    Tags(String),
+    Manifest(String, String),
+    Uploads(String),
+    Upload(String, Uuid),
+    Blob(String, String),
+}
+
+impl RegistryRoute {
+    fn parse(path: &str) -> Option<Self> {
+        let rest = path.strip_prefix("/v2")?;
+        if rest.is_empty() || rest == "/" {
+            return Some(RegistryRoute::Base);
+        }
+        let rest = rest.strip_prefix('/')?;
+
+        if let Some(repository) = rest.strip_suffix("/tags/list") {
+            return Some(RegistryRoute::Tags(repository.to_string()));
+        }
+        if let Some((repository, reference)) = rest.rsplit_once("/manifests/") {
+            return Some(RegistryRoute::Manifest(
+                repository.to_string(),
+                reference.to_string(),
+            ));
+        }
+        if let Some((repository, upload)) = rest.rsplit_once("/blobs/uploads") {
+            return match upload.trim_start_matches('/') {
+                "" => Some(RegistryRoute::Uploads(repository.to_string())),
+                upload_id => Uuid::from_str(upload_id)
+                    .ok()
+                    .map(|upload_id| RegistryRoute::Upload(repository.to_string(), upload_id)),
+            };
+        }
+        if let Some((repository, digest)) = rest.rsplit_once("/blobs/") {
+            return Some(RegistryRoute::Blob(
+                repository.to_string(),
+                digest.to_string(),
+            ));
+        }
+
+        None
+    }
+}
+
+/// Docker logs in with basic auth, so the password is the session token.
+/// Bearer tokens are accepted too for other clients.
+async fn registry_user(context: &Ctx) -> Option<User> {
+    let authorization = context.req_header("Authorization")?;
+    let token = match authorization.split_once(' ')? {
+        ("Basic", credentials) => {
+            let credentials = general_purpose::STANDARD.decode(credentials).ok()?;
+            let credentials = String::from_utf8(credentials).ok()?;
+            credentials.split_once(':')?.1.to_string()
+        }
+        ("Bearer", token) => token.to_string(),
+        _ => return None,
+    };
+
+    session_user(context, &token).await
+}
+
+fn oci_error(mut context: Ctx, error: OciError) -> MiddlewareResult<Ctx> {
+    if let OciError::Internal(e) = &error {
+        tracing::error!("An error occurred in the registry: {e:#?}");
+    }
+    if let OciError::Unauthorized = &error {
+        context.set(
+            "WWW-Authenticate",
+            &format!("Basic realm=\"{}\"", registry_host()),
+        );
+    }
+
+    let message = error.to_string();
+    context.status(error.status());
+    context.set("Content-Type", "application/json");
+    context.body(&error.body());
+
+    Err(ThrusterError {
+        context,
+        message,
+        cause: None,
+    })
+}
+
+/// Serves the built-in OCI registry under `/v2/`. It sits in front of the
+/// router because repository names can contain any number of slashes.
+#[middleware_fn]
+pub(crate) async fn oci_registry(
+    mut context: Ctx,
+    next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let (method, (pull_token, path)) = match context.hyper_request.as_ref() {
+        Some(r) => (
+            r.request.method().to_string(),
+            split_pull_token(r.request.uri().path()),
+        ),
+        None => return next(context).await,
+    };
+    let route = RegistryRoute::parse(&path);
+    let route = match route {
+        Some(route) => route,
+        None => return next(context).await,
+    };
+
+    context.set("Docker-Distribution-API-Version", "registry/2.0");
+
+    let access = match pull_token {
+        Some(token) => RegistryAccess::PullToken(token),
+        None => match registry_user(&context).await {
+            Some(user) => RegistryAccess::User(user),
+            None => return oci_error(context, OciError::Unauthorized),
+        },
+    };
+
+    match handle(&mut context, &access, &method, route).await {
+        Ok(()) => Ok(context),
+        Err(error) => oci_error(context, error),
+    }
+}
+
+/// Blob bodies are streamed to and from storage, only manifests are read
+/// into memory. Mirror repositories are read through from the image's own
+/// registry.
+async fn handle(
+    context: &mut Ctx,
+    access: &RegistryAccess,
+    method: &str,
+    route: RegistryRoute,
+) -> Result<(), OciError> {
+    let db: &Pool = context.extra.get();
+    let db = db
+        .get()
+        .await
+        .map_err(|e| OciError::Internal(Box::new(e)))?;
+    let store: &BlobStore = context.extra.get();
+    let store = store.clone();
+    let encryptor: &Encryptor = context.extra.get();
+    let encryptor = encryptor.clone();
+    let registry: &RegistryClient = context.extra.get();
+    let registry = registry.clone();
+    let query = context.query_params();
+
+    match (method, route) {
+        ("GET" | "HEAD", RegistryRoute::Base) => {
+            context.set("Content-Type", "application/json");
+            context.body("{}");
+        }
+        ("GET", RegistryRoute::Tags(repository)) => {
+            let image = pull_image(&db, &encryptor, access, &repository).await?;
+            let tags = list_tags(&db, &image).await?;
+
+            context.set("Content-Type", "application/json");
+            context.body(&serde_json::json!({ "name": repository, "tags": tags }).to_string());
+        }
+        (method @ ("GET" | "HEAD"), RegistryRoute::Manifest(repository, reference)) => {
+            let image = pull_image(&db, &encryptor, access, &repository).await?;
+            let manifest = if is_mirror_repository(&repository) {
+                read_mirrored_manifest(&db, &encryptor, &registry, &image, &reference).await?
+            } else {
+                read_manifest(&db, &store, &image, &reference).await?
+            };
+
+            context.set("Content-Type", &manifest.media_type);
+            context.set("Docker-Content-Digest", &manifest.digest);
+            context.set("Content-Length", &manifest.bytes.len().to_string());
+            if method == "GET" {
+                context.set_body_bytes(manifest.bytes);
+            }
+        }
+        ("PUT", RegistryRoute::Manifest(repository, reference)) => {
+            let media_type = context
+                .req_header("Content-Type")
+                .unwrap_or("application/vnd.oci.image.manifest.v1+json")
+                .to_string();
+            let (image, user) = writable_image(context, &db, access, &repository).await?;
+            let body = context
+                .take_body()
+                .await
+                .map_err(|e| OciError::Internal(Box::new(e)))?;
+            let (digest, version) =
+                put_manifest(&db, &store, &image, &reference, &media_type, body).await?;
+
+            if let Some(version) = version {
+                audit::record(
+                    &db,
+                    context,
+                    Some(user.id),
+                    "image_version.create",
+                    "image_version",
+                    Some(version.id),
+                )
+                .await
+                .map_err(OciError::Internal)?;
+            }
+
+            context.status(201);
+            context.set("Location", &format!("/v2/{repository}/manifests/{digest}"));
+            context.set("Docker-Content-Digest", &digest);
+        }
+        (method @ ("GET" | "HEAD"), RegistryRoute::Blob(repository, digest)) => {
+            let image = pull_image(&db, &encryptor, access, &repository).await?;
+
+            context.set("Content-Type", "application/octet-stream");
+            context.set("Docker-Content-Digest", &digest);
+            if is_mirror_repository(&repository) {
+                let (size, reader) =
+                    read_mirrored_blob(&db, &encryptor, &registry, &image, &digest).await?;
+                if let Some(size) = size {
+                    context.set("Content-Length", &size.to_string());
+                }
+                if method == "GET" {
+                    context.set_body_stream(reader.into_body());
+                }
+            } else if method == "GET" {
+                match read_blob(&db, &store, &image, &digest).await? {
+                    (_, BlobContent::Redirect(url)) => {
+                        context.status(307);
+                        context.set("Location", &url);
+                    }
+                    (size, BlobContent::Stream(reader)) => {
+                        context.set("Content-Length", &size.to_string());
+                        context.set_body_stream(reader.into_body());
+                    }
+                }
+            } else {
+                let size = blob_size(&db, &image, &digest).await?;
+                context.set("Content-Length", &size.to_string());
+            }
+        }
+        ("POST", RegistryRoute::Uploads(repository)) => {
+            let (image, _) = writable_image(context, &db, access, &repository).await?;
+
+            match query.get("digest") {
+                Some(digest) => {
+                    upload_monolithic(&db, &store, &image, digest, context.take_body_stream())
+                        .await?;
+
+                    context.status(201);
+                    context.set("Location", &format!("/v2/{repository}/blobs/{digest}"));
+                    context.set("Docker-Content-Digest", digest);
+                }
+                None => {
+                    let upload_id = start_upload(&store, &image).await?;
+
+                    context.status(202);
+                    context.set(
+                        "Location",
+                        &format!("/v2/{repository}/blobs/uploads/{upload_id}"),
+                    );
+                    context.set("Docker-Upload-UUID", &upload_id.to_string());
+                    context.set("Range", "0-0");
+                }
+            }
+        }
+        ("GET", RegistryRoute::Upload(repository, upload_id)) => {
+            let (image, _) = writable_image(context, &db, access, &repository).await?;
+            let size = upload_size(&store, &image, &upload_id).await?;
+
+            context.status(204);
+            context.set(
+                "Location",
+                &format!("/v2/{repository}/blobs/uploads/{upload_id}"),
+            );
+            context.set("Range", &format!("0-{}", size.saturating_sub(1)));
+        }
+        ("PATCH", RegistryRoute::Upload(repository, upload_id)) => {
+            let (image, _) = writable_image(context, &db, access, &repository).await?;
+            let size =
+                append_upload(&store, &image, &upload_id, context.take_body_stream()).await?;
+
+            context.status(202);
+            context.set(
+                "Location",
+                &format!("/v2/{repository}/blobs/uploads/{upload_id}"),
+            );
+            context.set("Docker-Upload-UUID", &upload_id.to_string());
+            context.set("Range", &format!("0-{}", size.saturating_sub(1)));
+        }
+        ("PUT", RegistryRoute::Upload(repository, upload_id)) => {
+            let (image, _) = writable_image(context, &db, access, &repository).await?;
+            let digest = query.get("digest").ok_or(OciError::DigestInvalid)?;
+            finish_upload(
+                &db,
+                &store,
+                &image,
+                &upload_id,
+                digest,
+                context.take_body_stream(),
+            )
+            .await?;
+
+            context.status(201);
+            context.set("Location", &format!("/v2/{repository}/blobs/{digest}"));
+            context.set("Docker-Content-Digest", digest);
+        }
+        ("DELETE", RegistryRoute::Upload(repository, upload_id)) => {
+            let (image, _) = writable_image(context, &db, access, &repository).await?;
+            cancel_upload(&store, &image, &upload_id).await?;
+
+            context.status(204);
+        }
+        _ => {
+            context.status(405);
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads the image for a push, recording its creation the first time a
+/// repository is pushed to. Pull tokens can't push.
+async fn writable_image<'a>(
+    context: &Ctx,
+    db: &impl GenericClient,
+    access: &'a RegistryAccess,
+    repository: &str,
+) -> Result<(Image, &'a User), OciError> {
+    let RegistryAccess::User(user) = access else {
+        return Err(OciError::Denied);
+    };
+    let (image, created) = push_image(db, &user.id, repository).await?;
+
+    if created {
+        audit::record(
+            db,
+            context,
+            Some(user.id),
+            "image.create",
+            "image",
+            Some(image.id),
+        )
+        .await
+        .map_err(OciError::Internal)?;
+    }
+
+    Ok((image, user))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            registry_credentials::{
+                tests::create_registry_credential_helper, CreateRegistryCredential,
+            },
+            sessions::tests::create_user_and_session_helper,
+        },
+        models::ImageVersion,
+        services::oci_registry::{
+            mirror_reference, pull_token, pull_token_expiring_at, sha256_digest, with_pull_token,
+        },
+        testing::{StubResponse, StubServer},
+        thruster_extensions::TestResponseExt,
+    };
+    use chrono::Utc;
+    use rand::distributions::DistString;
+    use thruster::Testable;
+
+    fn basic_auth(session_token: &str) -> (String, String) {
+        (
+            "Authorization".to_string(),
+            format!(
+                "Basic {}",
+                general_purpose::STANDARD.encode(format!("docker:{session_token}"))
+            ),
+        )
+    }
+
+    fn random_repository() -> String {
+        format!(
+            "images/pushed-{}",
+            rand::distributions::Alphanumeric
+                .sample_string(&mut rand::thread_rng(), 16)
+                .to_lowercase()
+        )
+    }
+
+    async fn push_blob_helper(
+        app: &impl Testable,
+        session_token: &str,
+        repository: &str,
+        bytes: &[u8],
+    ) -> String {
+        let digest = sha256_digest(bytes);
+        let _ = app
+            .post(
+                &format!("/v2/{repository}/blobs/uploads/?digest={digest}"),
+                vec![basic_auth(session_token)],
+                bytes.to_vec(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status");
+
+        digest
+    }
+
+    async fn start_upload_helper(
+        app: &impl Testable,
+        session_token: &str,
+        repository: &str,
+    ) -> String {
+        app.post(
+            &format!("/v2/{repository}/blobs/uploads/"),
+            vec![basic_auth(session_token)],
+            vec![],
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(202, "It should have an accepted status")
+        .headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("location"))
+        .map(|(_, v)| v.clone())
+        .expect("It should return the upload location")
+    }
+
+    fn manifest(config: &str, layer: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": config,
+                "size": 2
+            },
+            "layers": [{
+                "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                "digest": layer,
+                "size": 5
+            }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn registry_route_should_match_from_the_end_of_the_path() {
+        let upload_id = Uuid::new_v4();
+
+        assert_eq!(RegistryRoute::parse("/v2/"), Some(RegistryRoute::Base));
+        assert_eq!(
+            RegistryRoute::parse("/v2/a/b/c/tags/list"),
+            Some(RegistryRoute::Tags("a/b/c".to_string()))
+        );
+        assert_eq!(
+            RegistryRoute::parse("/v2/a/b/manifests/latest"),
+            Some(RegistryRoute::Manifest(
+                "a/b".to_string(),
+                "latest".to_string()
+            ))
+        );
+        assert_eq!(
+            RegistryRoute::parse("/v2/a/b/blobs/uploads/"),
+            Some(RegistryRoute::Uploads("a/b".to_string()))
+        );
+        assert_eq!(
+            RegistryRoute::parse(&format!("/v2/a/b/blobs/uploads/{upload_id}")),
+            Some(RegistryRoute::Upload("a/b".to_string(), upload_id))
+        );
+        assert_eq!(
+            RegistryRoute::parse("/v2/a/b/blobs/sha256:abc"),
+            Some(RegistryRoute::Blob(
+                "a/b".to_string(),
+                "sha256:abc".to_string()
+            ))
+        );
+        assert_eq!(RegistryRoute::parse("/v2/a/b/other"), None);
+        assert_eq!(RegistryRoute::parse("/images"), None);
+        assert_eq!(
+            split_pull_token("/v2/pull-abc/a/b/blobs/sha256:abc"),
+            (
+                Some("abc".to_string()),
+                "/v2/a/b/blobs/sha256:abc".to_string()
+            )
+        );
+        assert_eq!(
+            split_pull_token("/v2/a/b/tags/list"),
+            (None, "/v2/a/b/tags/list".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn registry_should_challenge_anonymous_clients() {
+        let test_app = crate::app::init().await.commit();
+
+        let _ = (&test_app as &dyn Testable)
+            .get("/v2/", vec![])
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+    }
+
+    #[tokio::test]
+    async fn docker_push_should_create_images_and_versions() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let repository = random_repository();
+
+        let config = push_blob_helper(&test_app, &session.token, &repository, b"{}").await;
+        let layer = push_blob_helper(&test_app, &session.token, &repository, b"hello").await;
+        let manifest = manifest(&config, &layer);
+
+        let _ = (&test_app as &dyn Testable)
+            .put(
+                &format!("/v2/{repository}/manifests/v1"),
+                vec![
+                    basic_auth(&session.token),
+                    (
+                        "Content-Type".to_string(),
+                        "application/vnd.oci.image.manifest.v1+json".to_string(),
+                    ),
+                ],
+                manifest.clone(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status");
+
+        let images = (&test_app as &dyn Testable)
+            .get(
+                "/images",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<Vec<Image>>();
+        let image = images
+            .iter()
+            .find(|image| image.image_url == format!("{}/{repository}", registry_host()))
+            .expect("It should create an image for the repository");
+
+        let versions = (&test_app as &dyn Testable)
+            .get(
+                &format!("/images/{}/versions", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<Vec<ImageVersion>>();
+        assert_eq!(versions.len(), 1, "It should create a version");
+        assert_eq!(versions[0].version_number, "v1");
+        assert_eq!(versions[0].hash, sha256_digest(&manifest));
+
+        let pulled = (&test_app as &dyn Testable)
+            .get(
+                &format!("/v2/{repository}/manifests/v1"),
+                vec![basic_auth(&session.token)],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status");
+        assert_eq!(pulled.body, manifest);
+
+        let pulled = (&test_app as &dyn Testable)
+            .get(
+                &format!("/v2/{repository}/blobs/{layer}"),
+                vec![basic_auth(&session.token)],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status");
+        assert_eq!(pulled.body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn chunked_uploads_should_verify_the_digest() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let repository = random_repository();
+
+        let location = start_upload_helper(&test_app, &session.token, &repository).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .patch(&location, vec![basic_auth(&session.token)], b"hel".to_vec())
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(202, "It should have an accepted status");
+
+        let _ = (&test_app as &dyn Testable)
+            .put(
+                &format!("{location}?digest={}", sha256_digest(b"nope")),
+                vec![basic_auth(&session.token)],
+                b"lo".to_vec(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(400, "It should reject mismatched digests");
+
+        let _ = (&test_app as &dyn Testable)
+            .put(
+                &format!("{location}?digest={}", sha256_digest(b"hello")),
+                vec![basic_auth(&session.token)],
+                b"lo".to_vec(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(404, "It should cancel uploads that fail verification");
+
+        let location = start_upload_helper(&test_app, &session.token, &repository).await;
+        let _ = (&test_app as &dyn Testable)
+            .patch(&location, vec![basic_auth(&session.token)], b"hel".to_vec())
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(202, "It should have an accepted status");
+
+        let _ = (&test_app as &dyn Testable)
+            .put(
+                &format!("{location}?digest={}", sha256_digest(b"hello")),
+                vec![basic_auth(&session.token)],
+                b"lo".to_vec(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status");
+    }
+
+    #[tokio::test]
+    async fn pull_tokens_should_only_read_their_image() {
+        let test_app = crate::app::init().await.commit();
+        let encryptor = crate::app::generate_default_server_config().await.encryptor;
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let repository = random_repository();
+        let layer = push_blob_helper(&test_app, &session.token, &repository, b"hello").await;
+        let other_repository = random_repository();
+        let _ = push_blob_helper(&test_app, &session.token, &other_repository, b"hello").await;
+
+        let image_url = format!("{}/{repository}", registry_host());
+        let images = (&test_app as &dyn Testable)
+            .get(
+                "/images",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .json::<Vec<Image>>();
+        let image = images
+            .iter()
+            .find(|image| image.image_url == image_url)
+            .expect("It should create an image for the repository");
+        let reference = with_pull_token(&encryptor, image, &format!("{image_url}@{layer}"));
+        let token = pull_token(&encryptor, &image.id);
+        assert_eq!(
+            reference,
+            format!("{}/pull-{token}/{repository}@{layer}", registry_host())
+        );
+
+        let pulled = (&test_app as &dyn Testable)
+            .get(
+                &format!("/v2/pull-{token}/{repository}/blobs/{layer}"),
+                vec![],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status");
+        assert_eq!(pulled.body, b"hello");
+
+        let _ = (&test_app as &dyn Testable)
+            .get(
+                &format!("/v2/pull-{token}/{other_repository}/blobs/{layer}"),
+                vec![],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(404, "It should not read other images");
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                &format!("/v2/pull-{token}/{repository}/blobs/uploads/"),
+                vec![],
+                vec![],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(403, "It should not push");
+
+        let expired = pull_token_expiring_at(&encryptor, &image.id, Utc::now().timestamp() - 1);
+        let (_, signature) = token.split_once('-').unwrap();
+        for token in [expired, format!("{}-{signature}", i64::MAX)] {
+            let _ = (&test_app as &dyn Testable)
+                .get(
+                    &format!("/v2/pull-{token}/{repository}/blobs/{layer}"),
+                    vec![],
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(404, "It should not read with expired or altered tokens");
+        }
+    }
+
+    #[tokio::test]
+    async fn pull_tokens_should_read_private_images_through_the_mirror() {
+        let test_app = crate::app::init().await.commit();
+        let encryptor = crate::app::generate_default_server_config().await.encryptor;
+        let manifest = r#"{"schemaVersion":2,"layers":[]}"#;
+        let layer = sha256_digest(b"weights");
+        let layer_path = format!("/v2/team/private/blobs/{layer}");
+        let upstream = StubServer::start(move |request| {
+            // "robot:secret"
+            if request.headers.get("authorization").map(|v| v.as_str())
+                != Some("Basic cm9ib3Q6c2VjcmV0")
+            {
+                return StubResponse::new(401).header("WWW-Authenticate", r#"Basic realm="stub""#);
+            }
+
+            match request.path.as_str() {
+                "/v2/team/private/manifests/v1" => StubResponse::new(200)
+                    .header("Content-Type", "application/vnd.oci.image.manifest.v1+json")
+                    .body(manifest),
+                path if path == layer_path => StubResponse::new(200).body("weights"),
+                _ => StubResponse::new(404),
+            }
+        })
+        .await;
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let authorization = (
+            "Authorization".to_string(),
+            format!("Bearer {}", session.token),
+        );
+        let image = (&test_app as &dyn Testable)
+            .post(
+                "/images",
+                vec![authorization.clone()],
+                serde_json::json!({
+                    "nickname": "private",
+                    "image_url": format!("{}/team/private:v1", upstream.host()),
+                    "verify": false,
+                })
+                .to_string()
+                .into_bytes(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status")
+            .json::<Image>();
+        let _ = create_registry_credential_helper(
+            &test_app,
+            &session.token,
+            &CreateRegistryCredential {
+                username: "robot".to_string(),
+                token: "secret".to_string(),
+                image_id: Some(image.id),
+                ..Default::default()
+            },
+        )
+        .await;
+        let image_version = (&test_app as &dyn Testable)
+            .get(
+                &format!("/images/{}/versions", image.id),
+                vec![authorization],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .json::<Vec<ImageVersion>>()
+            .remove(0);
+
+        let token = pull_token(&encryptor, &image.id);
+        assert_eq!(
+            mirror_reference(&encryptor, &image, &image_version),
+            format!("{}/pull-{token}/mirrors/{}:v1", registry_host(), image.id)
+        );
+
+        let pulled = (&test_app as &dyn Testable)
+            .get(
+                &format!("/v2/pull-{token}/mirrors/{}/manifests/v1", image.id),
+                vec![],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should read the manifest through");
+        assert_eq!(pulled.body, manifest.as_bytes());
+
+        let pulled = (&test_app as &dyn Testable)
+            .get(
+                &format!("/v2/pull-{token}/mirrors/{}/blobs/{layer}", image.id),
+                vec![],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should stream the blob through");
+        assert_eq!(pulled.body, b"weights");
+
+        let _ = (&test_app as &dyn Testable)
+            .get(&format!("/v2/mirrors/{}/manifests/v1", image.id), vec![])
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should need a pull token");
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                &format!("/v2/mirrors/{}/blobs/uploads/", image.id),
+                vec![basic_auth(&session.token)],
+                vec![],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(403, "It should not push to mirrors");
+    }
+
+    #[tokio::test]
+    async fn docker_push_should_deny_repositories_owned_by_others() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let repository = random_repository();
+        let _ = push_blob_helper(&test_app, &session.token, &repository, b"hello").await;
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                &format!(
+                    "/v2/{repository}/blobs/uploads/?digest={}",
+                    sha256_digest(b"hello")
+                ),
+                vec![basic_auth(&session.token)],
+                b"hello".to_vec(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(403, "It should have a forbidden status");
+    }
+}
@@ -0,0 +1,585 @@
+use std::{collections::HashMap, str::FromStr};
+
+use deadpool_postgres::{GenericClient, Pool};
+use serde::{Deserialize, Serialize};
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::{submission_error, Error},
+    models::{Image, ImageVersion, Job, JobStatus, Pipeline, PipelineJob, User},
+    services::{
+        audit,
+        jobs::{launch_job, record_job, validate_job},
+        pipelines::{
+            add_dependency, pipeline_dependencies, topological_order, PipelineStatus,
+            MAX_PIPELINE_JOBS,
+        },
+        schedules::JobTemplate,
+    },
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct CreatePipelineJob {
+    /// Names the job within the pipeline.
+    pub(crate) key: String,
+    /// Keys of the jobs that have to complete before this one starts.
+    #[serde(default)]
+    pub(crate) depends_on: Vec<String>,
+    pub(crate) image_id: Uuid,
+    pub(crate) image_version_id: Uuid,
+    #[serde(flatten)]
+    pub(crate) template: JobTemplate,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct CreatePipeline {
+    pub(crate) name: String,
+    pub(crate) jobs: Vec<CreatePipelineJob>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct PipelineJobDetails {
+    pub(crate) key: String,
+    pub(crate) depends_on: Vec<String>,
+    #[serde(flatten)]
+    pub(crate) job: Job,
+}
+
+/// A pipeline with its jobs and where it is overall.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct PipelineDetails {
+    #[serde(flatten)]
+    pub(crate) pipeline: Pipeline,
+    pub(crate) status: PipelineStatus,
+    pub(crate) jobs: Vec<PipelineJobDetails>,
+}
+
+async fn pipeline_details(
+    db: &impl GenericClient,
+    pipeline: Pipeline,
+) -> Result<PipelineDetails, Box<dyn std::error::Error>> {
+    let pipeline_jobs = PipelineJob::read_where_pipeline_id(db, &pipeline.id).await?;
+    let mut dependencies = pipeline_dependencies(db, &pipeline.id).await?;
+    let keys: HashMap<Uuid, String> = pipeline_jobs
+        .iter()
+        .map(|pipeline_job| (pipeline_job.job_id, pipeline_job.key.clone()))
+        .collect();
+
+    let mut jobs = vec![];
+    for pipeline_job in pipeline_jobs {
+        let depends_on = dependencies
+            .remove(&pipeline_job.job_id)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|job_id| keys.get(job_id).cloned())
+            .collect();
+        jobs.push(PipelineJobDetails {
+            key: pipeline_job.key,
+            depends_on,
+            job: Job::read(db, &pipeline_job.job_id).await?,
+        });
+    }
+    let statuses: Vec<JobStatus> = jobs.iter().map(|job| job.job.status.clone()).collect();
+
+    Ok(PipelineDetails {
+        pipeline,
+        status: PipelineStatus::of(&statuses),
+        jobs,
+    })
+}
+
+/// Submits a set of jobs at once. Jobs without `depends_on` are queued
+/// straight away, the rest wait as `Blocked` until their parents complete
+/// and are cancelled if one of them doesn't.
+#[thruster::json_request]
+pub(crate) async fn create_pipeline(
+    create_pipeline: CreatePipeline,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let CreatePipeline { name, jobs } = create_pipeline;
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let mut db = db.get().await.unwrap();
+    let db = db.transaction().await.unwrap();
+
+    if name.trim().is_empty() {
+        return Err(
+            Error::UnprocessableEntity(context, "Pipelines need a name".to_string()).into(),
+        );
+    }
+    if jobs.is_empty() || jobs.len() > MAX_PIPELINE_JOBS {
+        return Err(Error::UnprocessableEntity(
+            context,
+            format!("Pipelines need between 1 and {MAX_PIPELINE_JOBS} jobs"),
+        )
+        .into());
+    }
+    let graph: Vec<(String, Vec<String>)> = jobs
+        .iter()
+        .map(|job| (job.key.clone(), job.depends_on.clone()))
+        .collect();
+    let order = match topological_order(&graph) {
+        Ok(order) => order,
+        Err(message) => return Err(Error::UnprocessableEntity(context, message).into()),
+    };
+
+    let mut versions = vec![];
+    for job in &jobs {
+        let image = Image::read(&db, &job.image_id).await.map_err(|e| {
+            tracing::error!("An error occurred while fetching an image: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+        if image.user_id != user.id {
+            tracing::error!("User does not own image");
+            return Err(ThrusterError::unauthorized_error(context));
+        }
+
+        let image_version = ImageVersion::read_where_image_id(&db, &image.id)
+            .await
+            .map_err(|e| {
+                tracing::error!("An error occurred while fetching an image: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?
+            .into_iter()
+            .find(|v| v.id == job.image_version_id)
+            .ok_or_else(|| ThrusterError::not_found_error(context.clone_ctx()))?;
+
+        validate_job(
+            &db,
+            &user.id,
+            &image,
+            &image_version,
+            &job.template.spec,
+            &job.template.input,
+        )
+        .await
+        .map_err(|e| submission_error(&context, e))?;
+        versions.push((image, image_version));
+    }
+
+    let pipeline = Pipeline::create(&db, user.id, name).await.map_err(|e| {
+        tracing::error!("An error occurred while creating a pipeline: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let mut job_ids: HashMap<&str, Uuid> = HashMap::new();
+    for index in order {
+        let job = &jobs[index];
+        let (image, image_version) = &versions[index];
+
+        let created = match job.depends_on.is_empty() {
+            true => {
+                launch_job(
+                    &db,
+                    &user.id,
+                    image,
+                    image_version,
+                    &job.template.spec,
+                    &job.template.input,
+                )
+                .await
+            }
+            false => {
+                record_job(
+                    &db,
+                    &user.id,
+                    image,
+                    image_version,
+                    &job.template.spec,
+                    &job.template.input,
+                    JobStatus::Blocked,
+                )
+                .await
+            }
+        };
+        let created = created.map_err(|e| submission_error(&context, e))?;
+
+        PipelineJob::create(&db, pipeline.id, created.id, job.key.clone())
+            .await
+            .map_err(|e| {
+                tracing::error!("An error occurred while adding a job to a pipeline: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?;
+        for parent in &job.depends_on {
+            add_dependency(&db, &created.id, &job_ids[parent.as_str()])
+                .await
+                .map_err(|e| {
+                    tracing::error!("An error occurred while recording a job dependency: {e:#?}");
+                    ThrusterError::generic_error(context.clone_ctx())
+                })?;
+        }
+
+        audit::record(
+            &db,
+            &context,
+            Some(user.id),
+            "job.create",
+            "job",
+            Some(created.id),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while recording an audit event: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+        job_ids.insert(job.key.as_str(), created.id);
+    }
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "pipeline.create",
+        "pipeline",
+        Some(pipeline.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let details = pipeline_details(&db, pipeline).await.map_err(|e| {
+        tracing::error!("An error occurred while fetching a pipeline's jobs: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    db.commit().await.unwrap();
+
+    context.json(&details).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(201);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_pipelines(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let pipelines = Pipeline::read_where_user_id(&db, &user.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching pipelines: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    let mut details = vec![];
+    for pipeline in pipelines {
+        details.push(pipeline_details(&db, pipeline).await.map_err(|e| {
+            tracing::error!("An error occurred while fetching a pipeline's jobs: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?);
+    }
+
+    context.json(&details).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_pipeline(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let pipeline_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid pipeline id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+    let pipeline = Pipeline::read(&db, &pipeline_id)
+        .await
+        .ok()
+        .filter(|pipeline| pipeline.user_id == user.id)
+        .ok_or_else(|| ThrusterError::not_found_error(context.clone_ctx()))?;
+
+    let details = pipeline_details(&db, pipeline).await.map_err(|e| {
+        tracing::error!("An error occurred while fetching a pipeline's jobs: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.json(&details).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            images::tests::{create_image_helper, create_image_version_helper},
+            sessions::tests::create_user_and_session_helper,
+        },
+        models::{Attempt, FailureClass},
+        services::{
+            job_queue::tests::{launcher, work_job},
+            jobs::{complete_attempt, fail_attempt, AttemptFailure, JobSpec},
+        },
+        thruster_extensions::TestResponseExt,
+    };
+    use chrono::Utc;
+    use thruster::Testable;
+
+    fn pipeline_job(
+        key: &str,
+        depends_on: &[&str],
+        image: &Image,
+        image_version: &ImageVersion,
+    ) -> CreatePipelineJob {
+        CreatePipelineJob {
+            key: key.to_string(),
+            depends_on: depends_on.iter().map(|key| key.to_string()).collect(),
+            image_id: image.id,
+            image_version_id: image_version.id,
+            template: JobTemplate {
+                spec: JobSpec {
+                    preset: Some("cpu-small".to_string()),
+                    ..Default::default()
+                },
+                input: Default::default(),
+            },
+        }
+    }
+
+    /// Creates a preprocess → train → evaluate pipeline.
+    async fn create_pipeline_helper(
+        app: &impl Testable,
+        user_id: &Uuid,
+        session_token: &str,
+    ) -> PipelineDetails {
+        let image = create_image_helper(app, user_id, session_token).await;
+        let image_version = create_image_version_helper(app, &image.id, session_token, "v1").await;
+
+        app.post(
+            "/pipelines",
+            vec![(
+                "Authorization".to_string(),
+                format!("Bearer {session_token}"),
+            )],
+            serde_json::to_vec(&CreatePipeline {
+                name: "training".to_string(),
+                jobs: vec![
+                    pipeline_job("evaluate", &["train"], &image, &image_version),
+                    pipeline_job("train", &["preprocess"], &image, &image_version),
+                    pipeline_job("preprocess", &[], &image, &image_version),
+                ],
+            })
+            .unwrap(),
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(201, "It should have a created status")
+        .json::<PipelineDetails>()
+    }
+
+    fn job_status(details: &PipelineDetails, key: &str) -> JobStatus {
+        details
+            .jobs
+            .iter()
+            .find(|job| job.key == key)
+            .unwrap()
+            .job
+            .status
+            .clone()
+    }
+
+    fn job_id(details: &PipelineDetails, key: &str) -> Uuid {
+        details
+            .jobs
+            .iter()
+            .find(|job| job.key == key)
+            .unwrap()
+            .job
+            .id
+    }
+
+    async fn get_pipeline_helper(
+        app: &impl Testable,
+        pipeline_id: &Uuid,
+        session_token: &str,
+    ) -> PipelineDetails {
+        app.get(
+            &format!("/pipelines/{pipeline_id}"),
+            vec![(
+                "Authorization".to_string(),
+                format!("Bearer {session_token}"),
+            )],
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(200, "It should have an ok status")
+        .json::<PipelineDetails>()
+    }
+
+    /// Starts `job_id` and returns its attempt, as a queue worker would.
+    pub(crate) async fn start_job(job_id: &Uuid) -> Attempt {
+        let config = crate::app::generate_default_server_config().await;
+        assert!(work_job(&config.db, &launcher(&config), job_id, Utc::now()).await);
+
+        Attempt::read_where_job_id(&config.db.get().await.unwrap(), job_id)
+            .await
+            .unwrap()
+            .remove(0)
+    }
+
+    #[tokio::test]
+    async fn create_pipeline_should_only_queue_jobs_without_parents() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let details = create_pipeline_helper(&test_app, &test_user.id, &session.token).await;
+
+        assert_eq!(details.status, PipelineStatus::Running);
+        assert!(matches!(
+            job_status(&details, "preprocess"),
+            JobStatus::Queued
+        ));
+        assert!(matches!(job_status(&details, "train"), JobStatus::Blocked));
+        assert!(matches!(
+            job_status(&details, "evaluate"),
+            JobStatus::Blocked
+        ));
+        let train = details.jobs.iter().find(|job| job.key == "train").unwrap();
+        assert_eq!(train.depends_on, vec!["preprocess".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn completed_jobs_should_release_their_dependents() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let details = create_pipeline_helper(&test_app, &test_user.id, &session.token).await;
+
+        let attempt = start_job(&job_id(&details, "preprocess")).await;
+        complete_attempt(&db.get().await.unwrap(), &attempt, Utc::now())
+            .await
+            .unwrap();
+
+        let details = get_pipeline_helper(&test_app, &details.pipeline.id, &session.token).await;
+        assert!(matches!(
+            job_status(&details, "preprocess"),
+            JobStatus::Completed
+        ));
+        assert!(matches!(job_status(&details, "train"), JobStatus::Queued));
+        assert!(matches!(
+            job_status(&details, "evaluate"),
+            JobStatus::Blocked
+        ));
+        assert_eq!(details.status, PipelineStatus::Running);
+    }
+
+    #[tokio::test]
+    async fn failed_jobs_should_cancel_their_dependents() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let details = create_pipeline_helper(&test_app, &test_user.id, &session.token).await;
+
+        let attempt = start_job(&job_id(&details, "preprocess")).await;
+        fail_attempt(
+            &db.get().await.unwrap(),
+            &attempt,
+            AttemptFailure {
+                class: FailureClass::NonZeroExit,
+                exit_code: Some(1),
+                error: None,
+            },
+            Utc::now(),
+        )
+        .await
+        .unwrap();
+
+        let details = get_pipeline_helper(&test_app, &details.pipeline.id, &session.token).await;
+        assert!(matches!(
+            job_status(&details, "preprocess"),
+            JobStatus::Failed
+        ));
+        assert!(matches!(
+            job_status(&details, "train"),
+            JobStatus::Cancelled
+        ));
+        assert!(matches!(
+            job_status(&details, "evaluate"),
+            JobStatus::Cancelled
+        ));
+        assert_eq!(details.status, PipelineStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn create_pipeline_should_reject_cycles() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                "/pipelines",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&CreatePipeline {
+                    name: "loop".to_string(),
+                    jobs: vec![
+                        pipeline_job("train", &["evaluate"], &image, &image_version),
+                        pipeline_job("evaluate", &["train"], &image, &image_version),
+                    ],
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(422, "It should have an unprocessable entity status");
+    }
+}
@@ -0,0 +1,198 @@
+use chrono::Utc;
+use deadpool_postgres::Pool;
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::Error,
+    models::User,
+    services::quotas::user_quotas,
+};
+
+#[thruster::middleware]
+pub(crate) async fn get_quotas(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let quotas = user_quotas(&db, &user.id, Utc::now()).await.map_err(|e| {
+        tracing::error!("An error occurred while fetching quotas: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.json(&quotas).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            images::tests::create_image_helper, jobs::CreateJob,
+            sessions::tests::create_user_and_session_helper,
+        },
+        models::{ImageVersion, OrganizationMember},
+        services::{
+            jobs::{JobInput, JobSpec},
+            quotas::Quota,
+        },
+        thruster_extensions::TestResponseExt,
+    };
+    use thruster::{testing::TestResponse, Testable};
+    use uuid::Uuid;
+
+    pub(crate) async fn submit_job(
+        app: &impl Testable,
+        token: &str,
+        image_id: &Uuid,
+        preset: &str,
+    ) -> TestResponse {
+        let headers = vec![("Authorization".to_string(), format!("Bearer {token}"))];
+        let image_version = app
+            .get(&format!("/images/{image_id}/versions"), headers.clone())
+            .await
+            .expect("Should correctly resolve")
+            .json::<Vec<ImageVersion>>()
+            .remove(0);
+
+        app.post(
+            "/jobs",
+            headers,
+            serde_json::to_vec(&CreateJob {
+                image_id: *image_id,
+                image_version_id: image_version.id,
+                spec: JobSpec {
+                    preset: Some(preset.to_string()),
+                    ..Default::default()
+                },
+                input: JobInput::default(),
+            })
+            .unwrap(),
+        )
+        .await
+        .expect("Should correctly resolve")
+    }
+
+    async fn get_quotas_helper(app: &impl Testable, token: &str) -> Vec<Quota> {
+        app.get(
+            "/quotas",
+            vec![("Authorization".to_string(), format!("Bearer {token}"))],
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(200, "It should have an ok status")
+        .json::<Vec<Quota>>()
+    }
+
+    #[tokio::test]
+    async fn create_job_should_respect_concurrency_quotas() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        db.execute(
+            "INSERT INTO quotas (user_id, max_concurrent_jobs) VALUES ($1, 1)",
+            &[&test_user.id],
+        )
+        .await
+        .unwrap();
+
+        submit_job(&test_app, &session.token, &image.id, "a10")
+            .await
+            .expect_status(201, "It should have a created status");
+        submit_job(&test_app, &session.token, &image.id, "cpu-small")
+            .await
+            .expect_status(429, "It should be over the concurrent job quota");
+
+        let quotas = get_quotas_helper(&test_app, &session.token).await;
+
+        assert_eq!(quotas.len(), 1);
+        assert_eq!(quotas[0].limits.max_concurrent_jobs, Some(1));
+        assert_eq!(
+            quotas[0].limits.max_gpus,
+            Some(2),
+            "It should use the plan's"
+        );
+        assert_eq!(quotas[0].usage.concurrent_jobs, 1);
+        assert_eq!(quotas[0].usage.gpus, 1);
+    }
+
+    #[tokio::test]
+    async fn create_job_should_reject_gpu_jobs_once_gpu_hours_are_used_up() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        db.execute(
+            "INSERT INTO quotas (user_id, max_monthly_gpu_hours) VALUES ($1, 0)",
+            &[&test_user.id],
+        )
+        .await
+        .unwrap();
+
+        submit_job(&test_app, &session.token, &image.id, "a10")
+            .await
+            .expect_status(403, "It should be over the GPU-hour quota");
+        submit_job(&test_app, &session.token, &image.id, "cpu-small")
+            .await
+            .expect_status(201, "CPU jobs should still be allowed");
+    }
+
+    #[tokio::test]
+    async fn create_job_should_respect_organization_quotas() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let organization_id = OrganizationMember::read_where_user_id(&db, &test_user.id)
+            .await
+            .unwrap()[0]
+            .organization_id;
+        db.execute(
+            "INSERT INTO quotas (organization_id, max_gpus) VALUES ($1, 1)",
+            &[&organization_id],
+        )
+        .await
+        .unwrap();
+
+        submit_job(&test_app, &session.token, &image.id, "a10")
+            .await
+            .expect_status(201, "It should have a created status");
+        submit_job(&test_app, &session.token, &image.id, "a10")
+            .await
+            .expect_status(429, "It should be over the organization's GPU quota");
+
+        let quotas = get_quotas_helper(&test_app, &session.token).await;
+
+        assert_eq!(quotas.len(), 2);
+        assert_eq!(quotas[1].organization_id, Some(organization_id));
+        assert_eq!(quotas[1].limits.max_gpus, Some(1));
+        assert_eq!(quotas[1].limits.max_concurrent_jobs, None);
+        assert_eq!(quotas[1].usage.gpus, 1);
+    }
+}
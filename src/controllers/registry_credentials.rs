@@ -0,0 +1,496 @@
+use std::str::FromStr;
+
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::Error,
+    models::{Image, NonSecureRegistryCredential, RegistryCredential, User},
+    reference::{is_valid_registry, Reference},
+    services::{audit, crypto::Encryptor, organizations::administered_organization_ids},
+};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct CreateRegistryCredential {
+    /// The registry host, defaults to the image's registry when attaching to
+    /// an image.
+    pub(crate) registry: Option<String>,
+    pub(crate) username: String,
+    pub(crate) token: String,
+    pub(crate) image_id: Option<Uuid>,
+    pub(crate) organization_id: Option<Uuid>,
+}
+
+#[thruster::json_request]
+pub(crate) async fn create_registry_credential(
+    create_registry_credential: CreateRegistryCredential,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let CreateRegistryCredential {
+        registry,
+        username,
+        token,
+        image_id,
+        organization_id,
+    } = create_registry_credential;
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let registry = match (image_id, organization_id) {
+        (Some(image_id), None) => {
+            let image = Image::read(&db, &image_id).await.map_err(|e| {
+                tracing::error!("Could not load image: {e:#?}");
+                ThrusterError::not_found_error(context.clone_ctx())
+            })?;
+
+            if image.user_id != user.id {
+                return Err(ThrusterError::unauthorized_error(context));
+            }
+
+            registry.or_else(|| {
+                Reference::parse(&image.image_url)
+                    .ok()
+                    .map(|reference| reference.registry)
+            })
+        }
+        (None, Some(organization_id)) => {
+            let administered = administered_organization_ids(&db, &user.id)
+                .await
+                .map_err(|e| {
+                    tracing::error!("An error occurred while fetching organizations: {e:#?}");
+                    ThrusterError::generic_error(context.clone_ctx())
+                })?;
+
+            if !administered.contains(&organization_id) {
+                return Err(ThrusterError::unauthorized_error(context));
+            }
+
+            registry
+        }
+        _ => {
+            return Err(Error::UnprocessableEntity(
+                context,
+                "Exactly one of image_id or organization_id is required".to_string(),
+            )
+            .into())
+        }
+    };
+
+    let registry = match registry {
+        Some(registry) if is_valid_registry(&registry) => registry,
+        _ => {
+            return Err(Error::UnprocessableEntity(
+                context,
+                "A valid registry host is required".to_string(),
+            )
+            .into())
+        }
+    };
+
+    let encryptor: &Encryptor = context.extra.get();
+    let encrypted_token = encryptor.encrypt_str(&token).map_err(|e| {
+        tracing::error!("An error occurred while encrypting a registry token: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let credential: NonSecureRegistryCredential = RegistryCredential::create(
+        &db,
+        user.id,
+        image_id,
+        organization_id,
+        registry,
+        username,
+        encrypted_token,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while creating a registry credential: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?
+    .into();
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "registry_credential.create",
+        "registry_credential",
+        Some(credential.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.json(&credential).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(201);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_registry_credentials(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let db: &Pool = context.extra.get();
+    let user: &Option<User> = context.extra.get();
+    let credentials = RegistryCredential::read_where_user_id(
+        &db.get().await.unwrap(),
+        &user.as_ref().unwrap().id,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while fetching registry credentials: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?
+    .into_iter()
+    .map(|credential| credential.into())
+    .collect::<Vec<NonSecureRegistryCredential>>();
+
+    context.json(&credentials).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn delete_registry_credential(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let credential_id =
+        Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+            tracing::error!("Invalid registry credential id format: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    let credential = RegistryCredential::read(&db, &credential_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Could not load registry credential: {e:#?}");
+            ThrusterError::not_found_error(context.clone_ctx())
+        })?;
+
+    // Organization credentials belong to the organization, any of its admins
+    // may remove them.
+    let may_delete = credential.user_id == user.id
+        || match credential.organization_id {
+            Some(organization_id) => administered_organization_ids(&db, &user.id)
+                .await
+                .map_err(|e| {
+                    tracing::error!("An error occurred while fetching organizations: {e:#?}");
+                    ThrusterError::generic_error(context.clone_ctx())
+                })?
+                .contains(&organization_id),
+            None => false,
+        };
+    if !may_delete {
+        return Err(ThrusterError::unauthorized_error(context));
+    }
+
+    RegistryCredential::destroy(&db, &credential.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while deleting a registry credential: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "registry_credential.delete",
+        "registry_credential",
+        Some(credential.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.status(204);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            images::tests::create_image_helper, quotas::tests::submit_job,
+            sessions::tests::create_user_and_session_helper,
+        },
+        models::{OrganizationMember, OrganizationRole},
+        services::registry_credentials::find_registry_auth,
+        thruster_extensions::TestResponseExt,
+    };
+    use thruster::Testable;
+
+    pub(crate) async fn create_registry_credential_helper(
+        app: &impl Testable,
+        session_token: &str,
+        create_registry_credential: &CreateRegistryCredential,
+    ) -> NonSecureRegistryCredential {
+        app.post(
+            "/registry-credentials",
+            vec![(
+                "Authorization".to_string(),
+                format!("Bearer {session_token}"),
+            )],
+            serde_json::to_vec(create_registry_credential).unwrap(),
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(201, "It should have a created status")
+        .json::<NonSecureRegistryCredential>()
+    }
+
+    #[tokio::test]
+    async fn create_registry_credential_should_encrypt_the_token() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let credential = create_registry_credential_helper(
+            &test_app,
+            &session.token,
+            &CreateRegistryCredential {
+                username: "robot".to_string(),
+                token: "hunter2".to_string(),
+                image_id: Some(image.id),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        assert_eq!(credential.registry, "ghcr.io");
+
+        let config = crate::app::generate_default_server_config().await;
+        let db = config.db.get().await.unwrap();
+        let stored = RegistryCredential::read(&db, &credential.id).await.unwrap();
+        assert!(
+            !stored.encrypted_token.contains("hunter2"),
+            "It should not store the token in plaintext"
+        );
+
+        let auth = find_registry_auth(
+            &db,
+            &config.encryptor,
+            &test_user.id,
+            Some(&image.id),
+            "ghcr.io",
+        )
+        .await
+        .unwrap()
+        .expect("It should find the image's credentials");
+        assert_eq!(auth.username, "robot");
+        assert_eq!(auth.password, "hunter2");
+    }
+
+    #[tokio::test]
+    async fn organization_credentials_should_apply_to_every_image_on_the_registry() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let config = crate::app::generate_default_server_config().await;
+        let db = config.db.get().await.unwrap();
+        let organization_id = administered_organization_ids(&db, &test_user.id)
+            .await
+            .unwrap()[0];
+
+        let _ = create_registry_credential_helper(
+            &test_app,
+            &session.token,
+            &CreateRegistryCredential {
+                registry: Some("ghcr.io".to_string()),
+                username: "org-robot".to_string(),
+                token: "org-secret".to_string(),
+                organization_id: Some(organization_id),
+                ..Default::default()
+            },
+        )
+        .await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+
+        let auth = find_registry_auth(
+            &db,
+            &config.encryptor,
+            &test_user.id,
+            Some(&image.id),
+            "ghcr.io",
+        )
+        .await
+        .unwrap()
+        .expect("It should fall back to the organization's credentials");
+        assert_eq!(auth.username, "org-robot");
+    }
+
+    #[tokio::test]
+    async fn jobs_should_run_images_that_need_credentials() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let _ = create_registry_credential_helper(
+            &test_app,
+            &session.token,
+            &CreateRegistryCredential {
+                username: "robot".to_string(),
+                token: "hunter2".to_string(),
+                image_id: Some(image.id),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        submit_job(&test_app, &session.token, &image.id, "cpu-small")
+            .await
+            .expect_status(201, "It should pull the image through the mirror");
+    }
+
+    #[tokio::test]
+    async fn delete_registry_credential_should_allow_any_organization_admin() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        let (creator, session) = create_user_and_session_helper(&test_app).await;
+        let (admin, admin_session) = create_user_and_session_helper(&test_app).await;
+        let (member, member_session) = create_user_and_session_helper(&test_app).await;
+        let organization_id = administered_organization_ids(&db, &creator.id)
+            .await
+            .unwrap()[0];
+        for (user_id, role) in [
+            (admin.id, OrganizationRole::Admin),
+            (member.id, OrganizationRole::Member),
+        ] {
+            OrganizationMember::create(&db, organization_id, user_id, role)
+                .await
+                .unwrap();
+        }
+        let credential = create_registry_credential_helper(
+            &test_app,
+            &session.token,
+            &CreateRegistryCredential {
+                registry: Some("ghcr.io".to_string()),
+                username: "org-robot".to_string(),
+                token: "org-secret".to_string(),
+                organization_id: Some(organization_id),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        for (token, status) in [(&member_session.token, 401), (&admin_session.token, 204)] {
+            let _ = (&test_app as &dyn Testable)
+                .delete(
+                    &format!("/registry-credentials/{}", credential.id),
+                    vec![("Authorization".to_string(), format!("Bearer {token}"))],
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(status, "Only organization admins should delete it");
+        }
+    }
+
+    #[tokio::test]
+    async fn create_registry_credential_should_require_the_owning_user() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                "/registry-credentials",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&CreateRegistryCredential {
+                    username: "robot".to_string(),
+                    token: "hunter2".to_string(),
+                    image_id: Some(image.id),
+                    ..Default::default()
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+    }
+
+    #[tokio::test]
+    async fn get_registry_credentials_should_not_return_tokens() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let _ = create_registry_credential_helper(
+            &test_app,
+            &session.token,
+            &CreateRegistryCredential {
+                username: "robot".to_string(),
+                token: "hunter2".to_string(),
+                image_id: Some(image.id),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let response = (&test_app as &dyn Testable)
+            .get(
+                "/registry-credentials",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status");
+
+        let credentials = response.json::<Vec<NonSecureRegistryCredential>>();
+        assert_eq!(credentials.len(), 1, "It should have a credential");
+        assert!(
+            !String::from_utf8_lossy(&response.body).contains("encrypted_token"),
+            "It should not return the sealed token"
+        );
+    }
+}
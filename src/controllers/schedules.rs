@@ -0,0 +1,620 @@
+use std::str::FromStr;
+
+use chrono::Utc;
+use deadpool_postgres::{GenericClient, Pool};
+use serde::{Deserialize, Serialize};
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::{submission_error, Error},
+    models::{Image, ImageVersion, Schedule, ScheduleRun, User},
+    services::{
+        audit,
+        jobs::validate_job,
+        schedules::{parse_schedule, read_template, write_template, JobTemplate, DEFAULT_TIMEZONE},
+    },
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct CreateSchedule {
+    pub(crate) name: String,
+    /// Five fields: minute, hour, day of month, month and day of week.
+    pub(crate) cron: String,
+    /// Defaults to UTC.
+    pub(crate) timezone: Option<String>,
+    pub(crate) image_id: Uuid,
+    pub(crate) image_version_id: Uuid,
+    /// Defaults to enabled.
+    pub(crate) enabled: Option<bool>,
+    #[serde(flatten)]
+    pub(crate) template: JobTemplate,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct UpdateSchedule {
+    pub(crate) name: Option<String>,
+    pub(crate) cron: Option<String>,
+    pub(crate) timezone: Option<String>,
+    pub(crate) enabled: Option<bool>,
+}
+
+/// A schedule along with the job it launches.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ScheduleDetails {
+    #[serde(flatten)]
+    pub(crate) schedule: Schedule,
+    #[serde(flatten)]
+    pub(crate) template: JobTemplate,
+}
+
+#[thruster::json_request]
+pub(crate) async fn create_schedule(
+    create_schedule: CreateSchedule,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let CreateSchedule {
+        name,
+        cron,
+        timezone,
+        image_id,
+        image_version_id,
+        enabled,
+        template,
+    } = create_schedule;
+    let timezone = timezone.unwrap_or_else(|| DEFAULT_TIMEZONE.to_string());
+    let enabled = enabled.unwrap_or(true);
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let mut db = db.get().await.unwrap();
+    let db = db.transaction().await.unwrap();
+
+    if name.trim().is_empty() {
+        return Err(
+            Error::UnprocessableEntity(context, "Schedules need a name".to_string()).into(),
+        );
+    }
+    let cron_schedule = match parse_schedule(&cron, &timezone) {
+        Ok(cron_schedule) => cron_schedule,
+        Err(message) => return Err(Error::UnprocessableEntity(context, message).into()),
+    };
+
+    let image = Image::read(&db, &image_id).await.map_err(|e| {
+        tracing::error!("An error occurred while fetching an image: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    if image.user_id != user.id {
+        tracing::error!("User does not own image");
+        return Err(ThrusterError::unauthorized_error(context));
+    }
+
+    let image_version = ImageVersion::read_where_image_id(&db, &image_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching an image: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?
+        .into_iter()
+        .find(|v| v.id == image_version_id)
+        .ok_or_else(|| ThrusterError::not_found_error(context.clone_ctx()))?;
+
+    validate_job(
+        &db,
+        &user.id,
+        &image,
+        &image_version,
+        &template.spec,
+        &template.input,
+    )
+    .await
+    .map_err(|e| submission_error(&context, e))?;
+
+    let next_run_at = enabled
+        .then(|| cron_schedule.next_after(Utc::now()))
+        .flatten();
+    let schedule = Schedule::create(
+        &db,
+        user.id,
+        name,
+        cron,
+        timezone,
+        image_version.id,
+        enabled,
+        next_run_at,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while creating a schedule: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+    write_template(&db, &schedule.id, &template)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while writing a schedule's template: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "schedule.create",
+        "schedule",
+        Some(schedule.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    db.commit().await.unwrap();
+
+    context
+        .json(&ScheduleDetails { schedule, template })
+        .map_err(|_e| {
+            Error::GenericError(
+                context.clone_ctx(),
+                "Serialization error".to_string(),
+                serde_json::Value::default(),
+            )
+            .into()
+        })?;
+
+    context.status(201);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_schedules(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let db: &Pool = context.extra.get();
+    let user: &Option<User> = context.extra.get();
+    let schedules =
+        Schedule::read_where_user_id(&db.get().await.unwrap(), &user.as_ref().unwrap().id)
+            .await
+            .map_err(|e| {
+                tracing::error!("An error occurred while fetching schedules: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?;
+
+    context.json(&schedules).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+async fn owned_schedule(
+    context: &Ctx,
+    db: &impl GenericClient,
+    user_id: &Uuid,
+) -> Result<Schedule, ThrusterError<Ctx>> {
+    let schedule_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid schedule id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    Schedule::read(db, &schedule_id)
+        .await
+        .ok()
+        .filter(|schedule| schedule.user_id == *user_id)
+        .ok_or_else(|| ThrusterError::not_found_error(context.clone_ctx()))
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_schedule(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let schedule = owned_schedule(&context, &db, &user.id).await?;
+    let template = read_template(&db, &schedule.id).await.map_err(|e| {
+        tracing::error!("An error occurred while fetching a schedule's template: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context
+        .json(&ScheduleDetails { schedule, template })
+        .map_err(|_e| {
+            Error::GenericError(
+                context.clone_ctx(),
+                "Serialization error".to_string(),
+                serde_json::Value::default(),
+            )
+            .into()
+        })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+/// Renames, reschedules, enables or disables a schedule. Disabled schedules
+/// keep their history but have no next run.
+#[thruster::json_request]
+pub(crate) async fn update_schedule(
+    update_schedule: UpdateSchedule,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let UpdateSchedule {
+        name,
+        cron,
+        timezone,
+        enabled,
+    } = update_schedule;
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let mut schedule = owned_schedule(&context, &db, &user.id).await?;
+
+    if let Some(name) = name {
+        if name.trim().is_empty() {
+            return Err(
+                Error::UnprocessableEntity(context, "Schedules need a name".to_string()).into(),
+            );
+        }
+        schedule.name = name;
+    }
+    if let Some(cron) = cron {
+        schedule.cron = cron;
+    }
+    if let Some(timezone) = timezone {
+        schedule.timezone = timezone;
+    }
+    if let Some(enabled) = enabled {
+        schedule.enabled = enabled;
+    }
+
+    let cron_schedule = match parse_schedule(&schedule.cron, &schedule.timezone) {
+        Ok(cron_schedule) => cron_schedule,
+        Err(message) => return Err(Error::UnprocessableEntity(context, message).into()),
+    };
+    schedule.next_run_at = schedule
+        .enabled
+        .then(|| cron_schedule.next_after(Utc::now()))
+        .flatten();
+
+    let schedule = schedule.update(&db).await.map_err(|e| {
+        tracing::error!("An error occurred while updating a schedule: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "schedule.update",
+        "schedule",
+        Some(schedule.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.json(&schedule).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn delete_schedule(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let schedule = owned_schedule(&context, &db, &user.id).await?;
+
+    db.execute(
+        "DELETE FROM schedule_runs WHERE schedule_id = $1",
+        &[&schedule.id],
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while deleting schedule runs: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+    Schedule::destroy(&db, &schedule.id).await.map_err(|e| {
+        tracing::error!("An error occurred while deleting a schedule: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "schedule.delete",
+        "schedule",
+        Some(schedule.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.status(204);
+
+    Ok(context)
+}
+
+/// Lists the times a schedule fired, newest first.
+#[thruster::middleware]
+pub(crate) async fn get_schedule_runs(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let schedule = owned_schedule(&context, &db, &user.id).await?;
+
+    let mut runs = ScheduleRun::read_where_schedule_id(&db, &schedule.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching schedule runs: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+    runs.sort_by_key(|run| std::cmp::Reverse(run.scheduled_for));
+
+    context.json(&runs).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            images::tests::{create_image_helper, create_image_version_helper},
+            sessions::tests::create_user_and_session_helper,
+        },
+        services::{
+            jobs::{JobInput, JobSpec},
+            schedules::fire_schedule,
+        },
+        thruster_extensions::TestResponseExt,
+    };
+    use thruster::Testable;
+
+    fn create_schedule_body(image: &Image, image_version: &ImageVersion, cron: &str) -> Vec<u8> {
+        serde_json::to_vec(&CreateSchedule {
+            name: "nightly".to_string(),
+            cron: cron.to_string(),
+            timezone: Some("Europe/Berlin".to_string()),
+            image_id: image.id,
+            image_version_id: image_version.id,
+            enabled: None,
+            template: JobTemplate {
+                spec: JobSpec {
+                    preset: Some("cpu-small".to_string()),
+                    ..Default::default()
+                },
+                input: JobInput {
+                    args: vec!["--nightly".to_string()],
+                    ..Default::default()
+                },
+            },
+        })
+        .unwrap()
+    }
+
+    pub(crate) async fn create_schedule_helper(
+        app: &impl Testable,
+        user_id: &Uuid,
+        session_token: &str,
+    ) -> ScheduleDetails {
+        let image = create_image_helper(app, user_id, session_token).await;
+        let image_version = create_image_version_helper(app, &image.id, session_token, "v1").await;
+
+        app.post(
+            "/schedules",
+            vec![(
+                "Authorization".to_string(),
+                format!("Bearer {session_token}"),
+            )],
+            create_schedule_body(&image, &image_version, "30 2 * * *"),
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(201, "It should have a created status")
+        .json::<ScheduleDetails>()
+    }
+
+    #[tokio::test]
+    async fn create_schedule_should_work() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let details = create_schedule_helper(&test_app, &test_user.id, &session.token).await;
+
+        assert_eq!(details.schedule.user_id, test_user.id);
+        assert!(details.schedule.enabled);
+        assert!(details.schedule.next_run_at.unwrap() > Utc::now());
+        assert_eq!(details.template.input.args, vec!["--nightly".to_string()]);
+
+        let fetched = (&test_app as &dyn Testable)
+            .get(
+                &format!("/schedules/{}", details.schedule.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an ok status")
+            .json::<ScheduleDetails>();
+        assert_eq!(fetched.template.spec.preset.as_deref(), Some("cpu-small"));
+    }
+
+    #[tokio::test]
+    async fn create_schedule_should_reject_invalid_cron_expressions() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+
+        for cron in ["0 25 * * *", "* * * * * *", "every night"] {
+            let _ = (&test_app as &dyn Testable)
+                .post(
+                    "/schedules",
+                    vec![(
+                        "Authorization".to_string(),
+                        format!("Bearer {}", session.token),
+                    )],
+                    create_schedule_body(&image, &image_version, cron),
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(422, "It should have an unprocessable entity status");
+        }
+    }
+
+    #[tokio::test]
+    async fn update_schedule_should_disable_and_enable() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let details = create_schedule_helper(&test_app, &test_user.id, &session.token).await;
+
+        for (update, enabled) in [
+            (
+                UpdateSchedule {
+                    enabled: Some(false),
+                    ..Default::default()
+                },
+                false,
+            ),
+            (
+                UpdateSchedule {
+                    enabled: Some(true),
+                    cron: Some("0 6 * * MON".to_string()),
+                    ..Default::default()
+                },
+                true,
+            ),
+        ] {
+            let schedule = (&test_app as &dyn Testable)
+                .patch(
+                    &format!("/schedules/{}", details.schedule.id),
+                    vec![(
+                        "Authorization".to_string(),
+                        format!("Bearer {}", session.token),
+                    )],
+                    serde_json::to_vec(&update).unwrap(),
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(200, "It should have an OK status")
+                .json::<Schedule>();
+
+            assert_eq!(schedule.enabled, enabled);
+            assert_eq!(
+                schedule.next_run_at.is_some(),
+                enabled,
+                "Only enabled schedules should have a next run"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn get_schedule_runs_should_list_fired_jobs() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let details = create_schedule_helper(&test_app, &test_user.id, &session.token).await;
+        let run = fire_schedule(
+            &db,
+            &details.schedule.id,
+            details.schedule.next_run_at.unwrap(),
+        )
+        .await
+        .unwrap()
+        .expect("It should fire once due");
+
+        let runs = (&test_app as &dyn Testable)
+            .get(
+                &format!("/schedules/{}/runs", details.schedule.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an ok status")
+            .json::<Vec<ScheduleRun>>();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].job_id, run.job_id);
+        assert!(runs[0].job_id.is_some());
+
+        let (_other_user, other_session) = create_user_and_session_helper(&test_app).await;
+        let _ = (&test_app as &dyn Testable)
+            .get(
+                &format!("/schedules/{}/runs", details.schedule.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", other_session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(404, "It should have a not found status");
+    }
+}
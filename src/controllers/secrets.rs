@@ -0,0 +1,427 @@
+use std::str::FromStr;
+
+use deadpool_postgres::{GenericClient, Pool};
+use serde::{Deserialize, Serialize};
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::Error,
+    models::{NonSecureSecret, Secret, User},
+    services::{audit, crypto::Encryptor, image_config::is_valid_env_name},
+};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct CreateSecret {
+    /// The environment variable the secret is exposed as in jobs.
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct UpdateSecret {
+    value: String,
+}
+
+#[thruster::json_request]
+pub(crate) async fn create_secret(
+    create_secret: CreateSecret,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let CreateSecret { name, value } = create_secret;
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    if !is_valid_env_name(&name) {
+        return Err(
+            Error::UnprocessableEntity(context, format!("Invalid secret name: {name}")).into(),
+        );
+    }
+
+    let existing = Secret::read_where_user_id(&db, &user.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching secrets: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    if existing.iter().any(|secret| secret.name == name) {
+        return Err(Error::Conflict(context, format!("Secret {name} already exists")).into());
+    }
+
+    let encryptor: &Encryptor = context.extra.get();
+    let encrypted_value = encryptor.encrypt_str(&value).map_err(|e| {
+        tracing::error!("An error occurred while encrypting a secret: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let secret: NonSecureSecret = Secret::create(&db, user.id, name, encrypted_value)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while creating a secret: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?
+        .into();
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "secret.create",
+        "secret",
+        Some(secret.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.json(&secret).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(201);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_secrets(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let db: &Pool = context.extra.get();
+    let user: &Option<User> = context.extra.get();
+    let secrets = Secret::read_where_user_id(&db.get().await.unwrap(), &user.as_ref().unwrap().id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching secrets: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?
+        .into_iter()
+        .map(|secret| secret.into())
+        .collect::<Vec<NonSecureSecret>>();
+
+    context.json(&secrets).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+async fn owned_secret(
+    context: &Ctx,
+    db: &impl GenericClient,
+    user_id: &Uuid,
+) -> Result<Secret, ThrusterError<Ctx>> {
+    let secret_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid secret id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let secret = Secret::read(db, &secret_id).await.map_err(|e| {
+        tracing::error!("Could not load secret: {e:#?}");
+        ThrusterError::not_found_error(context.clone_ctx())
+    })?;
+
+    if secret.user_id != *user_id {
+        return Err(ThrusterError::unauthorized_error(context.clone_ctx()));
+    }
+
+    Ok(secret)
+}
+
+/// Replaces a secret's value. Jobs pick it up the next time they start.
+#[thruster::json_request]
+pub(crate) async fn update_secret(
+    update_secret: UpdateSecret,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let mut secret = owned_secret(&context, &db, &user.id).await?;
+
+    let encryptor: &Encryptor = context.extra.get();
+    secret.encrypted_value = encryptor.encrypt_str(&update_secret.value).map_err(|e| {
+        tracing::error!("An error occurred while encrypting a secret: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let secret: NonSecureSecret = secret
+        .update(&db)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while updating a secret: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?
+        .into();
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "secret.update",
+        "secret",
+        Some(secret.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.json(&secret).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn delete_secret(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let secret = owned_secret(&context, &db, &user.id).await?;
+
+    Secret::destroy(&db, &secret.id).await.map_err(|e| {
+        tracing::error!("An error occurred while deleting a secret: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "secret.delete",
+        "secret",
+        Some(secret.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.status(204);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        controllers::sessions::tests::create_user_and_session_helper,
+        services::secrets::resolve_secrets, thruster_extensions::TestResponseExt,
+    };
+    use thruster::Testable;
+
+    pub(crate) async fn create_secret_helper(
+        app: &impl Testable,
+        session_token: &str,
+        name: &str,
+        value: &str,
+    ) -> NonSecureSecret {
+        app.post(
+            "/secrets",
+            vec![(
+                "Authorization".to_string(),
+                format!("Bearer {session_token}"),
+            )],
+            serde_json::to_vec(&CreateSecret {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .unwrap(),
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(201, "It should have a created status")
+        .json::<NonSecureSecret>()
+    }
+
+    #[tokio::test]
+    async fn create_secret_should_encrypt_the_value() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let secret = create_secret_helper(&test_app, &session.token, "HF_TOKEN", "hunter2").await;
+
+        let config = crate::app::generate_default_server_config().await;
+        let db = config.db.get().await.unwrap();
+        let stored = Secret::read(&db, &secret.id).await.unwrap();
+        assert!(
+            !stored.encrypted_value.contains("hunter2"),
+            "It should not store the value in plaintext"
+        );
+
+        let resolved = resolve_secrets(
+            &db,
+            &config.encryptor,
+            &test_user.id,
+            &["HF_TOKEN".to_string()],
+        )
+        .await
+        .unwrap();
+        assert_eq!(resolved["HF_TOKEN"], "hunter2");
+    }
+
+    #[tokio::test]
+    async fn create_secret_should_reject_duplicate_and_invalid_names() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = create_secret_helper(&test_app, &session.token, "HF_TOKEN", "hunter2").await;
+
+        for (name, status) in [("HF_TOKEN", 409), ("not-an-env-var", 422)] {
+            let _ = (&test_app as &dyn Testable)
+                .post(
+                    "/secrets",
+                    vec![(
+                        "Authorization".to_string(),
+                        format!("Bearer {}", session.token),
+                    )],
+                    serde_json::to_vec(&CreateSecret {
+                        name: name.to_string(),
+                        value: "x".to_string(),
+                    })
+                    .unwrap(),
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(status, "It should reject the name");
+        }
+    }
+
+    #[tokio::test]
+    async fn get_secrets_should_not_return_values() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = create_secret_helper(&test_app, &session.token, "HF_TOKEN", "hunter2").await;
+
+        let response = (&test_app as &dyn Testable)
+            .get(
+                "/secrets",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status");
+
+        let body = String::from_utf8(response.body.clone()).unwrap();
+        assert!(body.contains("HF_TOKEN"));
+        assert!(!body.contains("hunter2"));
+        assert!(!body.contains("encrypted_value"));
+    }
+
+    #[tokio::test]
+    async fn update_secret_should_replace_the_value() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let secret = create_secret_helper(&test_app, &session.token, "HF_TOKEN", "hunter2").await;
+
+        let _ = (&test_app as &dyn Testable)
+            .put(
+                &format!("/secrets/{}", secret.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&UpdateSecret {
+                    value: "hunter3".to_string(),
+                })
+                .unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status");
+
+        let config = crate::app::generate_default_server_config().await;
+        let db = config.db.get().await.unwrap();
+        let resolved = resolve_secrets(
+            &db,
+            &config.encryptor,
+            &test_user.id,
+            &["HF_TOKEN".to_string()],
+        )
+        .await
+        .unwrap();
+        assert_eq!(resolved["HF_TOKEN"], "hunter3");
+    }
+
+    #[tokio::test]
+    async fn delete_secret_should_require_the_owning_user() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let secret = create_secret_helper(&test_app, &session.token, "HF_TOKEN", "hunter2").await;
+        let (_other_user, other_session) = create_user_and_session_helper(&test_app).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .delete(
+                &format!("/secrets/{}", secret.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", other_session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+
+        let _ = (&test_app as &dyn Testable)
+            .delete(
+                &format!("/secrets/{}", secret.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(204, "It should have a no content status");
+    }
+}
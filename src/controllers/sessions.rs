@@ -16,6 +16,7 @@ use crate::{
     app::{ClonableCtx, Ctx},
     errors::Error,
     models::User,
+    services::audit,
 };
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -75,6 +76,20 @@ pub(crate) async fn create_session(
         .await
         .unwrap();
 
+    audit::record(
+        &db.get().await.unwrap(),
+        &context,
+        Some(user.id),
+        "session.create",
+        "user",
+        Some(user.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
     context.cookie(
         "Authorization",
         &urlencoding::encode(&format!("Bearer {token}")),
@@ -113,26 +128,10 @@ pub(crate) async fn authenticate(
         })
         .unwrap_or("       ")[7..];
 
-    let redis: &redis::Client = context.extra.get();
-
-    let mut conn = redis.get_multiplexed_async_connection().await.unwrap();
-    let user_id: Option<Uuid> = conn
-        .get(_session_key(token))
-        .await
-        .ok()
-        .flatten()
-        .and_then(|v: String| Uuid::parse_str(&v).ok());
-
-    match user_id {
-        Some(user_id) => {
-            let db_user = {
-                let db: &Pool = context.extra.get();
-                User::read(&db.get().await.unwrap(), &user_id)
-                    .await
-                    .unwrap()
-            };
-            let user: &mut Option<User> = context.extra.get_mut();
-            *user = Some(db_user);
+    match session_user(&context, token).await {
+        Some(user) => {
+            let current_user: &mut Option<User> = context.extra.get_mut();
+            *current_user = Some(user);
             context = next(context).await?;
         }
         None => {
@@ -143,6 +142,22 @@ pub(crate) async fn authenticate(
     Ok(context)
 }
 
+/// Looks up the user a session token was issued to, if it hasn't expired.
+pub(crate) async fn session_user(context: &Ctx, token: &str) -> Option<User> {
+    let redis: &redis::Client = context.extra.get();
+
+    let mut conn = redis.get_multiplexed_async_connection().await.unwrap();
+    let user_id: Uuid = conn
+        .get(_session_key(token))
+        .await
+        .ok()
+        .flatten()
+        .and_then(|v: String| Uuid::parse_str(&v).ok())?;
+
+    let db: &Pool = context.extra.get();
+    User::read(&db.get().await.unwrap(), &user_id).await.ok()
+}
+
 fn _session_key(token: &str) -> String {
     format!("{token}:session")
 }
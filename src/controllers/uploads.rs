@@ -0,0 +1,337 @@
+use deadpool_postgres::Pool;
+use hyper::body::HttpBody;
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::Error,
+    models::{Upload, User},
+    services::{
+        audit,
+        blob_store::BlobStore,
+        uploads::{max_upload_bytes, upload_key, UploadError, UploadWriter},
+    },
+    thruster_extensions::RequestExt,
+};
+
+/// Header clients can send the file's hex sha256 in to have it verified.
+const CHECKSUM_HEADER: &str = "X-Checksum-Sha256";
+
+fn upload_error(context: Ctx, e: UploadError) -> ThrusterError<Ctx> {
+    match e {
+        UploadError::TooLarge(_) => Error::PayloadTooLarge(context, e.to_string()).into(),
+        UploadError::ChecksumMismatch | UploadError::Invalid(_) => {
+            Error::UnprocessableEntity(context, e.to_string()).into()
+        }
+        UploadError::Storage(e) => {
+            tracing::error!("An error occurred while storing an upload: {e:#?}");
+            ThrusterError::generic_error(context)
+        }
+    }
+}
+
+/// Streams the file into `upload`, from the first file field of a
+/// `multipart/form-data` body or from the raw body named by `filename`.
+/// Returns the file's name.
+async fn receive_upload(
+    upload: &mut UploadWriter,
+    mut body: hyper::Body,
+    content_type: &str,
+    filename: Option<String>,
+) -> Result<String, UploadError> {
+    match multer::parse_boundary(content_type) {
+        Ok(boundary) => {
+            let mut multipart = multer::Multipart::new(body, boundary);
+            let mut field = loop {
+                match multipart.next_field().await {
+                    Ok(Some(field)) if field.file_name().is_some() => break field,
+                    Ok(Some(_)) => continue,
+                    Ok(None) => {
+                        return Err(UploadError::Invalid(
+                            "The form has no file field".to_string(),
+                        ))
+                    }
+                    Err(e) => return Err(UploadError::Invalid(e.to_string())),
+                }
+            };
+            let filename = field.file_name().unwrap_or_default().to_string();
+
+            while let Some(chunk) = field
+                .chunk()
+                .await
+                .map_err(|e| UploadError::Invalid(e.to_string()))?
+            {
+                upload.push(&chunk).await?;
+            }
+
+            Ok(filename)
+        }
+        Err(_) => {
+            let filename = filename
+                .ok_or_else(|| UploadError::Invalid("A filename is required".to_string()))?;
+
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk.map_err(|e| UploadError::Invalid(e.to_string()))?;
+                upload.push(&chunk).await?;
+            }
+
+            Ok(filename)
+        }
+    }
+}
+
+/// Accepts a file either as a `multipart/form-data` field or as the raw
+/// request body, named by the `filename` query param. The body is written to
+/// storage and checked against the size limit as it streams in.
+#[thruster::middleware]
+pub(crate) async fn create_upload(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user_id = user.as_ref().unwrap().id;
+    let query = context.query_params();
+    let content_type = context
+        .req_header("Content-Type")
+        .unwrap_or_default()
+        .to_string();
+    let expected_sha256 = context
+        .req_header(CHECKSUM_HEADER)
+        .map(|v| v.to_string())
+        .or_else(|| query.get("sha256").cloned());
+    let blob_store: &BlobStore = context.extra.get();
+    let blob_store = blob_store.clone();
+
+    let key = upload_key(&user_id);
+    let mut upload = match UploadWriter::new(&blob_store, &key, max_upload_bytes()).await {
+        Ok(upload) => upload,
+        Err(e) => return Err(upload_error(context, e)),
+    };
+    let body = context.take_body_stream();
+    let filename = match receive_upload(
+        &mut upload,
+        body,
+        &content_type,
+        query.get("filename").cloned(),
+    )
+    .await
+    {
+        Ok(filename) => filename,
+        Err(e) => {
+            if let Err(e) = upload.abort(&blob_store).await {
+                tracing::error!("An error occurred while discarding an upload: {e:#?}");
+            }
+            return Err(upload_error(context, e));
+        }
+    };
+
+    let (size_bytes, sha256) = match upload.finish(&blob_store, expected_sha256.as_deref()).await {
+        Ok(finished) => finished,
+        Err(e) => return Err(upload_error(context, e)),
+    };
+    let size_bytes = size_bytes as i64;
+
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+    let upload = Upload::create(&db, user_id, filename, key, size_bytes, sha256)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while creating an upload: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user_id),
+        "upload.create",
+        "upload",
+        Some(upload.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.json(&upload).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(201);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_uploads(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let db: &Pool = context.extra.get();
+    let user: &Option<User> = context.extra.get();
+    let uploads = Upload::read_where_user_id(&db.get().await.unwrap(), &user.as_ref().unwrap().id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching uploads: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    context.json(&uploads).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        controllers::sessions::tests::create_user_and_session_helper,
+        thruster_extensions::TestResponseExt,
+    };
+    use thruster::Testable;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    pub(crate) async fn create_upload_helper(
+        app: &impl Testable,
+        session_token: &str,
+        filename: &str,
+        contents: &[u8],
+    ) -> Upload {
+        app.post(
+            &format!("/uploads?filename={filename}"),
+            vec![(
+                "Authorization".to_string(),
+                format!("Bearer {session_token}"),
+            )],
+            contents.to_vec(),
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(201, "It should have a created status")
+        .json::<Upload>()
+    }
+
+    #[tokio::test]
+    async fn create_upload_should_store_raw_bodies() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let upload = create_upload_helper(&test_app, &session.token, "prompt.txt", b"hello").await;
+
+        assert_eq!(upload.user_id, test_user.id);
+        assert_eq!(upload.filename, "prompt.txt");
+        assert_eq!(upload.size_bytes, 5);
+        assert_eq!(upload.sha256, HELLO_SHA256);
+
+        let blob_store = crate::app::generate_default_server_config()
+            .await
+            .blob_store;
+        assert_eq!(
+            blob_store.get(&upload.key).await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn create_upload_should_accept_multipart_forms() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let body = "--XYZ\r\n\
+             Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+             ignored\r\n\
+             --XYZ\r\n\
+             Content-Disposition: form-data; name=\"file\"; filename=\"train.csv\"\r\n\
+             Content-Type: text/csv\r\n\r\n\
+             hello\r\n\
+             --XYZ--\r\n";
+
+        let upload = (&test_app as &dyn Testable)
+            .post(
+                "/uploads",
+                vec![
+                    (
+                        "Authorization".to_string(),
+                        format!("Bearer {}", session.token),
+                    ),
+                    (
+                        "Content-Type".to_string(),
+                        "multipart/form-data; boundary=XYZ".to_string(),
+                    ),
+                ],
+                body.as_bytes().to_vec(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status")
+            .json::<Upload>();
+
+        assert_eq!(upload.filename, "train.csv");
+        assert_eq!(upload.sha256, HELLO_SHA256);
+    }
+
+    #[tokio::test]
+    async fn create_upload_should_verify_checksums() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+
+        for (checksum, status) in [("00", 422), (HELLO_SHA256, 201)] {
+            let _ = (&test_app as &dyn Testable)
+                .post(
+                    "/uploads?filename=prompt.txt",
+                    vec![
+                        (
+                            "Authorization".to_string(),
+                            format!("Bearer {}", session.token),
+                        ),
+                        (CHECKSUM_HEADER.to_string(), checksum.to_string()),
+                    ],
+                    b"hello".to_vec(),
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(status, "It should check the upload's checksum");
+        }
+    }
+
+    #[tokio::test]
+    async fn create_upload_should_require_a_filename() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                "/uploads",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                b"hello".to_vec(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(422, "It should have an unprocessable entity status");
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::Error,
+    models::User,
+    services::{
+        organizations::administered_organization_ids,
+        quotas::month_start,
+        usage::{summarize_usage, UsageGroup, UsageSummary},
+    },
+    thruster_extensions::{parse_param, RequestExt},
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct UsageReport {
+    /// Set when the report covers an organization's members.
+    pub(crate) organization_id: Option<Uuid>,
+    pub(crate) from: DateTime<Utc>,
+    pub(crate) to: DateTime<Utc>,
+    pub(crate) group_by: UsageGroup,
+    pub(crate) usage: Vec<UsageSummary>,
+}
+
+/// The query of a usage report, covering this month by day by default.
+struct UsageQuery {
+    organization_id: Option<Uuid>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    group_by: UsageGroup,
+}
+
+fn usage_query(params: &HashMap<String, String>, now: DateTime<Utc>) -> Result<UsageQuery, String> {
+    let query = UsageQuery {
+        organization_id: parse_param(params, "organization_id")?,
+        from: parse_param(params, "from")?.unwrap_or_else(|| month_start(now)),
+        to: parse_param(params, "to")?.unwrap_or(now),
+        group_by: parse_param(params, "group_by")?.unwrap_or(UsageGroup::Day),
+    };
+    if query.from >= query.to {
+        return Err("from must be before to".to_string());
+    }
+
+    Ok(query)
+}
+
+/// Sums up the user's usage, or with `organization_id` that of every member
+/// of an organization they administer.
+#[thruster::middleware]
+pub(crate) async fn get_usage(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let query = match usage_query(&context.query_params(), Utc::now()) {
+        Ok(query) => query,
+        Err(message) => return Err(Error::UnprocessableEntity(context, message).into()),
+    };
+
+    let user_ids = match query.organization_id {
+        Some(organization_id) => {
+            let administered = administered_organization_ids(&db, &user.id)
+                .await
+                .map_err(|e| {
+                    tracing::error!("An error occurred while fetching organizations: {e:#?}");
+                    ThrusterError::generic_error(context.clone_ctx())
+                })?;
+            if !administered.contains(&organization_id) {
+                return Err(ThrusterError::unauthorized_error(context));
+            }
+
+            db.query(
+                "SELECT user_id FROM organization_members WHERE organization_id = $1",
+                &[&organization_id],
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("An error occurred while fetching organization members: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?
+            .iter()
+            .map(|row| row.get("user_id"))
+            .collect()
+        }
+        None => vec![user.id],
+    };
+
+    let usage = summarize_usage(&db, &user_ids, query.from, query.to, query.group_by)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while summarizing usage: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    context
+        .json(&UsageReport {
+            organization_id: query.organization_id,
+            from: query.from,
+            to: query.to,
+            group_by: query.group_by,
+            usage,
+        })
+        .map_err(|_e| {
+            Error::GenericError(
+                context.clone_ctx(),
+                "Serialization error".to_string(),
+                serde_json::Value::default(),
+            )
+            .into()
+        })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            jobs::tests::create_job_helper, pipelines::tests::start_job,
+            sessions::tests::create_user_and_session_helper,
+        },
+        models::{OrganizationMember, UsageRecord},
+        services::{compute::MachineTimes, jobs::complete_attempt, usage::record_machine_times},
+        thruster_extensions::TestResponseExt,
+    };
+    use thruster::Testable;
+
+    /// Runs a job whose machine was up for `seconds`, ending an hour ago.
+    async fn run_job(user_id: &Uuid, app: &impl Testable, token: &str, seconds: i64) -> Uuid {
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        let job = create_job_helper(app, user_id, token).await;
+        let attempt = start_job(&job.id).await;
+        let stopped_at = Utc::now() - chrono::Duration::hours(1);
+        record_machine_times(
+            &db,
+            &attempt.id,
+            MachineTimes {
+                started_at: Some(stopped_at - chrono::Duration::seconds(seconds)),
+                stopped_at: Some(stopped_at),
+            },
+        )
+        .await
+        .unwrap();
+        complete_attempt(&db, &attempt, Utc::now()).await.unwrap();
+
+        job.id
+    }
+
+    async fn get_usage_helper(app: &impl Testable, token: &str, query: &str) -> UsageReport {
+        app.get(
+            &format!("/usage{query}"),
+            vec![("Authorization".to_string(), format!("Bearer {token}"))],
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(200, "It should have an ok status")
+        .json::<UsageReport>()
+    }
+
+    #[tokio::test]
+    async fn get_usage_should_sum_up_machine_time() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let job_id = run_job(&test_user.id, &test_app, &session.token, 600).await;
+        run_job(&test_user.id, &test_app, &session.token, 300).await;
+
+        let from = (Utc::now() - chrono::Duration::days(1)).to_rfc3339();
+        let report = get_usage_helper(
+            &test_app,
+            &session.token,
+            &format!("?group_by=kind&from={}", urlencoding::encode(&from)),
+        )
+        .await;
+
+        assert_eq!(report.usage.len(), 1);
+        assert_eq!(report.usage[0].group, "a100-pcie-40gb");
+        assert_eq!(report.usage[0].jobs, 2);
+        assert_eq!(report.usage[0].seconds, 900);
+        assert_eq!(report.usage[0].gpu_seconds, 900);
+
+        let records = (&test_app as &dyn Testable)
+            .get(
+                &format!("/jobs/{job_id}/usage"),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an ok status")
+            .json::<Vec<UsageRecord>>();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].seconds, 600);
+        assert_eq!(records[0].gpus, 1);
+    }
+
+    #[tokio::test]
+    async fn get_usage_should_cover_organization_members() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        let (admin, admin_session) = create_user_and_session_helper(&test_app).await;
+        let (member, member_session) = create_user_and_session_helper(&test_app).await;
+        let organization_id = OrganizationMember::read_where_user_id(&db, &admin.id)
+            .await
+            .unwrap()[0]
+            .organization_id;
+        OrganizationMember::create(
+            &db,
+            organization_id,
+            member.id,
+            crate::models::OrganizationRole::Member,
+        )
+        .await
+        .unwrap();
+        run_job(&admin.id, &test_app, &admin_session.token, 60).await;
+        run_job(&member.id, &test_app, &member_session.token, 120).await;
+
+        let from = (Utc::now() - chrono::Duration::days(1)).to_rfc3339();
+        let report = get_usage_helper(
+            &test_app,
+            &admin_session.token,
+            &format!(
+                "?group_by=user&organization_id={organization_id}&from={}",
+                urlencoding::encode(&from)
+            ),
+        )
+        .await;
+
+        assert_eq!(report.usage.len(), 2);
+        assert_eq!(report.usage.iter().map(|u| u.seconds).sum::<i64>(), 180);
+
+        (&test_app as &dyn Testable)
+            .get(
+                &format!("/usage?organization_id={organization_id}"),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", member_session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "Only admins should see an organization's usage");
+    }
+}
@@ -15,6 +15,7 @@ use crate::{
     app::{ClonableCtx, Ctx},
     errors::Error,
     models::{NonSecureUser, User},
+    services::{audit, organizations::create_personal_organization},
 };
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -41,11 +42,32 @@ pub(crate) async fn create_user(
         .unwrap()
         .to_string();
 
-    let user: NonSecureUser = User::create(&db, email, password_hash)
+    let user: NonSecureUser = User::create(&db, email.clone(), password_hash)
         .await
         .unwrap()
         .into();
 
+    create_personal_organization(&db, user.id, email)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while creating an organization: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "user.create",
+        "user",
+        Some(user.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
     #[cfg(not(test))]
     {
         let fly: &FlyClient = context.extra.get();
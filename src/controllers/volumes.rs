@@ -0,0 +1,808 @@
+use std::str::FromStr;
+
+use deadpool_postgres::{GenericClient, Pool};
+use fly::{apis::configuration::Configuration as FlyClient, models::VolumeSnapshot};
+use serde::{Deserialize, Serialize};
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::Error,
+    models::{User, Volume},
+    services::{
+        audit,
+        fly::DEFAULT_REGION,
+        organizations::administered_organization_ids,
+        volumes::{is_valid_volume_name, manageable_volumes, volume_in_use, MAX_VOLUME_SIZE_GB},
+    },
+};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct CreateVolume {
+    name: String,
+    size_gb: i32,
+    region: Option<String>,
+    /// Shares the volume with an organization the user administers.
+    organization_id: Option<Uuid>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct ExtendVolume {
+    size_gb: i32,
+}
+
+#[thruster::json_request]
+pub(crate) async fn create_volume(
+    create_volume: CreateVolume,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let CreateVolume {
+        name,
+        size_gb,
+        region,
+        organization_id,
+    } = create_volume;
+    let region = region.unwrap_or_else(|| DEFAULT_REGION.to_string());
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    if !is_valid_volume_name(&name) {
+        return Err(
+            Error::UnprocessableEntity(context, format!("Invalid volume name: {name}")).into(),
+        );
+    }
+    if !(1..=MAX_VOLUME_SIZE_GB).contains(&size_gb) {
+        return Err(Error::UnprocessableEntity(
+            context,
+            format!("Volumes must be between 1 and {MAX_VOLUME_SIZE_GB}GB"),
+        )
+        .into());
+    }
+
+    if let Some(organization_id) = organization_id {
+        let administered = administered_organization_ids(&db, &user.id)
+            .await
+            .map_err(|e| {
+                tracing::error!("An error occurred while fetching organizations: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?;
+
+        if !administered.contains(&organization_id) {
+            return Err(ThrusterError::unauthorized_error(context));
+        }
+    }
+
+    let existing = Volume::read_where_user_id(&db, &user.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching volumes: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    if existing.iter().any(|volume| volume.name == name) {
+        return Err(Error::Conflict(context, format!("Volume {name} already exists")).into());
+    }
+
+    #[allow(unused_mut)]
+    let mut fly_volume_id = None;
+    #[cfg(not(test))]
+    {
+        let fly: &FlyClient = context.extra.get();
+        let fly_volume =
+            crate::services::fly::create_volume(fly, &user.id.to_string(), &name, &region, size_gb)
+                .await
+                .map_err(|e| {
+                    tracing::error!(
+                        "An error occurred while calling fly.io to create a volume: {e:#?}"
+                    );
+                    ThrusterError::generic_error(context.clone_ctx())
+                })?;
+        fly_volume_id = fly_volume.id;
+    }
+
+    let volume = Volume::create(
+        &db,
+        user.id,
+        organization_id,
+        name,
+        region,
+        size_gb,
+        fly_volume_id,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while creating a volume: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "volume.create",
+        "volume",
+        Some(volume.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.json(&volume).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(201);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_volumes(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+
+    let administered = administered_organization_ids(&db, &user.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching organizations: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+    let volumes = manageable_volumes(&db, &user.id, &administered)
+        .await
+        .map_err(|e| {
+            tracing::error!("An error occurred while fetching volumes: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    context.json(&volumes).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+async fn manageable_volume(
+    context: &Ctx,
+    db: &impl GenericClient,
+    user_id: &Uuid,
+) -> Result<Volume, ThrusterError<Ctx>> {
+    let volume_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid volume id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let volume = Volume::read(db, &volume_id).await.map_err(|e| {
+        tracing::error!("Could not load volume: {e:#?}");
+        ThrusterError::not_found_error(context.clone_ctx())
+    })?;
+
+    // Volumes shared with an organization may be managed by any of its admins.
+    let may_manage = volume.user_id == *user_id
+        || match volume.organization_id {
+            Some(organization_id) => administered_organization_ids(db, user_id)
+                .await
+                .map_err(|e| {
+                    tracing::error!("An error occurred while fetching organizations: {e:#?}");
+                    ThrusterError::generic_error(context.clone_ctx())
+                })?
+                .contains(&organization_id),
+            None => false,
+        };
+    if !may_manage {
+        return Err(ThrusterError::unauthorized_error(context.clone_ctx()));
+    }
+
+    Ok(volume)
+}
+
+/// Grows a volume. Volumes can't shrink, so the new size must be larger.
+#[thruster::json_request]
+pub(crate) async fn extend_volume(
+    extend_volume: ExtendVolume,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let mut volume = manageable_volume(&context, &db, &user.id).await?;
+
+    if extend_volume.size_gb <= volume.size_gb || extend_volume.size_gb > MAX_VOLUME_SIZE_GB {
+        return Err(Error::UnprocessableEntity(
+            context,
+            format!(
+                "Volumes can only grow, to between {}GB and {MAX_VOLUME_SIZE_GB}GB",
+                volume.size_gb + 1
+            ),
+        )
+        .into());
+    }
+
+    #[cfg(not(test))]
+    {
+        let fly: &FlyClient = context.extra.get();
+        if let Some(fly_volume_id) = &volume.fly_volume_id {
+            crate::services::fly::extend_volume(
+                fly,
+                &volume.user_id.to_string(),
+                fly_volume_id,
+                extend_volume.size_gb,
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!(
+                    "An error occurred while calling fly.io to extend a volume: {e:#?}"
+                );
+                ThrusterError::generic_error(context.clone_ctx())
+            })?;
+        }
+    }
+
+    volume.size_gb = extend_volume.size_gb;
+    let volume = volume.update(&db).await.map_err(|e| {
+        tracing::error!("An error occurred while updating a volume: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "volume.extend",
+        "volume",
+        Some(volume.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.json(&volume).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+/// Starts a snapshot of a volume. fly.io takes them asynchronously, they show
+/// up in the volume's snapshots once done.
+#[thruster::middleware]
+pub(crate) async fn create_volume_snapshot(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let volume = manageable_volume(&context, &db, &user.id).await?;
+
+    #[cfg(not(test))]
+    {
+        let fly: &FlyClient = context.extra.get();
+        if let Some(fly_volume_id) = &volume.fly_volume_id {
+            crate::services::fly::snapshot_volume(fly, &volume.user_id.to_string(), fly_volume_id)
+                .await
+                .map_err(|e| {
+                    tracing::error!(
+                        "An error occurred while calling fly.io to snapshot a volume: {e:#?}"
+                    );
+                    ThrusterError::generic_error(context.clone_ctx())
+                })?;
+        }
+    }
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "volume.snapshot",
+        "volume",
+        Some(volume.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.status(202);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn get_volume_snapshots(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let volume = manageable_volume(&context, &db, &user.id).await?;
+
+    #[allow(unused_mut)]
+    let mut snapshots: Vec<VolumeSnapshot> = vec![];
+    #[cfg(not(test))]
+    {
+        let fly: &FlyClient = context.extra.get();
+        if let Some(fly_volume_id) = &volume.fly_volume_id {
+            snapshots = crate::services::fly::list_volume_snapshots(
+                fly,
+                &volume.user_id.to_string(),
+                fly_volume_id,
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("An error occurred while calling fly.io to list snapshots: {e:#?}");
+                ThrusterError::generic_error(context.clone_ctx())
+            })?;
+        }
+    }
+
+    context.json(&snapshots).map_err(|_e| {
+        Error::GenericError(
+            context.clone_ctx(),
+            "Serialization error".to_string(),
+            serde_json::Value::default(),
+        )
+        .into()
+    })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[thruster::middleware]
+pub(crate) async fn delete_volume(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let db = db.get().await.unwrap();
+
+    let volume = manageable_volume(&context, &db, &user.id).await?;
+
+    let in_use = volume_in_use(&db, &volume.id).await.map_err(|e| {
+        tracing::error!("An error occurred while checking volume attachments: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+    if in_use {
+        return Err(Error::Conflict(
+            context,
+            format!("Volume {} is attached to a running job", volume.name),
+        )
+        .into());
+    }
+
+    #[cfg(not(test))]
+    {
+        let fly: &FlyClient = context.extra.get();
+        if let Some(fly_volume_id) = &volume.fly_volume_id {
+            crate::services::fly::delete_volume(fly, &volume.user_id.to_string(), fly_volume_id)
+                .await
+                .map_err(|e| {
+                    tracing::error!(
+                        "An error occurred while calling fly.io to delete a volume: {e:#?}"
+                    );
+                    ThrusterError::generic_error(context.clone_ctx())
+                })?;
+        }
+    }
+
+    Volume::destroy(&db, &volume.id).await.map_err(|e| {
+        tracing::error!("An error occurred while deleting a volume: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "volume.delete",
+        "volume",
+        Some(volume.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    context.status(204);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            images::tests::{create_image_helper, create_image_version_helper},
+            jobs::CreateJob,
+            schedules::CreateSchedule,
+            sessions::tests::create_user_and_session_helper,
+        },
+        models::{OrganizationMember, OrganizationRole},
+        services::{jobs::JobSpec, schedules::JobTemplate, volumes::VolumeMount},
+        thruster_extensions::TestResponseExt,
+    };
+    use thruster::Testable;
+
+    pub(crate) async fn create_volume_helper(
+        app: &impl Testable,
+        session_token: &str,
+        name: &str,
+    ) -> Volume {
+        app.post(
+            "/volumes",
+            vec![(
+                "Authorization".to_string(),
+                format!("Bearer {session_token}"),
+            )],
+            serde_json::to_vec(&CreateVolume {
+                name: name.to_string(),
+                size_gb: 10,
+                region: Some("iad".to_string()),
+                ..Default::default()
+            })
+            .unwrap(),
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(201, "It should have a created status")
+        .json::<Volume>()
+    }
+
+    #[tokio::test]
+    async fn create_volume_should_work() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let volume = create_volume_helper(&test_app, &session.token, "datasets").await;
+
+        assert_eq!(volume.user_id, test_user.id);
+        assert_eq!(volume.region, "iad");
+        assert_eq!(volume.size_gb, 10);
+    }
+
+    #[tokio::test]
+    async fn organization_admins_should_manage_organization_volumes() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        let (creator, session) = create_user_and_session_helper(&test_app).await;
+        let (admin, admin_session) = create_user_and_session_helper(&test_app).await;
+        let (member, member_session) = create_user_and_session_helper(&test_app).await;
+        let organization_id = administered_organization_ids(&db, &creator.id)
+            .await
+            .unwrap()[0];
+        for (user_id, role) in [
+            (admin.id, OrganizationRole::Admin),
+            (member.id, OrganizationRole::Member),
+        ] {
+            OrganizationMember::create(&db, organization_id, user_id, role)
+                .await
+                .unwrap();
+        }
+
+        let create = |token: &str| {
+            (&test_app as &dyn Testable).post(
+                "/volumes",
+                vec![("Authorization".to_string(), format!("Bearer {token}"))],
+                serde_json::to_vec(&CreateVolume {
+                    name: "shared".to_string(),
+                    size_gb: 10,
+                    organization_id: Some(organization_id),
+                    ..Default::default()
+                })
+                .unwrap(),
+            )
+        };
+        let _ = create(&member_session.token)
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "Only organization admins should share volumes");
+        let volume = create(&session.token)
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(201, "It should have a created status")
+            .json::<Volume>();
+        assert_eq!(volume.organization_id, Some(organization_id));
+
+        let volumes = (&test_app as &dyn Testable)
+            .get(
+                "/volumes",
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", admin_session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<Vec<Volume>>();
+        assert!(
+            volumes.iter().any(|v| v.id == volume.id),
+            "It should list the organization's volumes to its admins"
+        );
+
+        for (token, status) in [(&member_session.token, 401), (&admin_session.token, 204)] {
+            let _ = (&test_app as &dyn Testable)
+                .delete(
+                    &format!("/volumes/{}", volume.id),
+                    vec![("Authorization".to_string(), format!("Bearer {token}"))],
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(status, "Only organization admins should delete it");
+        }
+    }
+
+    #[tokio::test]
+    async fn create_volume_should_reject_invalid_names_and_sizes() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let _ = create_volume_helper(&test_app, &session.token, "datasets").await;
+
+        for (name, size_gb, status) in [
+            ("datasets", 10, 409),
+            ("Data-Sets", 10, 422),
+            ("scratch", 0, 422),
+            ("scratch", MAX_VOLUME_SIZE_GB + 1, 422),
+        ] {
+            let _ = (&test_app as &dyn Testable)
+                .post(
+                    "/volumes",
+                    vec![(
+                        "Authorization".to_string(),
+                        format!("Bearer {}", session.token),
+                    )],
+                    serde_json::to_vec(&CreateVolume {
+                        name: name.to_string(),
+                        size_gb,
+                        region: None,
+                        ..Default::default()
+                    })
+                    .unwrap(),
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(status, "It should reject the volume");
+        }
+    }
+
+    #[tokio::test]
+    async fn extend_volume_should_only_grow() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let volume = create_volume_helper(&test_app, &session.token, "datasets").await;
+
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                &format!("/volumes/{}/extend", volume.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&ExtendVolume { size_gb: 5 }).unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(422, "It should have an unprocessable entity status");
+
+        let volume = (&test_app as &dyn Testable)
+            .post(
+                &format!("/volumes/{}/extend", volume.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&ExtendVolume { size_gb: 20 }).unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<Volume>();
+
+        assert_eq!(volume.size_gb, 20);
+    }
+
+    #[tokio::test]
+    async fn create_volume_snapshot_should_require_the_owning_user() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+        let volume = create_volume_helper(&test_app, &session.token, "datasets").await;
+        let (_other_user, other_session) = create_user_and_session_helper(&test_app).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                &format!("/volumes/{}/snapshots", volume.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", other_session.token),
+                )],
+                vec![],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                &format!("/volumes/{}/snapshots", volume.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                vec![],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(202, "It should have an accepted status");
+    }
+
+    #[tokio::test]
+    async fn attached_volumes_should_not_be_shared_or_deleted() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let volume = create_volume_helper(&test_app, &session.token, "datasets").await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+        let create_job = || {
+            serde_json::to_vec(&CreateJob {
+                image_id: image.id,
+                image_version_id: image_version.id,
+                spec: JobSpec {
+                    preset: Some("cpu-small".to_string()),
+                    volume: Some(VolumeMount {
+                        volume_id: volume.id,
+                        path: "/data".to_string(),
+                    }),
+                    ..Default::default()
+                },
+                input: Default::default(),
+            })
+            .unwrap()
+        };
+
+        for status in [201, 409] {
+            let _ = (&test_app as &dyn Testable)
+                .post(
+                    "/jobs",
+                    vec![(
+                        "Authorization".to_string(),
+                        format!("Bearer {}", session.token),
+                    )],
+                    create_job(),
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(status, "Only one job should get the volume");
+        }
+
+        let _ = (&test_app as &dyn Testable)
+            .delete(
+                &format!("/volumes/{}", volume.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(409, "It should have a conflict status");
+    }
+
+    #[tokio::test]
+    async fn jobs_and_schedules_should_not_mount_other_users_volumes() {
+        let test_app = crate::app::init().await.commit();
+
+        let (_owner, owner_session) = create_user_and_session_helper(&test_app).await;
+        let volume = create_volume_helper(&test_app, &owner_session.token, "datasets").await;
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let image_version =
+            create_image_version_helper(&test_app, &image.id, &session.token, "v1").await;
+        let spec = JobSpec {
+            preset: Some("cpu-small".to_string()),
+            volume: Some(VolumeMount {
+                volume_id: volume.id,
+                path: "/data".to_string(),
+            }),
+            ..Default::default()
+        };
+
+        for (path, body) in [
+            (
+                "/jobs",
+                serde_json::to_vec(&CreateJob {
+                    image_id: image.id,
+                    image_version_id: image_version.id,
+                    spec: spec.clone(),
+                    input: Default::default(),
+                })
+                .unwrap(),
+            ),
+            (
+                "/schedules",
+                serde_json::to_vec(&CreateSchedule {
+                    name: "nightly".to_string(),
+                    cron: "0 3 * * *".to_string(),
+                    timezone: None,
+                    image_id: image.id,
+                    image_version_id: image_version.id,
+                    enabled: None,
+                    template: JobTemplate {
+                        spec: spec.clone(),
+                        input: Default::default(),
+                    },
+                })
+                .unwrap(),
+            ),
+        ] {
+            let _ = (&test_app as &dyn Testable)
+                .post(
+                    path,
+                    vec![(
+                        "Authorization".to_string(),
+                        format!("Bearer {}", session.token),
+                    )],
+                    body,
+                )
+                .await
+                .expect("Should correctly resolve")
+                .expect_status(422, "It should have an unprocessable entity status");
+        }
+    }
+}
@@ -0,0 +1,656 @@
+use std::str::FromStr;
+
+use deadpool_postgres::{Pool, Transaction};
+use serde::{Deserialize, Serialize};
+use thruster::{
+    context::context_ext::ContextExt,
+    errors::{ErrorSet, ThrusterError},
+    Context, ContextState, MiddlewareNext, MiddlewareResult,
+};
+use uuid::Uuid;
+
+use crate::{
+    app::{ClonableCtx, Ctx},
+    errors::Error,
+    models::{Image, ImageVersion, ImageWebhook, Job, User},
+    services::{
+        audit,
+        crypto::Encryptor,
+        images::register_push,
+        jobs::{launch_job, JobInput, JobSpec},
+        plans::user_plan,
+        presets::find_preset,
+        webhooks::{
+            generate_secret, parse_push_notification, verify_signature, PushedTag, SIGNATURE_HEADER,
+        },
+    },
+    thruster_extensions::RequestExt,
+};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct ConfigureImageWebhook {
+    /// When set, every pushed tag starts a job with this spec.
+    auto_launch: Option<JobSpec>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ImageWebhookConfiguration {
+    id: Uuid,
+    image_id: Uuid,
+    url: String,
+    /// Only returned when the webhook is configured, calling configure again
+    /// rotates it.
+    secret: String,
+    auto_launch: Option<JobSpec>,
+}
+
+/// What came of one pushed tag. Tags are registered independently, so one
+/// that fails doesn't hold back the others.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct PushedTagResult {
+    tag: String,
+    digest: String,
+    /// The version the push is registered as, missing if that failed.
+    version: Option<ImageVersion>,
+    /// Whether this push created `version`.
+    created: bool,
+    /// The job launched for a new version when auto launch is on.
+    job: Option<Job>,
+    /// Why registering the push or launching its job failed.
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct RegistryWebhookResult {
+    tags: Vec<PushedTagResult>,
+}
+
+#[thruster::json_request]
+pub(crate) async fn configure_image_webhook(
+    configure_image_webhook: ConfigureImageWebhook,
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let ConfigureImageWebhook { auto_launch } = configure_image_webhook;
+    let user: &Option<User> = context.extra.get();
+    let user = user.as_ref().unwrap();
+    let db: &Pool = context.extra.get();
+    let mut db = db.get().await.unwrap();
+    let db = db.transaction().await.unwrap();
+
+    let image_id = Uuid::from_str(&context.params().get("id").unwrap().param).map_err(|e| {
+        tracing::error!("Invalid image id format: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let image = Image::read(&db, &image_id).await.map_err(|e| {
+        tracing::error!("Could not load image: {e:#?}");
+        ThrusterError::not_found_error(context.clone_ctx())
+    })?;
+
+    if image.user_id != user.id {
+        return Err(ThrusterError::unauthorized_error(context));
+    }
+
+    if let Some(preset) = auto_launch
+        .as_ref()
+        .and_then(|spec| spec.preset.as_ref())
+        .filter(|preset| find_preset(preset).is_none())
+    {
+        return Err(Error::UnprocessableEntity(
+            context,
+            format!("Unknown resource preset: {preset}"),
+        )
+        .into());
+    }
+    if auto_launch
+        .as_ref()
+        .is_some_and(|spec| spec.volume.is_some())
+    {
+        return Err(Error::UnprocessableEntity(
+            context,
+            "Auto-launched jobs can't mount volumes".to_string(),
+        )
+        .into());
+    }
+    if auto_launch
+        .as_ref()
+        .is_some_and(|spec| spec.retry.is_some())
+    {
+        return Err(Error::UnprocessableEntity(
+            context,
+            "Auto-launched jobs can't be retried".to_string(),
+        )
+        .into());
+    }
+    if let Some(spec) = &auto_launch {
+        let plan = user_plan(&db, &user.id).await.map_err(|e| {
+            tracing::error!("An error occurred while fetching a user's plan: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+        if let Err(message) = plan.max_runtime(spec.max_runtime_seconds) {
+            return Err(Error::UnprocessableEntity(context, message).into());
+        }
+    }
+
+    db.execute(
+        "DELETE FROM image_webhooks WHERE image_id = $1",
+        &[&image.id],
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while removing an image webhook: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let secret = generate_secret();
+    let encryptor: &Encryptor = context.extra.get();
+    let encrypted_secret = encryptor.encrypt_str(&secret).map_err(|e| {
+        tracing::error!("An error occurred while encrypting a webhook secret: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let webhook = ImageWebhook::create(
+        &db,
+        image.id,
+        encrypted_secret,
+        auto_launch.as_ref().and_then(|spec| spec.cpu.clone()),
+        auto_launch.as_ref().and_then(|spec| spec.gpu.clone()),
+        auto_launch.is_some(),
+        auto_launch.as_ref().and_then(|spec| spec.preset.clone()),
+        auto_launch
+            .as_ref()
+            .and_then(|spec| spec.max_runtime_seconds),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while creating an image webhook: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    audit::record(
+        &db,
+        &context,
+        Some(user.id),
+        "image_webhook.configure",
+        "image",
+        Some(image.id),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("An error occurred while recording an audit event: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    db.commit().await.unwrap();
+
+    context
+        .json(&ImageWebhookConfiguration {
+            id: webhook.id,
+            image_id: image.id,
+            url: format!("/webhooks/registry/{}", image.id),
+            secret,
+            auto_launch,
+        })
+        .map_err(|_e| {
+            Error::GenericError(
+                context.clone_ctx(),
+                "Serialization error".to_string(),
+                serde_json::Value::default(),
+            )
+            .into()
+        })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+/// Registers a pushed tag as a version and launches its job, each in a
+/// savepoint. A launch that fails is recorded and reported, the version is
+/// kept.
+async fn receive_pushed_tag(
+    db: &mut Transaction<'_>,
+    context: &Ctx,
+    image: &Image,
+    pushed_tag: PushedTag,
+    auto_launch: Option<&JobSpec>,
+) -> PushedTagResult {
+    let mut result = PushedTagResult {
+        tag: pushed_tag.tag,
+        digest: pushed_tag.digest,
+        version: None,
+        created: false,
+        job: None,
+        error: None,
+    };
+
+    let registered = async {
+        let savepoint = db.savepoint("register_push").await?;
+        let (version, created) =
+            register_push(&savepoint, &image.id, &result.tag, &result.digest).await?;
+        if created {
+            audit::record(
+                &savepoint,
+                context,
+                None,
+                "image_version.create",
+                "image_version",
+                Some(version.id),
+            )
+            .await?;
+        }
+        savepoint.commit().await?;
+
+        Ok::<_, Box<dyn std::error::Error>>((version, created))
+    }
+    .await;
+    let (version, created) = match registered {
+        Ok(registered) => registered,
+        Err(e) => {
+            tracing::error!("An error occurred while registering a pushed tag: {e:#?}");
+            result.error = Some(format!("Could not register {}", result.tag));
+            return result;
+        }
+    };
+
+    if let Some(spec) = auto_launch.filter(|_| created) {
+        let launched = async {
+            let savepoint = db.savepoint("launch").await?;
+            let job = launch_job(
+                &savepoint,
+                &image.user_id,
+                image,
+                &version,
+                spec,
+                &JobInput::default(),
+            )
+            .await?;
+            audit::record(&savepoint, context, None, "job.create", "job", Some(job.id)).await?;
+            savepoint.commit().await?;
+
+            Ok::<_, Box<dyn std::error::Error>>(job)
+        }
+        .await;
+
+        match launched {
+            Ok(job) => result.job = Some(job),
+            Err(e) => {
+                tracing::warn!(
+                    "Could not launch a job for pushed version {}: {e}",
+                    version.id
+                );
+                if let Err(e) = audit::record(
+                    &*db,
+                    context,
+                    None,
+                    "image_webhook.launch_failed",
+                    "image_version",
+                    Some(version.id),
+                )
+                .await
+                {
+                    tracing::error!("An error occurred while recording an audit event: {e:#?}");
+                }
+                result.error = Some(e.to_string());
+            }
+        }
+    }
+
+    result.version = Some(version);
+    result.created = created;
+    result
+}
+
+/// Receives push notifications from Docker Registry and Harbor. The request
+/// is authenticated by an HMAC of the body with the image's webhook secret,
+/// rather than a session.
+#[thruster::middleware]
+pub(crate) async fn receive_registry_webhook(
+    mut context: Ctx,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    let image_id =
+        Uuid::from_str(&context.params().get("image_id").unwrap().param).map_err(|e| {
+            tracing::error!("Invalid image id format: {e:#?}");
+            ThrusterError::not_found_error(context.clone_ctx())
+        })?;
+    let signature = context
+        .req_header(SIGNATURE_HEADER)
+        .map(|v| v.to_string())
+        .unwrap_or_default();
+
+    let payload = context.take_body().await.map_err(|e| {
+        tracing::error!("Could not read webhook payload: {e:#?}");
+        ThrusterError::generic_error(context.clone_ctx())
+    })?;
+
+    let db: &Pool = context.extra.get();
+    let mut db = db.get().await.unwrap();
+    let mut db = db.transaction().await.unwrap();
+
+    let webhook = ImageWebhook::read_by_image_id(&db, &image_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Could not load image webhook: {e:#?}");
+            ThrusterError::not_found_error(context.clone_ctx())
+        })?;
+
+    let encryptor: &Encryptor = context.extra.get();
+    let secret = encryptor
+        .decrypt_str(&webhook.encrypted_secret)
+        .map_err(|e| {
+            tracing::error!("An error occurred while decrypting a webhook secret: {e:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
+        })?;
+
+    if verify_signature(&secret, &payload, &signature).is_err() {
+        return Err(ThrusterError::unauthorized_error(context));
+    }
+
+    let pushed = parse_push_notification(&payload)
+        .map_err(|e| Error::UnprocessableEntity(context.clone_ctx(), e.to_string()).into())?;
+
+    let image = Image::read(&db, &image_id).await.map_err(|e| {
+        tracing::error!("Could not load image: {e:#?}");
+        ThrusterError::not_found_error(context.clone_ctx())
+    })?;
+
+    let auto_launch = webhook.auto_launch.then(|| JobSpec {
+        preset: webhook.auto_launch_preset.clone(),
+        cpu: webhook.auto_launch_cpu.clone(),
+        gpu: webhook.auto_launch_gpu.clone(),
+        max_runtime_seconds: webhook.auto_launch_max_runtime_seconds,
+        ..Default::default()
+    });
+
+    let mut tags = vec![];
+    for pushed_tag in pushed {
+        tags.push(
+            receive_pushed_tag(&mut db, &context, &image, pushed_tag, auto_launch.as_ref()).await,
+        );
+    }
+
+    db.commit().await.unwrap();
+
+    context
+        .json(&RegistryWebhookResult { tags })
+        .map_err(|_e| {
+            Error::GenericError(
+                context.clone_ctx(),
+                "Serialization error".to_string(),
+                serde_json::Value::default(),
+            )
+            .into()
+        })?;
+
+    context.status(200);
+
+    Ok(context)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        controllers::{
+            images::tests::create_image_helper, sessions::tests::create_user_and_session_helper,
+        },
+        models::BillingAccount,
+        services::webhooks::sign,
+        thruster_extensions::TestResponseExt,
+    };
+    use thruster::Testable;
+
+    const DIGEST: &str = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+    async fn configure_image_webhook_helper(
+        app: &impl Testable,
+        image_id: &Uuid,
+        session_token: &str,
+        auto_launch: Option<JobSpec>,
+    ) -> ImageWebhookConfiguration {
+        app.put(
+            &format!("/images/{image_id}/webhook"),
+            vec![(
+                "Authorization".to_string(),
+                format!("Bearer {session_token}"),
+            )],
+            serde_json::to_vec(&ConfigureImageWebhook { auto_launch }).unwrap(),
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(200, "It should have an OK status")
+        .json::<ImageWebhookConfiguration>()
+    }
+
+    fn push_payload(tag: &str) -> Vec<u8> {
+        push_payload_for(tag, DIGEST)
+    }
+
+    fn push_payload_for(tag: &str, digest: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "events": [{
+                "action": "push",
+                "target": {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": digest,
+                    "repository": "images/test",
+                    "tag": tag
+                }
+            }]
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn receive_registry_webhook_should_create_versions() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let webhook =
+            configure_image_webhook_helper(&test_app, &image.id, &session.token, None).await;
+
+        let payload = push_payload("v2");
+        let result = (&test_app as &dyn Testable)
+            .post(
+                &webhook.url,
+                vec![(
+                    SIGNATURE_HEADER.to_string(),
+                    sign(&webhook.secret, &payload),
+                )],
+                payload,
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<RegistryWebhookResult>();
+
+        assert_eq!(result.tags.len(), 1, "It should report the tag");
+        let version = result.tags[0].version.as_ref().unwrap();
+        assert!(result.tags[0].created, "It should create a version");
+        assert_eq!(version.version_number, "v2");
+        assert_eq!(version.hash, DIGEST);
+        assert!(result.tags[0].job.is_none(), "It should not launch jobs");
+    }
+
+    async fn send_push_helper(
+        app: &impl Testable,
+        webhook: &ImageWebhookConfiguration,
+        payload: Vec<u8>,
+    ) -> RegistryWebhookResult {
+        app.post(
+            &webhook.url,
+            vec![(
+                SIGNATURE_HEADER.to_string(),
+                sign(&webhook.secret, &payload),
+            )],
+            payload,
+        )
+        .await
+        .expect("Should correctly resolve")
+        .expect_status(200, "It should have an OK status")
+        .json::<RegistryWebhookResult>()
+    }
+
+    #[tokio::test]
+    async fn receive_registry_webhook_should_register_repushed_tags_as_new_versions() {
+        let test_app = crate::app::init().await.commit();
+        let other_digest =
+            "sha256:0f1e2d3c4b5a69788796a5b4c3d2e1f00f1e2d3c4b5a69788796a5b4c3d2e1f0";
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let webhook =
+            configure_image_webhook_helper(&test_app, &image.id, &session.token, None).await;
+
+        let mut versions = vec![];
+        for digest in [DIGEST, other_digest] {
+            let result =
+                send_push_helper(&test_app, &webhook, push_payload_for("latest", digest)).await;
+            assert!(result.tags[0].created, "It should register each digest");
+            let version = result.tags[0].version.as_ref().unwrap();
+            versions.push((version.version_number.clone(), version.hash.clone()));
+        }
+        assert_eq!(
+            versions,
+            [
+                ("latest".to_string(), DIGEST.to_string()),
+                ("latest-0f1e2d3c4b5a".to_string(), other_digest.to_string())
+            ],
+            "It should keep the tag on its first digest"
+        );
+
+        let result = send_push_helper(
+            &test_app,
+            &webhook,
+            push_payload_for("latest", other_digest),
+        )
+        .await;
+        assert!(
+            !result.tags[0].created && result.tags[0].error.is_none(),
+            "It should recognise digests it already registered"
+        );
+    }
+
+    #[tokio::test]
+    async fn receive_registry_webhook_should_keep_versions_whose_launch_failed() {
+        let test_app = crate::app::init().await.commit();
+        let db = crate::app::generate_default_server_config().await.db;
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let webhook = configure_image_webhook_helper(
+            &test_app,
+            &image.id,
+            &session.token,
+            Some(JobSpec {
+                preset: Some("a10".to_string()),
+                ..Default::default()
+            }),
+        )
+        .await;
+        BillingAccount::create(
+            &db.get().await.unwrap(),
+            test_user.id,
+            "cus_test".to_string(),
+            true,
+        )
+        .await
+        .unwrap();
+
+        let result = send_push_helper(&test_app, &webhook, push_payload("v2")).await;
+
+        assert!(result.tags[0].created, "It should keep the version");
+        assert!(result.tags[0].job.is_none());
+        assert!(
+            result.tags[0].error.is_some(),
+            "It should report the failure"
+        );
+        let versions = ImageVersion::read_where_image_id(&db.get().await.unwrap(), &image.id)
+            .await
+            .unwrap();
+        assert!(versions.iter().any(|v| v.version_number == "v2"));
+    }
+
+    #[tokio::test]
+    async fn receive_registry_webhook_should_reject_bad_signatures() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let webhook =
+            configure_image_webhook_helper(&test_app, &image.id, &session.token, None).await;
+
+        let payload = push_payload("v2");
+        let _ = (&test_app as &dyn Testable)
+            .post(
+                &webhook.url,
+                vec![(SIGNATURE_HEADER.to_string(), sign("wrong", &payload))],
+                payload,
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+    }
+
+    #[tokio::test]
+    async fn receive_registry_webhook_should_auto_launch_jobs() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let webhook = configure_image_webhook_helper(
+            &test_app,
+            &image.id,
+            &session.token,
+            Some(JobSpec {
+                preset: Some("a10".to_string()),
+                ..Default::default()
+            }),
+        )
+        .await;
+
+        let payload = push_payload("v2");
+        let result = (&test_app as &dyn Testable)
+            .post(
+                &webhook.url,
+                vec![(
+                    SIGNATURE_HEADER.to_string(),
+                    sign(&webhook.secret, &payload),
+                )],
+                payload,
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(200, "It should have an OK status")
+            .json::<RegistryWebhookResult>();
+
+        let job = result.tags[0].job.as_ref().expect("It should launch a job");
+        let version = result.tags[0].version.as_ref().unwrap();
+        assert_eq!(job.image_version_id, Some(version.id));
+    }
+
+    #[tokio::test]
+    async fn configure_image_webhook_should_require_the_owning_user() {
+        let test_app = crate::app::init().await.commit();
+
+        let (test_user, session) = create_user_and_session_helper(&test_app).await;
+        let image = create_image_helper(&test_app, &test_user.id, &session.token).await;
+        let (_test_user, session) = create_user_and_session_helper(&test_app).await;
+
+        let _ = (&test_app as &dyn Testable)
+            .put(
+                &format!("/images/{}/webhook", image.id),
+                vec![(
+                    "Authorization".to_string(),
+                    format!("Bearer {}", session.token),
+                )],
+                serde_json::to_vec(&ConfigureImageWebhook::default()).unwrap(),
+            )
+            .await
+            .expect("Should correctly resolve")
+            .expect_status(401, "It should have an unauthorized status");
+    }
+}
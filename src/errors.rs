@@ -1,9 +1,35 @@
-use thruster::errors::ThrusterError;
+use thruster::{
+    errors::{ErrorSet, ThrusterError},
+    Context,
+};
 
-use crate::app::Ctx;
+use crate::{
+    app::{ClonableCtx, Ctx},
+    services::{
+        billing::BillingError, credits::CreditError, jobs::InvalidJobError, quotas::QuotaError,
+        volumes::VolumeInUseError,
+    },
+};
 
 pub enum Error {
     GenericError(Ctx, String, #[allow(dead_code)] serde_json::Value),
+    Conflict(Ctx, String),
+    UnprocessableEntity(Ctx, String),
+    PayloadTooLarge(Ctx, String),
+    Forbidden(Ctx, String),
+    TooManyRequests(Ctx, String),
+    PaymentRequired(Ctx, String),
+}
+
+fn status_error(mut context: Ctx, status: u32, message: String) -> ThrusterError<Ctx> {
+    context.status(status);
+    context.body(&message);
+
+    ThrusterError {
+        context,
+        message,
+        cause: None,
+    }
 }
 
 impl Into<ThrusterError<Ctx>> for Error {
@@ -14,6 +40,50 @@ impl Into<ThrusterError<Ctx>> for Error {
                 message,
                 cause: None,
             },
+            Error::Conflict(context, message) => status_error(context, 409, message),
+            Error::UnprocessableEntity(context, message) => status_error(context, 422, message),
+            Error::PayloadTooLarge(context, message) => status_error(context, 413, message),
+            Error::Forbidden(context, message) => status_error(context, 403, message),
+            Error::TooManyRequests(context, message) => status_error(context, 429, message),
+            Error::PaymentRequired(context, message) => status_error(context, 402, message),
+        }
+    }
+}
+
+/// Turns an error submitting jobs into a response. Going over a quota is
+/// reported as such, 429 when waiting for running jobs to finish helps and
+/// 403 when it doesn't. Suspended accounts and ones out of credit are asked
+/// to pay with a 402, volumes another job holds are a 409 and jobs that
+/// can't be submitted as given a 422.
+pub(crate) fn submission_error(
+    context: &Ctx,
+    error: Box<dyn std::error::Error>,
+) -> ThrusterError<Ctx> {
+    if let Some(BillingError::Suspended(message)) = error.downcast_ref::<BillingError>() {
+        return Error::PaymentRequired(context.clone_ctx(), message.clone()).into();
+    }
+    if let Some(CreditError::Exhausted(message) | CreditError::LimitReached(message)) =
+        error.downcast_ref::<CreditError>()
+    {
+        return Error::PaymentRequired(context.clone_ctx(), message.clone()).into();
+    }
+    if let Some(VolumeInUseError(message)) = error.downcast_ref::<VolumeInUseError>() {
+        return Error::Conflict(context.clone_ctx(), message.clone()).into();
+    }
+    if let Some(InvalidJobError(message)) = error.downcast_ref::<InvalidJobError>() {
+        return Error::UnprocessableEntity(context.clone_ctx(), message.clone()).into();
+    }
+
+    match error.downcast_ref::<QuotaError>() {
+        Some(QuotaError::Busy(message)) => {
+            Error::TooManyRequests(context.clone_ctx(), message.clone()).into()
+        }
+        Some(QuotaError::Exhausted(message)) => {
+            Error::Forbidden(context.clone_ctx(), message.clone()).into()
+        }
+        None => {
+            tracing::error!("An error occurred while launching a job: {error:#?}");
+            ThrusterError::generic_error(context.clone_ctx())
         }
     }
 }
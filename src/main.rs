@@ -6,7 +6,10 @@ mod app;
 mod controllers;
 mod errors;
 mod models;
+mod reference;
 mod services;
+#[cfg(test)]
+mod testing;
 mod thruster_extensions;
 
 #[tokio::main]
@@ -19,7 +22,34 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
         .parse::<u16>()
         .expect("Could not parse PORT");
 
-    let server = HyperServer::new(app::init().await);
+    let config = app::generate_default_server_config().await;
+    tokio::spawn(services::watchdog::run(
+        config.db.clone(),
+        config.fly.clone(),
+    ));
+    tokio::spawn(services::reconciler::run(
+        config.db.clone(),
+        config.fly.clone(),
+    ));
+    tokio::spawn(services::credits::run(
+        config.db.clone(),
+        config.fly.clone(),
+    ));
+    tokio::spawn(services::schedules::run(config.db.clone()));
+    if let Some(payments) = config.payments.clone() {
+        tokio::spawn(services::billing::run(config.db.clone(), payments));
+    }
+    for _ in 0..services::job_queue::WORKERS {
+        tokio::spawn(services::job_queue::run_worker(
+            config.db.clone(),
+            config.fly.clone(),
+            config.encryptor.clone(),
+            config.blob_store.clone(),
+            config.object_storage.clone(),
+        ));
+    }
+
+    let server = HyperServer::new(app::init_with_config(config).await);
     info!("Starting on port {port}");
 
     server.build("0.0.0.0", port).await;
@@ -28,7 +28,7 @@ pub struct Image {
     pub id: Uuid,
     #[petelib(queryable)]
     pub user_id: Uuid,
-    nickname: String,
+    pub nickname: String,
     pub image_url: String,
     #[petelib(readonly)]
     created_at: DateTime<Utc>,
@@ -40,8 +40,8 @@ pub struct ImageVersion {
     #[petelib(readonly, id)]
     pub id: Uuid,
     #[petelib(queryable)]
-    image_id: Uuid,
-    hash: String,
+    pub image_id: Uuid,
+    pub hash: String,
     pub version_number: String,
     #[petelib(readonly)]
     created_at: DateTime<Utc>,
@@ -52,19 +52,427 @@ pub enum JobStatus {
     Completed,
     Failed,
     Pending,
+    TimedOut,
+    /// Waiting for a worker to start its machine.
+    Queued,
+    /// Waiting for the jobs it depends on to complete, or for a free slot
+    /// in its batch.
+    Blocked,
+    /// A job it depends on didn't complete, so it never ran, or it was
+    /// stopped when its credit ran out.
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Statuses of jobs that may still be holding on to a machine.
+    pub fn active() -> Vec<JobStatus> {
+        vec![JobStatus::Queued, JobStatus::Pending]
+    }
+
+    /// Statuses of jobs that may still run.
+    pub fn unfinished() -> Vec<JobStatus> {
+        vec![JobStatus::Queued, JobStatus::Pending, JobStatus::Blocked]
+    }
 }
 
 #[petelib(create, read, update, destroy)]
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Job {
     #[petelib(readonly, id)]
-    id: Uuid,
+    pub id: Uuid,
     #[petelib(queryable)]
-    user_id: Uuid,
-    status: JobStatus,
-    image_version_id: Uuid,
+    pub user_id: Uuid,
+    pub status: JobStatus,
+    /// `None` once the job has finished and its image was deleted.
+    pub image_version_id: Option<Uuid>,
     #[petelib(readonly)]
     created_at: DateTime<Utc>,
     #[petelib(readonly)]
     updated_at: DateTime<Utc>,
 }
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, ToSql, FromSql)]
+pub enum AttemptStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// Why an attempt at running a job failed, retry policies pick which of
+/// these are worth another attempt.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, ToSql, FromSql)]
+pub enum FailureClass {
+    /// Fly couldn't find a host for the machine.
+    Capacity,
+    /// The container exited with a non-zero code.
+    NonZeroExit,
+    /// The machine disappeared without reporting an exit.
+    Lost,
+    /// The job ran past its maximum runtime.
+    Timeout,
+    /// The job was stopped when its credit ran out.
+    OutOfCredits,
+}
+
+/// One machine started for a job, jobs that are retried have several.
+#[petelib(create, read)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Attempt {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub job_id: Uuid,
+    pub number: i32,
+    pub status: AttemptStatus,
+    pub machine_id: Option<String>,
+    pub failure: Option<FailureClass>,
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+    #[petelib(readonly)]
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, ToSql, FromSql)]
+pub enum OrganizationRole {
+    Admin,
+    Member,
+}
+
+#[petelib(create, read, update, destroy)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Organization {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    pub name: String,
+    #[petelib(readonly)]
+    created_at: DateTime<Utc>,
+}
+
+#[petelib(create, read, destroy)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OrganizationMember {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub organization_id: Uuid,
+    #[petelib(queryable)]
+    pub user_id: Uuid,
+    pub role: OrganizationRole,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[petelib(create, read)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuditEvent {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub organization_id: Option<Uuid>,
+    pub actor_id: Option<Uuid>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub request_id: String,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[petelib(create, read, destroy)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RegistryCredential {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub user_id: Uuid,
+    pub image_id: Option<Uuid>,
+    pub organization_id: Option<Uuid>,
+    pub registry: String,
+    pub username: String,
+    #[petelib(secure)]
+    pub encrypted_token: String,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[petelib(create, read, update, destroy)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Secret {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub user_id: Uuid,
+    pub name: String,
+    #[petelib(secure)]
+    pub encrypted_value: String,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+    #[petelib(readonly)]
+    pub updated_at: DateTime<Utc>,
+}
+
+#[petelib(create, read, update, destroy)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Volume {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub user_id: Uuid,
+    /// Set when the volume is shared with an organization, whose admins can
+    /// then manage it. It stays in the owner's fly.io app.
+    pub organization_id: Option<Uuid>,
+    pub name: String,
+    pub region: String,
+    pub size_gb: i32,
+    /// Unset until fly.io has created the volume.
+    pub fly_volume_id: Option<String>,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[petelib(create, read)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Artifact {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub job_id: Uuid,
+    /// The file's path relative to the job's artifact prefix.
+    pub name: String,
+    pub key: String,
+    pub size_bytes: i64,
+    pub etag: String,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[petelib(create, read, destroy)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Upload {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub user_id: Uuid,
+    pub filename: String,
+    pub key: String,
+    pub size_bytes: i64,
+    /// Hex encoded sha256 of the file's contents.
+    pub sha256: String,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[petelib(create, read, destroy)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImageWebhook {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(index)]
+    pub image_id: Uuid,
+    #[petelib(secure)]
+    pub encrypted_secret: String,
+    pub auto_launch_cpu: Option<String>,
+    pub auto_launch_gpu: Option<String>,
+    pub auto_launch: bool,
+    pub auto_launch_preset: Option<String>,
+    pub auto_launch_max_runtime_seconds: Option<i64>,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+/// Launches a job from its template every time its cron expression fires.
+#[petelib(create, read, update, destroy)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Schedule {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub user_id: Uuid,
+    pub name: String,
+    pub cron: String,
+    /// An IANA timezone name the cron expression is read in.
+    pub timezone: String,
+    pub image_version_id: Uuid,
+    pub enabled: bool,
+    /// Unset while the schedule is disabled.
+    pub next_run_at: Option<DateTime<Utc>>,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+/// One firing of a schedule, with the job it launched or why it couldn't.
+#[petelib(create, read)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ScheduleRun {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub schedule_id: Uuid,
+    pub scheduled_for: DateTime<Utc>,
+    pub job_id: Option<Uuid>,
+    pub error: Option<String>,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+/// Jobs submitted together, some of which wait on others.
+#[petelib(create, read)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Pipeline {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub user_id: Uuid,
+    pub name: String,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[petelib(create, read)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PipelineJob {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub pipeline_id: Uuid,
+    pub job_id: Uuid,
+    /// Names the job within its pipeline, `depends_on` refers to these.
+    pub key: String,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+/// Jobs submitted from one template with different params.
+#[petelib(create, read)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JobBatch {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub user_id: Uuid,
+    pub name: String,
+    /// How many of the batch's jobs may be queued or running at once.
+    pub max_parallelism: Option<i32>,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+/// How long one attempt's machine ran and on what.
+#[petelib(create, read)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UsageRecord {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub user_id: Uuid,
+    #[petelib(queryable)]
+    pub job_id: Uuid,
+    pub attempt_id: Uuid,
+    pub cpu_kind: String,
+    pub cpus: i32,
+    pub memory_mb: i32,
+    pub gpu_kind: Option<String>,
+    pub gpus: i32,
+    pub started_at: DateTime<Utc>,
+    pub stopped_at: DateTime<Utc>,
+    pub seconds: i64,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+/// A user's customer at the payment provider.
+#[petelib(create, read)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BillingAccount {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(index)]
+    pub user_id: Uuid,
+    pub customer_id: String,
+    /// Set while the account has unpaid invoices, jobs can't be submitted.
+    pub suspended: bool,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, ToSql, FromSql)]
+pub enum InvoiceStatus {
+    /// Sent to the payment provider, or about to be.
+    Open,
+    Paid,
+    /// The payment provider couldn't collect it.
+    Failed,
+}
+
+/// What a user owes for one calendar month.
+#[petelib(create, read)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Invoice {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub user_id: Uuid,
+    /// Set when the invoice pays for an organization's credit, `user_id` is
+    /// then the admin who bought it.
+    pub organization_id: Option<Uuid>,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub plan: String,
+    pub amount_cents: i64,
+    pub currency: String,
+    pub status: InvoiceStatus,
+    pub provider_invoice_id: Option<String>,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+    #[petelib(readonly)]
+    pub paid_at: Option<DateTime<Utc>>,
+}
+
+/// Prepaid credit, of a user or shared by an organization's members.
+#[petelib(create, read)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreditAccount {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub organization_id: Option<Uuid>,
+    pub balance_cents: i64,
+    /// The most that may be spent each calendar month.
+    pub spend_limit_cents: Option<i64>,
+    /// Whether running jobs are stopped once the balance is used up, rather
+    /// than only new ones being refused.
+    pub stop_jobs_at_zero: bool,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, ToSql, FromSql)]
+pub enum LedgerEntryKind {
+    /// Credit bought through an invoice.
+    Purchase,
+    /// A finished attempt's machine time.
+    Usage,
+}
+
+/// A change to a credit account's balance.
+#[petelib(create, read)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreditLedgerEntry {
+    #[petelib(readonly, id)]
+    pub id: Uuid,
+    #[petelib(queryable)]
+    pub credit_account_id: Uuid,
+    pub kind: LedgerEntryKind,
+    /// Negative for debits.
+    pub amount_cents: i64,
+    /// The balance after the entry.
+    pub balance_cents: i64,
+    pub description: String,
+    pub usage_record_id: Option<Uuid>,
+    pub invoice_id: Option<Uuid>,
+    #[petelib(readonly)]
+    pub created_at: DateTime<Utc>,
+}
@@ -0,0 +1,303 @@
+use std::fmt;
+
+const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_API: &str = "registry-1.docker.io";
+const MAX_NAME_LENGTH: usize = 255;
+pub const MAX_TAG_LENGTH: usize = 128;
+
+#[derive(Debug, PartialEq)]
+pub enum ReferenceError {
+    Empty,
+    HasScheme,
+    InvalidRegistry(String),
+    InvalidRepository(String),
+    InvalidTag(String),
+    InvalidDigest(String),
+    NameTooLong,
+}
+
+impl fmt::Display for ReferenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReferenceError::Empty => write!(f, "image reference is empty"),
+            ReferenceError::HasScheme => {
+                write!(
+                    f,
+                    "image reference must not include a scheme such as https://"
+                )
+            }
+            ReferenceError::InvalidRegistry(registry) => {
+                write!(f, "invalid registry host: {registry}")
+            }
+            ReferenceError::InvalidRepository(repository) => {
+                write!(f, "invalid repository name: {repository}")
+            }
+            ReferenceError::InvalidTag(tag) => write!(f, "invalid tag: {tag}"),
+            ReferenceError::InvalidDigest(digest) => write!(f, "invalid digest: {digest}"),
+            ReferenceError::NameTooLong => write!(
+                f,
+                "repository name must be at most {MAX_NAME_LENGTH} characters"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReferenceError {}
+
+/// An OCI image reference, e.g. `registry.lionfi.sh/team/model:v1` or
+/// `ubuntu@sha256:...`, following the grammar of the distribution project.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl Reference {
+    pub fn parse(reference: &str) -> Result<Self, ReferenceError> {
+        if reference.is_empty() {
+            return Err(ReferenceError::Empty);
+        }
+        if reference.contains("://") {
+            return Err(ReferenceError::HasScheme);
+        }
+
+        let (rest, digest) = match reference.split_once('@') {
+            Some((rest, digest)) => {
+                if !is_valid_digest(digest) {
+                    return Err(ReferenceError::InvalidDigest(digest.to_string()));
+                }
+                (rest, Some(digest.to_string()))
+            }
+            None => (reference, None),
+        };
+
+        let last_slash = rest.rfind('/').map_or(0, |i| i + 1);
+        let (name, tag) = match rest[last_slash..].rfind(':') {
+            Some(i) => {
+                let tag = &rest[last_slash + i + 1..];
+                if !is_valid_tag(tag) {
+                    return Err(ReferenceError::InvalidTag(tag.to_string()));
+                }
+                (&rest[..last_slash + i], Some(tag.to_string()))
+            }
+            None => (rest, None),
+        };
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, repository))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), repository.to_string())
+            }
+            Some(_) => (DOCKER_HUB.to_string(), name.to_string()),
+            None => (DOCKER_HUB.to_string(), format!("library/{name}")),
+        };
+
+        if !is_valid_registry(&registry) {
+            return Err(ReferenceError::InvalidRegistry(registry));
+        }
+        if !repository.split('/').all(is_valid_path_component) {
+            return Err(ReferenceError::InvalidRepository(repository));
+        }
+        if registry.len() + 1 + repository.len() > MAX_NAME_LENGTH {
+            return Err(ReferenceError::NameTooLong);
+        }
+
+        Ok(Reference {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+
+    /// The fully qualified repository, without tag or digest.
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.registry, self.repository)
+    }
+
+    /// The host serving the distribution API for this registry.
+    pub fn api_host(&self) -> &str {
+        if self.registry == DOCKER_HUB {
+            DOCKER_HUB_API
+        } else {
+            &self.registry
+        }
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{tag}")?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{digest}")?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn is_valid_tag(tag: &str) -> bool {
+    let mut chars = tag.chars();
+    tag.len() <= MAX_TAG_LENGTH
+        && chars
+            .next()
+            .map_or(false, |c| c.is_ascii_alphanumeric() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+}
+
+pub fn is_valid_digest(digest: &str) -> bool {
+    let Some((algorithm, encoded)) = digest.split_once(':') else {
+        return false;
+    };
+    let is_lower_hex = |s: &str| {
+        s.chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    };
+
+    match algorithm {
+        "sha256" => encoded.len() == 64 && is_lower_hex(encoded),
+        "sha512" => encoded.len() == 128 && is_lower_hex(encoded),
+        _ => {
+            !algorithm.is_empty()
+                && algorithm
+                    .split(|c| c == '+' || c == '.' || c == '_' || c == '-')
+                    .all(|part| {
+                        !part.is_empty()
+                            && part
+                                .chars()
+                                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+                    })
+                && encoded.len() >= 32
+                && encoded
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '=' || c == '_' || c == '-')
+        }
+    }
+}
+
+pub fn is_valid_registry(registry: &str) -> bool {
+    let (host, port) = match registry.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (registry, None),
+    };
+
+    port.map_or(true, |p| {
+        !p.is_empty() && p.chars().all(|c| c.is_ascii_digit())
+    }) && !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Path components are lowercase alphanumerics separated by `.`, `_`, `__` or
+/// any run of `-`.
+fn is_valid_path_component(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    let is_alnum = |b: u8| b.is_ascii_lowercase() || b.is_ascii_digit();
+
+    if bytes.is_empty() || !is_alnum(bytes[0]) || !is_alnum(bytes[bytes.len() - 1]) {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if is_alnum(bytes[i]) {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        while i < bytes.len() && !is_alnum(bytes[i]) {
+            i += 1;
+        }
+        let separator = &component[start..i];
+        if !(separator == "." || separator == "_" || separator == "__")
+            && !separator.bytes().all(|b| b == b'-')
+        {
+            return false;
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:0f1e2d3c4b5a69788796a5b4c3d2e1f00f1e2d3c4b5a69788796a5b4c3d2e1f0";
+
+    #[test]
+    fn parse_should_default_to_docker_hub() {
+        let reference = Reference::parse("ubuntu").unwrap();
+
+        assert_eq!(reference.registry, "docker.io");
+        assert_eq!(reference.repository, "library/ubuntu");
+        assert_eq!(reference.api_host(), "registry-1.docker.io");
+        assert_eq!(reference.tag, None);
+    }
+
+    #[test]
+    fn parse_should_split_registry_repository_tag_and_digest() {
+        let reference =
+            Reference::parse(&format!("localhost:5000/team/model:v1.2@{DIGEST}")).unwrap();
+
+        assert_eq!(reference.registry, "localhost:5000");
+        assert_eq!(reference.repository, "team/model");
+        assert_eq!(reference.tag.as_deref(), Some("v1.2"));
+        assert_eq!(reference.digest.as_deref(), Some(DIGEST));
+        assert_eq!(
+            reference.to_string(),
+            format!("localhost:5000/team/model:v1.2@{DIGEST}")
+        );
+    }
+
+    #[test]
+    fn parse_should_reject_malformed_references() {
+        assert_eq!(Reference::parse(""), Err(ReferenceError::Empty));
+        assert_eq!(
+            Reference::parse("https://registry.lionfi.sh/images/model"),
+            Err(ReferenceError::HasScheme)
+        );
+        assert!(matches!(
+            Reference::parse("registry.lionfi.sh/Images/model"),
+            Err(ReferenceError::InvalidRepository(_))
+        ));
+        assert!(matches!(
+            Reference::parse("registry.lionfi.sh/images//model"),
+            Err(ReferenceError::InvalidRepository(_))
+        ));
+        assert!(matches!(
+            Reference::parse("registry.lionfi.sh/images/model:-bad"),
+            Err(ReferenceError::InvalidTag(_))
+        ));
+        assert!(matches!(
+            Reference::parse("registry.lionfi.sh/images/model@sha256:abc"),
+            Err(ReferenceError::InvalidDigest(_))
+        ));
+        assert!(matches!(
+            Reference::parse("registry-.lionfi.sh/images/model"),
+            Err(ReferenceError::InvalidRegistry(_))
+        ));
+    }
+
+    #[test]
+    fn path_components_should_allow_the_distribution_separators() {
+        assert!(is_valid_path_component("my-model"));
+        assert!(is_valid_path_component("my--model"));
+        assert!(is_valid_path_component("my__model"));
+        assert!(is_valid_path_component("my.model_v2"));
+        assert!(!is_valid_path_component("my___model"));
+        assert!(!is_valid_path_component("my..model"));
+        assert!(!is_valid_path_component("model-"));
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::GenericClient;
+use uuid::Uuid;
+
+use crate::services::object_storage::S3Client;
+
+/// The largest single file a job can upload.
+pub const MAX_ARTIFACT_BYTES: u64 = 50 * 1024 * 1024 * 1024;
+/// How long a job's upload form stays valid.
+const UPLOAD_EXPIRY_SECONDS: i64 = 7 * 24 * 60 * 60;
+/// How long download links stay valid.
+pub const DOWNLOAD_EXPIRY_SECONDS: u64 = 60 * 60;
+
+pub fn artifact_prefix(job_id: &Uuid) -> String {
+    format!("artifacts/{job_id}/")
+}
+
+/// The environment a job's machine gets to upload its artifacts with:
+/// `ARTIFACTS_UPLOAD_URL` and the JSON encoded form fields to post along with
+/// each file in `ARTIFACTS_UPLOAD_FIELDS`, e.g.
+///
+/// ```sh
+/// curl $(echo $ARTIFACTS_UPLOAD_FIELDS | jq -r 'to_entries[] | "-F \(.key)=\(.value)"') \
+///   -F file=@model.bin $ARTIFACTS_UPLOAD_URL
+/// ```
+pub fn upload_env(s3: &S3Client, job_id: &Uuid, now: DateTime<Utc>) -> BTreeMap<String, String> {
+    let post = s3.presign_post(
+        &artifact_prefix(job_id),
+        MAX_ARTIFACT_BYTES,
+        UPLOAD_EXPIRY_SECONDS,
+        now,
+    );
+
+    BTreeMap::from([
+        ("ARTIFACTS_UPLOAD_URL".to_string(), post.url),
+        (
+            "ARTIFACTS_UPLOAD_FIELDS".to_string(),
+            serde_json::to_string(&post.fields).unwrap_or_default(),
+        ),
+    ])
+}
+
+pub async fn record_artifact(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    key: &str,
+    size_bytes: i64,
+    etag: &str,
+) -> Result<(), tokio_postgres::Error> {
+    let name = key
+        .strip_prefix(&artifact_prefix(job_id))
+        .unwrap_or(key)
+        .to_string();
+
+    db.execute(
+        "INSERT INTO artifacts (job_id, name, key, size_bytes, etag) VALUES ($1, $2, $3, $4, $5)
+          ON CONFLICT (job_id, name) DO UPDATE SET size_bytes = $4, etag = $5",
+        &[job_id, &name, &key, &size_bytes, &etag],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Indexes whatever the job has uploaded so far.
+pub async fn sync_artifacts(
+    db: &impl GenericClient,
+    s3: &S3Client,
+    job_id: &Uuid,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for object in s3.list_objects(&artifact_prefix(job_id)).await? {
+        record_artifact(db, job_id, &object.key, object.size, &object.etag).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::Artifact,
+        testing::{StubResponse, StubServer},
+    };
+
+    #[tokio::test]
+    async fn sync_artifacts_should_index_uploaded_files() {
+        let job_id = Uuid::new_v4();
+        let listing = format!(
+            "<ListBucketResult><IsTruncated>false</IsTruncated>\
+             <Contents><Key>artifacts/{job_id}/weights/model.bin</Key><Size>2048</Size>\
+             <ETag>&quot;abc&quot;</ETag></Contents></ListBucketResult>"
+        );
+        let server =
+            StubServer::start(move |_request| StubResponse::new(200).body(listing.clone())).await;
+        let s3 = S3Client::new(&server.url(), "lim", "us-east-1", "key", "secret");
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+
+        sync_artifacts(&db, &s3, &job_id).await.unwrap();
+        sync_artifacts(&db, &s3, &job_id).await.unwrap();
+
+        let artifacts = Artifact::read_where_job_id(&db, &job_id).await.unwrap();
+        assert_eq!(artifacts.len(), 1, "It should not index files twice");
+        assert_eq!(artifacts[0].name, "weights/model.bin");
+        assert_eq!(artifacts[0].size_bytes, 2048);
+        assert_eq!(artifacts[0].etag, "abc");
+    }
+
+    #[test]
+    fn upload_env_should_scope_uploads_to_the_job() {
+        let job_id = Uuid::new_v4();
+        let s3 = S3Client::new("http://localhost:9000", "lim", "us-east-1", "key", "secret");
+
+        let env = upload_env(&s3, &job_id, Utc::now());
+        let fields: BTreeMap<String, String> =
+            serde_json::from_str(&env["ARTIFACTS_UPLOAD_FIELDS"]).unwrap();
+
+        assert_eq!(env["ARTIFACTS_UPLOAD_URL"], "http://localhost:9000/lim");
+        assert_eq!(fields["key"], format!("artifacts/{job_id}/${{filename}}"));
+    }
+}
@@ -0,0 +1,153 @@
+use chrono::{DateTime, Utc};
+use deadpool_postgres::GenericClient;
+use tokio_postgres::{types::ToSql, Row};
+use uuid::Uuid;
+
+use crate::{
+    app::Ctx,
+    models::{AuditEvent, OrganizationMember},
+    thruster_extensions::RequestExt,
+};
+
+pub const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Debug, Default)]
+pub struct AuditEventFilter {
+    pub organization_ids: Vec<Uuid>,
+    pub actor_id: Option<Uuid>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<Uuid>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+/// The user owning the target of an event, for events with no actor such as
+/// the versions and jobs created by registry webhooks.
+async fn target_owner_id(
+    db: &impl GenericClient,
+    target_type: &str,
+    target_id: Option<Uuid>,
+) -> Result<Option<Uuid>, tokio_postgres::Error> {
+    let query = match target_type {
+        "image" => "SELECT user_id FROM images WHERE id = $1",
+        "image_version" => {
+            "SELECT images.user_id FROM image_versions
+             JOIN images ON images.id = image_versions.image_id
+             WHERE image_versions.id = $1"
+        }
+        "job" => "SELECT user_id FROM jobs WHERE id = $1",
+        _ => return Ok(None),
+    };
+    let Some(target_id) = target_id else {
+        return Ok(None);
+    };
+
+    Ok(db
+        .query_opt(query, &[&target_id])
+        .await?
+        .map(|row| row.get("user_id")))
+}
+
+/// Records an audit event for an action taken by `actor_id` against a target,
+/// capturing the caller's IP and request id from the current request. Events
+/// without an actor belong to the organization of the target's owner.
+pub async fn record(
+    db: &impl GenericClient,
+    context: &Ctx,
+    actor_id: Option<Uuid>,
+    action: &str,
+    target_type: &str,
+    target_id: Option<Uuid>,
+) -> Result<AuditEvent, Box<dyn std::error::Error>> {
+    let owner_id = match actor_id {
+        Some(actor_id) => Some(actor_id),
+        None => target_owner_id(db, target_type, target_id).await?,
+    };
+    let organization_id = match owner_id {
+        Some(owner_id) => OrganizationMember::read_where_user_id(db, &owner_id)
+            .await?
+            .into_iter()
+            .min_by_key(|m| m.created_at)
+            .map(|m| m.organization_id),
+        None => None,
+    };
+
+    Ok(AuditEvent::create(
+        db,
+        organization_id,
+        actor_id,
+        action.to_string(),
+        target_type.to_string(),
+        target_id,
+        context.client_ip(),
+        context.request_id(),
+    )
+    .await?)
+}
+
+pub async fn list(
+    db: &impl GenericClient,
+    filter: &AuditEventFilter,
+) -> Result<Vec<AuditEvent>, tokio_postgres::Error> {
+    let mut clauses = vec!["organization_id = ANY($1)".to_string()];
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&filter.organization_ids];
+
+    if let Some(actor_id) = &filter.actor_id {
+        params.push(actor_id);
+        clauses.push(format!("actor_id = ${}", params.len()));
+    }
+    if let Some(action) = &filter.action {
+        params.push(action);
+        clauses.push(format!("action = ${}", params.len()));
+    }
+    if let Some(target_type) = &filter.target_type {
+        params.push(target_type);
+        clauses.push(format!("target_type = ${}", params.len()));
+    }
+    if let Some(target_id) = &filter.target_id {
+        params.push(target_id);
+        clauses.push(format!("target_id = ${}", params.len()));
+    }
+    if let Some(from) = &filter.from {
+        params.push(from);
+        clauses.push(format!("created_at >= ${}", params.len()));
+    }
+    if let Some(to) = &filter.to {
+        params.push(to);
+        clauses.push(format!("created_at < ${}", params.len()));
+    }
+
+    params.push(&filter.limit);
+    let limit_index = params.len();
+    params.push(&filter.offset);
+    let offset_index = params.len();
+
+    let rows = db
+        .query(
+            &format!(
+                "SELECT * FROM audit_events WHERE {} ORDER BY created_at DESC, id LIMIT ${limit_index} OFFSET ${offset_index}",
+                clauses.join(" AND ")
+            ),
+            &params,
+        )
+        .await?;
+
+    Ok(rows.iter().map(audit_event_from_row).collect())
+}
+
+fn audit_event_from_row(row: &Row) -> AuditEvent {
+    AuditEvent {
+        id: row.get("id"),
+        organization_id: row.get("organization_id"),
+        actor_id: row.get("actor_id"),
+        action: row.get("action"),
+        target_type: row.get("target_type"),
+        target_id: row.get("target_id"),
+        ip: row.get("ip"),
+        request_id: row.get("request_id"),
+        created_at: row.get("created_at"),
+    }
+}
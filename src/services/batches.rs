@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::GenericClient;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{models::JobStatus, services::job_queue::queue_blocked_job};
+
+/// The most jobs one batch may have.
+pub const MAX_BATCH_JOBS: usize = 1000;
+
+/// Expands a grid of parameter values into every combination of them, in
+/// the grid's key order.
+pub fn expand_grid(grid: &BTreeMap<String, Vec<Value>>) -> Result<Vec<Value>, String> {
+    grid.values()
+        .try_fold(1usize, |size, values| size.checked_mul(values.len()))
+        .filter(|size| *size <= MAX_BATCH_JOBS)
+        .ok_or_else(|| format!("Parameter grids can have at most {MAX_BATCH_JOBS} combinations"))?;
+
+    let mut sets = vec![serde_json::Map::new()];
+    for (name, values) in grid {
+        sets = sets
+            .into_iter()
+            .flat_map(|set| {
+                values.iter().map(move |value| {
+                    let mut set = set.clone();
+                    set.insert(name.clone(), value.clone());
+                    set
+                })
+            })
+            .collect();
+    }
+
+    Ok(sets.into_iter().map(Value::Object).collect())
+}
+
+/// Layers one parameter set over the template's params.
+pub fn merge_params(base: Option<&Value>, set: &Value) -> Result<Value, String> {
+    let Value::Object(set) = set else {
+        return Err("Parameter sets must be objects".to_string());
+    };
+    let mut params = match base {
+        Some(Value::Object(base)) => base.clone(),
+        Some(_) => return Err("Params must be an object".to_string()),
+        None => serde_json::Map::new(),
+    };
+    params.extend(set.clone());
+
+    Ok(Value::Object(params))
+}
+
+pub async fn add_to_batch(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    batch_id: &Uuid,
+    index: i32,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE jobs SET batch_id = $1, batch_index = $2 WHERE id = $3",
+        &[batch_id, &index, job_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn batch_job_ids(
+    db: &impl GenericClient,
+    batch_id: &Uuid,
+) -> Result<Vec<Uuid>, tokio_postgres::Error> {
+    let rows = db
+        .query(
+            "SELECT id FROM jobs WHERE batch_id = $1 ORDER BY batch_index",
+            &[batch_id],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get("id")).collect())
+}
+
+/// Queues the batch's next blocked jobs, as many as its `max_parallelism`
+/// and the user's quotas leave room for. The batch is locked while its jobs
+/// are counted, so jobs finishing at once can't both fill the same slot.
+pub async fn release_batch(
+    db: &impl GenericClient,
+    batch_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<(), tokio_postgres::Error> {
+    let max_parallelism: Option<i32> = db
+        .query_one(
+            "SELECT max_parallelism FROM job_batches WHERE id = $1 FOR UPDATE",
+            &[batch_id],
+        )
+        .await?
+        .get("max_parallelism");
+
+    let free = match max_parallelism {
+        Some(max_parallelism) => {
+            let active: i64 = db
+                .query_one(
+                    "SELECT COUNT(*) FROM jobs WHERE batch_id = $1 AND status = ANY($2)",
+                    &[batch_id, &JobStatus::active()],
+                )
+                .await?
+                .get(0);
+            Some((i64::from(max_parallelism) - active).max(0))
+        }
+        None => None,
+    };
+
+    let rows = db
+        .query(
+            "SELECT id FROM jobs WHERE batch_id = $1 AND status = $2
+             ORDER BY batch_index
+             LIMIT $3",
+            &[batch_id, &JobStatus::Blocked, &free],
+        )
+        .await?;
+    // The batch's jobs all use the same resources, once one doesn't fit the
+    // rest won't either.
+    for row in rows {
+        if !queue_blocked_job(db, &row.get("id"), now).await? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// How many of a batch's jobs are in each state.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct BatchProgress {
+    pub total: i64,
+    /// Waiting for a free slot.
+    pub waiting: i64,
+    pub queued: i64,
+    pub running: i64,
+    pub completed: i64,
+    /// Failed or timed out.
+    pub failed: i64,
+    pub cancelled: i64,
+}
+
+pub async fn batch_progress(
+    db: &impl GenericClient,
+    batch_id: &Uuid,
+) -> Result<BatchProgress, tokio_postgres::Error> {
+    let rows = db
+        .query(
+            "SELECT status, COUNT(*) AS count FROM jobs WHERE batch_id = $1 GROUP BY status",
+            &[batch_id],
+        )
+        .await?;
+
+    let mut progress = BatchProgress::default();
+    for row in rows {
+        let count: i64 = row.get("count");
+        progress.total += count;
+        match row.get::<_, JobStatus>("status") {
+            JobStatus::Blocked => progress.waiting += count,
+            JobStatus::Queued => progress.queued += count,
+            JobStatus::Pending => progress.running += count,
+            JobStatus::Completed => progress.completed += count,
+            JobStatus::Failed | JobStatus::TimedOut => progress.failed += count,
+            JobStatus::Cancelled => progress.cancelled += count,
+        }
+    }
+
+    Ok(progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn expand_grid_should_produce_every_combination() {
+        let grid = BTreeMap::from([
+            ("lr".to_string(), vec![json!(0.1), json!(0.01)]),
+            (
+                "batch_size".to_string(),
+                vec![json!(32), json!(64), json!(128)],
+            ),
+        ]);
+
+        let sets = expand_grid(&grid).unwrap();
+
+        assert_eq!(sets.len(), 6);
+        assert_eq!(sets[0], json!({ "batch_size": 32, "lr": 0.1 }));
+        assert_eq!(sets[1], json!({ "batch_size": 32, "lr": 0.01 }));
+        assert_eq!(sets[5], json!({ "batch_size": 128, "lr": 0.01 }));
+    }
+
+    #[test]
+    fn expand_grid_should_limit_the_number_of_combinations() {
+        let values: Vec<Value> = (0..100).map(|i| json!(i)).collect();
+        let grid = BTreeMap::from([("a".to_string(), values.clone()), ("b".to_string(), values)]);
+
+        assert!(expand_grid(&grid).is_err());
+    }
+
+    #[test]
+    fn merge_params_should_override_the_template() {
+        assert_eq!(
+            merge_params(
+                Some(&json!({ "epochs": 10, "lr": 0.1 })),
+                &json!({ "lr": 0.01 })
+            )
+            .unwrap(),
+            json!({ "epochs": 10, "lr": 0.01 })
+        );
+        assert!(merge_params(None, &json!([1, 2])).is_err());
+    }
+}
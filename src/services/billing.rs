@@ -0,0 +1,537 @@
+use std::{collections::BTreeMap, fmt, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{GenericClient, Pool};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::types::Json;
+use uuid::Uuid;
+
+use crate::{
+    models::{BillingAccount, Invoice, InvoiceStatus, User},
+    services::{
+        clock::{Clock, SystemClock},
+        credits::credit_purchase,
+        payments::{PaymentEvent, PaymentProvider},
+        plans::{user_plan, Plan, PLANS},
+        presets::{ResourcePreset, RESOURCE_PRESETS},
+        quotas::month_start,
+    },
+};
+
+/// How often last month's invoices are checked on.
+pub const BILLING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+pub const CURRENCY: &str = "usd";
+
+/// One thing an invoice charges for.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct InvoiceLine {
+    pub description: String,
+    /// The preset machine time is billed as, unset for the plan's fee.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// Machine hours billed, per GPU for GPU machines.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hours: Option<f64>,
+    pub amount_cents: i64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BillingError {
+    /// The account has invoices that couldn't be collected.
+    Suspended(String),
+}
+
+impl fmt::Display for BillingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BillingError::Suspended(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for BillingError {}
+
+/// Machine time on one kind of guest over a billing period.
+#[derive(Debug, PartialEq)]
+pub struct MachineTime {
+    pub cpu_kind: String,
+    pub gpu_kind: Option<String>,
+    /// Multiplied by the GPUs for GPU machines.
+    pub seconds: i64,
+}
+
+/// The preset a guest is billed as: the one with its GPU kind, or for CPU
+/// machines the first CPU preset with its CPU kind.
+pub fn billed_preset(cpu_kind: &str, gpu_kind: Option<&str>) -> Option<&'static ResourcePreset> {
+    RESOURCE_PRESETS.iter().find(|preset| match gpu_kind {
+        Some(gpu_kind) => preset.gpu_kind == Some(gpu_kind),
+        None => preset.gpu_kind.is_none() && preset.cpu_kind == cpu_kind,
+    })
+}
+
+/// What `seconds` of machine time cost at `cents_per_hour`, to the nearest
+/// cent.
+pub fn cost_cents(seconds: i64, cents_per_hour: i64) -> i64 {
+    (seconds * cents_per_hour + 1800) / 3600
+}
+
+/// What `seconds` on a guest cost, unset for guests without a price.
+pub fn machine_cost_cents(
+    cpu_kind: &str,
+    gpu_kind: Option<&str>,
+    gpus: i32,
+    seconds: i64,
+) -> Option<i64> {
+    billed_preset(cpu_kind, gpu_kind)
+        .map(|preset| cost_cents(seconds * i64::from(gpus.max(1)), preset.cents_per_hour))
+}
+
+/// Prices a period: the plan's fee, then the machine time of each preset
+/// rounded to the nearest cent.
+pub fn invoice_lines(plan: &Plan, usage: &[MachineTime]) -> Result<Vec<InvoiceLine>, String> {
+    let mut lines = vec![];
+    if plan.monthly_price_cents > 0 {
+        lines.push(InvoiceLine {
+            description: format!("{} plan", plan.name),
+            preset: None,
+            hours: None,
+            amount_cents: plan.monthly_price_cents,
+        });
+    }
+
+    let mut seconds: BTreeMap<&str, (&ResourcePreset, i64)> = BTreeMap::new();
+    for time in usage {
+        let preset = billed_preset(&time.cpu_kind, time.gpu_kind.as_deref()).ok_or_else(|| {
+            format!(
+                "No price for {} machines",
+                time.gpu_kind.as_deref().unwrap_or(&time.cpu_kind)
+            )
+        })?;
+        seconds.entry(preset.name).or_insert((preset, 0)).1 += time.seconds;
+    }
+    for (name, (preset, seconds)) in seconds {
+        lines.push(InvoiceLine {
+            description: format!("{name} machine time"),
+            preset: Some(name.to_string()),
+            hours: Some(seconds as f64 / 3600.0),
+            amount_cents: cost_cents(seconds, preset.cents_per_hour),
+        });
+    }
+
+    Ok(lines)
+}
+
+/// The calendar month before the one `now` is in.
+pub fn previous_month(now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+    let end = month_start(now);
+
+    (month_start(end - chrono::Duration::days(1)), end)
+}
+
+/// Machine time to invoice, usage paid for with credit is left out.
+async fn machine_time(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    period_start: DateTime<Utc>,
+    period_end: DateTime<Utc>,
+) -> Result<Vec<MachineTime>, tokio_postgres::Error> {
+    let rows = db
+        .query(
+            "SELECT cpu_kind, gpu_kind, SUM(seconds * GREATEST(gpus, 1))::BIGINT AS seconds
+             FROM usage_records
+             WHERE user_id = $1 AND stopped_at >= $2 AND stopped_at < $3
+               AND NOT EXISTS (
+                 SELECT 1 FROM credit_ledger_entries WHERE usage_record_id = usage_records.id
+               )
+             GROUP BY cpu_kind, gpu_kind",
+            &[user_id, &period_start, &period_end],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| MachineTime {
+            cpu_kind: row.get("cpu_kind"),
+            gpu_kind: row.get("gpu_kind"),
+            seconds: row.get("seconds"),
+        })
+        .collect())
+}
+
+pub async fn read_invoice_lines(
+    db: &impl GenericClient,
+    invoice_id: &Uuid,
+) -> Result<Vec<InvoiceLine>, tokio_postgres::Error> {
+    let row = db
+        .query_one("SELECT lines FROM invoices WHERE id = $1", &[invoice_id])
+        .await?;
+
+    Ok(row.get::<_, Json<Vec<InvoiceLine>>>("lines").0)
+}
+
+pub async fn write_invoice_lines(
+    db: &impl GenericClient,
+    invoice_id: &Uuid,
+    lines: &[InvoiceLine],
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE invoices SET lines = $1 WHERE id = $2",
+        &[&Json(lines), invoice_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Prices `user_id`'s period and records the invoice, or returns the one
+/// already recorded for it. Invoices with nothing to pay are paid already.
+pub async fn generate_invoice(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    period_start: DateTime<Utc>,
+    period_end: DateTime<Utc>,
+) -> Result<Invoice, Box<dyn std::error::Error>> {
+    if let Some(invoice) = Invoice::read_where_user_id(db, user_id)
+        .await?
+        .into_iter()
+        .find(|invoice| invoice.period_start == period_start)
+    {
+        return Ok(invoice);
+    }
+
+    let plan = user_plan(db, user_id).await?;
+    let lines = invoice_lines(
+        plan,
+        &machine_time(db, user_id, period_start, period_end).await?,
+    )?;
+    let amount_cents: i64 = lines.iter().map(|line| line.amount_cents).sum();
+
+    let invoice = Invoice::create(
+        db,
+        *user_id,
+        None,
+        period_start,
+        period_end,
+        plan.name.to_string(),
+        amount_cents,
+        CURRENCY.to_string(),
+        InvoiceStatus::Open,
+        None,
+    )
+    .await?;
+    write_invoice_lines(db, &invoice.id, &lines).await?;
+    if amount_cents == 0 {
+        db.execute(
+            "UPDATE invoices SET status = $1, paid_at = NOW() WHERE id = $2",
+            &[&InvoiceStatus::Paid, &invoice.id],
+        )
+        .await?;
+    }
+
+    Ok(Invoice::read(db, &invoice.id).await?)
+}
+
+/// The user's customer at the payment provider, created the first time
+/// they're billed.
+async fn billing_customer(
+    db: &impl GenericClient,
+    provider: &dyn PaymentProvider,
+    user_id: &Uuid,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let existing = db
+        .query_opt(
+            "SELECT customer_id FROM billing_accounts WHERE user_id = $1",
+            &[user_id],
+        )
+        .await?;
+    if let Some(row) = existing {
+        return Ok(row.get("customer_id"));
+    }
+
+    let user = User::read(db, user_id).await?;
+    let customer_id = provider.create_customer(user_id, &user.email).await?;
+    BillingAccount::create(db, *user_id, customer_id.clone(), false).await?;
+
+    Ok(customer_id)
+}
+
+/// Sends an open invoice to the payment provider to be collected.
+pub async fn submit_invoice(
+    db: &impl GenericClient,
+    provider: &dyn PaymentProvider,
+    invoice: &Invoice,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let customer_id = billing_customer(db, provider, &invoice.user_id).await?;
+    let lines = read_invoice_lines(db, &invoice.id).await?;
+    let provider_invoice_id = provider
+        .create_invoice(&customer_id, invoice, &lines)
+        .await?;
+
+    db.execute(
+        "UPDATE invoices SET provider_invoice_id = $1 WHERE id = $2",
+        &[&provider_invoice_id, &invoice.id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Invoices everyone who used machines or was on a paid plan during the
+/// period, then sends every open invoice that hasn't been sent yet. Each
+/// invoice is recorded in its own transaction, once per user and period.
+pub async fn bill_period(
+    db: &Pool,
+    provider: &dyn PaymentProvider,
+    period_start: DateTime<Utc>,
+    period_end: DateTime<Utc>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = db.get().await?;
+    let paid_plans: Vec<&str> = PLANS
+        .iter()
+        .filter(|plan| plan.monthly_price_cents > 0)
+        .map(|plan| plan.name)
+        .collect();
+    let rows = client
+        .query(
+            "SELECT user_id FROM usage_records WHERE stopped_at >= $1 AND stopped_at < $2
+             UNION
+             SELECT id FROM users WHERE plan = ANY($3) AND created_at < $2
+             EXCEPT
+             SELECT user_id FROM invoices WHERE period_start = $1",
+            &[&period_start, &period_end, &paid_plans],
+        )
+        .await?;
+
+    for row in rows {
+        let user_id: Uuid = row.get(0);
+        let transaction = client.transaction().await?;
+        // Errors aren't Send, so they're logged before anything else is awaited.
+        let invoiced = generate_invoice(&transaction, &user_id, period_start, period_end)
+            .await
+            .map_err(|e| tracing::error!("Could not invoice user {user_id}: {e:#?}"))
+            .is_ok();
+        if invoiced {
+            transaction.commit().await?;
+        }
+    }
+
+    let unsent = client
+        .query(
+            "SELECT id FROM invoices WHERE status = $1 AND provider_invoice_id IS NULL",
+            &[&InvoiceStatus::Open],
+        )
+        .await?;
+    for row in unsent {
+        let invoice = Invoice::read(&client, &row.get("id")).await?;
+        if let Err(e) = submit_invoice(&client, provider, &invoice).await {
+            tracing::error!("Could not send invoice {}: {e:#?}", invoice.id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Suspends accounts with failed invoices and reinstates them once they're
+/// all paid. Failed credit purchases only leave the credit unpaid, they
+/// don't suspend the buyer.
+async fn update_suspension(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE billing_accounts
+         SET suspended = EXISTS (
+           SELECT 1 FROM invoices i
+           WHERE i.user_id = $1
+             AND i.status = $2
+             AND NOT EXISTS (SELECT 1 FROM credit_purchases p WHERE p.invoice_id = i.id)
+         )
+         WHERE user_id = $1",
+        &[user_id, &InvoiceStatus::Failed],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Records what the payment provider reported. Events for invoices we don't
+/// know are ignored, and a late failure doesn't undo a payment. Paying for
+/// a credit purchase adds the credit.
+pub async fn handle_payment_event(
+    db: &impl GenericClient,
+    event: PaymentEvent,
+    now: DateTime<Utc>,
+) -> Result<(), tokio_postgres::Error> {
+    let row = match event {
+        PaymentEvent::InvoicePaid(invoice_id) => {
+            let row = db
+                .query_opt(
+                    "UPDATE invoices SET status = $1, paid_at = $2
+                     WHERE id = $3 AND status <> $1
+                     RETURNING user_id",
+                    &[&InvoiceStatus::Paid, &now, &invoice_id],
+                )
+                .await?;
+            if row.is_some() {
+                credit_purchase(db, &invoice_id).await?;
+            }
+            row
+        }
+        PaymentEvent::InvoiceFailed(invoice_id) => {
+            db.query_opt(
+                "UPDATE invoices SET status = $1 WHERE id = $2 AND status <> $3 RETURNING user_id",
+                &[&InvoiceStatus::Failed, &invoice_id, &InvoiceStatus::Paid],
+            )
+            .await?
+        }
+    };
+    if let Some(row) = row {
+        update_suspension(db, &row.get("user_id")).await?;
+    }
+
+    Ok(())
+}
+
+/// Fails for accounts whose job submission is suspended.
+pub async fn check_billing(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let suspended = db
+        .query_opt(
+            "SELECT suspended FROM billing_accounts WHERE user_id = $1",
+            &[user_id],
+        )
+        .await?
+        .is_some_and(|row| row.get("suspended"));
+    if suspended {
+        return Err(BillingError::Suspended(
+            "Job submission is suspended until the account's unpaid invoices are paid".to_string(),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Bills last month until the process exits.
+pub async fn run(db: Pool, provider: Arc<dyn PaymentProvider>) {
+    let mut interval = tokio::time::interval(BILLING_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let (period_start, period_end) = previous_month(SystemClock.now());
+        if let Err(e) = bill_period(&db, provider.as_ref(), period_start, period_end).await {
+            tracing::error!("An error occurred while billing: {e:#?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{payments::FakePaymentProvider, plans::find_plan};
+    use chrono::TimeZone;
+
+    #[test]
+    fn invoice_lines_should_price_machine_time_by_preset() {
+        let usage = vec![
+            MachineTime {
+                cpu_kind: "performance".to_string(),
+                gpu_kind: Some("a100-pcie-40gb".to_string()),
+                seconds: 90 * 60,
+            },
+            MachineTime {
+                cpu_kind: "shared".to_string(),
+                gpu_kind: None,
+                seconds: 10 * 60,
+            },
+        ];
+
+        let lines = invoice_lines(find_plan("pro").unwrap(), &usage).unwrap();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].amount_cents, 4_900);
+        assert_eq!(lines[1].preset.as_deref(), Some("a100-40gb"));
+        assert_eq!(lines[1].amount_cents, 375);
+        assert_eq!(lines[2].preset.as_deref(), Some("cpu-small"));
+        assert_eq!(
+            lines[2].amount_cents, 1,
+            "It should round to the nearest cent"
+        );
+
+        assert!(invoice_lines(
+            find_plan("free").unwrap(),
+            &[MachineTime {
+                cpu_kind: "performance".to_string(),
+                gpu_kind: Some("h100".to_string()),
+                seconds: 60,
+            }]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn previous_month_should_span_the_whole_month() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 10, 8, 0, 0).unwrap();
+
+        assert_eq!(
+            previous_month(now),
+            (
+                Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn bill_period_should_invoice_usage_once() {
+        let config = crate::app::generate_default_server_config().await;
+        let db = config.db.get().await.unwrap();
+        let provider = FakePaymentProvider::default();
+
+        let user = User::create(
+            &db,
+            format!("{}@example.com", Uuid::new_v4()),
+            "hash".to_string(),
+        )
+        .await
+        .unwrap();
+        // A period of its own, so other tests' usage doesn't land in it.
+        let period_start = Utc.with_ymd_and_hms(1990, 1, 1, 0, 0, 0).unwrap()
+            + chrono::Duration::hours(rand::random::<u16>().into());
+        let period_end = period_start + chrono::Duration::hours(1);
+        db.execute(
+            "INSERT INTO usage_records
+               (user_id, job_id, attempt_id, cpu_kind, cpus, memory_mb, gpu_kind, gpus,
+                started_at, stopped_at, seconds)
+             VALUES ($1, $2, $3, 'performance', 8, 32768, 'a10', 1, $4, $4, 7200)",
+            &[&user.id, &Uuid::new_v4(), &Uuid::new_v4(), &period_start],
+        )
+        .await
+        .unwrap();
+
+        bill_period(&config.db, &provider, period_start, period_end)
+            .await
+            .unwrap();
+        bill_period(&config.db, &provider, period_start, period_end)
+            .await
+            .unwrap();
+
+        let invoices = Invoice::read_where_user_id(&db, &user.id).await.unwrap();
+        assert_eq!(invoices.len(), 1);
+        assert_eq!(invoices[0].amount_cents, 300);
+        assert_eq!(
+            invoices[0].provider_invoice_id,
+            Some(format!("in_{}", invoices[0].id))
+        );
+        let sent = provider.invoices.lock().unwrap();
+        let sent: Vec<_> = sent
+            .iter()
+            .filter(|(_, invoice_id, _)| *invoice_id == invoices[0].id)
+            .collect();
+        assert_eq!(sent.len(), 1, "It should only send the invoice once");
+        assert_eq!(sent[0].0, format!("cus_{}", user.id));
+    }
+}
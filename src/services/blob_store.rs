@@ -0,0 +1,507 @@
+use std::path::PathBuf;
+
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::services::object_storage::{CompletedPart, ObjectStorageError, S3Client};
+
+/// Writes to S3 are buffered up to this and sent as one part of a multipart
+/// upload. Every part but the last has to be at least 5MiB.
+pub const PART_SIZE: usize = 8 * 1024 * 1024;
+/// How much of a file on disk is read at once.
+const READ_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum BlobStoreError {
+    Io(std::io::Error),
+    ObjectStorage(ObjectStorageError),
+}
+
+impl std::fmt::Display for BlobStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlobStoreError::Io(e) => write!(f, "could not access blob on disk: {e}"),
+            BlobStoreError::ObjectStorage(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for BlobStoreError {}
+
+impl From<std::io::Error> for BlobStoreError {
+    fn from(e: std::io::Error) -> Self {
+        BlobStoreError::Io(e)
+    }
+}
+
+impl From<ObjectStorageError> for BlobStoreError {
+    fn from(e: ObjectStorageError) -> Self {
+        BlobStoreError::ObjectStorage(e)
+    }
+}
+
+/// Where we keep content we host ourselves, keyed by slash separated paths.
+/// Keys are always built by us, never taken from requests verbatim.
+#[derive(Clone, Debug)]
+pub enum BlobStore {
+    Filesystem(PathBuf),
+    S3(S3Client),
+}
+
+impl BlobStore {
+    /// Uses S3 when `S3_ENDPOINT` is set, otherwise the directory in
+    /// `BLOB_STORE_PATH` (defaults to `./data/blobs`).
+    pub fn from_env() -> Self {
+        match S3Client::from_env() {
+            Some(s3) => BlobStore::S3(s3),
+            None => BlobStore::Filesystem(PathBuf::from(
+                std::env::var("BLOB_STORE_PATH").unwrap_or_else(|_| "data/blobs".to_string()),
+            )),
+        }
+    }
+
+    pub async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), BlobStoreError> {
+        match self {
+            BlobStore::Filesystem(root) => {
+                let path = root.join(key);
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                Ok(tokio::fs::write(path, bytes).await?)
+            }
+            BlobStore::S3(s3) => Ok(s3.put_object(key, bytes).await?),
+        }
+    }
+
+    /// Starts writing a new object at `key`, which only appears once the
+    /// writer is finished.
+    pub async fn writer(&self, key: &str) -> Result<BlobWriter, BlobStoreError> {
+        let target = match self {
+            BlobStore::Filesystem(root) => {
+                let path = root.join(key);
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                WriteTarget::File(tokio::fs::File::create(path).await?)
+            }
+            BlobStore::S3(s3) => WriteTarget::S3 {
+                s3: s3.clone(),
+                progress: S3Progress::default(),
+                buffer: vec![],
+                saved: false,
+            },
+        };
+
+        Ok(BlobWriter {
+            key: key.to_string(),
+            size: 0,
+            target,
+        })
+    }
+
+    /// Picks a writer saved with `BlobWriter::save` back up, or returns
+    /// `None` if there is nothing being written at `key`.
+    pub async fn resume(&self, key: &str) -> Result<Option<BlobWriter>, BlobStoreError> {
+        match self {
+            BlobStore::Filesystem(root) => {
+                let file = match tokio::fs::OpenOptions::new()
+                    .append(true)
+                    .open(root.join(key))
+                    .await
+                {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+
+                Ok(Some(BlobWriter {
+                    key: key.to_string(),
+                    size: file.metadata().await?.len(),
+                    target: WriteTarget::File(file),
+                }))
+            }
+            BlobStore::S3(s3) => {
+                let Some(progress) = S3Progress::load(s3, key).await? else {
+                    return Ok(None);
+                };
+                let buffer = s3.get_object(&tail_key(key)).await?.unwrap_or_default();
+
+                Ok(Some(BlobWriter {
+                    key: key.to_string(),
+                    size: progress.size,
+                    target: WriteTarget::S3 {
+                        s3: s3.clone(),
+                        progress,
+                        buffer,
+                        saved: true,
+                    },
+                }))
+            }
+        }
+    }
+
+    /// How much has been written to a saved writer, without resuming it.
+    pub async fn saved_size(&self, key: &str) -> Result<Option<u64>, BlobStoreError> {
+        match self {
+            BlobStore::Filesystem(_) => self.size(key).await,
+            BlobStore::S3(s3) => Ok(S3Progress::load(s3, key).await?.map(|p| p.size)),
+        }
+    }
+
+    /// Opens the object at `key` to read a chunk at a time.
+    pub async fn reader(&self, key: &str) -> Result<Option<BlobReader>, BlobStoreError> {
+        match self {
+            BlobStore::Filesystem(root) => match tokio::fs::File::open(root.join(key)).await {
+                Ok(file) => Ok(Some(BlobReader::File(file))),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            BlobStore::S3(s3) => Ok(s3.get_object_stream(key).await?.map(BlobReader::S3)),
+        }
+    }
+
+    /// Moves the object at `from` to `to`, replacing whatever was there.
+    pub async fn rename(&self, from: &str, to: &str) -> Result<(), BlobStoreError> {
+        match self {
+            BlobStore::Filesystem(root) => {
+                let path = root.join(to);
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                Ok(tokio::fs::rename(root.join(from), path).await?)
+            }
+            BlobStore::S3(s3) => {
+                let size = s3.head_object(from).await?.unwrap_or_default();
+                s3.copy_object(from, to, size).await?;
+                Ok(s3.delete_object(from).await?)
+            }
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobStoreError> {
+        match self {
+            BlobStore::Filesystem(root) => match tokio::fs::read(root.join(key)).await {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            BlobStore::S3(s3) => Ok(s3.get_object(key).await?),
+        }
+    }
+
+    pub async fn size(&self, key: &str) -> Result<Option<u64>, BlobStoreError> {
+        match self {
+            BlobStore::Filesystem(root) => match tokio::fs::metadata(root.join(key)).await {
+                Ok(metadata) => Ok(Some(metadata.len())),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            BlobStore::S3(s3) => Ok(s3.head_object(key).await?),
+        }
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), BlobStoreError> {
+        match self {
+            BlobStore::Filesystem(root) => match tokio::fs::remove_file(root.join(key)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+            BlobStore::S3(s3) => Ok(s3.delete_object(key).await?),
+        }
+    }
+
+    /// A URL machines can download the object from, which only exists for
+    /// objects kept in S3.
+    pub fn download_url(&self, key: &str, expires_in_seconds: u64) -> Option<String> {
+        match self {
+            BlobStore::Filesystem(_) => None,
+            BlobStore::S3(s3) => Some(s3.presign(
+                reqwest::Method::GET,
+                key,
+                expires_in_seconds,
+                chrono::Utc::now(),
+            )),
+        }
+    }
+}
+
+/// Where a saved S3 writer's multipart upload is kept, next to the object.
+fn progress_key(key: &str) -> String {
+    format!("{key}.progress")
+}
+
+/// Where a saved S3 writer keeps what it hasn't sent as a part yet.
+fn tail_key(key: &str) -> String {
+    format!("{key}.tail")
+}
+
+/// The multipart upload behind an S3 writer, which is only started once
+/// there's more than a part to write.
+#[derive(Default, Deserialize, Serialize)]
+struct S3Progress {
+    upload_id: Option<String>,
+    parts: Vec<CompletedPart>,
+    size: u64,
+}
+
+impl S3Progress {
+    async fn load(s3: &S3Client, key: &str) -> Result<Option<Self>, BlobStoreError> {
+        match s3.get_object(&progress_key(key)).await? {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes).ok()),
+            None => Ok(None),
+        }
+    }
+
+    async fn upload_part(
+        &mut self,
+        s3: &S3Client,
+        key: &str,
+        part: Vec<u8>,
+    ) -> Result<(), BlobStoreError> {
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let upload_id = s3.create_multipart_upload(key).await?;
+                self.upload_id = Some(upload_id.clone());
+                upload_id
+            }
+        };
+        let part_number = self.parts.len() as u32 + 1;
+        self.parts
+            .push(s3.upload_part(key, &upload_id, part_number, part).await?);
+
+        Ok(())
+    }
+}
+
+enum WriteTarget {
+    File(tokio::fs::File),
+    S3 {
+        s3: S3Client,
+        progress: S3Progress,
+        buffer: Vec<u8>,
+        saved: bool,
+    },
+}
+
+/// Writes an object a chunk at a time, holding at most a part of it in
+/// memory. Writes can be spread over several requests with `save` and
+/// `BlobStore::resume`.
+pub struct BlobWriter {
+    key: String,
+    size: u64,
+    target: WriteTarget,
+}
+
+impl BlobWriter {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), BlobStoreError> {
+        match &mut self.target {
+            WriteTarget::File(file) => file.write_all(chunk).await?,
+            WriteTarget::S3 {
+                s3,
+                progress,
+                buffer,
+                ..
+            } => {
+                buffer.extend_from_slice(chunk);
+                while buffer.len() >= PART_SIZE {
+                    let part = buffer.drain(..PART_SIZE).collect();
+                    progress.upload_part(s3, &self.key, part).await?;
+                }
+            }
+        }
+        self.size += chunk.len() as u64;
+
+        Ok(())
+    }
+
+    /// Keeps what has been written so far for `BlobStore::resume`, returning
+    /// the size.
+    pub async fn save(self) -> Result<u64, BlobStoreError> {
+        match self.target {
+            WriteTarget::File(mut file) => file.flush().await?,
+            WriteTarget::S3 {
+                s3,
+                mut progress,
+                buffer,
+                ..
+            } => {
+                progress.size = self.size;
+                s3.put_object(&tail_key(&self.key), buffer).await?;
+                s3.put_object(
+                    &progress_key(&self.key),
+                    serde_json::to_vec(&progress).unwrap_or_default(),
+                )
+                .await?;
+            }
+        }
+
+        Ok(self.size)
+    }
+
+    /// Makes the object available at its key, returning its size.
+    pub async fn finish(self) -> Result<u64, BlobStoreError> {
+        match self.target {
+            WriteTarget::File(mut file) => file.flush().await?,
+            WriteTarget::S3 {
+                s3,
+                mut progress,
+                buffer,
+                saved,
+            } => {
+                match progress.upload_id.clone() {
+                    None => s3.put_object(&self.key, buffer).await?,
+                    Some(upload_id) => {
+                        if !buffer.is_empty() {
+                            progress.upload_part(&s3, &self.key, buffer).await?;
+                        }
+                        s3.complete_multipart_upload(&self.key, &upload_id, &progress.parts)
+                            .await?;
+                    }
+                }
+                if saved {
+                    s3.delete_object(&progress_key(&self.key)).await?;
+                    s3.delete_object(&tail_key(&self.key)).await?;
+                }
+            }
+        }
+
+        Ok(self.size)
+    }
+
+    /// Throws away everything written, saved or not.
+    pub async fn abort(self, store: &BlobStore) -> Result<(), BlobStoreError> {
+        if let WriteTarget::S3 {
+            s3,
+            progress,
+            saved,
+            ..
+        } = &self.target
+        {
+            if let Some(upload_id) = &progress.upload_id {
+                s3.abort_multipart_upload(&self.key, upload_id).await?;
+            }
+            if *saved {
+                s3.delete_object(&progress_key(&self.key)).await?;
+                s3.delete_object(&tail_key(&self.key)).await?;
+            }
+        }
+
+        store.delete(&self.key).await
+    }
+}
+
+/// Reads an object a chunk at a time.
+pub enum BlobReader {
+    File(tokio::fs::File),
+    S3(reqwest::Response),
+    /// A blob read through from another registry.
+    Upstream(reqwest::Response),
+}
+
+impl BlobReader {
+    /// Returns the next chunk, or `None` at the end of the object.
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>, BlobStoreError> {
+        match self {
+            BlobReader::File(file) => {
+                let mut buffer = vec![0; READ_SIZE];
+                match file.read(&mut buffer).await? {
+                    0 => Ok(None),
+                    n => {
+                        buffer.truncate(n);
+                        Ok(Some(buffer.into()))
+                    }
+                }
+            }
+            BlobReader::S3(response) | BlobReader::Upstream(response) => {
+                Ok(response.chunk().await.map_err(ObjectStorageError::from)?)
+            }
+        }
+    }
+
+    /// Hands the rest of the object over as a response body, read as the
+    /// client takes it.
+    pub fn into_body(mut self) -> hyper::Body {
+        let (mut sender, body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            while let Ok(Some(chunk)) = self.chunk().await {
+                if sender.send_data(chunk).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{StubResponse, StubServer};
+
+    #[tokio::test]
+    async fn filesystem_store_should_append_and_delete() {
+        let store = BlobStore::Filesystem(
+            std::env::temp_dir().join(format!("lim-blob-store-{}", uuid::Uuid::new_v4())),
+        );
+
+        let mut writer = store.writer("uploads/a").await.unwrap();
+        writer.write(b"hel").await.unwrap();
+        assert_eq!(writer.save().await.unwrap(), 3);
+        let mut writer = store.resume("uploads/a").await.unwrap().unwrap();
+        writer.write(b"lo").await.unwrap();
+        assert_eq!(writer.finish().await.unwrap(), 5);
+        assert_eq!(
+            store.get("uploads/a").await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(store.size("uploads/a").await.unwrap(), Some(5));
+        assert!(store.resume("uploads/b").await.unwrap().is_none());
+
+        store.rename("uploads/a", "blobs/a").await.unwrap();
+        let mut reader = store.reader("blobs/a").await.unwrap().unwrap();
+        assert_eq!(reader.chunk().await.unwrap(), Some(Bytes::from("hello")));
+        assert_eq!(reader.chunk().await.unwrap(), None);
+        store.delete("blobs/a").await.unwrap();
+
+        store.delete("uploads/a").await.unwrap();
+        assert_eq!(store.get("uploads/a").await.unwrap(), None);
+        store.delete("uploads/a").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn s3_store_should_write_in_parts() {
+        let server = StubServer::start(|request| match request.method.as_str() {
+            "POST" if request.path.ends_with("?uploads=") => StubResponse::new(200)
+                .body("<InitiateMultipartUploadResult><UploadId>1</UploadId></InitiateMultipartUploadResult>"),
+            "PUT" => StubResponse::new(200).header("ETag", "\"abc\""),
+            "POST" => StubResponse::new(200).body("<CompleteMultipartUploadResult/>"),
+            _ => StubResponse::new(404),
+        })
+        .await;
+        let store = BlobStore::S3(S3Client::new(&server.url(), "lim", "us-east-1", "", ""));
+
+        let mut writer = store.writer("uploads/a").await.unwrap();
+        writer.write(&vec![0; PART_SIZE - 1]).await.unwrap();
+        assert!(
+            server.requests().is_empty(),
+            "It should wait for a whole part"
+        );
+        writer.write(&[0; 2]).await.unwrap();
+        assert_eq!(writer.finish().await.unwrap(), PART_SIZE as u64 + 1);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[1].path, "/lim/uploads/a?partNumber=1&uploadId=1");
+        assert_eq!(requests[1].body.len(), PART_SIZE);
+        assert_eq!(requests[2].path, "/lim/uploads/a?partNumber=2&uploadId=1");
+        assert_eq!(requests[2].body.len(), 1);
+        assert_eq!(requests[3].path, "/lim/uploads/a?uploadId=1");
+    }
+}
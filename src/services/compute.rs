@@ -0,0 +1,211 @@
+use chrono::{DateTime, Utc};
+use fly::apis::configuration::Configuration as FlyClient;
+
+pub type ComputeError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Where a job's machine is at, as far as running the job goes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MachineExit {
+    /// Still starting or running.
+    Running,
+    /// The container exited with this code.
+    Exited(i32),
+    /// The machine is gone without having reported an exit.
+    Missing,
+    /// The machine stopped without reporting an exit, but is still around.
+    Unreported,
+}
+
+/// When a machine started and stopped, as its events report it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MachineTimes {
+    pub started_at: Option<DateTime<Utc>>,
+    pub stopped_at: Option<DateTime<Utc>>,
+}
+
+/// The machine operations background tasks need, so they can run against a
+/// fake in tests. Machines live in the app named after their user.
+pub trait ComputeBackend {
+    async fn stop_machine(&self, app_id: &str, machine_id: &str) -> Result<(), ComputeError>;
+    async fn destroy_machine(&self, app_id: &str, machine_id: &str) -> Result<(), ComputeError>;
+    async fn machine_exit(
+        &self,
+        app_id: &str,
+        machine_id: &str,
+    ) -> Result<MachineExit, ComputeError>;
+    async fn machine_times(
+        &self,
+        app_id: &str,
+        machine_id: &str,
+    ) -> Result<MachineTimes, ComputeError>;
+}
+
+impl ComputeBackend for FlyClient {
+    async fn stop_machine(&self, app_id: &str, machine_id: &str) -> Result<(), ComputeError> {
+        fly::apis::machines_api::machines_stop(
+            self,
+            app_id,
+            machine_id,
+            fly::models::StopRequest::default(),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn destroy_machine(&self, app_id: &str, machine_id: &str) -> Result<(), ComputeError> {
+        fly::apis::machines_api::machines_delete(self, app_id, machine_id, Some(true)).await?;
+
+        Ok(())
+    }
+
+    async fn machine_exit(
+        &self,
+        app_id: &str,
+        machine_id: &str,
+    ) -> Result<MachineExit, ComputeError> {
+        let machine = match fly::apis::machines_api::machines_show(self, app_id, machine_id).await {
+            Ok(machine) => machine,
+            Err(fly::apis::Error::ResponseError(response))
+                if response.status == reqwest::StatusCode::NOT_FOUND =>
+            {
+                return Ok(MachineExit::Missing)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if !matches!(machine.state.as_deref(), Some("stopped" | "destroyed")) {
+            return Ok(MachineExit::Running);
+        }
+
+        // Events are listed newest first, the exit code is on the exit event's
+        // request.
+        let exit_code = machine
+            .events
+            .unwrap_or_default()
+            .iter()
+            .find(|event| event.r#type.as_deref() == Some("exit"))
+            .and_then(|event| {
+                event
+                    .request
+                    .as_ref()?
+                    .get("exit_event")?
+                    .get("exit_code")?
+                    .as_i64()
+            });
+
+        Ok(match exit_code {
+            Some(code) => MachineExit::Exited(code as i32),
+            None => MachineExit::Unreported,
+        })
+    }
+
+    async fn machine_times(
+        &self,
+        app_id: &str,
+        machine_id: &str,
+    ) -> Result<MachineTimes, ComputeError> {
+        let machine = fly::apis::machines_api::machines_show(self, app_id, machine_id).await?;
+
+        // Events are listed newest first and timestamped in milliseconds.
+        let events = machine.events.unwrap_or_default();
+        let latest = |kind: &str| {
+            events
+                .iter()
+                .find(|event| event.r#type.as_deref() == Some(kind))
+                .and_then(|event| DateTime::from_timestamp_millis(event.timestamp?))
+        };
+
+        Ok(MachineTimes {
+            started_at: latest("start"),
+            stopped_at: latest("exit"),
+        })
+    }
+}
+
+/// Records the calls made to it instead of touching any machines.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeCompute {
+    pub calls: std::sync::Mutex<Vec<(&'static str, String, String)>>,
+    /// Machines not listed here are running.
+    pub exits: std::sync::Mutex<std::collections::HashMap<String, MachineExit>>,
+    /// Machines not listed here have no events.
+    pub times: std::sync::Mutex<std::collections::HashMap<String, MachineTimes>>,
+}
+
+#[cfg(test)]
+impl FakeCompute {
+    pub fn exit(&self, machine_id: &str, exit: MachineExit) {
+        self.exits
+            .lock()
+            .unwrap()
+            .insert(machine_id.to_string(), exit);
+    }
+
+    pub fn times(&self, machine_id: &str, times: MachineTimes) {
+        self.times
+            .lock()
+            .unwrap()
+            .insert(machine_id.to_string(), times);
+    }
+
+    pub fn calls_for(&self, machine_id: &str) -> Vec<&'static str> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, _, id)| id == machine_id)
+            .map(|(call, _, _)| *call)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl ComputeBackend for FakeCompute {
+    async fn stop_machine(&self, app_id: &str, machine_id: &str) -> Result<(), ComputeError> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(("stop", app_id.to_string(), machine_id.to_string()));
+
+        Ok(())
+    }
+
+    async fn destroy_machine(&self, app_id: &str, machine_id: &str) -> Result<(), ComputeError> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(("destroy", app_id.to_string(), machine_id.to_string()));
+
+        Ok(())
+    }
+
+    async fn machine_exit(
+        &self,
+        _app_id: &str,
+        machine_id: &str,
+    ) -> Result<MachineExit, ComputeError> {
+        Ok(self
+            .exits
+            .lock()
+            .unwrap()
+            .get(machine_id)
+            .copied()
+            .unwrap_or(MachineExit::Running))
+    }
+
+    async fn machine_times(
+        &self,
+        _app_id: &str,
+        machine_id: &str,
+    ) -> Result<MachineTimes, ComputeError> {
+        Ok(self
+            .times
+            .lock()
+            .unwrap()
+            .get(machine_id)
+            .copied()
+            .unwrap_or_default())
+    }
+}
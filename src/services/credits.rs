@@ -0,0 +1,667 @@
+use std::{fmt, time::Duration};
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{GenericClient, Pool};
+use fly::apis::configuration::Configuration as FlyClient;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::{types::Json, Row};
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        AttemptStatus, CreditAccount, FailureClass, Invoice, InvoiceStatus, JobStatus,
+        LedgerEntryKind,
+    },
+    services::{
+        billing::{machine_cost_cents, write_invoice_lines, InvoiceLine, CURRENCY},
+        clock::{Clock, SystemClock},
+        compute::ComputeBackend,
+        jobs::{terminate_job, ActiveJob},
+        plans::user_plan,
+        presets::MachineResources,
+        quotas::month_start,
+    },
+};
+
+/// How often credit accounts that stop jobs are checked.
+pub const CREDIT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The credit account that pays for each user's jobs: their own, otherwise
+/// the oldest one of an organization they're a member of.
+const PAYING_ACCOUNTS: &str = "
+    SELECT DISTINCT ON (u.id) u.id AS user_id, ca.id AS credit_account_id
+    FROM users u
+    JOIN credit_accounts ca
+      ON ca.user_id = u.id
+      OR ca.organization_id IN (
+        SELECT organization_id FROM organization_members WHERE user_id = u.id
+      )
+    ORDER BY u.id, ca.user_id IS NULL, ca.created_at";
+
+#[derive(Debug, PartialEq)]
+pub enum CreditError {
+    /// The balance is used up.
+    Exhausted(String),
+    /// This month's spend limit is reached.
+    LimitReached(String),
+}
+
+impl fmt::Display for CreditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CreditError::Exhausted(message) | CreditError::LimitReached(message) => {
+                f.write_str(message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CreditError {}
+
+/// A credit account, with what's being spent from it.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreditStatus {
+    pub account: CreditAccount,
+    /// The balance less what running attempts have cost so far.
+    pub available_cents: i64,
+    /// Debited since the start of the month, including running attempts.
+    pub month_spend_cents: i64,
+}
+
+impl CreditStatus {
+    /// Checks whether new jobs may be charged to the account.
+    pub fn check(&self) -> Result<(), CreditError> {
+        let scope = match self.account.organization_id {
+            Some(organization_id) => format!("organization {organization_id}"),
+            None => "your account".to_string(),
+        };
+
+        if self.available_cents <= 0 {
+            return Err(CreditError::Exhausted(format!(
+                "The credit balance of {scope} is used up"
+            )));
+        }
+        if let Some(limit) = self.account.spend_limit_cents {
+            if self.month_spend_cents >= limit {
+                return Err(CreditError::LimitReached(format!(
+                    "The monthly spend limit of {limit} cents for {scope} is reached"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn credit_account_from_row(row: &Row) -> CreditAccount {
+    CreditAccount {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        organization_id: row.get("organization_id"),
+        balance_cents: row.get("balance_cents"),
+        spend_limit_cents: row.get("spend_limit_cents"),
+        stop_jobs_at_zero: row.get("stop_jobs_at_zero"),
+        created_at: row.get("created_at"),
+    }
+}
+
+/// Creates the credit account of a user, or of an organization when set, or
+/// updates its settings.
+pub async fn configure_credit_account(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    organization_id: Option<Uuid>,
+    spend_limit_cents: Option<i64>,
+    stop_jobs_at_zero: bool,
+) -> Result<CreditAccount, tokio_postgres::Error> {
+    let (column, owner_id) = match organization_id {
+        Some(organization_id) => ("organization_id", organization_id),
+        None => ("user_id", *user_id),
+    };
+    let row = db
+        .query_one(
+            &format!(
+                "INSERT INTO credit_accounts ({column}, spend_limit_cents, stop_jobs_at_zero)
+                 VALUES ($1, $2, $3)
+                 ON CONFLICT ({column}) DO UPDATE
+                 SET spend_limit_cents = EXCLUDED.spend_limit_cents,
+                     stop_jobs_at_zero = EXCLUDED.stop_jobs_at_zero
+                 RETURNING *"
+            ),
+            &[&owner_id, &spend_limit_cents, &stop_jobs_at_zero],
+        )
+        .await?;
+
+    Ok(credit_account_from_row(&row))
+}
+
+/// The credit account of a user, or of an organization when set.
+pub async fn find_credit_account(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    organization_id: Option<Uuid>,
+) -> Result<Option<CreditAccount>, tokio_postgres::Error> {
+    let row = match organization_id {
+        Some(organization_id) => {
+            db.query_opt(
+                "SELECT * FROM credit_accounts WHERE organization_id = $1",
+                &[&organization_id],
+            )
+            .await?
+        }
+        None => {
+            db.query_opt(
+                "SELECT * FROM credit_accounts WHERE user_id = $1",
+                &[user_id],
+            )
+            .await?
+        }
+    };
+
+    Ok(row.as_ref().map(credit_account_from_row))
+}
+
+/// The credit account `user_id`'s jobs are charged to, if any.
+pub async fn paying_account_id(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+) -> Result<Option<Uuid>, tokio_postgres::Error> {
+    let row = db
+        .query_opt(
+            &format!("SELECT credit_account_id FROM ({PAYING_ACCOUNTS}) p WHERE user_id = $1"),
+            &[user_id],
+        )
+        .await?;
+
+    Ok(row.map(|row| row.get("credit_account_id")))
+}
+
+/// The users whose jobs are charged to a credit account.
+async fn account_user_ids(
+    db: &impl GenericClient,
+    credit_account_id: &Uuid,
+) -> Result<Vec<Uuid>, tokio_postgres::Error> {
+    let rows = db
+        .query(
+            &format!("SELECT user_id FROM ({PAYING_ACCOUNTS}) p WHERE credit_account_id = $1"),
+            &[credit_account_id],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get("user_id")).collect())
+}
+
+/// What `user_ids`' running attempts have cost so far.
+async fn running_cost_cents(
+    db: &impl GenericClient,
+    user_ids: &[Uuid],
+    now: DateTime<Utc>,
+) -> Result<i64, tokio_postgres::Error> {
+    let rows = db
+        .query(
+            "SELECT a.resources, COALESCE(a.machine_started_at, a.created_at) AS started_at
+             FROM attempts a JOIN jobs j ON j.id = a.job_id
+             WHERE j.user_id = ANY($1) AND a.status = $2 AND a.resources IS NOT NULL",
+            &[&user_ids, &AttemptStatus::Running],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .filter_map(|row| {
+            let Json(resources) = row.get::<_, Json<MachineResources>>("resources");
+            let started_at: DateTime<Utc> = row.get("started_at");
+
+            machine_cost_cents(
+                &resources.cpu_kind,
+                resources.gpu_kind.as_deref(),
+                resources.gpus,
+                (now - started_at).num_seconds().max(0),
+            )
+        })
+        .sum())
+}
+
+pub async fn credit_status(
+    db: &impl GenericClient,
+    credit_account_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<CreditStatus, tokio_postgres::Error> {
+    let account = credit_account_from_row(
+        &db.query_one(
+            "SELECT * FROM credit_accounts WHERE id = $1",
+            &[credit_account_id],
+        )
+        .await?,
+    );
+    let user_ids = account_user_ids(db, &account.id).await?;
+    let running_cents = running_cost_cents(db, &user_ids, now).await?;
+    let debited_cents: i64 = db
+        .query_one(
+            "SELECT COALESCE(-SUM(amount_cents), 0)::BIGINT AS debited
+             FROM credit_ledger_entries
+             WHERE credit_account_id = $1 AND kind = $2 AND created_at >= $3",
+            &[&account.id, &LedgerEntryKind::Usage, &month_start(now)],
+        )
+        .await?
+        .get("debited");
+
+    Ok(CreditStatus {
+        available_cents: account.balance_cents - running_cents,
+        month_spend_cents: debited_cents + running_cents,
+        account,
+    })
+}
+
+/// Fails when `user_id`'s jobs are charged to a credit account that's used
+/// up or over its spend limit. Users without one are invoiced instead.
+pub async fn check_credits(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(credit_account_id) = paying_account_id(db, user_id).await? {
+        credit_status(db, &credit_account_id, now).await?.check()?;
+    }
+
+    Ok(())
+}
+
+async fn add_ledger_entry(
+    db: &impl GenericClient,
+    credit_account_id: &Uuid,
+    kind: LedgerEntryKind,
+    amount_cents: i64,
+    description: &str,
+    usage_record_id: Option<&Uuid>,
+    invoice_id: Option<&Uuid>,
+) -> Result<(), tokio_postgres::Error> {
+    let balance_cents: i64 = db
+        .query_one(
+            "UPDATE credit_accounts SET balance_cents = balance_cents + $1
+             WHERE id = $2
+             RETURNING balance_cents",
+            &[&amount_cents, credit_account_id],
+        )
+        .await?
+        .get("balance_cents");
+
+    db.execute(
+        "INSERT INTO credit_ledger_entries
+           (credit_account_id, kind, amount_cents, balance_cents, description,
+            usage_record_id, invoice_id)
+         VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        &[
+            credit_account_id,
+            &kind,
+            &amount_cents,
+            &balance_cents,
+            &description,
+            &usage_record_id,
+            &invoice_id,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Charges a usage record to the credit account paying for its user's jobs,
+/// if there is one. Usage that isn't charged is invoiced.
+pub async fn debit_usage(
+    db: &impl GenericClient,
+    usage_record_id: &Uuid,
+) -> Result<(), tokio_postgres::Error> {
+    let row = db
+        .query_one(
+            "SELECT user_id, job_id, cpu_kind, gpu_kind, gpus, seconds
+             FROM usage_records WHERE id = $1",
+            &[usage_record_id],
+        )
+        .await?;
+    let Some(credit_account_id) = paying_account_id(db, &row.get("user_id")).await? else {
+        return Ok(());
+    };
+    let cpu_kind: String = row.get("cpu_kind");
+    let gpu_kind: Option<String> = row.get("gpu_kind");
+    let seconds: i64 = row.get("seconds");
+    let Some(cost_cents) =
+        machine_cost_cents(&cpu_kind, gpu_kind.as_deref(), row.get("gpus"), seconds)
+    else {
+        tracing::warn!("No price for usage record {usage_record_id}, it will be invoiced");
+        return Ok(());
+    };
+
+    add_ledger_entry(
+        db,
+        &credit_account_id,
+        LedgerEntryKind::Usage,
+        -cost_cents,
+        &format!(
+            "{seconds}s of {} for job {}",
+            gpu_kind.as_deref().unwrap_or(&cpu_kind),
+            row.get::<_, Uuid>("job_id")
+        ),
+        Some(usage_record_id),
+        None,
+    )
+    .await
+}
+
+/// Records an invoice for `amount_cents` of credit, added to the account
+/// once it's paid. Credit for an organization is invoiced to `user_id` on
+/// its behalf.
+pub async fn purchase_credits(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    credit_account_id: &Uuid,
+    amount_cents: i64,
+    now: DateTime<Utc>,
+) -> Result<Invoice, Box<dyn std::error::Error>> {
+    let account = CreditAccount::read(db, credit_account_id).await?;
+    let plan = user_plan(db, user_id).await?;
+    let invoice = Invoice::create(
+        db,
+        *user_id,
+        account.organization_id,
+        now,
+        now,
+        plan.name.to_string(),
+        amount_cents,
+        CURRENCY.to_string(),
+        InvoiceStatus::Open,
+        None,
+    )
+    .await?;
+    write_invoice_lines(
+        db,
+        &invoice.id,
+        &[InvoiceLine {
+            description: "Prepaid credit".to_string(),
+            preset: None,
+            hours: None,
+            amount_cents,
+        }],
+    )
+    .await?;
+    db.execute(
+        "INSERT INTO credit_purchases (invoice_id, credit_account_id) VALUES ($1, $2)",
+        &[&invoice.id, credit_account_id],
+    )
+    .await?;
+
+    Ok(invoice)
+}
+
+/// Adds the credit bought with a paid invoice, invoices that aren't credit
+/// purchases are left alone.
+pub async fn credit_purchase(
+    db: &impl GenericClient,
+    invoice_id: &Uuid,
+) -> Result<(), tokio_postgres::Error> {
+    let row = db
+        .query_opt(
+            "SELECT p.credit_account_id, i.amount_cents
+             FROM credit_purchases p JOIN invoices i ON i.id = p.invoice_id
+             WHERE p.invoice_id = $1",
+            &[invoice_id],
+        )
+        .await?;
+    let Some(row) = row else {
+        return Ok(());
+    };
+
+    add_ledger_entry(
+        db,
+        &row.get("credit_account_id"),
+        LedgerEntryKind::Purchase,
+        row.get("amount_cents"),
+        "Prepaid credit",
+        None,
+        Some(invoice_id),
+    )
+    .await
+}
+
+/// Locks an active job if no other instance holds it, until `db`'s
+/// transaction ends.
+async fn claim_active_job(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+) -> Result<Option<ActiveJob>, tokio_postgres::Error> {
+    let row = db
+        .query_opt(
+            "SELECT id, user_id, machine_id FROM jobs
+             WHERE id = $1 AND status = ANY($2)
+             FOR UPDATE SKIP LOCKED",
+            &[job_id, &JobStatus::active()],
+        )
+        .await?;
+
+    Ok(row.map(|row| ActiveJob {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        machine_id: row.get("machine_id"),
+    }))
+}
+
+/// Destroys the job's machine and marks it `Cancelled`. Returns false if the
+/// machine couldn't be destroyed.
+async fn stop_job(
+    db: &impl GenericClient,
+    compute: &impl ComputeBackend,
+    job: &ActiveJob,
+    now: DateTime<Utc>,
+) -> Result<bool, tokio_postgres::Error> {
+    let terminated = terminate_job(
+        db,
+        compute,
+        job,
+        JobStatus::Cancelled,
+        FailureClass::OutOfCredits,
+        now,
+    )
+    .await?;
+    if terminated {
+        tracing::info!("Job {} was stopped when its credit ran out", job.id);
+    }
+
+    Ok(terminated)
+}
+
+/// Stops the active jobs charged to accounts set to stop them once their
+/// credit is used up, and marks them `Cancelled`. Jobs whose machine can't
+/// be destroyed are left alone, so the next pass tries again. Each job is
+/// claimed in its own transaction, so instances running at once never
+/// handle one twice.
+pub async fn stop_jobs_without_credit(
+    db: &Pool,
+    clock: &impl Clock,
+    compute: &impl ComputeBackend,
+) -> Result<Vec<Uuid>, Box<dyn std::error::Error>> {
+    let now = clock.now();
+    let mut stopped = vec![];
+    let mut client = db.get().await?;
+
+    let accounts = client
+        .query(
+            "SELECT id FROM credit_accounts WHERE stop_jobs_at_zero",
+            &[],
+        )
+        .await?;
+    for account in accounts {
+        let credit_account_id: Uuid = account.get("id");
+        if credit_status(&client, &credit_account_id, now)
+            .await?
+            .available_cents
+            > 0
+        {
+            continue;
+        }
+
+        let user_ids = account_user_ids(&client, &credit_account_id).await?;
+        let jobs = client
+            .query(
+                "SELECT id FROM jobs WHERE user_id = ANY($1) AND status = ANY($2)",
+                &[&user_ids, &JobStatus::active()],
+            )
+            .await?;
+        for job in jobs {
+            let job_id: Uuid = job.get("id");
+            let transaction = client.transaction().await?;
+            let Some(job) = claim_active_job(&transaction, &job_id).await? else {
+                continue;
+            };
+            if stop_job(&transaction, compute, &job, now).await? {
+                transaction.commit().await?;
+                stopped.push(job_id);
+            }
+        }
+    }
+
+    Ok(stopped)
+}
+
+/// Stops jobs without credit until the process exits.
+pub async fn run(db: Pool, fly: FlyClient) {
+    let mut interval = tokio::time::interval(CREDIT_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = stop_jobs_without_credit(&db, &SystemClock, &fly).await {
+            tracing::error!("An error occurred while stopping jobs without credit: {e:#?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{Attempt, Job, User},
+        services::{
+            clock::FakeClock,
+            compute::{FakeCompute, MachineTimes},
+            jobs::record_job_start,
+            presets::find_preset,
+            usage::{record_machine_times, write_attempt_resources},
+        },
+    };
+
+    fn status(available_cents: i64, month_spend_cents: i64, limit: Option<i64>) -> CreditStatus {
+        CreditStatus {
+            account: CreditAccount {
+                id: Uuid::new_v4(),
+                user_id: Some(Uuid::new_v4()),
+                organization_id: None,
+                balance_cents: available_cents,
+                spend_limit_cents: limit,
+                stop_jobs_at_zero: false,
+                created_at: Utc::now(),
+            },
+            available_cents,
+            month_spend_cents,
+        }
+    }
+
+    #[test]
+    fn credit_status_check_should_respect_balance_and_spend_limit() {
+        assert_eq!(status(100, 50, None).check(), Ok(()));
+        assert_eq!(status(100, 50, Some(51)).check(), Ok(()));
+        assert!(matches!(
+            status(0, 50, None).check(),
+            Err(CreditError::Exhausted(_))
+        ));
+        assert!(matches!(
+            status(100, 50, Some(50)).check(),
+            Err(CreditError::LimitReached(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stop_jobs_without_credit_should_stop_jobs_once_credit_runs_out() {
+        let pool = crate::app::generate_default_server_config().await.db;
+        let db = pool.get().await.unwrap();
+        let clock = FakeClock::new(Utc::now());
+        let compute = FakeCompute::default();
+        let machine_id = Uuid::new_v4().to_string();
+
+        let user = User::create(
+            &db,
+            format!("{}@example.com", Uuid::new_v4()),
+            "hash".to_string(),
+        )
+        .await
+        .unwrap();
+        let account = configure_credit_account(&db, &user.id, None, None, true)
+            .await
+            .unwrap();
+        db.execute(
+            "UPDATE credit_accounts SET balance_cents = 100 WHERE id = $1",
+            &[&account.id],
+        )
+        .await
+        .unwrap();
+        let job = Job::create(&db, user.id, JobStatus::Pending, Some(Uuid::new_v4()))
+            .await
+            .unwrap();
+        record_job_start(&db, &job.id, Some(&machine_id), 24 * 60 * 60, clock.now())
+            .await
+            .unwrap();
+        let attempt = Attempt::create(
+            &db,
+            job.id,
+            1,
+            AttemptStatus::Running,
+            Some(machine_id.clone()),
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        write_attempt_resources(&db, &attempt.id, &find_preset("a10").unwrap().resources())
+            .await
+            .unwrap();
+        record_machine_times(
+            &db,
+            &attempt.id,
+            MachineTimes {
+                started_at: Some(clock.now()),
+                stopped_at: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        // An a10 costs 150 cents an hour, so 100 cents last 40 minutes.
+        clock.advance(chrono::Duration::minutes(30));
+        let stopped = stop_jobs_without_credit(&pool, &clock, &compute)
+            .await
+            .unwrap();
+        assert!(!stopped.contains(&job.id));
+        assert_eq!(
+            credit_status(&db, &account.id, clock.now())
+                .await
+                .unwrap()
+                .available_cents,
+            25
+        );
+
+        clock.advance(chrono::Duration::minutes(10));
+        let stopped = stop_jobs_without_credit(&pool, &clock, &compute)
+            .await
+            .unwrap();
+        assert!(stopped.contains(&job.id));
+        assert_eq!(compute.calls_for(&machine_id), vec!["stop", "destroy"]);
+        assert!(matches!(
+            Job::read(&db, &job.id).await.unwrap().status,
+            JobStatus::Cancelled
+        ));
+
+        let status = credit_status(&db, &account.id, clock.now()).await.unwrap();
+        assert_eq!(status.account.balance_cents, 0, "It should debit the usage");
+        assert_eq!(status.month_spend_cents, 100);
+    }
+}
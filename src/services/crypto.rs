@@ -0,0 +1,211 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const NONCE_LENGTH: usize = 12;
+const WRAPPED_KEY_LENGTH: usize = 32 + 16;
+const ENVELOPE_VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    InvalidKey,
+    Malformed,
+    Decryption,
+}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::InvalidKey => write!(f, "encryption key must be 32 base64 encoded bytes"),
+            CryptoError::Malformed => write!(f, "sealed value is malformed"),
+            CryptoError::Decryption => write!(f, "could not decrypt sealed value"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// Envelope encryption for values we store at rest: every value is encrypted
+/// with its own data key, which is in turn wrapped by the master key from
+/// config, so rotating the master key only requires rewrapping data keys.
+#[derive(Clone)]
+pub struct Encryptor {
+    master_key: Key<Aes256Gcm>,
+}
+
+impl std::fmt::Debug for Encryptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Encryptor").finish_non_exhaustive()
+    }
+}
+
+impl Encryptor {
+    pub fn new(master_key: &str) -> Result<Self, CryptoError> {
+        let bytes = general_purpose::STANDARD
+            .decode(master_key)
+            .map_err(|_| CryptoError::InvalidKey)?;
+        if bytes.len() != 32 {
+            return Err(CryptoError::InvalidKey);
+        }
+
+        Ok(Encryptor {
+            master_key: *Key::<Aes256Gcm>::from_slice(&bytes),
+        })
+    }
+
+    /// Seals `plaintext` into `base64(version | key nonce | wrapped data key |
+    /// nonce | ciphertext)`.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<String, CryptoError> {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+
+        let key_nonce = Aes256Gcm::generate_nonce(OsRng);
+        let wrapped_key = Aes256Gcm::new(&self.master_key)
+            .encrypt(&key_nonce, data_key.as_slice())
+            .map_err(|_| CryptoError::InvalidKey)?;
+
+        let nonce = Aes256Gcm::generate_nonce(OsRng);
+        let ciphertext = Aes256Gcm::new(&data_key)
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| CryptoError::InvalidKey)?;
+
+        let mut sealed = vec![ENVELOPE_VERSION];
+        sealed.extend_from_slice(&key_nonce);
+        sealed.extend_from_slice(&wrapped_key);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+
+        Ok(general_purpose::STANDARD.encode(sealed))
+    }
+
+    pub fn decrypt(&self, sealed: &str) -> Result<Vec<u8>, CryptoError> {
+        let sealed = general_purpose::STANDARD
+            .decode(sealed)
+            .map_err(|_| CryptoError::Malformed)?;
+
+        let (version, rest) = sealed.split_first().ok_or(CryptoError::Malformed)?;
+        if *version != ENVELOPE_VERSION || rest.len() < 2 * NONCE_LENGTH + WRAPPED_KEY_LENGTH {
+            return Err(CryptoError::Malformed);
+        }
+
+        let (key_nonce, rest) = rest.split_at(NONCE_LENGTH);
+        let (wrapped_key, rest) = rest.split_at(WRAPPED_KEY_LENGTH);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+
+        let data_key = Aes256Gcm::new(&self.master_key)
+            .decrypt(Nonce::from_slice(key_nonce), wrapped_key)
+            .map_err(|_| CryptoError::Decryption)?;
+
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key))
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| CryptoError::Decryption)
+    }
+
+    pub fn encrypt_str(&self, plaintext: &str) -> Result<String, CryptoError> {
+        self.encrypt(plaintext.as_bytes())
+    }
+
+    pub fn decrypt_str(&self, sealed: &str) -> Result<String, CryptoError> {
+        String::from_utf8(self.decrypt(sealed)?).map_err(|_| CryptoError::Malformed)
+    }
+
+    /// Signs `message` for tokens we hand out and check later without
+    /// storing them, as lowercase hex. The signing key is derived from the
+    /// master key rather than being the master key itself.
+    pub fn sign(&self, message: &str) -> String {
+        format!("{:x}", self.mac(message).finalize().into_bytes())
+    }
+
+    /// Checks a signature made by `sign` in constant time.
+    pub fn verify(&self, message: &str, signature: &str) -> bool {
+        match decode_hex(signature) {
+            Some(signature) => self.mac(message).verify_slice(&signature).is_ok(),
+            None => false,
+        }
+    }
+
+    fn mac(&self, message: &str) -> Hmac<Sha256> {
+        let mut signing_key = Hmac::<Sha256>::new_from_slice(self.master_key.as_slice())
+            .expect("HMAC accepts keys of any length");
+        signing_key.update(b"lim-signing-key");
+        let mut mac = Hmac::<Sha256>::new_from_slice(&signing_key.finalize().into_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(message.as_bytes());
+
+        mac
+    }
+}
+
+/// Decodes a hex string, e.g. a signature, `None` when it isn't hex.
+pub fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    #[test]
+    fn encrypt_should_round_trip() {
+        let encryptor = Encryptor::new(KEY).unwrap();
+
+        let sealed = encryptor.encrypt_str("hunter2").unwrap();
+
+        assert!(!sealed.contains("hunter2"));
+        assert_eq!(encryptor.decrypt_str(&sealed).unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn encrypt_should_use_a_fresh_data_key_each_time() {
+        let encryptor = Encryptor::new(KEY).unwrap();
+
+        assert_ne!(
+            encryptor.encrypt_str("hunter2").unwrap(),
+            encryptor.encrypt_str("hunter2").unwrap()
+        );
+    }
+
+    #[test]
+    fn decrypt_should_reject_other_master_keys() {
+        let sealed = Encryptor::new(KEY).unwrap().encrypt_str("hunter2").unwrap();
+        let other = Encryptor::new(&general_purpose::STANDARD.encode([7u8; 32])).unwrap();
+
+        assert!(matches!(
+            other.decrypt_str(&sealed),
+            Err(CryptoError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn sign_should_only_verify_with_the_same_key_and_message() {
+        let encryptor = Encryptor::new(KEY).unwrap();
+        let other = Encryptor::new(&general_purpose::STANDARD.encode([7u8; 32])).unwrap();
+
+        let signature = encryptor.sign("hello");
+
+        assert!(encryptor.verify("hello", &signature));
+        assert!(!encryptor.verify("hullo", &signature));
+        assert!(!other.verify("hello", &signature));
+        assert!(!encryptor.verify("hello", "not hex"));
+    }
+
+    #[test]
+    fn new_should_reject_short_keys() {
+        assert!(matches!(
+            Encryptor::new(&general_purpose::STANDARD.encode([7u8; 16])),
+            Err(CryptoError::InvalidKey)
+        ));
+    }
+}
@@ -1,12 +1,31 @@
+use std::collections::HashMap;
+
 use fly::{
-    apis::{configuration::Configuration as FlyClient, Error},
+    apis::configuration::Configuration as FlyClient,
     models::{
-        FlyPeriodMachineConfig, FlyPeriodMachineGuest, FlyPeriodMachineMount, FlyPeriodMachinePort,
-        FlyPeriodMachineService, Machine,
+        CreateVolumeRequest, ExtendVolumeRequest, FlyPeriodMachineCheck, FlyPeriodMachineConfig,
+        FlyPeriodMachineFile, FlyPeriodMachineGuest, FlyPeriodMachineInit, FlyPeriodMachineMount,
+        FlyPeriodMachinePort, FlyPeriodMachineService, Machine, Volume as FlyVolume,
+        VolumeSnapshot,
+    },
+};
+
+use base64::{engine::general_purpose, Engine};
+
+use crate::{
+    models::{Image, ImageVersion, Volume},
+    services::{
+        image_config::{ImageConfig, PortMapping},
+        presets::MachineResources,
+        registry::machine_image_reference,
+        uploads::{
+            fetch_manifest, FileDownload, FETCH_MANIFEST_PATH, FETCH_SCRIPT, FETCH_SCRIPT_PATH,
+        },
     },
 };
 
-use crate::models::{Image, ImageVersion};
+/// Where machines and volumes go unless something pins them elsewhere.
+pub const DEFAULT_REGION: &str = "ord";
 
 pub async fn create_app(fly: &FlyClient, app_id: &str) -> Result<(), Box<dyn std::error::Error>> {
     Ok(fly::apis::apps_api::apps_create(
@@ -22,57 +41,231 @@ pub async fn create_app(fly: &FlyClient, app_id: &str) -> Result<(), Box<dyn std
     .await?)
 }
 
-pub async fn create_machine(
-    fly: &FlyClient,
-    app_id: &str,
-    cpu: &str,
-    gpu: &str,
+/// Builds the machine config for a job from the image's (merged) config and
+/// the resources picked for it.
+pub fn machine_config(
     image: &Image,
     image_version: &ImageVersion,
-) -> Result<Machine, Box<dyn std::error::Error>> {
-    Ok(fly::apis::machines_api::machines_create(
-        &fly,
-        app_id,
-        fly::models::CreateMachineRequest {
-            region: Some("ord".to_string()),
-            config: Some(Box::new(FlyPeriodMachineConfig {
-                files: Some(vec![]),
-                image: Some(format!(
-                    "{}:{}",
-                    image.image_url, image_version.version_number
-                )),
-                mounts: None,
-                // mounts: Some(vec![FlyPeriodMachineMount {
-                //     path: Some("/app/repositories".to_string()),
-                //     size_gb: Some(20),
-                //     volume: Some("repositories".to_string()),
-                //     ..Default::default()
-                // }]),
-                services: Some(vec![FlyPeriodMachineService {
+    config: &ImageConfig,
+    resources: &MachineResources,
+) -> FlyPeriodMachineConfig {
+    let ports = config.ports.clone().unwrap_or_else(|| {
+        vec![PortMapping {
+            internal_port: 8888,
+            port: 8080,
+            handlers: vec!["http".to_string()],
+        }]
+    });
+
+    let checks = config.health_check_path.as_ref().map(|path| {
+        HashMap::from([(
+            "health".to_string(),
+            FlyPeriodMachineCheck {
+                r#type: Some("http".to_string()),
+                port: ports.first().map(|p| p.internal_port as i32),
+                path: Some(path.clone()),
+                method: Some("GET".to_string()),
+                ..Default::default()
+            },
+        )])
+    });
+
+    FlyPeriodMachineConfig {
+        files: Some(vec![]),
+        image: Some(machine_image_reference(image, image_version)),
+        mounts: None,
+        env: Some(config.env.clone().into_iter().collect()),
+        init: Some(Box::new(FlyPeriodMachineInit {
+            entrypoint: config.entrypoint.clone(),
+            cmd: config.cmd.clone(),
+            ..Default::default()
+        })),
+        checks,
+        services: Some(
+            ports
+                .iter()
+                .map(|p| FlyPeriodMachineService {
                     autostart: Some(true),
                     autostop: Some(true),
-                    internal_port: Some(8888),
+                    internal_port: Some(p.internal_port as i32),
                     min_machines_running: Some(0),
                     ports: Some(vec![FlyPeriodMachinePort {
                         force_https: Some(false),
-                        handlers: Some(vec!["http".to_string()]),
-                        port: Some(8080),
+                        handlers: Some(p.handlers.clone()),
+                        port: Some(p.port as i32),
                         ..Default::default()
                     }]),
                     protocol: Some("tcp".to_string()),
                     ..Default::default()
-                }]),
-                guest: Some(Box::new(FlyPeriodMachineGuest {
-                    cpus: Some(4),
-                    cpu_kind: Some(cpu.to_string()),
-                    gpu_kind: Some(gpu.to_string()),
-                    memory_mb: Some(1024 * 16),
-                    ..Default::default()
-                })),
-                ..Default::default()
-            })),
+                })
+                .collect(),
+        ),
+        guest: Some(Box::new(FlyPeriodMachineGuest {
+            cpus: Some(resources.cpus),
+            cpu_kind: Some(resources.cpu_kind.clone()),
+            gpu_kind: resources.gpu_kind.clone(),
+            gpus: (resources.gpus > 0).then_some(resources.gpus),
+            memory_mb: Some(resources.memory_mb),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+}
+
+/// Mounts `volume` at `path`. Machines can only mount volumes in their own
+/// region, so callers must create the machine in `volume.region`.
+pub fn volume_mount(volume: &Volume, path: &str) -> FlyPeriodMachineMount {
+    FlyPeriodMachineMount {
+        path: Some(path.to_string()),
+        volume: volume.fly_volume_id.clone(),
+        ..Default::default()
+    }
+}
+
+/// Has the machine download `downloads` before running the job's command,
+/// by writing a fetch script into it and making that the entrypoint.
+pub fn with_file_downloads(config: &mut FlyPeriodMachineConfig, downloads: &[FileDownload]) {
+    if downloads.is_empty() {
+        return;
+    }
+
+    let file = |guest_path: &str, contents: &str| FlyPeriodMachineFile {
+        guest_path: Some(guest_path.to_string()),
+        raw_value: Some(general_purpose::STANDARD.encode(contents)),
+        ..Default::default()
+    };
+    config.files.get_or_insert_with(Vec::new).extend([
+        file(FETCH_SCRIPT_PATH, FETCH_SCRIPT),
+        file(FETCH_MANIFEST_PATH, &fetch_manifest(downloads)),
+    ]);
+
+    let init = config.init.get_or_insert_with(Default::default);
+    let command = init
+        .entrypoint
+        .take()
+        .unwrap_or_default()
+        .into_iter()
+        .chain(init.cmd.take().unwrap_or_default())
+        .collect();
+    init.entrypoint = Some(vec!["/bin/sh".to_string(), FETCH_SCRIPT_PATH.to_string()]);
+    init.cmd = Some(command);
+}
+
+/// The metadata key machines are tagged with their job attempt under, so a
+/// create that was never recorded can be found rather than repeated.
+pub const ATTEMPT_METADATA_KEY: &str = "lim_attempt";
+
+pub async fn find_machine_by_metadata(
+    fly: &FlyClient,
+    app_id: &str,
+    key: &str,
+    value: &str,
+) -> Result<Option<Machine>, Box<dyn std::error::Error>> {
+    let machines = fly::apis::machines_api::machines_list(fly, app_id, None, None).await?;
+
+    Ok(machines.into_iter().find(|machine| {
+        machine
+            .config
+            .as_ref()
+            .and_then(|config| config.metadata.as_ref())
+            .and_then(|metadata| metadata.get(key))
+            .is_some_and(|tagged| tagged == value)
+    }))
+}
+
+/// Whether a machine couldn't be created because the region had no room for
+/// it, which is worth trying again later.
+pub fn is_capacity_error(message: &str) -> bool {
+    let message = message.to_lowercase();
+    [
+        "capacity",
+        "insufficient resources",
+        "could not reserve resource",
+    ]
+    .iter()
+    .any(|needle| message.contains(needle))
+}
+
+pub async fn create_machine(
+    fly: &FlyClient,
+    app_id: &str,
+    region: &str,
+    config: FlyPeriodMachineConfig,
+) -> Result<Machine, Box<dyn std::error::Error>> {
+    Ok(fly::apis::machines_api::machines_create(
+        fly,
+        app_id,
+        fly::models::CreateMachineRequest {
+            region: Some(region.to_string()),
+            config: Some(Box::new(config)),
             ..Default::default()
         },
     )
     .await?)
 }
+
+pub async fn create_volume(
+    fly: &FlyClient,
+    app_id: &str,
+    name: &str,
+    region: &str,
+    size_gb: i32,
+) -> Result<FlyVolume, Box<dyn std::error::Error>> {
+    Ok(fly::apis::volumes_api::volumes_create(
+        fly,
+        app_id,
+        CreateVolumeRequest {
+            name: Some(name.to_string()),
+            region: Some(region.to_string()),
+            size_gb: Some(size_gb),
+            encrypted: Some(true),
+            ..Default::default()
+        },
+    )
+    .await?)
+}
+
+pub async fn extend_volume(
+    fly: &FlyClient,
+    app_id: &str,
+    fly_volume_id: &str,
+    size_gb: i32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    fly::apis::volumes_api::volumes_extend(
+        fly,
+        app_id,
+        fly_volume_id,
+        ExtendVolumeRequest {
+            size_gb: Some(size_gb),
+        },
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn snapshot_volume(
+    fly: &FlyClient,
+    app_id: &str,
+    fly_volume_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(fly::apis::volumes_api::create_volume_snapshot(fly, app_id, fly_volume_id).await?)
+}
+
+pub async fn list_volume_snapshots(
+    fly: &FlyClient,
+    app_id: &str,
+    fly_volume_id: &str,
+) -> Result<Vec<VolumeSnapshot>, Box<dyn std::error::Error>> {
+    Ok(fly::apis::volumes_api::volumes_list_snapshots(fly, app_id, fly_volume_id).await?)
+}
+
+pub async fn delete_volume(
+    fly: &FlyClient,
+    app_id: &str,
+    fly_volume_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    fly::apis::volumes_api::volume_delete(fly, app_id, fly_volume_id).await?;
+
+    Ok(())
+}
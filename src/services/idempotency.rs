@@ -0,0 +1,366 @@
+use std::{future::Future, time::Duration};
+
+use redis::{aio::MultiplexedConnection, AsyncCommands, RedisResult, Script};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+/// Set on responses that are replays of an earlier request.
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+pub const IDEMPOTENCY_TTL_SECONDS: u64 = 24 * 60 * 60;
+/// How long a reservation outlives a request that never stores a response.
+/// It's refreshed while the request is handled, however long that takes.
+pub const IN_PROGRESS_TTL_SECONDS: u64 = 60;
+pub const MAX_KEY_LENGTH: usize = 255;
+
+/// What an endpoint answered with, kept to be replayed.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct IdempotencyRecord {
+    request_hash: String,
+    /// Which request reserved the key, so a request whose reservation
+    /// expired can't overwrite or free the one that took it over.
+    #[serde(default)]
+    token: Option<Uuid>,
+    /// Unset while the first request is still being handled.
+    response: Option<StoredResponse>,
+}
+
+/// A request's hold on a key, only it can refresh, store a response under or
+/// release the key.
+#[derive(Debug, PartialEq)]
+pub struct Claim {
+    redis_key: String,
+    request_hash: String,
+    token: Uuid,
+    /// The reservation as stored, which it's compared against.
+    reservation: String,
+}
+
+/// Runs the command in ARGV[2..] on KEYS[1] only when it still holds the
+/// reservation in ARGV[1].
+const IF_RESERVED: &str = r#"
+if redis.call("GET", KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+local command = {}
+for i = 2, #ARGV do
+    command[#command + 1] = ARGV[i]
+end
+redis.call(unpack(command))
+return 1
+"#;
+
+async fn if_reserved(
+    conn: &mut MultiplexedConnection,
+    claim: &Claim,
+    command: &[&str],
+) -> RedisResult<bool> {
+    let mut script = Script::new(IF_RESERVED).prepare_invoke();
+    script.key(&claim.redis_key).arg(&claim.reservation);
+    for arg in command {
+        script.arg(*arg);
+    }
+    let applied: i64 = script.invoke_async(conn).await?;
+
+    Ok(applied == 1)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Reservation {
+    /// The key is new, the request should be handled.
+    Reserved(Claim),
+    /// The same request is being handled by another call.
+    InProgress,
+    /// The key was used for a different request.
+    Mismatch,
+    Replay(StoredResponse),
+}
+
+/// Identifies a request by what it does, so a key reused for something else
+/// can be told apart from a retry.
+pub fn request_hash(method: &str, path: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_bytes());
+    hasher.update(b" ");
+    hasher.update(path.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+
+    format!("{:x}", hasher.finalize())
+}
+
+fn redis_key(user_id: &Uuid, key: &str) -> String {
+    format!("idempotency:{user_id}:{key}")
+}
+
+/// Claims `key` for a request, or says what became of the last request that
+/// used it. Keys are scoped to the user. The reservation only lasts
+/// `IN_PROGRESS_TTL_SECONDS` unless refreshed, so a caller that dies
+/// mid-request doesn't hold the key for a day.
+pub async fn reserve(
+    conn: &mut MultiplexedConnection,
+    user_id: &Uuid,
+    key: &str,
+    request_hash: &str,
+) -> RedisResult<Reservation> {
+    let token = Uuid::new_v4();
+    let reservation = serde_json::to_string(&IdempotencyRecord {
+        request_hash: request_hash.to_string(),
+        token: Some(token),
+        response: None,
+    })
+    .unwrap();
+    let reserved: Option<String> = redis::cmd("SET")
+        .arg(redis_key(user_id, key))
+        .arg(&reservation)
+        .arg("NX")
+        .arg("EX")
+        .arg(IN_PROGRESS_TTL_SECONDS)
+        .query_async(conn)
+        .await?;
+    if reserved.is_some() {
+        return Ok(Reservation::Reserved(Claim {
+            redis_key: redis_key(user_id, key),
+            request_hash: request_hash.to_string(),
+            token,
+            reservation,
+        }));
+    }
+
+    let existing: Option<String> = conn.get(redis_key(user_id, key)).await?;
+    let Some(existing) =
+        existing.and_then(|value| serde_json::from_str::<IdempotencyRecord>(&value).ok())
+    else {
+        return Ok(Reservation::InProgress);
+    };
+
+    Ok(match existing.response {
+        _ if existing.request_hash != request_hash => Reservation::Mismatch,
+        Some(response) => Reservation::Replay(response),
+        None => Reservation::InProgress,
+    })
+}
+
+/// Pushes back the expiry of `claim`'s reservation. Returns false when the
+/// key no longer holds it.
+pub async fn refresh(conn: &mut MultiplexedConnection, claim: &Claim) -> RedisResult<bool> {
+    if_reserved(
+        conn,
+        claim,
+        &[
+            "EXPIRE",
+            &claim.redis_key,
+            &IN_PROGRESS_TTL_SECONDS.to_string(),
+        ],
+    )
+    .await
+}
+
+/// Runs `request` while keeping `claim`'s reservation from expiring, however
+/// long it takes.
+pub async fn hold_during<F: Future>(
+    conn: &mut MultiplexedConnection,
+    claim: &Claim,
+    request: F,
+) -> F::Output {
+    tokio::pin!(request);
+    let mut interval = tokio::time::interval(Duration::from_secs(IN_PROGRESS_TTL_SECONDS / 3));
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            output = &mut request => return output,
+            _ = interval.tick() => {
+                if let Err(e) = refresh(conn, claim).await {
+                    tracing::error!("An error occurred while refreshing an idempotency key: {e:#?}");
+                }
+            }
+        }
+    }
+}
+
+/// Keeps `response` to be replayed for `IDEMPOTENCY_TTL_SECONDS`, if the key
+/// is still reserved by `claim`. Returns whether it was stored.
+pub async fn store_response(
+    conn: &mut MultiplexedConnection,
+    claim: &Claim,
+    response: StoredResponse,
+) -> RedisResult<bool> {
+    let record = serde_json::to_string(&IdempotencyRecord {
+        request_hash: claim.request_hash.clone(),
+        token: Some(claim.token),
+        response: Some(response),
+    })
+    .unwrap();
+
+    if_reserved(
+        conn,
+        claim,
+        &[
+            "SET",
+            &claim.redis_key,
+            &record,
+            "EX",
+            &IDEMPOTENCY_TTL_SECONDS.to_string(),
+        ],
+    )
+    .await
+}
+
+/// Frees the key `claim` reserved after a request that failed, so it can be
+/// retried. A key since reserved by another request is left alone.
+pub async fn release(conn: &mut MultiplexedConnection, claim: &Claim) -> RedisResult<()> {
+    if_reserved(conn, claim, &["DEL", &claim.redis_key]).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reserve_should_replay_stored_responses_for_the_same_request() {
+        let redis = crate::app::generate_default_server_config().await.cache;
+        let mut conn = redis.get_multiplexed_async_connection().await.unwrap();
+        let user_id = Uuid::new_v4();
+        let hash = request_hash("POST", "/jobs", b"{}");
+        let response = StoredResponse {
+            status: 201,
+            body: "{}".to_string(),
+        };
+
+        let Reservation::Reserved(claim) =
+            reserve(&mut conn, &user_id, "ci-1", &hash).await.unwrap()
+        else {
+            panic!("It should reserve new keys");
+        };
+        assert_eq!(
+            reserve(&mut conn, &user_id, "ci-1", &hash).await.unwrap(),
+            Reservation::InProgress
+        );
+
+        assert!(store_response(&mut conn, &claim, response.clone())
+            .await
+            .unwrap());
+        assert_eq!(
+            reserve(&mut conn, &user_id, "ci-1", &hash).await.unwrap(),
+            Reservation::Replay(response)
+        );
+        assert_eq!(
+            reserve(
+                &mut conn,
+                &user_id,
+                "ci-1",
+                &request_hash("POST", "/jobs", b"{\"preset\":\"a10\"}")
+            )
+            .await
+            .unwrap(),
+            Reservation::Mismatch
+        );
+        assert!(
+            matches!(
+                reserve(&mut conn, &Uuid::new_v4(), "ci-1", &hash)
+                    .await
+                    .unwrap(),
+                Reservation::Reserved(_)
+            ),
+            "It should scope keys to the user"
+        );
+    }
+
+    #[tokio::test]
+    async fn release_should_free_the_key() {
+        let redis = crate::app::generate_default_server_config().await.cache;
+        let mut conn = redis.get_multiplexed_async_connection().await.unwrap();
+        let user_id = Uuid::new_v4();
+        let hash = request_hash("POST", "/images", b"{}");
+
+        let Reservation::Reserved(claim) =
+            reserve(&mut conn, &user_id, "ci-1", &hash).await.unwrap()
+        else {
+            panic!("It should reserve new keys");
+        };
+        release(&mut conn, &claim).await.unwrap();
+
+        assert!(matches!(
+            reserve(&mut conn, &user_id, "ci-1", &hash).await.unwrap(),
+            Reservation::Reserved(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_reservations_should_not_touch_the_request_that_took_over() {
+        let redis = crate::app::generate_default_server_config().await.cache;
+        let mut conn = redis.get_multiplexed_async_connection().await.unwrap();
+        let user_id = Uuid::new_v4();
+        let hash = request_hash("POST", "/jobs", b"{}");
+
+        let Reservation::Reserved(expired) =
+            reserve(&mut conn, &user_id, "ci-1", &hash).await.unwrap()
+        else {
+            panic!("It should reserve new keys");
+        };
+        let _: () = conn.del(redis_key(&user_id, "ci-1")).await.unwrap();
+        let Reservation::Reserved(current) =
+            reserve(&mut conn, &user_id, "ci-1", &hash).await.unwrap()
+        else {
+            panic!("It should reserve the expired key again");
+        };
+
+        let response = StoredResponse {
+            status: 201,
+            body: "{}".to_string(),
+        };
+        assert!(!refresh(&mut conn, &expired).await.unwrap());
+        assert!(!store_response(&mut conn, &expired, response.clone())
+            .await
+            .unwrap());
+        release(&mut conn, &expired).await.unwrap();
+        assert_eq!(
+            reserve(&mut conn, &user_id, "ci-1", &hash).await.unwrap(),
+            Reservation::InProgress,
+            "It should leave the current reservation alone"
+        );
+
+        assert!(refresh(&mut conn, &current).await.unwrap());
+        assert!(store_response(&mut conn, &current, response.clone())
+            .await
+            .unwrap());
+        assert_eq!(
+            reserve(&mut conn, &user_id, "ci-1", &hash).await.unwrap(),
+            Reservation::Replay(response)
+        );
+    }
+
+    #[tokio::test]
+    async fn reserve_should_only_hold_the_key_until_a_response_is_stored() {
+        let redis = crate::app::generate_default_server_config().await.cache;
+        let mut conn = redis.get_multiplexed_async_connection().await.unwrap();
+        let user_id = Uuid::new_v4();
+        let hash = request_hash("POST", "/jobs", b"{}");
+
+        let Reservation::Reserved(claim) =
+            reserve(&mut conn, &user_id, "ci-1", &hash).await.unwrap()
+        else {
+            panic!("It should reserve new keys");
+        };
+        let ttl: i64 = conn.ttl(redis_key(&user_id, "ci-1")).await.unwrap();
+        assert!(ttl > 0 && ttl <= IN_PROGRESS_TTL_SECONDS as i64);
+
+        let response = StoredResponse {
+            status: 201,
+            body: "{}".to_string(),
+        };
+        store_response(&mut conn, &claim, response).await.unwrap();
+        let ttl: i64 = conn.ttl(redis_key(&user_id, "ci-1")).await.unwrap();
+        assert!(ttl > IN_PROGRESS_TTL_SECONDS as i64);
+    }
+}
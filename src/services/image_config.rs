@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+
+use deadpool_postgres::GenericClient;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::types::Json;
+use uuid::Uuid;
+
+use crate::services::presets::find_preset;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PortMapping {
+    /// The port the container listens on.
+    pub internal_port: u16,
+    /// The port exposed on the machine's public address.
+    pub port: u16,
+    #[serde(default = "default_handlers")]
+    pub handlers: Vec<String>,
+}
+
+fn default_handlers() -> Vec<String> {
+    vec!["http".to_string()]
+}
+
+/// How an image wants to be run. Set on an image, and optionally overridden
+/// field by field on a version.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ImageConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ports: Option<Vec<PortMapping>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource_preset: Option<String>,
+    /// A JSON Schema that a job's `params` must match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params_schema: Option<serde_json::Value>,
+}
+
+pub fn is_valid_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+impl ImageConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(name) = self.env.keys().find(|name| !is_valid_env_name(name)) {
+            return Err(format!("Invalid environment variable name: {name}"));
+        }
+        if let Some(ports) = &self.ports {
+            if ports.iter().any(|p| p.internal_port == 0 || p.port == 0) {
+                return Err("Ports must be between 1 and 65535".to_string());
+            }
+        }
+        if let Some(path) = self
+            .health_check_path
+            .as_ref()
+            .filter(|p| !p.starts_with('/'))
+        {
+            return Err(format!("Health check path must start with /: {path}"));
+        }
+        if let Some(preset) = self
+            .resource_preset
+            .as_ref()
+            .filter(|p| find_preset(p).is_none())
+        {
+            return Err(format!("Unknown resource preset: {preset}"));
+        }
+        if let Some(schema) = &self.params_schema {
+            jsonschema::JSONSchema::compile(schema)
+                .map_err(|e| format!("Invalid params schema: {e}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Layers a version's overrides on top of the image's config.
+    pub fn merge(&self, overrides: &ImageConfig) -> ImageConfig {
+        let mut env = self.env.clone();
+        env.extend(overrides.env.clone());
+
+        ImageConfig {
+            entrypoint: overrides.entrypoint.clone().or(self.entrypoint.clone()),
+            cmd: overrides.cmd.clone().or(self.cmd.clone()),
+            env,
+            ports: overrides.ports.clone().or(self.ports.clone()),
+            health_check_path: overrides
+                .health_check_path
+                .clone()
+                .or(self.health_check_path.clone()),
+            resource_preset: overrides
+                .resource_preset
+                .clone()
+                .or(self.resource_preset.clone()),
+            params_schema: overrides
+                .params_schema
+                .clone()
+                .or(self.params_schema.clone()),
+        }
+    }
+}
+
+pub async fn read_image_config(
+    db: &impl GenericClient,
+    image_id: &Uuid,
+) -> Result<ImageConfig, tokio_postgres::Error> {
+    let row = db
+        .query_one("SELECT config FROM images WHERE id = $1", &[image_id])
+        .await?;
+
+    Ok(row.get::<_, Json<ImageConfig>>("config").0)
+}
+
+pub async fn write_image_config(
+    db: &impl GenericClient,
+    image_id: &Uuid,
+    config: &ImageConfig,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE images SET config = $1 WHERE id = $2",
+        &[&Json(config), image_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn read_version_config(
+    db: &impl GenericClient,
+    image_version_id: &Uuid,
+) -> Result<ImageConfig, tokio_postgres::Error> {
+    let row = db
+        .query_one(
+            "SELECT config FROM image_versions WHERE id = $1",
+            &[image_version_id],
+        )
+        .await?;
+
+    Ok(row.get::<_, Json<ImageConfig>>("config").0)
+}
+
+pub async fn write_version_config(
+    db: &impl GenericClient,
+    image_version_id: &Uuid,
+    config: &ImageConfig,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE image_versions SET config = $1 WHERE id = $2",
+        &[&Json(config), image_version_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// The config a version actually runs with.
+pub async fn effective_config(
+    db: &impl GenericClient,
+    image_id: &Uuid,
+    image_version_id: &Uuid,
+) -> Result<ImageConfig, tokio_postgres::Error> {
+    let image_config = read_image_config(db, image_id).await?;
+    let version_config = read_version_config(db, image_version_id).await?;
+
+    Ok(image_config.merge(&version_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_should_prefer_version_overrides() {
+        let image = ImageConfig {
+            cmd: Some(vec!["serve".to_string()]),
+            env: BTreeMap::from([
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "1".to_string()),
+            ]),
+            resource_preset: Some("a10".to_string()),
+            ..Default::default()
+        };
+        let version = ImageConfig {
+            cmd: Some(vec!["train".to_string()]),
+            env: BTreeMap::from([("B".to_string(), "2".to_string())]),
+            ..Default::default()
+        };
+
+        let merged = image.merge(&version);
+
+        assert_eq!(merged.cmd, Some(vec!["train".to_string()]));
+        assert_eq!(merged.env["A"], "1");
+        assert_eq!(merged.env["B"], "2");
+        assert_eq!(merged.resource_preset.as_deref(), Some("a10"));
+    }
+
+    #[test]
+    fn validate_should_reject_bad_values() {
+        let bad_env = ImageConfig {
+            env: BTreeMap::from([("1BAD".to_string(), "x".to_string())]),
+            ..Default::default()
+        };
+        let bad_path = ImageConfig {
+            health_check_path: Some("health".to_string()),
+            ..Default::default()
+        };
+        let bad_preset = ImageConfig {
+            resource_preset: Some("tpu".to_string()),
+            ..Default::default()
+        };
+        let bad_schema = ImageConfig {
+            params_schema: Some(serde_json::json!({ "type": "not-a-type" })),
+            ..Default::default()
+        };
+
+        assert!(bad_env.validate().is_err());
+        assert!(bad_path.validate().is_err());
+        assert!(bad_preset.validate().is_err());
+        assert!(bad_schema.validate().is_err());
+        assert!(ImageConfig::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+
+use deadpool_postgres::GenericClient;
+use uuid::Uuid;
+
+use crate::{models::ImageVersion, reference::MAX_TAG_LENGTH};
+
+/// A pushed tag already has a version pinned to another digest. Versions
+/// never change once created, so jobs and their retries run what they were
+/// submitted with.
+#[derive(Debug)]
+pub struct TagMovedError(pub String);
+
+impl fmt::Display for TagMovedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TagMovedError {}
+
+/// Creates the version for a pushed `tag`. Returns `None` when the tag
+/// already points at `digest` and a `TagMovedError` when it points elsewhere.
+pub async fn pin_tag(
+    db: &impl GenericClient,
+    image_id: &Uuid,
+    tag: &str,
+    digest: &str,
+) -> Result<Option<ImageVersion>, Box<dyn std::error::Error>> {
+    let existing = ImageVersion::read_where_image_id(db, image_id)
+        .await?
+        .into_iter()
+        .find(|v| v.version_number == tag);
+
+    match existing {
+        Some(version) if version.hash == digest => Ok(None),
+        Some(version) => Err(Box::new(TagMovedError(format!(
+            "Tag {tag} already points at {}, push a new tag instead",
+            if version.hash.is_empty() {
+                "an unpinned version"
+            } else {
+                &version.hash
+            }
+        )))),
+        None => Ok(Some(
+            ImageVersion::create(db, *image_id, digest.to_string(), tag.to_string()).await?,
+        )),
+    }
+}
+
+/// Names the version a tag gets when it's pushed again at another digest,
+/// e.g. `latest-0f1e2d3c4b5a`, keeping it a valid tag.
+fn repushed_tag(tag: &str, digest: &str) -> String {
+    let hex = digest.split_once(':').map_or(digest, |(_, hex)| hex);
+    let suffix = &hex[..hex.len().min(12)];
+    let tag = &tag[..tag.len().min(MAX_TAG_LENGTH - suffix.len() - 1)];
+
+    format!("{tag}-{suffix}")
+}
+
+/// Registers a push of `tag` at `digest` as a version of its own. Versions
+/// never change, so when the tag already points at another digest the push
+/// gets a version named by `repushed_tag`. Returns the version and whether
+/// it was created by this push.
+pub async fn register_push(
+    db: &impl GenericClient,
+    image_id: &Uuid,
+    tag: &str,
+    digest: &str,
+) -> Result<(ImageVersion, bool), Box<dyn std::error::Error>> {
+    let versions = ImageVersion::read_where_image_id(db, image_id).await?;
+    let repushed = repushed_tag(tag, digest);
+    let version_number = if versions.iter().any(|v| v.version_number == tag) {
+        repushed
+    } else {
+        tag.to_string()
+    };
+
+    if let Some(version) = versions.into_iter().find(|v| {
+        v.hash == digest && (v.version_number == tag || v.version_number == version_number)
+    }) {
+        return Ok((version, false));
+    }
+
+    match create_version(db, image_id, digest.to_string(), version_number.clone()).await? {
+        Some(version) => Ok((version, true)),
+        None => Err(Box::new(TagMovedError(format!(
+            "Version {version_number} was created by another push at the same time"
+        )))),
+    }
+}
+
+/// Creates a version unless the image already has one for `tag`, in which
+/// case `None` is returned.
+pub async fn create_version(
+    db: &impl GenericClient,
+    image_id: &Uuid,
+    digest: String,
+    tag: String,
+) -> Result<Option<ImageVersion>, Box<dyn std::error::Error>> {
+    let row = db
+        .query_opt(
+            "INSERT INTO image_versions (image_id, hash, version_number)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (image_id, version_number) DO NOTHING
+            RETURNING id",
+            &[image_id, &digest, &tag],
+        )
+        .await?;
+
+    match row {
+        Some(row) => Ok(Some(ImageVersion::read(db, &row.get("id")).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Unlinks finished jobs from versions about to be deleted, they keep their
+/// history without one.
+pub async fn detach_jobs(
+    db: &impl GenericClient,
+    image_version_ids: &[Uuid],
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE jobs SET image_version_id = NULL WHERE image_version_id = ANY($1)",
+        &[&image_version_ids],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Deletes the rows that belong to an image about to be deleted: its
+/// webhook, registry credentials and the manifests and blobs pushed to it.
+pub async fn delete_image_rows(
+    db: &impl GenericClient,
+    image_id: &Uuid,
+) -> Result<(), tokio_postgres::Error> {
+    for table in [
+        "image_webhooks",
+        "registry_credentials",
+        "registry_manifests",
+        "registry_blobs",
+    ] {
+        db.execute(
+            &format!("DELETE FROM {table} WHERE image_id = $1"),
+            &[image_id],
+        )
+        .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,429 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{GenericClient, Pool};
+use fly::apis::configuration::Configuration as FlyClient;
+use uuid::Uuid;
+
+use crate::{
+    models::{Job, JobStatus},
+    services::{
+        blob_store::BlobStore,
+        compute::ComputeBackend,
+        crypto::Encryptor,
+        jobs::{create_attempt_machine, plan_attempt, record_attempt, JobLauncher},
+        object_storage::S3Client,
+        pipelines::job_finished,
+        quotas::fits_quota,
+    },
+};
+
+/// How long an idle worker waits before checking the queue again.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How many workers each instance runs.
+pub const WORKERS: usize = 4;
+/// How many times starting a job may error before it's failed.
+pub const MAX_PROVISION_FAILURES: i32 = 5;
+
+/// How long a claimed entry is leased to its worker. A worker that dies part
+/// way through leaves the entry to be taken over once the lease lapses.
+pub const LEASE_SECONDS: i64 = 300;
+
+/// A queued job, leased to the worker that claimed it.
+#[derive(Debug)]
+pub struct QueueEntry {
+    pub id: Uuid,
+    pub job_id: Uuid,
+    pub failures: i32,
+    /// When the lease lapses, as stored, so the worker can tell whether it
+    /// still holds the entry.
+    pub locked_until: DateTime<Utc>,
+}
+
+/// Queues `job_id` to be started at `run_at`, moving it if it's queued
+/// already.
+pub async fn enqueue_job(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    run_at: DateTime<Utc>,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "INSERT INTO job_queue (job_id, run_at) VALUES ($1, $2)
+         ON CONFLICT (job_id) DO UPDATE SET run_at = EXCLUDED.run_at, failures = 0",
+        &[job_id, &run_at],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Queues a blocked job that's ready to run if it fits in its user's
+/// quotas, returning false if it doesn't. Those stay blocked until one of the
+/// user's jobs finishes and frees some room.
+pub async fn queue_blocked_job(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<bool, tokio_postgres::Error> {
+    let row = db
+        .query_one("SELECT user_id, gpus FROM jobs WHERE id = $1", &[job_id])
+        .await?;
+    let gpus: i32 = row.get("gpus");
+    if !fits_quota(db, &row.get("user_id"), gpus.into(), now).await? {
+        return Ok(false);
+    }
+
+    let queued = db
+        .execute(
+            "UPDATE jobs SET status = $1, updated_at = NOW() WHERE id = $2 AND status = $3",
+            &[&JobStatus::Queued, job_id, &JobStatus::Blocked],
+        )
+        .await?;
+    if queued > 0 {
+        enqueue_job(db, job_id, now).await?;
+    }
+
+    Ok(true)
+}
+
+async fn claim(
+    db: &impl GenericClient,
+    job_id: Option<&Uuid>,
+    now: DateTime<Utc>,
+) -> Result<Option<QueueEntry>, tokio_postgres::Error> {
+    let locked_until = now + chrono::Duration::seconds(LEASE_SECONDS);
+    // Taking over a lapsed lease counts as a failure, so a job that keeps
+    // killing its worker is given up on like any other.
+    let row = db
+        .query_opt(
+            "UPDATE job_queue
+             SET locked_until = $3,
+                 failures = failures + CASE WHEN locked_until IS NULL THEN 0 ELSE 1 END
+             WHERE id = (
+                 SELECT id FROM job_queue
+                 WHERE run_at <= $1 AND ($2::uuid IS NULL OR job_id = $2)
+                   AND (locked_until IS NULL OR locked_until <= $1)
+                 ORDER BY run_at
+                 LIMIT 1
+                 FOR UPDATE SKIP LOCKED
+             )
+             RETURNING id, job_id, failures, locked_until",
+            &[&now, &job_id, &locked_until],
+        )
+        .await?;
+
+    Ok(row.map(|row| QueueEntry {
+        id: row.get("id"),
+        job_id: row.get("job_id"),
+        failures: row.get("failures"),
+        locked_until: row.get("locked_until"),
+    }))
+}
+
+/// Leases the next due entry no other worker holds. The lease is committed
+/// with the claim, so no transaction stays open while the job is started.
+pub async fn claim_next(
+    db: &impl GenericClient,
+    now: DateTime<Utc>,
+) -> Result<Option<QueueEntry>, tokio_postgres::Error> {
+    claim(db, None, now).await
+}
+
+/// Like `claim_next`, for one job.
+pub async fn claim_job(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<Option<QueueEntry>, tokio_postgres::Error> {
+    claim(db, Some(job_id), now).await
+}
+
+/// Counts a failed try at `entry`, pushing it back with a backoff until
+/// `MAX_PROVISION_FAILURES`, when the job is failed. Does nothing if the
+/// lease has passed to another worker.
+async fn record_failure(
+    db: &impl GenericClient,
+    entry: &QueueEntry,
+    message: &str,
+    now: DateTime<Utc>,
+) -> Result<(), tokio_postgres::Error> {
+    let failures = entry.failures + 1;
+    if failures >= MAX_PROVISION_FAILURES {
+        let deleted = db
+            .execute(
+                "DELETE FROM job_queue WHERE id = $1 AND locked_until = $2",
+                &[&entry.id, &entry.locked_until],
+            )
+            .await?;
+        let failed = deleted > 0
+            && db
+                .execute(
+                    "UPDATE jobs SET status = $1, updated_at = NOW()
+                     WHERE id = $2 AND status = $3",
+                    &[&JobStatus::Failed, &entry.job_id, &JobStatus::Queued],
+                )
+                .await?
+                > 0;
+        if failed {
+            job_finished(db, &entry.job_id, now).await?;
+        }
+    } else {
+        let run_at = now + chrono::Duration::seconds(5 << failures);
+        db.execute(
+            "UPDATE job_queue
+             SET run_at = $3, failures = $4, last_error = $5, locked_until = NULL
+             WHERE id = $1 AND locked_until = $2",
+            &[&entry.id, &entry.locked_until, &run_at, &failures, &message],
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Starts the attempt for a leased entry. The job is read with no
+/// transaction open, its machine created, and the attempt recorded in a
+/// transaction of its own that first checks the lease is still held.
+async fn start_entry(
+    db: &Pool,
+    launcher: &JobLauncher<'_>,
+    entry: &QueueEntry,
+    now: DateTime<Utc>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = db.get().await?;
+    let job = match Job::read(&client, &entry.job_id).await {
+        Ok(job) if matches!(job.status, JobStatus::Queued) => job,
+        _ => {
+            client
+                .execute(
+                    "DELETE FROM job_queue WHERE id = $1 AND locked_until = $2",
+                    &[&entry.id, &entry.locked_until],
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+    let plan = plan_attempt(&client, launcher, &job, now).await?;
+    drop(client);
+
+    let created = create_attempt_machine(launcher, &job, &plan).await;
+
+    let mut client = db.get().await?;
+    let transaction = client.transaction().await?;
+    // Deleted first, so a failed attempt's retry can queue the job again.
+    let held = transaction
+        .execute(
+            "DELETE FROM job_queue WHERE id = $1 AND locked_until = $2",
+            &[&entry.id, &entry.locked_until],
+        )
+        .await?;
+    if held == 0 {
+        // Another worker took the entry over, it finds the machine by its
+        // metadata.
+        return Ok(());
+    }
+    let queued = transaction
+        .query_opt(
+            "SELECT 1 FROM jobs WHERE id = $1 AND status = $2 FOR UPDATE",
+            &[&job.id, &JobStatus::Queued],
+        )
+        .await?
+        .is_some();
+    if !queued {
+        // Stopped while its machine was being created.
+        transaction.commit().await?;
+        if let Ok(Some(machine_id)) = &created {
+            launcher
+                .fly
+                .destroy_machine(&job.user_id.to_string(), machine_id)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        return Ok(());
+    }
+    record_attempt(&transaction, &job, &plan, created, now).await?;
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Starts the leased job's next attempt. Any error pushes the entry back
+/// with a backoff until `MAX_PROVISION_FAILURES`, when the job is failed.
+/// Machines are matched to attempts by their metadata, so a try that
+/// created one but never recorded it doesn't leave a second behind.
+pub async fn provision(
+    db: &Pool,
+    launcher: &JobLauncher<'_>,
+    entry: &QueueEntry,
+    now: DateTime<Utc>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let started = if entry.failures >= MAX_PROVISION_FAILURES {
+        Err("The workers starting the job stopped responding".to_string())
+    } else {
+        start_entry(db, launcher, entry, now)
+            .await
+            .map_err(|e| e.to_string())
+    };
+
+    if let Err(message) = started {
+        tracing::error!("Could not start job {}: {message}", entry.job_id);
+
+        record_failure(&db.get().await?, entry, &message, now).await?;
+    }
+
+    Ok(())
+}
+
+/// Claims and provisions the next due job, returning whether there was one.
+async fn work_once(
+    db: &Pool,
+    launcher: &JobLauncher<'_>,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(entry) = claim_next(&db.get().await?, Utc::now()).await? else {
+        return Ok(false);
+    };
+    provision(db, launcher, &entry, Utc::now()).await?;
+
+    Ok(true)
+}
+
+/// Runs a queue worker until the process exits.
+pub async fn run_worker(
+    db: Pool,
+    fly: FlyClient,
+    encryptor: Encryptor,
+    blob_store: BlobStore,
+    object_storage: Option<S3Client>,
+) {
+    let launcher = JobLauncher {
+        fly: &fly,
+        encryptor: &encryptor,
+        blob_store: &blob_store,
+        object_storage: object_storage.as_ref(),
+    };
+
+    loop {
+        match work_once(&db, &launcher).await {
+            Ok(true) => {}
+            Ok(false) => tokio::time::sleep(POLL_INTERVAL).await,
+            Err(e) => {
+                tracing::error!("An error occurred while working the job queue: {e:#?}");
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::app::ServerConfig;
+
+    pub(crate) fn launcher(config: &ServerConfig) -> JobLauncher<'_> {
+        JobLauncher {
+            fly: &config.fly,
+            encryptor: &config.encryptor,
+            blob_store: &config.blob_store,
+            object_storage: config.object_storage.as_ref(),
+        }
+    }
+
+    /// Provisions `job_id` if it's due, as a worker would.
+    pub(crate) async fn work_job(
+        db: &Pool,
+        launcher: &JobLauncher<'_>,
+        job_id: &Uuid,
+        now: DateTime<Utc>,
+    ) -> bool {
+        let client = db.get().await.unwrap();
+        let Some(entry) = claim_job(&client, job_id, now).await.unwrap() else {
+            return false;
+        };
+        provision(db, launcher, &entry, now).await.unwrap();
+
+        true
+    }
+
+    #[tokio::test]
+    async fn claim_should_skip_entries_held_by_other_workers() {
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+        let job_id = Uuid::new_v4();
+        let now = Utc::now();
+        enqueue_job(&db, &job_id, now).await.unwrap();
+
+        let entry = claim_job(&db, &job_id, now).await.unwrap().unwrap();
+        assert_eq!(entry.failures, 0);
+        assert!(
+            claim_job(&db, &job_id, now).await.unwrap().is_none(),
+            "It should not hand out a leased entry"
+        );
+
+        let lapsed = now + chrono::Duration::seconds(LEASE_SECONDS);
+        let entry = claim_job(&db, &job_id, lapsed)
+            .await
+            .unwrap()
+            .expect("It should release entries when their worker goes away");
+        assert_eq!(entry.failures, 1, "It should count the lapse as a failure");
+    }
+
+    #[tokio::test]
+    async fn record_failure_should_back_off_and_respect_leases() {
+        let config = crate::app::generate_default_server_config().await;
+        let db = config.db.get().await.unwrap();
+        let job_id = Uuid::new_v4();
+        let now = Utc::now();
+        enqueue_job(&db, &job_id, now).await.unwrap();
+
+        let entry = claim_job(&db, &job_id, now).await.unwrap().unwrap();
+        record_failure(&db, &entry, "boom", now).await.unwrap();
+
+        assert!(
+            claim_job(&db, &job_id, now).await.unwrap().is_none(),
+            "It should back off"
+        );
+        let retry = now + chrono::Duration::seconds(10);
+        let entry = claim_job(&db, &job_id, retry).await.unwrap().unwrap();
+        assert_eq!(entry.failures, 1);
+
+        let stale = QueueEntry {
+            locked_until: now,
+            ..entry
+        };
+        record_failure(&db, &stale, "boom", retry).await.unwrap();
+        let row = db
+            .query_one(
+                "SELECT failures, last_error FROM job_queue WHERE job_id = $1",
+                &[&job_id],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            row.get::<_, i32>("failures"),
+            1,
+            "It should leave entries leased to other workers alone"
+        );
+        assert_eq!(
+            row.get::<_, Option<String>>("last_error").as_deref(),
+            Some("boom")
+        );
+    }
+
+    #[tokio::test]
+    async fn claim_should_wait_until_entries_are_due() {
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+        let job_id = Uuid::new_v4();
+        let now = Utc::now();
+
+        enqueue_job(&db, &job_id, now + chrono::Duration::seconds(30))
+            .await
+            .unwrap();
+
+        assert!(claim_job(&db, &job_id, now).await.unwrap().is_none());
+        assert!(claim_job(&db, &job_id, now + chrono::Duration::seconds(30))
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
@@ -0,0 +1,965 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::GenericClient;
+use fly::apis::configuration::Configuration as FlyClient;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::types::Json;
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        Attempt, AttemptStatus, FailureClass, Image, ImageVersion, Job, JobStatus, Upload, Volume,
+    },
+    services::{
+        artifacts::upload_env,
+        billing::check_billing,
+        blob_store::BlobStore,
+        compute::ComputeBackend,
+        credits::check_credits,
+        crypto::Encryptor,
+        fly::is_capacity_error,
+        image_config::{effective_config, is_valid_env_name, ImageConfig},
+        job_queue::enqueue_job,
+        object_storage::S3Client,
+        pipelines::job_finished,
+        plans::user_plan,
+        presets::{resolve_resources, MachineResources, ResourceError},
+        quotas::check_quota,
+        registry_credentials::needs_mirror,
+        retries::RetryPolicy,
+        secrets::{missing_secrets, resolve_secrets},
+        uploads::JobFile,
+        usage::{record_usage, write_attempt_resources},
+        volumes::{attach_volume, lock_volume, volume_in_use, VolumeInUseError, VolumeMount},
+    },
+};
+
+/// How a job should be run, shared by every path that submits jobs.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct JobSpec {
+    /// A named resource preset, defaults to the image's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// Overrides the preset's cpu kind.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<String>,
+    /// Overrides the preset's gpu kind.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpu: Option<String>,
+    /// A volume to mount, the machine is placed in the volume's region.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<VolumeMount>,
+    /// How long the job may run before its machine is destroyed, defaults
+    /// to the user's plan's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_runtime_seconds: Option<i64>,
+    /// Whether and when failed attempts are retried, defaults to never.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+}
+
+impl JobSpec {
+    pub fn resources(&self, config: &ImageConfig) -> Result<MachineResources, ResourceError> {
+        resolve_resources(
+            self.preset.as_deref(),
+            config.resource_preset.as_deref(),
+            self.cpu.as_deref(),
+            self.gpu.as_deref(),
+        )
+    }
+}
+
+/// The environment variable a job's `params` are passed in, as JSON.
+pub const PARAMS_ENV: &str = "JOB_PARAMS";
+
+/// What a job is asked to do, so one image can run different workloads.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct JobInput {
+    /// Layered on top of the image's environment.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Replaces the image's command.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<Vec<String>>,
+    /// Appended to the command.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Free form parameters, checked against the image's `params_schema`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<serde_json::Value>,
+    /// Names of the user's secrets to expose as environment variables.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secrets: Vec<String>,
+    /// Uploaded files to download into the machine before the command runs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<JobFile>,
+}
+
+impl JobInput {
+    pub fn validate(&self, config: &ImageConfig) -> Result<(), String> {
+        if let Some(name) = self.env.keys().find(|name| !is_valid_env_name(name)) {
+            return Err(format!("Invalid environment variable name: {name}"));
+        }
+        if self.env.contains_key(PARAMS_ENV) || self.secrets.iter().any(|name| name == PARAMS_ENV) {
+            return Err(format!("{PARAMS_ENV} is reserved for params"));
+        }
+        if let Some(name) = self
+            .secrets
+            .iter()
+            .find(|name| self.env.contains_key(*name))
+        {
+            return Err(format!("{name} is set both as a secret and in env"));
+        }
+        for file in &self.files {
+            file.validate()?;
+        }
+        // Files are fetched by a wrapper that then runs the job's command, so
+        // the command has to be known rather than left to the image.
+        if !self.files.is_empty()
+            && self.command.is_none()
+            && config.entrypoint.is_none()
+            && config.cmd.is_none()
+        {
+            return Err("Jobs with files need a command".to_string());
+        }
+
+        match (&self.params, &config.params_schema) {
+            (Some(params), Some(schema)) => {
+                let schema = jsonschema::JSONSchema::compile(schema)
+                    .map_err(|e| format!("Invalid params schema: {e}"))?;
+                if let Err(errors) = schema.validate(params) {
+                    let errors = errors
+                        .map(|e| format!("{}: {e}", e.instance_path))
+                        .collect::<Vec<_>>();
+                    return Err(format!("Invalid params: {}", errors.join(", ")));
+                }
+            }
+            (Some(params), None) if !params.is_object() => {
+                return Err("Params must be an object".to_string());
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// The config a job's machine runs with, once its input is applied.
+    pub fn apply(&self, config: &ImageConfig) -> ImageConfig {
+        let mut config = config.clone();
+        config.env.extend(self.env.clone());
+        if let Some(params) = &self.params {
+            config
+                .env
+                .insert(PARAMS_ENV.to_string(), params.to_string());
+        }
+        if let Some(command) = &self.command {
+            config.cmd = Some(command.clone());
+        }
+        if !self.args.is_empty() {
+            let mut cmd = config.cmd.unwrap_or_default();
+            cmd.extend(self.args.clone());
+            config.cmd = Some(cmd);
+        }
+
+        config
+    }
+}
+
+pub async fn read_job_input(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+) -> Result<JobInput, tokio_postgres::Error> {
+    let row = db
+        .query_one("SELECT input FROM jobs WHERE id = $1", &[job_id])
+        .await?;
+
+    Ok(row.get::<_, Json<JobInput>>("input").0)
+}
+
+pub async fn read_job_spec(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+) -> Result<JobSpec, tokio_postgres::Error> {
+    let row = db
+        .query_one("SELECT spec FROM jobs WHERE id = $1", &[job_id])
+        .await?;
+
+    Ok(row.get::<_, Json<JobSpec>>("spec").0)
+}
+
+pub async fn write_job_spec(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    spec: &JobSpec,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE jobs SET spec = $1 WHERE id = $2",
+        &[&Json(spec), job_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn write_job_input(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    input: &JobInput,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE jobs SET input = $1 WHERE id = $2",
+        &[&Json(input), job_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Records the machine a job was started on and when, for the watchdog, and
+/// moves it out of the queue.
+pub async fn record_job_start(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    machine_id: Option<&str>,
+    max_runtime_seconds: i64,
+    started_at: DateTime<Utc>,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE jobs
+         SET status = $1, machine_id = $2, max_runtime_seconds = $3, started_at = $4
+         WHERE id = $5",
+        &[
+            &JobStatus::Pending,
+            &machine_id,
+            &max_runtime_seconds,
+            &started_at,
+            job_id,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn read_max_runtime(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+) -> Result<Option<i64>, tokio_postgres::Error> {
+    let row = db
+        .query_one(
+            "SELECT max_runtime_seconds FROM jobs WHERE id = $1",
+            &[job_id],
+        )
+        .await?;
+
+    Ok(row.get("max_runtime_seconds"))
+}
+
+/// Counts the jobs on any of `image_version_ids` that may still run.
+pub async fn unfinished_job_count(
+    db: &impl GenericClient,
+    image_version_ids: &[Uuid],
+) -> Result<i64, tokio_postgres::Error> {
+    let row = db
+        .query_one(
+            "SELECT COUNT(*) FROM jobs WHERE image_version_id = ANY($1) AND status = ANY($2)",
+            &[&image_version_ids, &JobStatus::unfinished()],
+        )
+        .await?;
+
+    Ok(row.get(0))
+}
+
+/// The clients launching a job needs, borrowed from the request's state.
+pub struct JobLauncher<'a> {
+    pub fly: &'a FlyClient,
+    pub encryptor: &'a Encryptor,
+    pub blob_store: &'a BlobStore,
+    /// Where jobs upload their artifacts, if configured.
+    pub object_storage: Option<&'a S3Client>,
+}
+
+/// Records a job for `image_version` and queues it, a worker starts its
+/// machine.
+pub async fn launch_job(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    image: &Image,
+    image_version: &ImageVersion,
+    spec: &JobSpec,
+    input: &JobInput,
+) -> Result<Job, Box<dyn std::error::Error>> {
+    let job = record_job(
+        db,
+        user_id,
+        image,
+        image_version,
+        spec,
+        input,
+        JobStatus::Queued,
+    )
+    .await?;
+    enqueue_job(db, &job.id, Utc::now()).await?;
+
+    Ok(job)
+}
+
+/// A job that can't be submitted as given, e.g. for an unknown preset or a
+/// volume that isn't the user's.
+#[derive(Debug)]
+pub struct InvalidJobError(pub String);
+
+impl std::fmt::Display for InvalidJobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidJobError {}
+
+/// What `validate_job` works out for a job that can be submitted.
+#[derive(Debug)]
+pub struct ValidJob {
+    pub resources: MachineResources,
+    pub max_runtime_seconds: i64,
+}
+
+/// Checks that `user_id` can submit a job with `spec` and `input` for
+/// `image_version`. `record_job` runs it for every job, and the templates
+/// schedules, pipelines and batches keep are checked with it up front.
+pub async fn validate_job(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    image: &Image,
+    image_version: &ImageVersion,
+    spec: &JobSpec,
+    input: &JobInput,
+) -> Result<ValidJob, Box<dyn std::error::Error>> {
+    let invalid =
+        |message: String| -> Box<dyn std::error::Error> { Box::new(InvalidJobError(message)) };
+
+    let config = effective_config(db, &image.id, &image_version.id).await?;
+    let resources = spec
+        .resources(&config)
+        .map_err(|e| invalid(e.to_string()))?;
+    let max_runtime_seconds = user_plan(db, user_id)
+        .await?
+        .max_runtime(spec.max_runtime_seconds)
+        .map_err(invalid)?;
+    if let Some(retry) = &spec.retry {
+        retry.validate().map_err(invalid)?;
+    }
+    input.validate(&config).map_err(invalid)?;
+
+    let missing = missing_secrets(db, user_id, &input.secrets).await?;
+    if !missing.is_empty() {
+        return Err(invalid(format!("Unknown secrets: {}", missing.join(", "))));
+    }
+    for file in &input.files {
+        let owned = Upload::read(db, &file.upload_id)
+            .await
+            .is_ok_and(|upload| upload.user_id == *user_id);
+        if !owned {
+            return Err(invalid(format!("Unknown upload {}", file.upload_id)));
+        }
+    }
+    if let Some(mount) = &spec.volume {
+        mount.validate().map_err(invalid)?;
+        // Volumes live in their owner's fly.io app and jobs run in their
+        // submitter's, so even organization volumes only mount for the owner.
+        let owned = Volume::read(db, &mount.volume_id)
+            .await
+            .is_ok_and(|volume| volume.user_id == *user_id);
+        if !owned {
+            return Err(invalid(format!("Unknown volume {}", mount.volume_id)));
+        }
+    }
+
+    Ok(ValidJob {
+        resources,
+        max_runtime_seconds,
+    })
+}
+
+/// Checks and records a job for `image_version` without queueing it, for
+/// jobs that wait on others before they start.
+pub async fn record_job(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    image: &Image,
+    image_version: &ImageVersion,
+    spec: &JobSpec,
+    input: &JobInput,
+    status: JobStatus,
+) -> Result<Job, Box<dyn std::error::Error>> {
+    let ValidJob { resources, .. } =
+        validate_job(db, user_id, image, image_version, spec, input).await?;
+    check_billing(db, user_id).await?;
+    check_credits(db, user_id, Utc::now()).await?;
+    if let Some(mount) = &spec.volume {
+        lock_volume(db, &mount.volume_id).await?;
+        if volume_in_use(db, &mount.volume_id).await? {
+            let volume = Volume::read(db, &mount.volume_id).await?;
+            return Err(VolumeInUseError(format!(
+                "Volume {} is attached to a running job",
+                volume.name
+            ))
+            .into());
+        }
+    }
+
+    // Jobs waiting on others are checked as a whole when they're submitted.
+    if matches!(status, JobStatus::Queued) {
+        check_quota(db, user_id, 1, resources.gpus.into(), Utc::now()).await?;
+    }
+
+    let job = Job::create(db, *user_id, status, Some(image_version.id)).await?;
+    db.execute(
+        "UPDATE jobs SET gpus = $1 WHERE id = $2",
+        &[&resources.gpus, &job.id],
+    )
+    .await?;
+    write_job_input(db, &job.id, input).await?;
+    write_job_spec(db, &job.id, spec).await?;
+    if let Some(mount) = &spec.volume {
+        attach_volume(db, &job.id, mount).await?;
+    }
+
+    Ok(job)
+}
+
+async fn next_attempt_number(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+) -> Result<i32, tokio_postgres::Error> {
+    let row = db
+        .query_one(
+            "SELECT COALESCE(MAX(number), 0) + 1 FROM attempts WHERE job_id = $1",
+            &[job_id],
+        )
+        .await?;
+
+    Ok(row.get(0))
+}
+
+/// What starting the next attempt at a job needs, read up front so nothing
+/// is held open while its machine is created.
+#[cfg_attr(test, allow(dead_code))]
+pub struct AttemptPlan {
+    image: Image,
+    image_version: ImageVersion,
+    config: ImageConfig,
+    resources: MachineResources,
+    max_runtime_seconds: i64,
+    volume: Option<(Volume, VolumeMount)>,
+    uploads: Vec<(Upload, JobFile)>,
+    mirrored: bool,
+    number: i32,
+}
+
+/// Reads and checks everything the next attempt at `job` needs.
+pub async fn plan_attempt(
+    db: &impl GenericClient,
+    launcher: &JobLauncher<'_>,
+    job: &Job,
+    now: DateTime<Utc>,
+) -> Result<AttemptPlan, Box<dyn std::error::Error>> {
+    let image_version_id = job.image_version_id.ok_or("The job's image was deleted")?;
+    let image_version = ImageVersion::read(db, &image_version_id).await?;
+    let image = Image::read(db, &image_version.image_id).await?;
+    let mirrored = needs_mirror(db, &image).await?;
+    let spec = read_job_spec(db, &job.id).await?;
+    let input = read_job_input(db, &job.id).await?;
+
+    let config = effective_config(db, &image.id, &image_version.id).await?;
+    let resources = spec.resources(&config)?;
+    let max_runtime_seconds = user_plan(db, &job.user_id)
+        .await?
+        .max_runtime(spec.max_runtime_seconds)?;
+    let mut config = input.apply(&config);
+    config
+        .env
+        .extend(resolve_secrets(db, launcher.encryptor, &job.user_id, &input.secrets).await?);
+    let volume = match spec.volume {
+        Some(mount) => Some((Volume::read(db, &mount.volume_id).await?, mount)),
+        None => None,
+    };
+    let mut uploads = vec![];
+    for file in input.files {
+        uploads.push((Upload::read(db, &file.upload_id).await?, file));
+    }
+    if let Some(s3) = launcher.object_storage {
+        config.env.extend(upload_env(s3, &job.id, now));
+    }
+    let number = next_attempt_number(db, &job.id).await?;
+
+    Ok(AttemptPlan {
+        image,
+        image_version,
+        config,
+        resources,
+        max_runtime_seconds,
+        volume,
+        uploads,
+        mirrored,
+        number,
+    })
+}
+
+/// Creates the machine `plan` describes, or finds the one an earlier try
+/// created but never recorded. Makes no database calls, so it can run with
+/// no transaction open.
+#[cfg_attr(test, allow(unused_variables))]
+pub async fn create_attempt_machine(
+    launcher: &JobLauncher<'_>,
+    job: &Job,
+    plan: &AttemptPlan,
+) -> Result<Option<String>, String> {
+    #[cfg(test)]
+    let created: Result<Option<String>, String> = Ok(None);
+    #[cfg(not(test))]
+    let created = {
+        use crate::services::{
+            fly::{
+                find_machine_by_metadata, volume_mount, with_file_downloads, ATTEMPT_METADATA_KEY,
+                DEFAULT_REGION,
+            },
+            oci_registry::{mirror_reference, with_pull_token},
+            uploads::{FileDownload, DOWNLOAD_EXPIRY_SECONDS},
+        };
+
+        let downloads = plan
+            .uploads
+            .iter()
+            .map(|(upload, file)| {
+                Ok(FileDownload {
+                    path: file.path.clone(),
+                    url: launcher
+                        .blob_store
+                        .download_url(&upload.key, DOWNLOAD_EXPIRY_SECONDS)
+                        .ok_or("Passing files to jobs requires S3 blob storage")?,
+                    sha256: upload.sha256.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let mut machine_config = crate::services::fly::machine_config(
+            &plan.image,
+            &plan.image_version,
+            &plan.config,
+            &plan.resources,
+        );
+        machine_config.image = if plan.mirrored {
+            Some(mirror_reference(
+                launcher.encryptor,
+                &plan.image,
+                &plan.image_version,
+            ))
+        } else {
+            machine_config
+                .image
+                .map(|reference| with_pull_token(launcher.encryptor, &plan.image, &reference))
+        };
+        if let Some((volume, mount)) = &plan.volume {
+            machine_config.mounts = Some(vec![volume_mount(volume, &mount.path)]);
+        }
+        with_file_downloads(&mut machine_config, &downloads);
+        let attempt_key = format!("{}:{}", job.id, plan.number);
+        machine_config.metadata = Some(std::collections::HashMap::from([(
+            ATTEMPT_METADATA_KEY.to_string(),
+            attempt_key.clone(),
+        )]));
+
+        let app_id = job.user_id.to_string();
+        let existing =
+            find_machine_by_metadata(launcher.fly, &app_id, ATTEMPT_METADATA_KEY, &attempt_key)
+                .await
+                .map_err(|e| e.to_string())?;
+        match existing {
+            Some(machine) => Ok(machine.id),
+            None => crate::services::fly::create_machine(
+                launcher.fly,
+                &app_id,
+                plan.volume
+                    .as_ref()
+                    .map(|(volume, _)| volume.region.as_str())
+                    .unwrap_or(DEFAULT_REGION),
+                machine_config,
+            )
+            .await
+            .map(|machine| machine.id)
+            .map_err(|e| e.to_string()),
+        }
+    };
+
+    created
+}
+
+/// Records the attempt `create_attempt_machine` started. Fly running out of
+/// capacity is recorded as a failed attempt and left to the job's retry
+/// policy, any other error is returned.
+pub async fn record_attempt(
+    db: &impl GenericClient,
+    job: &Job,
+    plan: &AttemptPlan,
+    created: Result<Option<String>, String>,
+    now: DateTime<Utc>,
+) -> Result<Attempt, Box<dyn std::error::Error>> {
+    match created {
+        Ok(machine_id) => {
+            let attempt = Attempt::create(
+                db,
+                job.id,
+                plan.number,
+                AttemptStatus::Running,
+                machine_id.clone(),
+                None,
+                None,
+                None,
+            )
+            .await?;
+            write_attempt_resources(db, &attempt.id, &plan.resources).await?;
+            record_job_start(
+                db,
+                &job.id,
+                machine_id.as_deref(),
+                plan.max_runtime_seconds,
+                now,
+            )
+            .await?;
+
+            Ok(attempt)
+        }
+        Err(message) if is_capacity_error(&message) => {
+            let attempt = Attempt::create(
+                db,
+                job.id,
+                plan.number,
+                AttemptStatus::Running,
+                None,
+                None,
+                None,
+                None,
+            )
+            .await?;
+            fail_attempt(
+                db,
+                &attempt,
+                AttemptFailure {
+                    class: FailureClass::Capacity,
+                    exit_code: None,
+                    error: Some(message),
+                },
+                now,
+            )
+            .await?;
+
+            Ok(Attempt::read(db, &attempt.id).await?)
+        }
+        Err(message) => Err(message.into()),
+    }
+}
+
+/// How an attempt failed.
+#[derive(Debug)]
+pub struct AttemptFailure {
+    pub class: FailureClass,
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+}
+
+/// Marks `attempt` as failed, then either schedules the job's next attempt
+/// or fails the job as its retry policy says. Returns when the retry is due.
+pub async fn fail_attempt(
+    db: &impl GenericClient,
+    attempt: &Attempt,
+    failure: AttemptFailure,
+    now: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, tokio_postgres::Error> {
+    db.execute(
+        "UPDATE attempts
+         SET status = $1, failure = $2, exit_code = $3, error = $4, finished_at = $5
+         WHERE id = $6",
+        &[
+            &AttemptStatus::Failed,
+            &failure.class,
+            &failure.exit_code,
+            &failure.error,
+            &now,
+            &attempt.id,
+        ],
+    )
+    .await?;
+    record_usage(db, &attempt.id, now).await?;
+
+    let retry_at = read_job_spec(db, &attempt.job_id)
+        .await?
+        .retry
+        .unwrap_or_default()
+        .retry_at(attempt.number, failure.class, now);
+
+    match retry_at {
+        Some(retry_at) => {
+            let requeued = db
+                .execute(
+                    "UPDATE jobs
+                     SET status = $1, machine_id = NULL, started_at = NULL, updated_at = NOW()
+                     WHERE id = $2 AND status = ANY($3)",
+                    &[&JobStatus::Queued, &attempt.job_id, &JobStatus::active()],
+                )
+                .await?;
+            if requeued > 0 {
+                enqueue_job(db, &attempt.job_id, retry_at).await?;
+            }
+        }
+        None => {
+            let failed = db
+                .execute(
+                    "UPDATE jobs SET status = $1, updated_at = NOW()
+                     WHERE id = $2 AND status = ANY($3)",
+                    &[&JobStatus::Failed, &attempt.job_id, &JobStatus::active()],
+                )
+                .await?;
+            if failed > 0 {
+                job_finished(db, &attempt.job_id, now).await?;
+            }
+        }
+    }
+
+    Ok(retry_at)
+}
+
+/// Marks `attempt` as succeeded and its job as completed, releasing jobs
+/// that were waiting on it.
+pub async fn complete_attempt(
+    db: &impl GenericClient,
+    attempt: &Attempt,
+    now: DateTime<Utc>,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE attempts SET status = $1, exit_code = 0, finished_at = $2 WHERE id = $3",
+        &[&AttemptStatus::Succeeded, &now, &attempt.id],
+    )
+    .await?;
+    record_usage(db, &attempt.id, now).await?;
+    let completed = db
+        .execute(
+            "UPDATE jobs SET status = $1, updated_at = NOW() WHERE id = $2 AND status = ANY($3)",
+            &[&JobStatus::Completed, &attempt.job_id, &JobStatus::active()],
+        )
+        .await?;
+    if completed > 0 {
+        job_finished(db, &attempt.job_id, now).await?;
+    }
+
+    Ok(())
+}
+
+/// An active job about to be terminated, with the machine it runs on.
+#[derive(Debug)]
+pub struct ActiveJob {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub machine_id: Option<String>,
+}
+
+/// Destroys the job's machine, fails its running attempts with `failure` and
+/// marks it `status`. Returns false if the machine couldn't be destroyed, in
+/// which case nothing is written.
+pub async fn terminate_job(
+    db: &impl GenericClient,
+    compute: &impl ComputeBackend,
+    job: &ActiveJob,
+    status: JobStatus,
+    failure: FailureClass,
+    now: DateTime<Utc>,
+) -> Result<bool, tokio_postgres::Error> {
+    if let Some(machine_id) = &job.machine_id {
+        let app_id = job.user_id.to_string();
+        if let Err(e) = compute.stop_machine(&app_id, machine_id).await {
+            tracing::warn!("Could not stop machine {machine_id} of job {}: {e}", job.id);
+        }
+        if let Err(e) = compute.destroy_machine(&app_id, machine_id).await {
+            tracing::error!(
+                "Could not destroy machine {machine_id} of job {}: {e}",
+                job.id
+            );
+            return Ok(false);
+        }
+    }
+
+    let attempts = db
+        .query(
+            "UPDATE attempts SET status = $1, failure = $2, finished_at = $3
+             WHERE job_id = $4 AND status = $5
+             RETURNING id",
+            &[
+                &AttemptStatus::Failed,
+                &failure,
+                &now,
+                &job.id,
+                &AttemptStatus::Running,
+            ],
+        )
+        .await?;
+    for attempt in attempts {
+        record_usage(db, &attempt.get("id"), now).await?;
+    }
+    let updated = db
+        .execute(
+            "UPDATE jobs SET status = $1, updated_at = NOW() WHERE id = $2 AND status = ANY($3)",
+            &[&status, &job.id, &JobStatus::active()],
+        )
+        .await?;
+    if updated > 0 {
+        job_finished(db, &job.id, now).await?;
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::User;
+
+    #[test]
+    fn apply_should_layer_input_over_the_image_config() {
+        let config = ImageConfig {
+            cmd: Some(vec!["python".to_string(), "train.py".to_string()]),
+            env: BTreeMap::from([
+                ("MODEL".to_string(), "small".to_string()),
+                ("EPOCHS".to_string(), "1".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let input = JobInput {
+            env: BTreeMap::from([("EPOCHS".to_string(), "10".to_string())]),
+            args: vec!["--fast".to_string()],
+            params: Some(serde_json::json!({ "lr": 0.1 })),
+            ..Default::default()
+        };
+
+        let applied = input.apply(&config);
+
+        assert_eq!(
+            applied.cmd,
+            Some(vec![
+                "python".to_string(),
+                "train.py".to_string(),
+                "--fast".to_string()
+            ])
+        );
+        assert_eq!(applied.env["MODEL"], "small");
+        assert_eq!(applied.env["EPOCHS"], "10");
+        assert_eq!(applied.env[PARAMS_ENV], r#"{"lr":0.1}"#);
+
+        let replaced = JobInput {
+            command: Some(vec!["bash".to_string()]),
+            ..Default::default()
+        }
+        .apply(&config);
+
+        assert_eq!(replaced.cmd, Some(vec!["bash".to_string()]));
+    }
+
+    #[test]
+    fn validate_should_check_params_against_the_schema() {
+        let config = ImageConfig {
+            params_schema: Some(serde_json::json!({
+                "type": "object",
+                "properties": { "lr": { "type": "number" } },
+                "required": ["lr"],
+            })),
+            ..Default::default()
+        };
+        let input = |params| JobInput {
+            params: Some(params),
+            ..Default::default()
+        };
+
+        assert!(input(serde_json::json!({ "lr": 0.1 }))
+            .validate(&config)
+            .is_ok());
+        assert!(input(serde_json::json!({ "lr": "fast" }))
+            .validate(&config)
+            .is_err());
+        assert!(input(serde_json::json!({})).validate(&config).is_err());
+        assert!(input(serde_json::json!([1]))
+            .validate(&ImageConfig::default())
+            .is_err());
+    }
+
+    #[test]
+    fn validate_should_reject_bad_env_names() {
+        let input = |name: &str| JobInput {
+            env: BTreeMap::from([(name.to_string(), "x".to_string())]),
+            ..Default::default()
+        };
+
+        assert!(input("GOOD_NAME").validate(&ImageConfig::default()).is_ok());
+        assert!(input("1BAD").validate(&ImageConfig::default()).is_err());
+        assert!(input(PARAMS_ENV).validate(&ImageConfig::default()).is_err());
+
+        let clashing = JobInput {
+            env: BTreeMap::from([("HF_TOKEN".to_string(), "x".to_string())]),
+            secrets: vec!["HF_TOKEN".to_string()],
+            ..Default::default()
+        };
+        assert!(clashing.validate(&ImageConfig::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn record_job_should_reject_unknown_uploads_as_invalid() {
+        let db = crate::app::generate_default_server_config().await.db;
+        let db = db.get().await.unwrap();
+        let user = User::create(
+            &db,
+            format!("{}@example.com", Uuid::new_v4()),
+            "hash".to_string(),
+        )
+        .await
+        .unwrap();
+        let image = Image::create(
+            &db,
+            user.id,
+            "trainer".to_string(),
+            "registry.example.com/trainer".to_string(),
+        )
+        .await
+        .unwrap();
+        let image_version =
+            ImageVersion::create(&db, image.id, "sha256:abc".to_string(), "v1".to_string())
+                .await
+                .unwrap();
+        let input = JobInput {
+            command: Some(vec!["train".to_string()]),
+            files: vec![JobFile {
+                upload_id: Uuid::new_v4(),
+                path: "/data/train.csv".to_string(),
+            }],
+            ..Default::default()
+        };
+
+        let error = record_job(
+            &db,
+            &user.id,
+            &image,
+            &image_version,
+            &JobSpec {
+                preset: Some("cpu-small".to_string()),
+                ..Default::default()
+            },
+            &input,
+            JobStatus::Queued,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(
+            error.downcast_ref::<InvalidJobError>().is_some(),
+            "It should be answered with a 422 rather than a 500: {error}"
+        );
+    }
+}
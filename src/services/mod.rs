@@ -1 +1,34 @@
+pub mod artifacts;
+pub mod audit;
+pub mod batches;
+pub mod billing;
+pub mod blob_store;
+pub mod clock;
+pub mod compute;
+pub mod credits;
+pub mod crypto;
 pub mod fly;
+pub mod idempotency;
+pub mod image_config;
+pub mod images;
+pub mod job_queue;
+pub mod jobs;
+pub mod object_storage;
+pub mod oci_registry;
+pub mod organizations;
+pub mod payments;
+pub mod pipelines;
+pub mod plans;
+pub mod presets;
+pub mod quotas;
+pub mod reconciler;
+pub mod registry;
+pub mod registry_credentials;
+pub mod retries;
+pub mod schedules;
+pub mod secrets;
+pub mod uploads;
+pub mod usage;
+pub mod volumes;
+pub mod watchdog;
+pub mod webhooks;
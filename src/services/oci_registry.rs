@@ -0,0 +1,753 @@
+use chrono::Utc;
+use deadpool_postgres::GenericClient;
+use hyper::body::HttpBody;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    models::{Image, ImageVersion, User},
+    reference::{is_valid_digest, is_valid_tag, Reference},
+    services::{
+        blob_store::{BlobReader, BlobStore, BlobWriter},
+        crypto::Encryptor,
+        images::{pin_tag, TagMovedError},
+        registry::{RegistryAuth, RegistryClient, RegistryError},
+        registry_credentials::find_registry_auth,
+    },
+};
+
+/// Errors from the OCI Distribution spec, rendered as
+/// `{"errors": [{"code": ..., "message": ...}]}`.
+#[derive(Debug)]
+pub enum OciError {
+    Unauthorized,
+    Denied,
+    NameInvalid,
+    NameUnknown,
+    BlobUnknown,
+    BlobUploadUnknown,
+    DigestInvalid,
+    ManifestUnknown,
+    ManifestInvalid,
+    ManifestBlobUnknown(String),
+    /// The tag already points at another manifest.
+    TagImmutable(String),
+    Internal(Box<dyn std::error::Error>),
+}
+
+impl OciError {
+    pub fn status(&self) -> u32 {
+        match self {
+            OciError::Unauthorized => 401,
+            OciError::Denied => 403,
+            OciError::NameUnknown
+            | OciError::BlobUnknown
+            | OciError::BlobUploadUnknown
+            | OciError::ManifestUnknown => 404,
+            OciError::NameInvalid
+            | OciError::DigestInvalid
+            | OciError::ManifestInvalid
+            | OciError::ManifestBlobUnknown(_) => 400,
+            OciError::TagImmutable(_) => 409,
+            OciError::Internal(_) => 500,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            OciError::Unauthorized => "UNAUTHORIZED",
+            OciError::Denied => "DENIED",
+            OciError::NameInvalid => "NAME_INVALID",
+            OciError::NameUnknown => "NAME_UNKNOWN",
+            OciError::BlobUnknown => "BLOB_UNKNOWN",
+            OciError::BlobUploadUnknown => "BLOB_UPLOAD_UNKNOWN",
+            OciError::DigestInvalid => "DIGEST_INVALID",
+            OciError::ManifestUnknown => "MANIFEST_UNKNOWN",
+            OciError::ManifestInvalid => "MANIFEST_INVALID",
+            OciError::ManifestBlobUnknown(_) => "MANIFEST_BLOB_UNKNOWN",
+            OciError::TagImmutable(_) => "DENIED",
+            OciError::Internal(_) => "UNKNOWN",
+        }
+    }
+
+    pub fn body(&self) -> String {
+        serde_json::json!({
+            "errors": [{ "code": self.code(), "message": self.to_string() }]
+        })
+        .to_string()
+    }
+}
+
+impl std::fmt::Display for OciError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OciError::Unauthorized => write!(f, "authentication required"),
+            OciError::Denied => write!(f, "requested access to the resource is denied"),
+            OciError::NameInvalid => write!(f, "invalid repository name"),
+            OciError::NameUnknown => write!(f, "repository name not known to registry"),
+            OciError::BlobUnknown => write!(f, "blob unknown to registry"),
+            OciError::BlobUploadUnknown => write!(f, "blob upload unknown to registry"),
+            OciError::DigestInvalid => write!(f, "provided digest did not match uploaded content"),
+            OciError::ManifestUnknown => write!(f, "manifest unknown"),
+            OciError::ManifestInvalid => write!(f, "manifest invalid"),
+            OciError::ManifestBlobUnknown(digest) => {
+                write!(f, "blob unknown to registry: {digest}")
+            }
+            OciError::TagImmutable(message) => write!(f, "{message}"),
+            OciError::Internal(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for OciError {}
+
+impl From<tokio_postgres::Error> for OciError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        OciError::Internal(Box::new(e))
+    }
+}
+
+impl From<crate::services::blob_store::BlobStoreError> for OciError {
+    fn from(e: crate::services::blob_store::BlobStoreError) -> Self {
+        OciError::Internal(Box::new(e))
+    }
+}
+
+/// How long the presigned URLs blob downloads are redirected to last.
+const BLOB_URL_EXPIRY_SECONDS: u64 = 15 * 60;
+
+/// The host users push to, e.g. `docker push registry.lionfi.sh/team/model`.
+pub fn registry_host() -> String {
+    std::env::var("REGISTRY_HOST").unwrap_or_else(|_| "registry.lionfi.sh".to_string())
+}
+
+pub fn sha256_digest(bytes: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(bytes))
+}
+
+fn blob_key(digest: &str) -> String {
+    format!("blobs/{}", digest.replacen(':', "/", 1))
+}
+
+fn upload_key(image_id: &Uuid, upload_id: &Uuid) -> String {
+    format!("uploads/{image_id}/{upload_id}")
+}
+
+/// Images on other registries that need credentials to pull are served to
+/// machines as `mirrors/<image id>`, read through from their registry with
+/// the owner's credentials.
+const MIRROR_PREFIX: &str = "mirrors/";
+
+pub fn is_mirror_repository(repository: &str) -> bool {
+    repository.starts_with(MIRROR_PREFIX)
+}
+
+/// Whether `reference` names a repository in the built-in registry. Those
+/// images are only created by pushing to them, so nobody can claim someone
+/// else's repository ahead of their first push.
+pub fn is_registry_reference(reference: &Reference) -> bool {
+    reference.registry == registry_host()
+}
+
+/// A repository in the built-in registry is an image whose url lives under
+/// our registry host.
+pub fn repository_image_url(repository: &str) -> Result<String, OciError> {
+    let image_url = format!("{}/{repository}", registry_host());
+    match Reference::parse(&image_url) {
+        Ok(reference) if reference.tag.is_none() && reference.digest.is_none() => Ok(image_url),
+        _ => Err(OciError::NameInvalid),
+    }
+}
+
+async fn find_repository_image(
+    db: &impl GenericClient,
+    repository: &str,
+) -> Result<Option<Image>, OciError> {
+    let row = match repository.strip_prefix(MIRROR_PREFIX) {
+        Some(image_id) => match Uuid::parse_str(image_id) {
+            Ok(image_id) => {
+                db.query_opt("SELECT id FROM images WHERE id = $1", &[&image_id])
+                    .await?
+            }
+            Err(_) => None,
+        },
+        None => {
+            let image_url = repository_image_url(repository)?;
+            db.query_opt(
+                "SELECT id FROM images WHERE image_url = $1 ORDER BY created_at LIMIT 1",
+                &[&image_url],
+            )
+            .await?
+        }
+    };
+
+    match row {
+        Some(row) => Ok(Some(
+            Image::read(db, &row.get("id"))
+                .await
+                .map_err(|e| OciError::Internal(e.into()))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Who a registry request comes from.
+pub enum RegistryAccess {
+    User(User),
+    /// A machine pulling with a token from `pull_token`.
+    PullToken(String),
+}
+
+/// How long a pull token reads its image for, long enough for Fly to pull
+/// (and retry pulling) a machine's image.
+const PULL_TOKEN_TTL_SECONDS: i64 = 6 * 60 * 60;
+
+fn pull_token_message(image_id: &Uuid, expires_at: i64) -> String {
+    format!("registry-pull:{image_id}:{expires_at}")
+}
+
+/// Fly can't send credentials along with a machine's image, so machines
+/// pull from `<registry host>/pull-<token>/<repository>` instead. A token
+/// can only read the image it was made for, until it expires.
+pub fn pull_token(encryptor: &Encryptor, image_id: &Uuid) -> String {
+    pull_token_expiring_at(
+        encryptor,
+        image_id,
+        Utc::now().timestamp() + PULL_TOKEN_TTL_SECONDS,
+    )
+}
+
+/// A pull token as `<expiry unix timestamp>-<signature>`.
+pub fn pull_token_expiring_at(encryptor: &Encryptor, image_id: &Uuid, expires_at: i64) -> String {
+    format!(
+        "{expires_at}-{}",
+        encryptor.sign(&pull_token_message(image_id, expires_at))
+    )
+}
+
+fn verify_pull_token(encryptor: &Encryptor, image_id: &Uuid, token: &str) -> bool {
+    match token
+        .split_once('-')
+        .and_then(|(expires_at, signature)| Some((expires_at.parse::<i64>().ok()?, signature)))
+    {
+        Some((expires_at, signature)) => {
+            expires_at > Utc::now().timestamp()
+                && encryptor.verify(&pull_token_message(image_id, expires_at), signature)
+        }
+        None => false,
+    }
+}
+
+/// Points a machine's image reference at the image's pull token when the
+/// image lives in the built-in registry.
+pub fn with_pull_token(encryptor: &Encryptor, image: &Image, reference: &str) -> String {
+    let host = registry_host();
+    match reference.strip_prefix(&format!("{host}/")) {
+        Some(rest) => format!("{host}/pull-{}/{rest}", pull_token(encryptor, &image.id)),
+        None => reference.to_string(),
+    }
+}
+
+/// Points a machine at the mirror of an image that needs credentials, pinned
+/// to the version's digest when it has one.
+pub fn mirror_reference(
+    encryptor: &Encryptor,
+    image: &Image,
+    image_version: &ImageVersion,
+) -> String {
+    let reference = if image_version.hash.is_empty() {
+        format!(":{}", image_version.version_number)
+    } else {
+        format!("@{}", image_version.hash)
+    };
+
+    format!(
+        "{}/pull-{}/{MIRROR_PREFIX}{}{reference}",
+        registry_host(),
+        pull_token(encryptor, &image.id),
+        image.id
+    )
+}
+
+/// Splits the pull token off a `/v2/pull-<token>/...` path, returning the
+/// path as it would be without it.
+pub fn split_pull_token(path: &str) -> (Option<String>, String) {
+    let token = path
+        .strip_prefix("/v2/pull-")
+        .and_then(|rest| rest.split_once('/'));
+
+    match token {
+        Some((token, rest)) => (Some(token.to_string()), format!("/v2/{rest}")),
+        None => (None, path.to_string()),
+    }
+}
+
+/// Loads the repository's image for reading, which only its owner and its
+/// pull token may do.
+pub async fn pull_image(
+    db: &impl GenericClient,
+    encryptor: &Encryptor,
+    access: &RegistryAccess,
+    repository: &str,
+) -> Result<Image, OciError> {
+    match (find_repository_image(db, repository).await?, access) {
+        (Some(image), RegistryAccess::User(user)) if image.user_id == user.id => Ok(image),
+        (Some(image), RegistryAccess::PullToken(token))
+            if verify_pull_token(encryptor, &image.id, token) =>
+        {
+            Ok(image)
+        }
+        _ => Err(OciError::NameUnknown),
+    }
+}
+
+/// Loads the repository's image for writing, creating it on the first push.
+/// Returns whether the image was created.
+pub async fn push_image(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    repository: &str,
+) -> Result<(Image, bool), OciError> {
+    if is_mirror_repository(repository) {
+        return Err(OciError::Denied);
+    }
+
+    match find_repository_image(db, repository).await? {
+        Some(image) if image.user_id == *user_id => Ok((image, false)),
+        Some(_) => Err(OciError::Denied),
+        None => {
+            let nickname = repository.rsplit('/').next().unwrap_or(repository);
+            let image = Image::create(
+                db,
+                *user_id,
+                nickname.to_string(),
+                repository_image_url(repository)?,
+            )
+            .await
+            .map_err(|e| OciError::Internal(e.into()))?;
+
+            Ok((image, true))
+        }
+    }
+}
+
+pub async fn blob_size(
+    db: &impl GenericClient,
+    image: &Image,
+    digest: &str,
+) -> Result<u64, OciError> {
+    let row = db
+        .query_opt(
+            "SELECT size FROM registry_blobs WHERE image_id = $1 AND digest = $2",
+            &[&image.id, &digest],
+        )
+        .await?
+        .ok_or(OciError::BlobUnknown)?;
+
+    Ok(row.get::<_, i64>("size") as u64)
+}
+
+/// Where a client gets a blob's content from.
+pub enum BlobContent {
+    /// A presigned URL to send the client to, so the bytes don't pass
+    /// through us.
+    Redirect(String),
+    Stream(BlobReader),
+}
+
+pub async fn read_blob(
+    db: &impl GenericClient,
+    store: &BlobStore,
+    image: &Image,
+    digest: &str,
+) -> Result<(u64, BlobContent), OciError> {
+    let size = blob_size(db, image, digest).await?;
+    let key = blob_key(digest);
+
+    if let Some(url) = store.download_url(&key, BLOB_URL_EXPIRY_SECONDS) {
+        return Ok((size, BlobContent::Redirect(url)));
+    }
+
+    match store.reader(&key).await? {
+        Some(reader) => Ok((size, BlobContent::Stream(reader))),
+        None => Err(OciError::BlobUnknown),
+    }
+}
+
+/// Writes a request body to `writer` as it arrives, hashing it along the way
+/// when asked to.
+async fn write_body(
+    writer: &mut BlobWriter,
+    body: &mut hyper::Body,
+    mut hasher: Option<&mut Sha256>,
+) -> Result<(), OciError> {
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| OciError::Internal(Box::new(e)))?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&chunk);
+        }
+        writer.write(&chunk).await?;
+    }
+
+    Ok(())
+}
+
+/// Hashes an object without holding it in memory.
+async fn stored_digest(store: &BlobStore, key: &str) -> Result<String, OciError> {
+    let mut reader = store
+        .reader(key)
+        .await?
+        .ok_or(OciError::BlobUploadUnknown)?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = reader.chunk().await? {
+        hasher.update(&chunk);
+    }
+
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+/// Moves a finished upload at `key` into content addressable storage if
+/// its content hashes to `digest`, otherwise throws it away.
+async fn store_blob(
+    db: &impl GenericClient,
+    store: &BlobStore,
+    image: &Image,
+    digest: &str,
+    key: &str,
+    actual_digest: &str,
+    size: u64,
+) -> Result<(), OciError> {
+    if !is_valid_digest(digest) || actual_digest != digest {
+        store.delete(key).await?;
+        return Err(OciError::DigestInvalid);
+    }
+
+    store.rename(key, &blob_key(digest)).await?;
+    db.execute(
+        "INSERT INTO registry_blobs (image_id, digest, size) VALUES ($1, $2, $3)
+          ON CONFLICT (image_id, digest) DO NOTHING",
+        &[&image.id, &digest, &(size as i64)],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn start_upload(store: &BlobStore, image: &Image) -> Result<Uuid, OciError> {
+    let upload_id = Uuid::new_v4();
+    store
+        .writer(&upload_key(&image.id, &upload_id))
+        .await?
+        .save()
+        .await?;
+
+    Ok(upload_id)
+}
+
+async fn resume_upload(
+    store: &BlobStore,
+    image: &Image,
+    upload_id: &Uuid,
+) -> Result<BlobWriter, OciError> {
+    store
+        .resume(&upload_key(&image.id, upload_id))
+        .await?
+        .ok_or(OciError::BlobUploadUnknown)
+}
+
+/// Appends a chunk to an upload as it streams in, returning the upload's
+/// size so far.
+pub async fn append_upload(
+    store: &BlobStore,
+    image: &Image,
+    upload_id: &Uuid,
+    mut chunk: hyper::Body,
+) -> Result<u64, OciError> {
+    let mut writer = resume_upload(store, image, upload_id).await?;
+    // Whatever made it in is kept, clients pick up from the returned range.
+    let written = write_body(&mut writer, &mut chunk, None).await;
+    let size = writer.save().await?;
+    written?;
+
+    Ok(size)
+}
+
+pub async fn upload_size(
+    store: &BlobStore,
+    image: &Image,
+    upload_id: &Uuid,
+) -> Result<u64, OciError> {
+    store
+        .saved_size(&upload_key(&image.id, upload_id))
+        .await?
+        .ok_or(OciError::BlobUploadUnknown)
+}
+
+pub async fn cancel_upload(
+    store: &BlobStore,
+    image: &Image,
+    upload_id: &Uuid,
+) -> Result<(), OciError> {
+    let writer = resume_upload(store, image, upload_id).await?;
+
+    Ok(writer.abort(store).await?)
+}
+
+/// Moves a finished upload into content addressable storage once its digest
+/// checks out. The upload came in over several requests, so it's hashed
+/// again from storage.
+pub async fn finish_upload(
+    db: &impl GenericClient,
+    store: &BlobStore,
+    image: &Image,
+    upload_id: &Uuid,
+    digest: &str,
+    mut last_chunk: hyper::Body,
+) -> Result<(), OciError> {
+    let key = upload_key(&image.id, upload_id);
+    let mut writer = resume_upload(store, image, upload_id).await?;
+    if let Err(e) = write_body(&mut writer, &mut last_chunk, None).await {
+        writer.save().await?;
+        return Err(e);
+    }
+    let size = writer.finish().await?;
+    let actual_digest = stored_digest(store, &key).await?;
+
+    store_blob(db, store, image, digest, &key, &actual_digest, size).await
+}
+
+/// Stores a blob sent in a single request, hashing it as it streams in.
+pub async fn upload_monolithic(
+    db: &impl GenericClient,
+    store: &BlobStore,
+    image: &Image,
+    digest: &str,
+    mut body: hyper::Body,
+) -> Result<(), OciError> {
+    let key = upload_key(&image.id, &Uuid::new_v4());
+    let mut writer = store.writer(&key).await?;
+    let mut hasher = Sha256::new();
+    if let Err(e) = write_body(&mut writer, &mut body, Some(&mut hasher)).await {
+        writer.abort(store).await?;
+        return Err(e);
+    }
+    let size = writer.finish().await?;
+    let actual_digest = format!("sha256:{:x}", hasher.finalize());
+
+    store_blob(db, store, image, digest, &key, &actual_digest, size).await
+}
+
+pub struct StoredManifest {
+    pub digest: String,
+    pub media_type: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Finds a manifest by tag or digest.
+pub async fn read_manifest(
+    db: &impl GenericClient,
+    store: &BlobStore,
+    image: &Image,
+    reference: &str,
+) -> Result<StoredManifest, OciError> {
+    let digest = if is_valid_digest(reference) {
+        reference.to_string()
+    } else {
+        ImageVersion::read_where_image_id(db, &image.id)
+            .await
+            .map_err(|e| OciError::Internal(e.into()))?
+            .into_iter()
+            .find(|v| v.version_number == reference)
+            .map(|v| v.hash)
+            .ok_or(OciError::ManifestUnknown)?
+    };
+
+    let row = db
+        .query_opt(
+            "SELECT media_type FROM registry_manifests WHERE image_id = $1 AND digest = $2",
+            &[&image.id, &digest],
+        )
+        .await?
+        .ok_or(OciError::ManifestUnknown)?;
+
+    let bytes = store
+        .get(&blob_key(&digest))
+        .await?
+        .ok_or(OciError::ManifestUnknown)?;
+
+    Ok(StoredManifest {
+        digest,
+        media_type: row.get("media_type"),
+        bytes,
+    })
+}
+
+/// Where a mirrored image really lives, with its owner's credentials for it.
+async fn mirror_upstream(
+    db: &impl GenericClient,
+    encryptor: &Encryptor,
+    image: &Image,
+) -> Result<(Reference, Option<RegistryAuth>), OciError> {
+    let reference =
+        Reference::parse(&image.image_url).map_err(|e| OciError::Internal(Box::new(e)))?;
+    let auth = find_registry_auth(
+        db,
+        encryptor,
+        &image.user_id,
+        Some(&image.id),
+        &reference.registry,
+    )
+    .await
+    .map_err(OciError::Internal)?;
+
+    Ok((reference, auth))
+}
+
+/// Reads a mirrored image's manifest from its own registry.
+pub async fn read_mirrored_manifest(
+    db: &impl GenericClient,
+    encryptor: &Encryptor,
+    registry: &RegistryClient,
+    image: &Image,
+    reference: &str,
+) -> Result<StoredManifest, OciError> {
+    let (upstream, auth) = mirror_upstream(db, encryptor, image).await?;
+    let manifest = registry
+        .fetch_manifest(
+            upstream.api_host(),
+            &upstream.repository,
+            reference,
+            auth.as_ref(),
+        )
+        .await
+        .map_err(|e| match e {
+            RegistryError::NotFound => OciError::ManifestUnknown,
+            e => OciError::Internal(Box::new(e)),
+        })?;
+
+    Ok(StoredManifest {
+        digest: manifest.digest,
+        media_type: manifest.media_type,
+        bytes: manifest.bytes,
+    })
+}
+
+/// Starts reading a mirrored image's blob from its own registry, returning
+/// its size when the registry sent one.
+pub async fn read_mirrored_blob(
+    db: &impl GenericClient,
+    encryptor: &Encryptor,
+    registry: &RegistryClient,
+    image: &Image,
+    digest: &str,
+) -> Result<(Option<u64>, BlobReader), OciError> {
+    if !is_valid_digest(digest) {
+        return Err(OciError::DigestInvalid);
+    }
+
+    let (upstream, auth) = mirror_upstream(db, encryptor, image).await?;
+    let response = registry
+        .fetch_blob(
+            upstream.api_host(),
+            &upstream.repository,
+            digest,
+            auth.as_ref(),
+        )
+        .await
+        .map_err(|e| match e {
+            RegistryError::NotFound => OciError::BlobUnknown,
+            e => OciError::Internal(Box::new(e)),
+        })?;
+
+    Ok((response.content_length(), BlobReader::Upstream(response)))
+}
+
+#[derive(Deserialize)]
+struct Descriptor {
+    digest: String,
+}
+
+#[derive(Deserialize)]
+struct ManifestReferences {
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+}
+
+/// Stores a manifest once everything it references has been pushed, and
+/// pins `reference` to it when it's a tag. Returns the manifest digest and
+/// the version created by the push, if any.
+pub async fn put_manifest(
+    db: &impl GenericClient,
+    store: &BlobStore,
+    image: &Image,
+    reference: &str,
+    media_type: &str,
+    bytes: Vec<u8>,
+) -> Result<(String, Option<ImageVersion>), OciError> {
+    let digest = sha256_digest(&bytes);
+    if is_valid_digest(reference) {
+        if reference != digest {
+            return Err(OciError::DigestInvalid);
+        }
+    } else if !is_valid_tag(reference) {
+        return Err(OciError::ManifestInvalid);
+    }
+
+    let references: ManifestReferences =
+        serde_json::from_slice(&bytes).map_err(|_| OciError::ManifestInvalid)?;
+    for blob in references.config.iter().chain(references.layers.iter()) {
+        if blob_size(db, image, &blob.digest).await.is_err() {
+            return Err(OciError::ManifestBlobUnknown(blob.digest.clone()));
+        }
+    }
+    for manifest in &references.manifests {
+        let exists = db
+            .query_opt(
+                "SELECT 1 FROM registry_manifests WHERE image_id = $1 AND digest = $2",
+                &[&image.id, &manifest.digest],
+            )
+            .await?
+            .is_some();
+        if !exists {
+            return Err(OciError::ManifestBlobUnknown(manifest.digest.clone()));
+        }
+    }
+
+    let size = bytes.len() as i64;
+    store.put(&blob_key(&digest), bytes).await?;
+    db.execute(
+        "INSERT INTO registry_manifests (image_id, digest, media_type, size) VALUES ($1, $2, $3, $4)
+          ON CONFLICT (image_id, digest) DO UPDATE SET media_type = EXCLUDED.media_type",
+        &[&image.id, &digest, &media_type, &size],
+    )
+    .await?;
+
+    let version = if is_valid_digest(reference) {
+        None
+    } else {
+        pin_tag(db, &image.id, reference, &digest)
+            .await
+            .map_err(|e| match e.downcast::<TagMovedError>() {
+                Ok(e) => OciError::TagImmutable(e.0),
+                Err(e) => OciError::Internal(e),
+            })?
+    };
+
+    Ok((digest, version))
+}
+
+pub async fn list_tags(db: &impl GenericClient, image: &Image) -> Result<Vec<String>, OciError> {
+    let mut tags = ImageVersion::read_where_image_id(db, &image.id)
+        .await
+        .map_err(|e| OciError::Internal(e.into()))?
+        .into_iter()
+        .map(|v| v.version_number)
+        .collect::<Vec<_>>();
+    tags.sort();
+
+    Ok(tags)
+}
@@ -0,0 +1,28 @@
+use deadpool_postgres::GenericClient;
+use uuid::Uuid;
+
+use crate::models::{Organization, OrganizationMember, OrganizationRole};
+
+/// Every user is the admin of a personal organization created alongside them.
+pub async fn create_personal_organization(
+    db: &impl GenericClient,
+    user_id: Uuid,
+    name: String,
+) -> Result<Organization, Box<dyn std::error::Error>> {
+    let organization = Organization::create(db, name).await?;
+    OrganizationMember::create(db, organization.id, user_id, OrganizationRole::Admin).await?;
+
+    Ok(organization)
+}
+
+pub async fn administered_organization_ids(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+) -> Result<Vec<Uuid>, Box<dyn std::error::Error>> {
+    Ok(OrganizationMember::read_where_user_id(db, user_id)
+        .await?
+        .into_iter()
+        .filter(|m| m.role == OrganizationRole::Admin)
+        .map(|m| m.organization_id)
+        .collect())
+}
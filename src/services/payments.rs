@@ -0,0 +1,422 @@
+use std::{future::Future, pin::Pin};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+    models::Invoice,
+    services::{billing::InvoiceLine, crypto::decode_hex},
+};
+
+pub const STRIPE_SIGNATURE_HEADER: &str = "Stripe-Signature";
+/// How old a signed webhook may be before it's treated as a replay.
+const SIGNATURE_TOLERANCE_SECONDS: i64 = 5 * 60;
+/// The metadata key invoices at the provider point back at ours with.
+const INVOICE_METADATA_KEY: &str = "invoice_id";
+
+#[derive(Debug)]
+pub enum PaymentError {
+    UnexpectedStatus(StatusCode, String),
+    Http(reqwest::Error),
+    InvalidSignature,
+    MalformedPayload,
+}
+
+impl std::fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaymentError::UnexpectedStatus(status, body) => {
+                write!(f, "payment provider responded with {status}: {body}")
+            }
+            PaymentError::Http(e) => write!(f, "could not reach payment provider: {e}"),
+            PaymentError::InvalidSignature => write!(f, "signature does not match the payload"),
+            PaymentError::MalformedPayload => write!(f, "payload is not a payment event"),
+        }
+    }
+}
+
+impl std::error::Error for PaymentError {}
+
+impl From<reqwest::Error> for PaymentError {
+    fn from(e: reqwest::Error) -> Self {
+        PaymentError::Http(e)
+    }
+}
+
+/// What the payment provider tells us happened to one of our invoices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaymentEvent {
+    InvoicePaid(Uuid),
+    InvoiceFailed(Uuid),
+}
+
+/// What a provider's calls return, boxed so providers can be used as trait
+/// objects.
+pub type PaymentFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, PaymentError>> + Send + 'a>>;
+
+/// Where invoices are sent to be collected. The server holds one as a trait
+/// object, so another provider or a fake in tests can stand in for Stripe.
+pub trait PaymentProvider: Send + Sync {
+    /// Creates a customer for `user_id`, returns the provider's id for it.
+    fn create_customer<'a>(
+        &'a self,
+        user_id: &'a Uuid,
+        email: &'a str,
+    ) -> PaymentFuture<'a, String>;
+    /// Bills `customer_id` for `invoice`, returns the provider's id for it.
+    /// Sending the same invoice again doesn't bill it twice.
+    fn create_invoice<'a>(
+        &'a self,
+        customer_id: &'a str,
+        invoice: &'a Invoice,
+        lines: &'a [InvoiceLine],
+    ) -> PaymentFuture<'a, String>;
+    /// Checks a webhook came from the provider and reads the event in it,
+    /// events that aren't about invoices being paid are ignored.
+    fn parse_webhook(
+        &self,
+        payload: &[u8],
+        signature: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<PaymentEvent>, PaymentError>;
+}
+
+/// A client for Stripe's API, or anything speaking it.
+#[derive(Clone)]
+pub struct StripeClient {
+    http: Client,
+    api_url: String,
+    api_key: String,
+    webhook_secret: String,
+}
+
+impl std::fmt::Debug for StripeClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StripeClient")
+            .field("api_url", &self.api_url)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize)]
+struct StripeObject {
+    id: String,
+}
+
+impl StripeClient {
+    pub fn new(api_url: &str, api_key: &str, webhook_secret: &str) -> Self {
+        StripeClient {
+            http: Client::new(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            webhook_secret: webhook_secret.to_string(),
+        }
+    }
+
+    /// Builds a client from `STRIPE_API_KEY` and `STRIPE_WEBHOOK_SECRET`, if
+    /// a key is set. `STRIPE_API_URL` points it at another implementation.
+    pub fn from_env() -> Option<Self> {
+        let api_key = std::env::var("STRIPE_API_KEY").ok()?;
+
+        Some(StripeClient::new(
+            &std::env::var("STRIPE_API_URL")
+                .unwrap_or_else(|_| "https://api.stripe.com".to_string()),
+            &api_key,
+            &std::env::var("STRIPE_WEBHOOK_SECRET").unwrap_or_default(),
+        ))
+    }
+
+    /// Posts a form to the API. Stripe replays the first response to requests
+    /// with the same idempotency key, so retries don't create duplicates.
+    async fn post(
+        &self,
+        path: &str,
+        idempotency_key: &str,
+        form: &[(&str, String)],
+    ) -> Result<StripeObject, PaymentError> {
+        let response = self
+            .http
+            .post(format!("{}{path}", self.api_url))
+            .bearer_auth(&self.api_key)
+            .header("Idempotency-Key", idempotency_key)
+            .form(form)
+            .send()
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(response.json().await?),
+            status => Err(PaymentError::UnexpectedStatus(
+                status,
+                response.text().await.unwrap_or_default(),
+            )),
+        }
+    }
+}
+
+impl PaymentProvider for StripeClient {
+    fn create_customer<'a>(
+        &'a self,
+        user_id: &'a Uuid,
+        email: &'a str,
+    ) -> PaymentFuture<'a, String> {
+        Box::pin(async move {
+            let customer = self
+                .post(
+                    "/v1/customers",
+                    &format!("customer-{user_id}"),
+                    &[
+                        ("email", email.to_string()),
+                        ("metadata[user_id]", user_id.to_string()),
+                    ],
+                )
+                .await?;
+
+            Ok(customer.id)
+        })
+    }
+
+    fn create_invoice<'a>(
+        &'a self,
+        customer_id: &'a str,
+        invoice: &'a Invoice,
+        lines: &'a [InvoiceLine],
+    ) -> PaymentFuture<'a, String> {
+        Box::pin(async move {
+            let created = self
+                .post(
+                    "/v1/invoices",
+                    &format!("invoice-{}", invoice.id),
+                    &[
+                        ("customer", customer_id.to_string()),
+                        ("currency", invoice.currency.clone()),
+                        ("collection_method", "charge_automatically".to_string()),
+                        ("pending_invoice_items_behavior", "exclude".to_string()),
+                        (
+                            &format!("metadata[{INVOICE_METADATA_KEY}]"),
+                            invoice.id.to_string(),
+                        ),
+                    ],
+                )
+                .await?;
+
+            for (index, line) in lines.iter().enumerate() {
+                self.post(
+                    "/v1/invoiceitems",
+                    &format!("invoice-{}-line-{index}", invoice.id),
+                    &[
+                        ("customer", customer_id.to_string()),
+                        ("invoice", created.id.clone()),
+                        ("currency", invoice.currency.clone()),
+                        ("amount", line.amount_cents.to_string()),
+                        ("description", line.description.clone()),
+                    ],
+                )
+                .await?;
+            }
+
+            self.post(
+                &format!("/v1/invoices/{}/finalize", created.id),
+                &format!("invoice-{}-finalize", invoice.id),
+                &[("auto_advance", "true".to_string())],
+            )
+            .await?;
+
+            Ok(created.id)
+        })
+    }
+
+    fn parse_webhook(
+        &self,
+        payload: &[u8],
+        signature: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<PaymentEvent>, PaymentError> {
+        verify_stripe_signature(&self.webhook_secret, payload, signature, now)?;
+
+        parse_stripe_event(payload)
+    }
+}
+
+/// Signs a payload the way Stripe does, `t=<timestamp>,v1=<hex>`.
+pub fn stripe_signature(secret: &str, payload: &[u8], timestamp: i64) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(payload);
+
+    format!("t={timestamp},v1={:x}", mac.finalize().into_bytes())
+}
+
+/// Checks a `Stripe-Signature` header against the payload in constant time,
+/// rejecting ones signed too long ago.
+pub fn verify_stripe_signature(
+    secret: &str,
+    payload: &[u8],
+    signature: &str,
+    now: DateTime<Utc>,
+) -> Result<(), PaymentError> {
+    let mut timestamp = None;
+    let mut signatures = vec![];
+    for part in signature.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signatures.extend(decode_hex(value)),
+            _ => {}
+        }
+    }
+    let timestamp = timestamp.ok_or(PaymentError::InvalidSignature)?;
+    if (now.timestamp() - timestamp).abs() > SIGNATURE_TOLERANCE_SECONDS {
+        return Err(PaymentError::InvalidSignature);
+    }
+
+    let verified = signatures.iter().any(|expected| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(payload);
+        mac.verify_slice(expected).is_ok()
+    });
+    if !verified {
+        return Err(PaymentError::InvalidSignature);
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct StripeEvent {
+    r#type: String,
+    data: StripeEventData,
+}
+
+#[derive(Deserialize)]
+struct StripeEventData {
+    object: StripeEventObject,
+}
+
+#[derive(Deserialize)]
+struct StripeEventObject {
+    #[serde(default)]
+    metadata: std::collections::HashMap<String, String>,
+}
+
+/// Reads the invoice events we act on out of a Stripe event.
+pub fn parse_stripe_event(payload: &[u8]) -> Result<Option<PaymentEvent>, PaymentError> {
+    let event: StripeEvent =
+        serde_json::from_slice(payload).map_err(|_| PaymentError::MalformedPayload)?;
+    let invoice_id = || {
+        event
+            .data
+            .object
+            .metadata
+            .get(INVOICE_METADATA_KEY)
+            .and_then(|id| id.parse::<Uuid>().ok())
+            .ok_or(PaymentError::MalformedPayload)
+    };
+
+    Ok(match event.r#type.as_str() {
+        "invoice.paid" => Some(PaymentEvent::InvoicePaid(invoice_id()?)),
+        "invoice.payment_failed" => Some(PaymentEvent::InvoiceFailed(invoice_id()?)),
+        _ => None,
+    })
+}
+
+/// Keeps what it's asked to bill instead of charging anyone.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakePaymentProvider {
+    pub customers: std::sync::Mutex<Vec<(Uuid, String)>>,
+    /// The customer, invoice and lines of every invoice sent.
+    pub invoices: std::sync::Mutex<Vec<(String, Uuid, Vec<InvoiceLine>)>>,
+}
+
+#[cfg(test)]
+impl PaymentProvider for FakePaymentProvider {
+    fn create_customer<'a>(
+        &'a self,
+        user_id: &'a Uuid,
+        email: &'a str,
+    ) -> PaymentFuture<'a, String> {
+        self.customers
+            .lock()
+            .unwrap()
+            .push((*user_id, email.to_string()));
+
+        Box::pin(std::future::ready(Ok(format!("cus_{user_id}"))))
+    }
+
+    fn create_invoice<'a>(
+        &'a self,
+        customer_id: &'a str,
+        invoice: &'a Invoice,
+        lines: &'a [InvoiceLine],
+    ) -> PaymentFuture<'a, String> {
+        self.invoices
+            .lock()
+            .unwrap()
+            .push((customer_id.to_string(), invoice.id, lines.to_vec()));
+
+        Box::pin(std::future::ready(Ok(format!("in_{}", invoice.id))))
+    }
+
+    fn parse_webhook(
+        &self,
+        payload: &[u8],
+        _signature: &str,
+        _now: DateTime<Utc>,
+    ) -> Result<Option<PaymentEvent>, PaymentError> {
+        parse_stripe_event(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_stripe_signature_should_accept_fresh_signatures_only() {
+        let payload = br#"{"type":"invoice.paid"}"#;
+        let now = Utc::now();
+        let signature = stripe_signature("whsec_test", payload, now.timestamp());
+
+        assert!(verify_stripe_signature("whsec_test", payload, &signature, now).is_ok());
+        assert!(verify_stripe_signature("whsec_other", payload, &signature, now).is_err());
+        assert!(verify_stripe_signature("whsec_test", b"{}", &signature, now).is_err());
+        assert!(verify_stripe_signature(
+            "whsec_test",
+            payload,
+            &signature,
+            now + chrono::Duration::minutes(10)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parse_stripe_event_should_read_invoice_events() {
+        let invoice_id = Uuid::new_v4();
+        let event = |kind: &str| {
+            serde_json::json!({
+                "type": kind,
+                "data": { "object": { "id": "in_1", "metadata": { "invoice_id": invoice_id } } },
+            })
+            .to_string()
+        };
+
+        assert_eq!(
+            parse_stripe_event(event("invoice.paid").as_bytes()).unwrap(),
+            Some(PaymentEvent::InvoicePaid(invoice_id))
+        );
+        assert_eq!(
+            parse_stripe_event(event("invoice.payment_failed").as_bytes()).unwrap(),
+            Some(PaymentEvent::InvoiceFailed(invoice_id))
+        );
+        assert_eq!(
+            parse_stripe_event(event("customer.created").as_bytes()).unwrap(),
+            None
+        );
+    }
+}
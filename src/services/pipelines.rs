@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::GenericClient;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    models::JobStatus,
+    services::{batches::release_batch, job_queue::queue_blocked_job},
+};
+
+/// The most jobs one pipeline may have.
+pub const MAX_PIPELINE_JOBS: usize = 100;
+
+/// Where a pipeline is, taken over all of its jobs.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum PipelineStatus {
+    /// Some jobs are still queued, running or waiting on others.
+    Running,
+    /// Every job completed.
+    Completed,
+    /// Nothing is left to run and some jobs didn't complete.
+    Failed,
+}
+
+impl PipelineStatus {
+    pub fn of(statuses: &[JobStatus]) -> PipelineStatus {
+        if statuses.iter().any(|status| {
+            matches!(
+                status,
+                JobStatus::Queued | JobStatus::Pending | JobStatus::Blocked
+            )
+        }) {
+            PipelineStatus::Running
+        } else if statuses
+            .iter()
+            .all(|status| matches!(status, JobStatus::Completed))
+        {
+            PipelineStatus::Completed
+        } else {
+            PipelineStatus::Failed
+        }
+    }
+}
+
+/// Orders a pipeline's jobs so each comes after the ones it depends on.
+/// `jobs` are pairs of a job's key and the keys it depends on, the result
+/// indexes into it.
+pub fn topological_order(jobs: &[(String, Vec<String>)]) -> Result<Vec<usize>, String> {
+    let mut indexes = HashMap::new();
+    for (index, (key, _)) in jobs.iter().enumerate() {
+        if key.is_empty() {
+            return Err("Pipeline jobs need a key".to_string());
+        }
+        if indexes.insert(key.as_str(), index).is_some() {
+            return Err(format!("Duplicate pipeline job key: {key}"));
+        }
+    }
+
+    let mut waiting_on = vec![0; jobs.len()];
+    let mut dependents = vec![vec![]; jobs.len()];
+    for (index, (key, depends_on)) in jobs.iter().enumerate() {
+        for parent in depends_on {
+            let Some(&parent_index) = indexes.get(parent.as_str()) else {
+                return Err(format!("{key} depends on unknown job {parent}"));
+            };
+            if parent_index == index {
+                return Err(format!("{key} depends on itself"));
+            }
+            waiting_on[index] += 1;
+            dependents[parent_index].push(index);
+        }
+    }
+
+    let mut order: Vec<usize> = (0..jobs.len()).filter(|&i| waiting_on[i] == 0).collect();
+    let mut next = 0;
+    while next < order.len() {
+        for &dependent in &dependents[order[next]] {
+            waiting_on[dependent] -= 1;
+            if waiting_on[dependent] == 0 {
+                order.push(dependent);
+            }
+        }
+        next += 1;
+    }
+
+    if order.len() != jobs.len() {
+        return Err("Pipeline jobs can't depend on each other in a cycle".to_string());
+    }
+
+    Ok(order)
+}
+
+pub async fn add_dependency(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    depends_on_job_id: &Uuid,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "INSERT INTO job_dependencies (job_id, depends_on_job_id) VALUES ($1, $2)",
+        &[job_id, depends_on_job_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Maps each of a pipeline's jobs to the jobs it depends on.
+pub async fn pipeline_dependencies(
+    db: &impl GenericClient,
+    pipeline_id: &Uuid,
+) -> Result<HashMap<Uuid, Vec<Uuid>>, tokio_postgres::Error> {
+    let rows = db
+        .query(
+            "SELECT d.job_id, d.depends_on_job_id FROM job_dependencies d
+             JOIN pipeline_jobs pj ON pj.job_id = d.job_id
+             WHERE pj.pipeline_id = $1",
+            &[pipeline_id],
+        )
+        .await?;
+
+    let mut dependencies: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+    for row in rows {
+        dependencies
+            .entry(row.get("job_id"))
+            .or_default()
+            .push(row.get("depends_on_job_id"));
+    }
+
+    Ok(dependencies)
+}
+
+/// Queues the blocked jobs waiting on `job_id` whose parents have all
+/// completed, as the user's quotas allow. Each is locked before its parents are looked at, so two
+/// parents finishing at once can't both miss the other's completion.
+async fn release_dependents(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<(), tokio_postgres::Error> {
+    let rows = db
+        .query(
+            "SELECT jobs.id FROM jobs
+             JOIN job_dependencies d ON d.job_id = jobs.id
+             WHERE d.depends_on_job_id = $1 AND jobs.status = $2
+             ORDER BY jobs.id
+             FOR UPDATE OF jobs",
+            &[job_id, &JobStatus::Blocked],
+        )
+        .await?;
+
+    for row in rows {
+        let dependent: Uuid = row.get("id");
+        let waiting = db
+            .query_one(
+                "SELECT EXISTS (
+                   SELECT 1 FROM job_dependencies d JOIN jobs parent ON parent.id = d.depends_on_job_id
+                    WHERE d.job_id = $1 AND parent.status <> $2
+                 )",
+                &[&dependent, &JobStatus::Completed],
+            )
+            .await?
+            .get::<_, bool>(0);
+        if waiting {
+            continue;
+        }
+
+        queue_blocked_job(db, &dependent, now).await?;
+    }
+
+    Ok(())
+}
+
+/// Cancels every blocked job downstream of `job_id`.
+async fn cancel_dependents(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "WITH RECURSIVE downstream (job_id) AS (
+           SELECT job_id FROM job_dependencies WHERE depends_on_job_id = $1
+           UNION
+           SELECT d.job_id FROM job_dependencies d
+             JOIN downstream ON d.depends_on_job_id = downstream.job_id
+         )
+         UPDATE jobs SET status = $2, updated_at = NOW()
+         WHERE id IN (SELECT job_id FROM downstream) AND status = $3",
+        &[job_id, &JobStatus::Cancelled, &JobStatus::Blocked],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Queues the user's blocked jobs that are only waiting on room in their
+/// quotas: the next ones in each of their batches, and pipeline jobs whose
+/// parents have all completed.
+async fn release_held(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<(), tokio_postgres::Error> {
+    let batches = db
+        .query(
+            "SELECT DISTINCT batch_id FROM jobs
+             WHERE user_id = $1 AND status = $2 AND batch_id IS NOT NULL",
+            &[user_id, &JobStatus::Blocked],
+        )
+        .await?;
+    for row in batches {
+        release_batch(db, &row.get("batch_id"), now).await?;
+    }
+
+    let rows = db
+        .query(
+            "SELECT jobs.id FROM jobs
+             WHERE jobs.user_id = $1 AND jobs.status = $2 AND jobs.batch_id IS NULL
+               AND NOT EXISTS (
+                 SELECT 1 FROM job_dependencies d JOIN jobs parent ON parent.id = d.depends_on_job_id
+                  WHERE d.job_id = jobs.id AND parent.status <> $3
+               )
+             ORDER BY jobs.created_at, jobs.id
+             FOR UPDATE OF jobs",
+            &[user_id, &JobStatus::Blocked, &JobStatus::Completed],
+        )
+        .await?;
+    for row in rows {
+        queue_blocked_job(db, &row.get("id"), now).await?;
+    }
+
+    Ok(())
+}
+
+/// Moves the jobs waiting on `job_id` along once it has stopped: its
+/// dependents are released once all their parents completed and cancelled
+/// if it didn't, and its batch gets the slot it held back. Jobs the user's
+/// quotas held back get the room it freed up.
+pub async fn job_finished(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<(), tokio_postgres::Error> {
+    let row = db
+        .query_one("SELECT status, user_id FROM jobs WHERE id = $1", &[job_id])
+        .await?;
+
+    match row.get::<_, JobStatus>("status") {
+        JobStatus::Completed => release_dependents(db, job_id, now).await?,
+        JobStatus::Failed | JobStatus::TimedOut | JobStatus::Cancelled => {
+            cancel_dependents(db, job_id).await?
+        }
+        JobStatus::Queued | JobStatus::Pending | JobStatus::Blocked => {}
+    }
+    // Covers the job's own batch.
+    release_held(db, &row.get("user_id"), now).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(key: &str, depends_on: &[&str]) -> (String, Vec<String>) {
+        (
+            key.to_string(),
+            depends_on.iter().map(|key| key.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn topological_order_should_put_parents_first() {
+        let jobs = vec![
+            job("evaluate", &["train"]),
+            job("train", &["preprocess"]),
+            job("preprocess", &[]),
+            job("report", &["evaluate", "preprocess"]),
+        ];
+
+        let order = topological_order(&jobs).unwrap();
+        let keys: Vec<&str> = order.iter().map(|&i| jobs[i].0.as_str()).collect();
+
+        assert_eq!(keys, vec!["preprocess", "train", "evaluate", "report"]);
+    }
+
+    #[test]
+    fn topological_order_should_reject_invalid_graphs() {
+        for jobs in [
+            vec![job("a", &[]), job("a", &[])],
+            vec![job("a", &["b"])],
+            vec![job("a", &["a"])],
+            vec![job("a", &["c"]), job("b", &["a"]), job("c", &["b"])],
+        ] {
+            assert!(topological_order(&jobs).is_err(), "{jobs:?}");
+        }
+    }
+
+    #[test]
+    fn pipeline_status_should_aggregate_job_statuses() {
+        assert_eq!(
+            PipelineStatus::of(&[JobStatus::Completed, JobStatus::Blocked]),
+            PipelineStatus::Running
+        );
+        assert_eq!(
+            PipelineStatus::of(&[JobStatus::Failed, JobStatus::Pending]),
+            PipelineStatus::Running
+        );
+        assert_eq!(
+            PipelineStatus::of(&[JobStatus::Completed, JobStatus::Completed]),
+            PipelineStatus::Completed
+        );
+        assert_eq!(
+            PipelineStatus::of(&[
+                JobStatus::Completed,
+                JobStatus::TimedOut,
+                JobStatus::Cancelled
+            ]),
+            PipelineStatus::Failed
+        );
+    }
+}
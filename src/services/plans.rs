@@ -0,0 +1,106 @@
+use deadpool_postgres::GenericClient;
+use serde::Serialize;
+use uuid::Uuid;
+
+/// The plan users are on until they pick another.
+pub const DEFAULT_PLAN: &str = "free";
+
+/// What a user's jobs are allowed, picked by `users.plan`.
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub name: &'static str,
+    /// How long a job may run when it doesn't ask for a limit.
+    pub default_max_runtime_seconds: i64,
+    /// The longest limit a job may ask for.
+    pub max_runtime_seconds: i64,
+    /// How many jobs may be queued or running at once.
+    pub max_concurrent_jobs: i64,
+    /// How many GPUs those jobs may use between them.
+    pub max_gpus: i64,
+    /// How many GPU-hours may be used each calendar month.
+    pub max_monthly_gpu_hours: f64,
+    /// Charged every month on top of the machines' time.
+    pub monthly_price_cents: i64,
+}
+
+impl Plan {
+    /// The runtime limit a job gets, given the one it asked for.
+    pub fn max_runtime(&self, requested: Option<i64>) -> Result<i64, String> {
+        match requested {
+            None => Ok(self.default_max_runtime_seconds),
+            Some(seconds) if seconds <= 0 => {
+                Err("max_runtime_seconds must be positive".to_string())
+            }
+            Some(seconds) if seconds > self.max_runtime_seconds => Err(format!(
+                "max_runtime_seconds can be at most {} on the {} plan",
+                self.max_runtime_seconds, self.name
+            )),
+            Some(seconds) => Ok(seconds),
+        }
+    }
+}
+
+pub const PLANS: &[Plan] = &[
+    Plan {
+        name: "free",
+        default_max_runtime_seconds: 60 * 60,
+        max_runtime_seconds: 4 * 60 * 60,
+        max_concurrent_jobs: 5,
+        max_gpus: 2,
+        max_monthly_gpu_hours: 20.0,
+        monthly_price_cents: 0,
+    },
+    Plan {
+        name: "pro",
+        default_max_runtime_seconds: 4 * 60 * 60,
+        max_runtime_seconds: 24 * 60 * 60,
+        max_concurrent_jobs: 20,
+        max_gpus: 8,
+        max_monthly_gpu_hours: 500.0,
+        monthly_price_cents: 4_900,
+    },
+    Plan {
+        name: "enterprise",
+        default_max_runtime_seconds: 24 * 60 * 60,
+        max_runtime_seconds: 7 * 24 * 60 * 60,
+        max_concurrent_jobs: 100,
+        max_gpus: 64,
+        max_monthly_gpu_hours: 10_000.0,
+        monthly_price_cents: 49_900,
+    },
+];
+
+pub fn find_plan(name: &str) -> Option<&'static Plan> {
+    PLANS.iter().find(|plan| plan.name == name)
+}
+
+/// The plan `user_id` is on, unknown plans are treated as the default one.
+pub async fn user_plan(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+) -> Result<&'static Plan, tokio_postgres::Error> {
+    let row = db
+        .query_one("SELECT plan FROM users WHERE id = $1", &[user_id])
+        .await?;
+
+    Ok(find_plan(row.get("plan")).unwrap_or_else(|| find_plan(DEFAULT_PLAN).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_runtime_should_default_and_cap_by_plan() {
+        let free = find_plan("free").unwrap();
+
+        assert_eq!(free.max_runtime(None), Ok(60 * 60));
+        assert_eq!(free.max_runtime(Some(600)), Ok(600));
+        assert!(free.max_runtime(Some(0)).is_err());
+        assert!(free.max_runtime(Some(24 * 60 * 60)).is_err());
+        assert_eq!(
+            find_plan("pro").unwrap().max_runtime(Some(24 * 60 * 60)),
+            Ok(24 * 60 * 60)
+        );
+    }
+}
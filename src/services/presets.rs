@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+
+/// The guest a machine is created with.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MachineResources {
+    pub cpu_kind: String,
+    pub cpus: i32,
+    pub memory_mb: i32,
+    pub gpu_kind: Option<String>,
+    pub gpus: i32,
+}
+
+/// A named machine size users can pick instead of spelling out a guest.
+#[derive(Debug, Serialize)]
+pub struct ResourcePreset {
+    pub name: &'static str,
+    pub cpu_kind: &'static str,
+    pub cpus: i32,
+    pub memory_mb: i32,
+    pub gpu_kind: Option<&'static str>,
+    pub gpus: i32,
+    /// What an hour of a machine of this size costs, per GPU for GPU
+    /// machines.
+    pub cents_per_hour: i64,
+}
+
+impl ResourcePreset {
+    pub fn resources(&self) -> MachineResources {
+        MachineResources {
+            cpu_kind: self.cpu_kind.to_string(),
+            cpus: self.cpus,
+            memory_mb: self.memory_mb,
+            gpu_kind: self.gpu_kind.map(|gpu_kind| gpu_kind.to_string()),
+            gpus: self.gpus,
+        }
+    }
+}
+
+pub const RESOURCE_PRESETS: &[ResourcePreset] = &[
+    ResourcePreset {
+        name: "cpu-small",
+        cpu_kind: "shared",
+        cpus: 2,
+        memory_mb: 2048,
+        gpu_kind: None,
+        gpus: 0,
+        cents_per_hour: 3,
+    },
+    ResourcePreset {
+        name: "cpu-large",
+        cpu_kind: "performance",
+        cpus: 8,
+        memory_mb: 16384,
+        gpu_kind: None,
+        gpus: 0,
+        cents_per_hour: 25,
+    },
+    ResourcePreset {
+        name: "a10",
+        cpu_kind: "performance",
+        cpus: 8,
+        memory_mb: 32768,
+        gpu_kind: Some("a10"),
+        gpus: 1,
+        cents_per_hour: 150,
+    },
+    ResourcePreset {
+        name: "l40s",
+        cpu_kind: "performance",
+        cpus: 8,
+        memory_mb: 32768,
+        gpu_kind: Some("l40s"),
+        gpus: 1,
+        cents_per_hour: 125,
+    },
+    ResourcePreset {
+        name: "a100-40gb",
+        cpu_kind: "performance",
+        cpus: 8,
+        memory_mb: 32768,
+        gpu_kind: Some("a100-pcie-40gb"),
+        gpus: 1,
+        cents_per_hour: 250,
+    },
+    ResourcePreset {
+        name: "a100-80gb",
+        cpu_kind: "performance",
+        cpus: 16,
+        memory_mb: 65536,
+        gpu_kind: Some("a100-sxm4-80gb"),
+        gpus: 1,
+        cents_per_hour: 350,
+    },
+];
+
+/// The guest jobs got before presets existed, used when a job only names its
+/// cpu and gpu kinds.
+const LEGACY_RESOURCES: MachineResources = MachineResources {
+    cpu_kind: String::new(),
+    cpus: 4,
+    memory_mb: 1024 * 16,
+    gpu_kind: None,
+    gpus: 0,
+};
+
+pub fn find_preset(name: &str) -> Option<&'static ResourcePreset> {
+    RESOURCE_PRESETS.iter().find(|preset| preset.name == name)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ResourceError {
+    UnknownPreset(String),
+    Missing,
+}
+
+impl std::fmt::Display for ResourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceError::UnknownPreset(name) => write!(f, "unknown resource preset {name}"),
+            ResourceError::Missing => {
+                write!(
+                    f,
+                    "a resource preset is required, either on the job or the image"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResourceError {}
+
+/// Picks a job's machine size: an explicit cpu/gpu kind overrides the
+/// preset, the job's preset overrides the image's default.
+pub fn resolve_resources(
+    preset: Option<&str>,
+    default_preset: Option<&str>,
+    cpu: Option<&str>,
+    gpu: Option<&str>,
+) -> Result<MachineResources, ResourceError> {
+    let base = match preset.or(default_preset) {
+        Some(name) => Some(
+            find_preset(name)
+                .ok_or_else(|| ResourceError::UnknownPreset(name.to_string()))?
+                .resources(),
+        ),
+        None => None,
+    };
+
+    let mut resources = match (base, cpu, gpu) {
+        (Some(resources), _, _) => resources,
+        (None, None, None) => return Err(ResourceError::Missing),
+        (None, _, _) => LEGACY_RESOURCES,
+    };
+    if let Some(cpu) = cpu {
+        resources.cpu_kind = cpu.to_string();
+    }
+    if let Some(gpu) = gpu {
+        resources.gpu_kind = Some(gpu.to_string());
+        resources.gpus = resources.gpus.max(1);
+    }
+    if resources.cpu_kind.is_empty() {
+        resources.cpu_kind = "performance".to_string();
+    }
+
+    Ok(resources)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_resources_should_prefer_the_job_preset() {
+        let resources = resolve_resources(Some("a10"), Some("cpu-small"), None, None).unwrap();
+
+        assert_eq!(resources, find_preset("a10").unwrap().resources());
+    }
+
+    #[test]
+    fn resolve_resources_should_fall_back_to_the_image_preset() {
+        let resources = resolve_resources(None, Some("cpu-small"), None, None).unwrap();
+
+        assert_eq!(resources, find_preset("cpu-small").unwrap().resources());
+    }
+
+    #[test]
+    fn resolve_resources_should_apply_explicit_kinds() {
+        let resources =
+            resolve_resources(None, None, Some("performance"), Some("a100-pcie-40gb")).unwrap();
+
+        assert_eq!(resources.cpus, 4);
+        assert_eq!(resources.memory_mb, 16384);
+        assert_eq!(resources.gpu_kind.as_deref(), Some("a100-pcie-40gb"));
+        assert_eq!(resources.gpus, 1);
+    }
+
+    #[test]
+    fn resolve_resources_should_reject_unknown_or_missing_presets() {
+        assert_eq!(
+            resolve_resources(Some("tpu"), None, None, None),
+            Err(ResourceError::UnknownPreset("tpu".to_string()))
+        );
+        assert_eq!(
+            resolve_resources(None, None, None, None),
+            Err(ResourceError::Missing)
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use std::fmt;
+
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use deadpool_postgres::GenericClient;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::{models::JobStatus, services::plans::user_plan};
+
+/// Limits on how much compute may be used at once and each month, unset
+/// limits don't apply.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct QuotaLimits {
+    pub max_concurrent_jobs: Option<i64>,
+    pub max_gpus: Option<i64>,
+    pub max_monthly_gpu_hours: Option<f64>,
+}
+
+impl QuotaLimits {
+    fn from_row(row: &Row) -> QuotaLimits {
+        QuotaLimits {
+            max_concurrent_jobs: row.get("max_concurrent_jobs"),
+            max_gpus: row.get("max_gpus"),
+            max_monthly_gpu_hours: row.get("max_monthly_gpu_hours"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct QuotaUsage {
+    /// Jobs queued or running.
+    pub concurrent_jobs: i64,
+    /// GPUs those jobs use between them.
+    pub gpus: i64,
+    /// GPU-hours used since the start of the month.
+    pub monthly_gpu_hours: f64,
+}
+
+/// The limits a user's jobs count against, their own or ones shared with an
+/// organization's other members.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct Quota {
+    /// Unset for the user's own quota.
+    pub organization_id: Option<Uuid>,
+    pub limits: QuotaLimits,
+    pub usage: QuotaUsage,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QuotaError {
+    /// Over a limit that frees up as running jobs finish.
+    Busy(String),
+    /// Over a limit that won't free up this month, if ever.
+    Exhausted(String),
+}
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaError::Busy(message) | QuotaError::Exhausted(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for QuotaError {}
+
+impl Quota {
+    /// Checks whether `jobs` more jobs using `gpus` GPUs between them fit.
+    pub fn check(&self, jobs: i64, gpus: i64) -> Result<(), QuotaError> {
+        let scope = match self.organization_id {
+            Some(organization_id) => format!("organization {organization_id}"),
+            None => "your account".to_string(),
+        };
+
+        if let Some(max) = self.limits.max_concurrent_jobs {
+            if jobs > max {
+                return Err(QuotaError::Exhausted(format!(
+                    "At most {max} jobs can run at once for {scope}"
+                )));
+            }
+            if self.usage.concurrent_jobs + jobs > max {
+                return Err(QuotaError::Busy(format!(
+                    "At most {max} jobs can be queued or running at once for {scope}, {} are",
+                    self.usage.concurrent_jobs
+                )));
+            }
+        }
+        if gpus == 0 {
+            return Ok(());
+        }
+        if let Some(max) = self.limits.max_monthly_gpu_hours {
+            if self.usage.monthly_gpu_hours >= max {
+                return Err(QuotaError::Exhausted(format!(
+                    "The monthly quota of {max} GPU-hours for {scope} is used up"
+                )));
+            }
+        }
+        if let Some(max) = self.limits.max_gpus {
+            if gpus > max {
+                return Err(QuotaError::Exhausted(format!(
+                    "At most {max} GPUs can be used at once for {scope}"
+                )));
+            }
+            if self.usage.gpus + gpus > max {
+                return Err(QuotaError::Busy(format!(
+                    "At most {max} GPUs can be in use at once for {scope}, {} are",
+                    self.usage.gpus
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// When the calendar month `now` is in started, monthly quotas reset then.
+pub fn month_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+        .unwrap()
+}
+
+async fn quota_usage(
+    db: &impl GenericClient,
+    user_ids: &[Uuid],
+    now: DateTime<Utc>,
+) -> Result<QuotaUsage, tokio_postgres::Error> {
+    let active = db
+        .query_one(
+            "SELECT COUNT(*) AS jobs, COALESCE(SUM(gpus), 0) AS gpus FROM jobs
+             WHERE user_id = ANY($1) AND status = ANY($2)",
+            &[&user_ids, &JobStatus::active()],
+        )
+        .await?;
+    // Attempts still running count up to now, ones started last month from
+    // the start of this one.
+    let monthly_gpu_hours: f64 = db
+        .query_one(
+            "SELECT COALESCE(SUM(
+               EXTRACT(EPOCH FROM COALESCE(a.finished_at, $3) - GREATEST(a.created_at, $2)) * j.gpus
+             ), 0)::DOUBLE PRECISION / 3600
+             FROM attempts a JOIN jobs j ON j.id = a.job_id
+             WHERE j.user_id = ANY($1) AND j.gpus > 0 AND COALESCE(a.finished_at, $3) > $2",
+            &[&user_ids, &month_start(now), &now],
+        )
+        .await?
+        .get(0);
+
+    Ok(QuotaUsage {
+        concurrent_jobs: active.get("jobs"),
+        gpus: active.get("gpus"),
+        monthly_gpu_hours,
+    })
+}
+
+/// Every quota `user_id`'s jobs count against: their own, from their plan
+/// unless overridden, and those of organizations they're a member of.
+pub async fn user_quotas(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<Vec<Quota>, tokio_postgres::Error> {
+    let plan = user_plan(db, user_id).await?;
+    let overrides = db
+        .query_opt("SELECT * FROM quotas WHERE user_id = $1", &[user_id])
+        .await?
+        .map(|row| QuotaLimits::from_row(&row))
+        .unwrap_or_default();
+
+    let mut quotas = vec![Quota {
+        organization_id: None,
+        limits: QuotaLimits {
+            max_concurrent_jobs: overrides
+                .max_concurrent_jobs
+                .or(Some(plan.max_concurrent_jobs)),
+            max_gpus: overrides.max_gpus.or(Some(plan.max_gpus)),
+            max_monthly_gpu_hours: overrides
+                .max_monthly_gpu_hours
+                .or(Some(plan.max_monthly_gpu_hours)),
+        },
+        usage: quota_usage(db, &[*user_id], now).await?,
+    }];
+
+    let rows = db
+        .query(
+            "SELECT q.* FROM quotas q
+             JOIN organization_members m ON m.organization_id = q.organization_id
+             WHERE m.user_id = $1
+             ORDER BY q.created_at",
+            &[user_id],
+        )
+        .await?;
+    for row in rows {
+        let organization_id: Uuid = row.get("organization_id");
+        let members: Vec<Uuid> = db
+            .query(
+                "SELECT user_id FROM organization_members WHERE organization_id = $1",
+                &[&organization_id],
+            )
+            .await?
+            .iter()
+            .map(|member| member.get("user_id"))
+            .collect();
+        quotas.push(Quota {
+            organization_id: Some(organization_id),
+            limits: QuotaLimits::from_row(&row),
+            usage: quota_usage(db, &members, now).await?,
+        });
+    }
+
+    Ok(quotas)
+}
+
+/// Locks the user and their organizations' quotas, so jobs checked against
+/// them at once can't both take the last slot.
+async fn lock_quotas(db: &impl GenericClient, user_id: &Uuid) -> Result<(), tokio_postgres::Error> {
+    db.execute("SELECT 1 FROM users WHERE id = $1 FOR UPDATE", &[user_id])
+        .await?;
+    db.execute(
+        "SELECT 1 FROM quotas q
+         JOIN organization_members m ON m.organization_id = q.organization_id
+         WHERE m.user_id = $1
+         FOR UPDATE OF q",
+        &[user_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Checks that `jobs` more jobs using `gpus` GPUs between them fit in every
+/// quota `user_id` is under.
+pub async fn check_quota(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    jobs: i64,
+    gpus: i64,
+    now: DateTime<Utc>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    lock_quotas(db, user_id).await?;
+
+    for quota in user_quotas(db, user_id, now).await? {
+        quota.check(jobs, gpus)?;
+    }
+
+    Ok(())
+}
+
+/// Whether one more job using `gpus` GPUs fits in every quota `user_id` is
+/// under, for blocked jobs that are ready to be queued.
+pub async fn fits_quota(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    gpus: i64,
+    now: DateTime<Utc>,
+) -> Result<bool, tokio_postgres::Error> {
+    lock_quotas(db, user_id).await?;
+
+    Ok(user_quotas(db, user_id, now)
+        .await?
+        .iter()
+        .all(|quota| quota.check(1, gpus).is_ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(usage: QuotaUsage) -> Quota {
+        Quota {
+            organization_id: None,
+            limits: QuotaLimits {
+                max_concurrent_jobs: Some(3),
+                max_gpus: Some(2),
+                max_monthly_gpu_hours: Some(10.0),
+            },
+            usage,
+        }
+    }
+
+    #[test]
+    fn check_should_tell_busy_from_exhausted_quotas() {
+        let idle = quota(QuotaUsage::default());
+        assert_eq!(idle.check(1, 2), Ok(()));
+        assert!(matches!(idle.check(4, 0), Err(QuotaError::Exhausted(_))));
+        assert!(matches!(idle.check(1, 3), Err(QuotaError::Exhausted(_))));
+
+        let busy = quota(QuotaUsage {
+            concurrent_jobs: 2,
+            gpus: 2,
+            monthly_gpu_hours: 4.5,
+        });
+        assert_eq!(busy.check(1, 0), Ok(()));
+        assert!(matches!(busy.check(2, 0), Err(QuotaError::Busy(_))));
+        assert!(matches!(busy.check(1, 1), Err(QuotaError::Busy(_))));
+
+        let spent = quota(QuotaUsage {
+            monthly_gpu_hours: 10.0,
+            ..Default::default()
+        });
+        assert_eq!(spent.check(1, 0), Ok(()));
+        assert!(matches!(spent.check(1, 1), Err(QuotaError::Exhausted(_))));
+    }
+
+    #[test]
+    fn month_start_should_truncate_to_the_first() {
+        let now = Utc.with_ymd_and_hms(2024, 11, 17, 13, 45, 2).unwrap();
+
+        assert_eq!(
+            month_start(now),
+            Utc.with_ymd_and_hms(2024, 11, 1, 0, 0, 0).unwrap()
+        );
+    }
+}
@@ -0,0 +1,507 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{GenericClient, Pool};
+use fly::apis::configuration::Configuration as FlyClient;
+use uuid::Uuid;
+
+use crate::{
+    models::{Attempt, AttemptStatus, FailureClass, JobStatus},
+    services::{
+        clock::{Clock, SystemClock},
+        compute::{ComputeBackend, MachineExit},
+        jobs::{complete_attempt, fail_attempt, AttemptFailure},
+        usage::record_machine_times,
+    },
+};
+
+/// How often running jobs' machines are checked on.
+pub const RECONCILE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Attempts whose machine is running.
+async fn running_attempts(db: &impl GenericClient) -> Result<Vec<Uuid>, tokio_postgres::Error> {
+    let rows = db
+        .query(
+            "SELECT attempts.id FROM attempts
+             JOIN jobs ON jobs.id = attempts.job_id
+             WHERE attempts.status = $1
+               AND attempts.machine_id IS NOT NULL
+               AND jobs.status = ANY($2)",
+            &[&AttemptStatus::Running, &JobStatus::active()],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get("id")).collect())
+}
+
+/// How long an attempt is leased to the instance reconciling it. One that
+/// dies part way through leaves the attempt to others once the lease lapses.
+pub const RECONCILE_LEASE_SECONDS: i64 = 120;
+
+/// A running attempt leased to this instance, with the user whose app its
+/// machine is in.
+struct ClaimedAttempt {
+    attempt: Attempt,
+    user_id: Uuid,
+    /// When the lease lapses, as stored, so the instance can tell whether it
+    /// still holds the attempt.
+    locked_until: DateTime<Utc>,
+}
+
+/// Leases a running attempt if no other instance holds it. The lease is
+/// committed with the claim, so no transaction stays open while fly.io is
+/// called.
+async fn claim_attempt(
+    db: &impl GenericClient,
+    attempt_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<Option<ClaimedAttempt>, Box<dyn std::error::Error>> {
+    let locked_until = now + chrono::Duration::seconds(RECONCILE_LEASE_SECONDS);
+    let row = db
+        .query_opt(
+            "UPDATE attempts SET reconcile_locked_until = $4
+             FROM jobs
+             WHERE jobs.id = attempts.job_id
+               AND attempts.id = $1
+               AND attempts.status = $2
+               AND attempts.machine_id IS NOT NULL
+               AND jobs.status = ANY($3)
+               AND (attempts.reconcile_locked_until IS NULL
+                    OR attempts.reconcile_locked_until <= $5)
+             RETURNING attempts.id, jobs.user_id, attempts.reconcile_locked_until",
+            &[
+                attempt_id,
+                &AttemptStatus::Running,
+                &JobStatus::active(),
+                &locked_until,
+                &now,
+            ],
+        )
+        .await?;
+
+    match row {
+        Some(row) => Ok(Some(ClaimedAttempt {
+            attempt: Attempt::read(db, &row.get("id")).await?,
+            user_id: row.get("user_id"),
+            locked_until: row.get("reconcile_locked_until"),
+        })),
+        None => Ok(None),
+    }
+}
+
+/// Gives up the lease on an attempt whose machine is still running, so the
+/// next pass checks on it again.
+async fn release_attempt(
+    db: &impl GenericClient,
+    claimed: &ClaimedAttempt,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE attempts SET reconcile_locked_until = NULL
+         WHERE id = $1 AND reconcile_locked_until = $2",
+        &[&claimed.attempt.id, &claimed.locked_until],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Locks the attempt and its job until `db`'s transaction ends, if the lease
+/// is still held and neither finished in the meantime, e.g. by the job being
+/// stopped. Returns the attempt as it is now.
+async fn lock_claimed_attempt(
+    db: &impl GenericClient,
+    claimed: &ClaimedAttempt,
+) -> Result<Option<Attempt>, Box<dyn std::error::Error>> {
+    let row = db
+        .query_opt(
+            "SELECT attempts.id FROM attempts
+             JOIN jobs ON jobs.id = attempts.job_id
+             WHERE attempts.id = $1
+               AND attempts.reconcile_locked_until = $2
+               AND attempts.status = $3
+               AND jobs.status = ANY($4)
+             FOR UPDATE",
+            &[
+                &claimed.attempt.id,
+                &claimed.locked_until,
+                &AttemptStatus::Running,
+                &JobStatus::active(),
+            ],
+        )
+        .await?;
+
+    match row {
+        Some(row) => Ok(Some(Attempt::read(db, &row.get("id")).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Records how the attempt's machine exited, if it has, completing, failing
+/// or queueing a retry for its job. The attempt is leased, fly.io called with
+/// no transaction open, and the outcome written in a short transaction that
+/// first checks the lease is still held.
+async fn reconcile_attempt(
+    db: &Pool,
+    compute: &impl ComputeBackend,
+    attempt_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = db.get().await?;
+    let Some(claimed) = claim_attempt(&client, attempt_id, now).await? else {
+        return Ok(());
+    };
+    let Some(machine_id) = claimed.attempt.machine_id.clone() else {
+        return Ok(());
+    };
+    drop(client);
+    let app_id = claimed.user_id.to_string();
+
+    // Machines that can't be checked on are left for the next pass, like
+    // running ones.
+    let exit = match compute.machine_exit(&app_id, &machine_id).await {
+        Ok(exit) => exit,
+        Err(e) => {
+            tracing::warn!("Could not check on machine {machine_id}: {e}");
+            MachineExit::Running
+        }
+    };
+    let failure = match exit {
+        MachineExit::Running => {
+            release_attempt(&db.get().await?, &claimed).await?;
+            return Ok(());
+        }
+        MachineExit::Exited(0) => None,
+        MachineExit::Exited(code) => Some(AttemptFailure {
+            class: FailureClass::NonZeroExit,
+            exit_code: Some(code),
+            error: None,
+        }),
+        MachineExit::Missing | MachineExit::Unreported => Some(AttemptFailure {
+            class: FailureClass::Lost,
+            exit_code: None,
+            error: Some(format!("Machine {machine_id} disappeared")),
+        }),
+    };
+
+    // Only machines that are gone altogether have nothing left to clean up.
+    let mut times = None;
+    if exit != MachineExit::Missing {
+        match compute.machine_times(&app_id, &machine_id).await {
+            Ok(machine_times) => times = Some(machine_times),
+            Err(e) => tracing::warn!("Could not fetch events of machine {machine_id}: {e}"),
+        }
+        if let Err(e) = compute.destroy_machine(&app_id, &machine_id).await {
+            tracing::warn!("Could not destroy machine {machine_id}: {e}");
+        }
+    }
+
+    let mut client = db.get().await?;
+    let transaction = client.transaction().await?;
+    let Some(attempt) = lock_claimed_attempt(&transaction, &claimed).await? else {
+        return Ok(());
+    };
+    if let Some(times) = times {
+        record_machine_times(&transaction, &attempt.id, times).await?;
+    }
+    match failure {
+        None => complete_attempt(&transaction, &attempt, now).await?,
+        Some(failure) => {
+            fail_attempt(&transaction, &attempt, failure, now).await?;
+        }
+    }
+    release_attempt(&transaction, &claimed).await?;
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Records how running attempts' machines exited, completing, failing or
+/// queueing retries for their jobs. Each attempt is leased first, so
+/// instances reconciling at once never handle one twice, and one that can't
+/// be reconciled doesn't hold back the rest.
+pub async fn reconcile_jobs(
+    db: &Pool,
+    clock: &impl Clock,
+    compute: &impl ComputeBackend,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let now = clock.now();
+    let attempt_ids = running_attempts(&db.get().await?).await?;
+
+    for attempt_id in attempt_ids {
+        if let Err(e) = reconcile_attempt(db, compute, &attempt_id, now).await {
+            tracing::error!("An error occurred while reconciling attempt {attempt_id}: {e:#?}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the reconciler until the process exits.
+pub async fn run(db: Pool, fly: FlyClient) {
+    let mut interval = tokio::time::interval(RECONCILE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = reconcile_jobs(&db, &SystemClock, &fly).await {
+            tracing::error!("An error occurred while reconciling jobs: {e:#?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app::ServerConfig,
+        models::{Image, ImageVersion, Job, UsageRecord, User},
+        services::{
+            clock::FakeClock,
+            compute::{FakeCompute, MachineTimes},
+            job_queue::tests::{launcher, work_job},
+            jobs::{launch_job, JobInput, JobSpec},
+            retries::RetryPolicy,
+        },
+    };
+    use chrono::Utc;
+
+    /// Launches a job, has a worker start it and pretends its attempt got a
+    /// machine.
+    async fn launch_job_on_machine(
+        config: &ServerConfig,
+        retry: Option<RetryPolicy>,
+    ) -> (Job, String) {
+        let db = config.db.get().await.unwrap();
+        let user = User::create(
+            &db,
+            format!("{}@example.com", Uuid::new_v4()),
+            "hash".to_string(),
+        )
+        .await
+        .unwrap();
+        let image = Image::create(
+            &db,
+            user.id,
+            "trainer".to_string(),
+            "registry.example.com/trainer".to_string(),
+        )
+        .await
+        .unwrap();
+        let image_version =
+            ImageVersion::create(&db, image.id, "sha256:abc".to_string(), "v1".to_string())
+                .await
+                .unwrap();
+        let spec = JobSpec {
+            preset: Some("cpu-small".to_string()),
+            retry,
+            ..Default::default()
+        };
+
+        let job = launch_job(
+            &db,
+            &user.id,
+            &image,
+            &image_version,
+            &spec,
+            &JobInput::default(),
+        )
+        .await
+        .unwrap();
+        assert!(work_job(&config.db, &launcher(config), &job.id, Utc::now()).await);
+        let machine_id = assign_machine(&db, &job.id).await;
+
+        (job, machine_id)
+    }
+
+    async fn assign_machine(db: &impl GenericClient, job_id: &Uuid) -> String {
+        let machine_id = Uuid::new_v4().to_string();
+        db.execute(
+            "UPDATE attempts SET machine_id = $1
+             WHERE job_id = $2 AND number = (SELECT MAX(number) FROM attempts WHERE job_id = $2)",
+            &[&machine_id, job_id],
+        )
+        .await
+        .unwrap();
+
+        machine_id
+    }
+
+    #[tokio::test]
+    async fn reconcile_jobs_should_complete_jobs_that_exit_cleanly() {
+        let config = crate::app::generate_default_server_config().await;
+        let db = config.db.get().await.unwrap();
+        let clock = FakeClock::new(Utc::now());
+        let compute = FakeCompute::default();
+
+        let (job, machine_id) = launch_job_on_machine(&config, None).await;
+
+        reconcile_jobs(&config.db, &clock, &compute).await.unwrap();
+        assert!(matches!(
+            Job::read(&db, &job.id).await.unwrap().status,
+            JobStatus::Pending
+        ));
+
+        compute.exit(&machine_id, MachineExit::Exited(0));
+        reconcile_jobs(&config.db, &clock, &compute).await.unwrap();
+
+        assert!(matches!(
+            Job::read(&db, &job.id).await.unwrap().status,
+            JobStatus::Completed
+        ));
+        let attempts = Attempt::read_where_job_id(&db, &job.id).await.unwrap();
+        assert_eq!(attempts.len(), 1);
+        assert_eq!(attempts[0].status, AttemptStatus::Succeeded);
+        assert_eq!(compute.calls_for(&machine_id), vec!["destroy"]);
+    }
+
+    #[tokio::test]
+    async fn reconcile_jobs_should_record_usage_from_machine_events() {
+        let config = crate::app::generate_default_server_config().await;
+        let db = config.db.get().await.unwrap();
+        let clock = FakeClock::new(Utc::now());
+        let compute = FakeCompute::default();
+
+        let (job, machine_id) = launch_job_on_machine(&config, None).await;
+        let started_at = clock.now() - chrono::Duration::seconds(100);
+        compute.times(
+            &machine_id,
+            MachineTimes {
+                started_at: Some(started_at),
+                stopped_at: Some(started_at + chrono::Duration::seconds(90)),
+            },
+        );
+        compute.exit(&machine_id, MachineExit::Exited(0));
+        reconcile_jobs(&config.db, &clock, &compute).await.unwrap();
+
+        let records = UsageRecord::read_where_job_id(&db, &job.id).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].seconds, 90);
+        assert_eq!(records[0].cpu_kind, "shared");
+        assert_eq!(records[0].memory_mb, 2048);
+        assert_eq!(records[0].gpu_kind, None);
+    }
+
+    #[tokio::test]
+    async fn reconcile_jobs_should_retry_retryable_failures_with_backoff() {
+        let config = crate::app::generate_default_server_config().await;
+        let db = config.db.get().await.unwrap();
+        let clock = FakeClock::new(Utc::now());
+        let compute = FakeCompute::default();
+
+        let (job, machine_id) = launch_job_on_machine(
+            &config,
+            Some(RetryPolicy {
+                max_attempts: 2,
+                backoff_seconds: 60,
+                retry_on: vec![FailureClass::NonZeroExit],
+            }),
+        )
+        .await;
+
+        compute.exit(&machine_id, MachineExit::Exited(137));
+        reconcile_jobs(&config.db, &clock, &compute).await.unwrap();
+
+        let attempts = Attempt::read_where_job_id(&db, &job.id).await.unwrap();
+        assert_eq!(attempts[0].failure, Some(FailureClass::NonZeroExit));
+        assert_eq!(attempts[0].exit_code, Some(137));
+        assert!(matches!(
+            Job::read(&db, &job.id).await.unwrap().status,
+            JobStatus::Queued
+        ));
+        assert!(
+            !work_job(&config.db, &launcher(&config), &job.id, clock.now()).await,
+            "It should wait out the backoff"
+        );
+
+        clock.advance(chrono::Duration::seconds(60));
+        assert!(work_job(&config.db, &launcher(&config), &job.id, clock.now()).await);
+
+        let attempts = Attempt::read_where_job_id(&db, &job.id).await.unwrap();
+        assert_eq!(attempts.len(), 2, "It should start a second attempt");
+
+        let machine_id = assign_machine(&db, &job.id).await;
+        compute.exit(&machine_id, MachineExit::Exited(1));
+        reconcile_jobs(&config.db, &clock, &compute).await.unwrap();
+
+        assert!(matches!(
+            Job::read(&db, &job.id).await.unwrap().status,
+            JobStatus::Failed
+        ));
+        assert!(
+            !work_job(&config.db, &launcher(&config), &job.id, clock.now()).await,
+            "It should stop after max_attempts"
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_jobs_should_fail_jobs_whose_failures_are_not_retryable() {
+        let config = crate::app::generate_default_server_config().await;
+        let db = config.db.get().await.unwrap();
+        let clock = FakeClock::new(Utc::now());
+        let compute = FakeCompute::default();
+
+        let (job, machine_id) = launch_job_on_machine(
+            &config,
+            Some(RetryPolicy {
+                max_attempts: 3,
+                ..Default::default()
+            }),
+        )
+        .await;
+
+        compute.exit(&machine_id, MachineExit::Missing);
+        reconcile_jobs(&config.db, &clock, &compute).await.unwrap();
+
+        let attempts = Attempt::read_where_job_id(&db, &job.id).await.unwrap();
+        assert_eq!(attempts[0].failure, Some(FailureClass::Lost));
+        assert!(matches!(
+            Job::read(&db, &job.id).await.unwrap().status,
+            JobStatus::Failed
+        ));
+        assert!(compute.calls_for(&machine_id).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reconcile_jobs_should_destroy_machines_that_stop_without_an_exit_code() {
+        let config = crate::app::generate_default_server_config().await;
+        let db = config.db.get().await.unwrap();
+        let clock = FakeClock::new(Utc::now());
+        let compute = FakeCompute::default();
+
+        let (job, machine_id) = launch_job_on_machine(&config, None).await;
+
+        compute.exit(&machine_id, MachineExit::Unreported);
+        reconcile_jobs(&config.db, &clock, &compute).await.unwrap();
+
+        let attempts = Attempt::read_where_job_id(&db, &job.id).await.unwrap();
+        assert_eq!(attempts[0].failure, Some(FailureClass::Lost));
+        assert_eq!(compute.calls_for(&machine_id), vec!["destroy"]);
+    }
+
+    #[tokio::test]
+    async fn reconcile_jobs_should_skip_attempts_leased_to_other_instances() {
+        let config = crate::app::generate_default_server_config().await;
+        let clock = FakeClock::new(Utc::now());
+        let compute = FakeCompute::default();
+
+        let (job, machine_id) = launch_job_on_machine(&config, None).await;
+        compute.exit(&machine_id, MachineExit::Exited(0));
+
+        let db = config.db.get().await.unwrap();
+        let attempt_id = Attempt::read_where_job_id(&db, &job.id).await.unwrap()[0].id;
+        assert!(claim_attempt(&db, &attempt_id, clock.now())
+            .await
+            .unwrap()
+            .is_some());
+
+        reconcile_jobs(&config.db, &clock, &compute).await.unwrap();
+        assert!(
+            compute.calls_for(&machine_id).is_empty(),
+            "It should leave the attempt to the instance holding it"
+        );
+
+        clock.advance(chrono::Duration::seconds(RECONCILE_LEASE_SECONDS));
+        reconcile_jobs(&config.db, &clock, &compute).await.unwrap();
+        assert_eq!(compute.calls_for(&machine_id), vec!["destroy"]);
+    }
+}
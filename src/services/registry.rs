@@ -0,0 +1,595 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    header, redirect, Client, Method, Response, StatusCode, Url,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    models::{Image, ImageVersion},
+    reference::Reference,
+};
+
+const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(Debug)]
+pub enum RegistryError {
+    NotFound,
+    Unauthorized,
+    PrivateHost(String),
+    DigestMismatch(String),
+    UnexpectedStatus(StatusCode),
+    Http(reqwest::Error),
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::NotFound => write!(f, "manifest not found"),
+            RegistryError::Unauthorized => write!(f, "registry denied access"),
+            RegistryError::PrivateHost(host) => {
+                write!(f, "{host} is not a public address")
+            }
+            RegistryError::DigestMismatch(digest) => {
+                write!(
+                    f,
+                    "registry claimed digest {digest} for a different manifest"
+                )
+            }
+            RegistryError::UnexpectedStatus(status) => {
+                write!(f, "registry responded with {status}")
+            }
+            RegistryError::Http(e) => write!(f, "could not reach registry: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl From<reqwest::Error> for RegistryError {
+    fn from(e: reqwest::Error) -> Self {
+        RegistryError::Http(e)
+    }
+}
+
+/// Credentials for pulling from a private registry.
+#[derive(Clone, Debug)]
+pub struct RegistryAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// A manifest downloaded from a registry.
+pub struct FetchedManifest {
+    pub digest: String,
+    pub media_type: String,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// Whether `ip` is on the public internet, rather than loopback, a private
+/// or shared network, link-local (cloud metadata lives there) or unspecified.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// The host of `url` when it's an IP address that isn't public. Names are
+/// checked by `PublicResolver` instead, when they're looked up.
+fn private_ip_host(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    let ip = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .ok()?;
+
+    (!is_public_ip(ip)).then(|| host.to_string())
+}
+
+/// Resolves names like the system resolver, but fails for names with any
+/// non-public address so registry urls can't reach internal services.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .collect::<Vec<_>>();
+            if addrs.iter().any(|addr| !is_public_ip(addr.ip())) {
+                return Err(RegistryError::PrivateHost(name.as_str().to_string()).into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// A minimal OCI Distribution API client, just enough to resolve tags to the
+/// manifest digests we pin machines to. Only talks to public addresses unless
+/// built with `allowing_private_hosts`.
+#[derive(Clone, Debug)]
+pub struct RegistryClient {
+    http: Client,
+    allow_private_hosts: bool,
+}
+
+impl Default for RegistryClient {
+    fn default() -> Self {
+        RegistryClient::new()
+    }
+}
+
+impl RegistryClient {
+    pub fn new() -> Self {
+        let http = Client::builder()
+            .dns_resolver(Arc::new(PublicResolver))
+            .redirect(redirect::Policy::custom(|attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if let Some(host) = private_ip_host(attempt.url()) {
+                    attempt.error(RegistryError::PrivateHost(host))
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .build()
+            .expect("Could not build the registry http client");
+
+        RegistryClient {
+            http,
+            allow_private_hosts: false,
+        }
+    }
+
+    /// A client that may also reach registries on this machine or a private
+    /// network, for tests and local setups.
+    pub fn allowing_private_hosts() -> Self {
+        RegistryClient {
+            http: Client::new(),
+            allow_private_hosts: true,
+        }
+    }
+
+    /// Allows private hosts when `REGISTRY_ALLOW_PRIVATE_HOSTS` is `true`.
+    pub fn from_env() -> Self {
+        if std::env::var("REGISTRY_ALLOW_PRIVATE_HOSTS").is_ok_and(|v| v == "true") {
+            RegistryClient::allowing_private_hosts()
+        } else {
+            RegistryClient::new()
+        }
+    }
+
+    fn check_host(&self, url: &str) -> Result<(), RegistryError> {
+        if self.allow_private_hosts {
+            return Ok(());
+        }
+
+        match Url::parse(url).ok().as_ref().and_then(private_ip_host) {
+            Some(host) => Err(RegistryError::PrivateHost(host)),
+            None => Ok(()),
+        }
+    }
+
+    /// Resolves `reference` (a tag or digest) in `repository` to the digest of
+    /// its manifest, e.g. `sha256:...`.
+    pub async fn resolve_digest(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+        auth: Option<&RegistryAuth>,
+    ) -> Result<String, RegistryError> {
+        Ok(self
+            .fetch_manifest(registry, repository, reference, auth)
+            .await?
+            .digest)
+    }
+
+    /// Downloads the manifest `reference` points at. Its digest is always
+    /// computed from the content, a `Docker-Content-Digest` or digest
+    /// reference that disagrees is an error.
+    pub async fn fetch_manifest(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+        auth: Option<&RegistryAuth>,
+    ) -> Result<FetchedManifest, RegistryError> {
+        let url = format!(
+            "{}/v2/{repository}/manifests/{reference}",
+            base_url(registry)
+        );
+
+        let response = self.send(Method::GET, &url, repository, auth).await?;
+        let header_value = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let claimed = header_value("Docker-Content-Digest");
+        let media_type = header_value("Content-Type")
+            .unwrap_or_else(|| "application/vnd.oci.image.manifest.v1+json".to_string());
+
+        let bytes = response.bytes().await?.to_vec();
+        let digest = format!("sha256:{:x}", Sha256::digest(&bytes));
+        let pinned = reference.starts_with("sha256:").then_some(reference);
+        if let Some(claimed) = [claimed.as_deref(), pinned]
+            .into_iter()
+            .flatten()
+            .find(|claimed| *claimed != digest)
+        {
+            return Err(RegistryError::DigestMismatch(claimed.to_string()));
+        }
+
+        Ok(FetchedManifest {
+            digest,
+            media_type,
+            bytes,
+        })
+    }
+
+    /// Requests a blob, following the registry's redirects to wherever it
+    /// keeps it. The body is left to the caller to stream.
+    pub async fn fetch_blob(
+        &self,
+        registry: &str,
+        repository: &str,
+        digest: &str,
+        auth: Option<&RegistryAuth>,
+    ) -> Result<Response, RegistryError> {
+        let url = format!("{}/v2/{repository}/blobs/{digest}", base_url(registry));
+
+        self.send(Method::GET, &url, repository, auth).await
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        repository: &str,
+        auth: Option<&RegistryAuth>,
+    ) -> Result<Response, RegistryError> {
+        self.check_host(url)?;
+        let response = self.request(method.clone(), url).send().await?;
+
+        let response = if response.status() == StatusCode::UNAUTHORIZED {
+            let challenge = response
+                .headers()
+                .get(header::WWW_AUTHENTICATE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+
+            match (parse_bearer_challenge(challenge), auth) {
+                (Some(challenge), _) => {
+                    let token = self.fetch_token(&challenge, repository, auth).await?;
+                    self.request(method, url).bearer_auth(token).send().await?
+                }
+                (None, Some(auth)) if challenge.starts_with("Basic") => {
+                    self.request(method, url)
+                        .basic_auth(&auth.username, Some(&auth.password))
+                        .send()
+                        .await?
+                }
+                _ => return Err(RegistryError::Unauthorized),
+            }
+        } else {
+            response
+        };
+
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            StatusCode::NOT_FOUND => Err(RegistryError::NotFound),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(RegistryError::Unauthorized),
+            status => Err(RegistryError::UnexpectedStatus(status)),
+        }
+    }
+
+    fn request(&self, method: Method, url: &str) -> reqwest::RequestBuilder {
+        self.http
+            .request(method, url)
+            .header(header::ACCEPT, MANIFEST_MEDIA_TYPES.join(", "))
+    }
+
+    async fn fetch_token(
+        &self,
+        challenge: &HashMap<String, String>,
+        repository: &str,
+        auth: Option<&RegistryAuth>,
+    ) -> Result<String, RegistryError> {
+        let realm = challenge.get("realm").ok_or(RegistryError::Unauthorized)?;
+        self.check_host(realm)?;
+        let scope = challenge
+            .get("scope")
+            .cloned()
+            .unwrap_or_else(|| format!("repository:{repository}:pull"));
+
+        let mut query = vec![("scope", scope)];
+        if let Some(service) = challenge.get("service") {
+            query.push(("service", service.clone()));
+        }
+
+        let request = self.http.get(realm).query(&query);
+        let request = match auth {
+            Some(auth) => request.basic_auth(&auth.username, Some(&auth.password)),
+            None => request,
+        };
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(RegistryError::Unauthorized);
+        }
+
+        let token = response.json::<TokenResponse>().await?;
+        token
+            .token
+            .or(token.access_token)
+            .ok_or(RegistryError::Unauthorized)
+    }
+}
+
+/// Parses `Bearer realm="...",service="...",scope="..."` into its parameters.
+fn parse_bearer_challenge(header: &str) -> Option<HashMap<String, String>> {
+    let params = header.strip_prefix("Bearer ")?;
+    let mut challenge = HashMap::new();
+    let mut rest = params.trim();
+
+    while !rest.is_empty() {
+        let (key, value) = rest.split_once('=')?;
+        let value = value.strip_prefix('"')?;
+        let (value, remainder) = value.split_once('"')?;
+        challenge.insert(key.trim().to_string(), value.to_string());
+        rest = remainder.trim_start_matches(',').trim();
+    }
+
+    Some(challenge)
+}
+
+fn base_url(registry: &str) -> String {
+    let host = registry.split(':').next().unwrap_or(registry);
+    if host == "localhost" || host == "127.0.0.1" {
+        format!("http://{registry}")
+    } else {
+        format!("https://{registry}")
+    }
+}
+
+/// The reference machines are launched with: pinned to the digest when we
+/// have one, falling back to the tag otherwise.
+pub fn machine_image_reference(image: &Image, image_version: &ImageVersion) -> String {
+    let name = Reference::parse(&image.image_url)
+        .map(|reference| reference.name())
+        .unwrap_or_else(|_| image.image_url.clone());
+
+    if image_version.hash.is_empty() {
+        format!("{name}:{}", image_version.version_number)
+    } else {
+        format!("{name}@{}", image_version.hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{StubResponse, StubServer};
+
+    const MANIFEST: &str = r#"{"schemaVersion":2}"#;
+
+    fn manifest_digest() -> String {
+        format!("sha256:{:x}", Sha256::digest(MANIFEST.as_bytes()))
+    }
+
+    fn manifest_response() -> StubResponse {
+        StubResponse::new(200)
+            .header("Docker-Content-Digest", &manifest_digest())
+            .body(MANIFEST)
+    }
+
+    #[tokio::test]
+    async fn resolve_digest_should_check_the_digest_header() {
+        let registry = StubServer::start(|request| {
+            assert_eq!(request.path, "/v2/team/model/manifests/latest");
+            manifest_response()
+        })
+        .await;
+
+        let digest = RegistryClient::allowing_private_hosts()
+            .resolve_digest(&registry.host(), "team/model", "latest", None)
+            .await
+            .expect("It should resolve the tag");
+
+        assert_eq!(digest, manifest_digest());
+    }
+
+    #[tokio::test]
+    async fn resolve_digest_should_reject_digest_headers_for_other_manifests() {
+        let claimed = "sha256:0f1e2d3c4b5a69788796a5b4c3d2e1f00f1e2d3c4b5a69788796a5b4c3d2e1f0";
+        let registry = StubServer::start(move |_request| {
+            StubResponse::new(200)
+                .header("Docker-Content-Digest", claimed)
+                .body(MANIFEST)
+        })
+        .await;
+
+        let result = RegistryClient::allowing_private_hosts()
+            .resolve_digest(&registry.host(), "team/model", "latest", None)
+            .await;
+
+        assert!(matches!(result, Err(RegistryError::DigestMismatch(digest)) if digest == claimed));
+    }
+
+    #[tokio::test]
+    async fn resolve_digest_should_hash_the_manifest_without_a_digest_header() {
+        let registry =
+            StubServer::start(move |_request| StubResponse::new(200).body(MANIFEST)).await;
+
+        let digest = RegistryClient::allowing_private_hosts()
+            .resolve_digest(&registry.host(), "team/model", "latest", None)
+            .await
+            .expect("It should resolve the tag");
+
+        assert_eq!(digest, manifest_digest());
+    }
+
+    #[tokio::test]
+    async fn resolve_digest_should_refuse_private_hosts() {
+        let registry = StubServer::start(|_request| manifest_response()).await;
+
+        for host in [
+            registry.host(),
+            "169.254.169.254".to_string(),
+            "10.0.0.1:5000".to_string(),
+            "[::1]:5000".to_string(),
+            "localhost:5000".to_string(),
+        ] {
+            let result = RegistryClient::new()
+                .resolve_digest(&host, "team/model", "latest", None)
+                .await;
+
+            assert!(result.is_err(), "{host} should be refused");
+        }
+        assert!(
+            registry.requests().is_empty(),
+            "It should not reach the registry"
+        );
+    }
+
+    #[test]
+    fn is_public_ip_should_only_allow_public_addresses() {
+        for ip in ["1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fdaa::3",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_digest_should_follow_bearer_challenges() {
+        let registry = StubServer::start(|request| {
+            if request.path.starts_with("/token") {
+                assert!(request
+                    .path
+                    .contains("scope=repository%3Ateam%2Fmodel%3Apull"));
+                return StubResponse::new(200).body(r#"{"token":"abc"}"#);
+            }
+
+            match request.headers.get("authorization").map(|v| v.as_str()) {
+                Some("Bearer abc") => manifest_response(),
+                _ => StubResponse::new(401).header(
+                    "WWW-Authenticate",
+                    &format!(
+                        r#"Bearer realm="http://{}/token",service="stub""#,
+                        request.host
+                    ),
+                ),
+            }
+        })
+        .await;
+
+        let digest = RegistryClient::allowing_private_hosts()
+            .resolve_digest(&registry.host(), "team/model", "latest", None)
+            .await
+            .expect("It should resolve the tag");
+
+        assert_eq!(digest, manifest_digest());
+    }
+
+    #[tokio::test]
+    async fn resolve_digest_should_report_missing_tags() {
+        let registry = StubServer::start(|_request| StubResponse::new(404)).await;
+
+        let result = RegistryClient::allowing_private_hosts()
+            .resolve_digest(&registry.host(), "team/model", "nope", None)
+            .await;
+
+        assert!(matches!(result, Err(RegistryError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn resolve_digest_should_send_credentials_to_the_token_endpoint() {
+        let registry = StubServer::start(|request| {
+            if request.path.starts_with("/token") {
+                return match request.headers.get("authorization").map(|v| v.as_str()) {
+                    // "robot:secret"
+                    Some("Basic cm9ib3Q6c2VjcmV0") => {
+                        StubResponse::new(200).body(r#"{"access_token":"private"}"#)
+                    }
+                    _ => StubResponse::new(401),
+                };
+            }
+
+            match request.headers.get("authorization").map(|v| v.as_str()) {
+                Some("Bearer private") => manifest_response(),
+                _ => StubResponse::new(401).header(
+                    "WWW-Authenticate",
+                    &format!(r#"Bearer realm="http://{}/token""#, request.host),
+                ),
+            }
+        })
+        .await;
+
+        let auth = RegistryAuth {
+            username: "robot".to_string(),
+            password: "secret".to_string(),
+        };
+
+        let anonymous = RegistryClient::allowing_private_hosts()
+            .resolve_digest(&registry.host(), "team/model", "latest", None)
+            .await;
+        assert!(matches!(anonymous, Err(RegistryError::Unauthorized)));
+
+        let digest = RegistryClient::allowing_private_hosts()
+            .resolve_digest(&registry.host(), "team/model", "latest", Some(&auth))
+            .await
+            .expect("It should resolve the tag");
+        assert_eq!(digest, manifest_digest());
+    }
+}
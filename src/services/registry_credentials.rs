@@ -0,0 +1,95 @@
+use deadpool_postgres::GenericClient;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::{
+    models::{Image, RegistryCredential},
+    reference::Reference,
+    services::{crypto::Encryptor, oci_registry::registry_host, registry::RegistryAuth},
+};
+
+/// Finds the credentials to pull from `registry` on behalf of `user_id`,
+/// preferring ones attached to the image itself over organization-wide ones.
+pub async fn find_registry_auth(
+    db: &impl GenericClient,
+    encryptor: &Encryptor,
+    user_id: &Uuid,
+    image_id: Option<&Uuid>,
+    registry: &str,
+) -> Result<Option<RegistryAuth>, Box<dyn std::error::Error>> {
+    let credential = find_registry_credential(db, user_id, image_id, registry).await?;
+
+    Ok(match credential {
+        Some(credential) => Some(RegistryAuth {
+            username: credential.username,
+            password: encryptor.decrypt_str(&credential.encrypted_token)?,
+        }),
+        None => None,
+    })
+}
+
+/// Whether machines pull `image` through the built-in registry's mirror:
+/// Fly can't be given credentials, so images on other registries that the
+/// owner has credentials for are read through with them instead.
+pub async fn needs_mirror(
+    db: &impl GenericClient,
+    image: &Image,
+) -> Result<bool, tokio_postgres::Error> {
+    let registry = match Reference::parse(&image.image_url) {
+        Ok(reference) if reference.registry != registry_host() => reference.registry,
+        _ => return Ok(false),
+    };
+
+    Ok(
+        find_registry_credential(db, &image.user_id, Some(&image.id), &registry)
+            .await?
+            .is_some(),
+    )
+}
+
+async fn find_registry_credential(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    image_id: Option<&Uuid>,
+    registry: &str,
+) -> Result<Option<RegistryCredential>, tokio_postgres::Error> {
+    let image_credential = match image_id {
+        Some(image_id) => db
+            .query_opt(
+                "SELECT * FROM registry_credentials WHERE image_id = $1 ORDER BY created_at DESC LIMIT 1",
+                &[image_id],
+            )
+            .await?
+            .as_ref()
+            .map(registry_credential_from_row),
+        None => None,
+    };
+
+    Ok(match image_credential {
+        Some(credential) => Some(credential),
+        None => db
+            .query_opt(
+                "SELECT rc.* FROM registry_credentials rc
+                  JOIN organization_members om ON om.organization_id = rc.organization_id
+                  WHERE om.user_id = $1 AND rc.registry = $2
+                  ORDER BY rc.created_at DESC LIMIT 1",
+                &[user_id, &registry],
+            )
+            .await?
+            .as_ref()
+            .map(registry_credential_from_row),
+    })
+}
+
+fn registry_credential_from_row(row: &Row) -> RegistryCredential {
+    RegistryCredential {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        image_id: row.get("image_id"),
+        organization_id: row.get("organization_id"),
+        registry: row.get("registry"),
+        username: row.get("username"),
+        encrypted_token: row.get("encrypted_token"),
+        created_at: row.get("created_at"),
+    }
+}
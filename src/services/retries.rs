@@ -0,0 +1,124 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::models::FailureClass;
+
+pub const MAX_ATTEMPTS: i32 = 10;
+pub const MAX_BACKOFF_SECONDS: i64 = 60 * 60;
+
+/// When a failed job is given another machine. The backoff doubles after
+/// every attempt, up to `MAX_BACKOFF_SECONDS`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Including the first attempt, so 1 never retries.
+    pub max_attempts: i32,
+    /// How long to wait before the first retry.
+    pub backoff_seconds: i64,
+    /// Which failures are worth retrying.
+    pub retry_on: Vec<FailureClass>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff_seconds: 30,
+            retry_on: vec![FailureClass::Capacity],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_ATTEMPTS).contains(&self.max_attempts) {
+            return Err(format!("max_attempts must be between 1 and {MAX_ATTEMPTS}"));
+        }
+        if !(0..=MAX_BACKOFF_SECONDS).contains(&self.backoff_seconds) {
+            return Err(format!(
+                "backoff_seconds must be between 0 and {MAX_BACKOFF_SECONDS}"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// When attempt `number` failing with `failure` should be retried, if at
+    /// all.
+    pub fn retry_at(
+        &self,
+        number: i32,
+        failure: FailureClass,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        if number >= self.max_attempts || !self.retry_on.contains(&failure) {
+            return None;
+        }
+
+        let backoff = self
+            .backoff_seconds
+            .saturating_mul(1 << (number - 1).clamp(0, 20))
+            .min(MAX_BACKOFF_SECONDS);
+
+        Some(now + Duration::seconds(backoff))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_at_should_back_off_until_attempts_run_out() {
+        let now = Utc::now();
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff_seconds: 10,
+            retry_on: vec![FailureClass::Capacity, FailureClass::NonZeroExit],
+        };
+
+        assert_eq!(
+            policy.retry_at(1, FailureClass::Capacity, now),
+            Some(now + Duration::seconds(10))
+        );
+        assert_eq!(
+            policy.retry_at(2, FailureClass::NonZeroExit, now),
+            Some(now + Duration::seconds(20))
+        );
+        assert_eq!(policy.retry_at(3, FailureClass::Capacity, now), None);
+        assert_eq!(policy.retry_at(1, FailureClass::Lost, now), None);
+        assert_eq!(
+            RetryPolicy::default().retry_at(1, FailureClass::Capacity, now),
+            None
+        );
+    }
+
+    #[test]
+    fn retry_at_should_cap_the_backoff() {
+        let now = Utc::now();
+        let policy = RetryPolicy {
+            max_attempts: MAX_ATTEMPTS,
+            backoff_seconds: MAX_BACKOFF_SECONDS,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            policy.retry_at(9, FailureClass::Capacity, now),
+            Some(now + Duration::seconds(MAX_BACKOFF_SECONDS))
+        );
+    }
+
+    #[test]
+    fn validate_should_bound_attempts_and_backoff() {
+        let policy = |max_attempts, backoff_seconds| RetryPolicy {
+            max_attempts,
+            backoff_seconds,
+            ..Default::default()
+        };
+
+        assert!(policy(3, 30).validate().is_ok());
+        assert!(policy(0, 30).validate().is_err());
+        assert!(policy(MAX_ATTEMPTS + 1, 30).validate().is_err());
+        assert!(policy(3, -1).validate().is_err());
+    }
+}
@@ -0,0 +1,468 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use deadpool_postgres::{GenericClient, Pool, Transaction};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::types::Json;
+use uuid::Uuid;
+
+use crate::{
+    models::{Image, ImageVersion, Job, Schedule, ScheduleRun},
+    services::{
+        clock::{Clock, SystemClock},
+        jobs::{launch_job, JobInput, JobSpec},
+    },
+};
+
+/// How often each instance looks for schedules that are due.
+pub const SCHEDULER_INTERVAL: Duration = Duration::from_secs(15);
+pub const DEFAULT_TIMEZONE: &str = "UTC";
+
+/// The job a schedule launches, as it would be passed to `POST /jobs`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct JobTemplate {
+    #[serde(flatten)]
+    pub spec: JobSpec,
+    #[serde(flatten)]
+    pub input: JobInput,
+}
+
+/// A cron expression read in a timezone.
+#[derive(Debug)]
+pub struct CronSchedule {
+    schedule: cron::Schedule,
+    timezone: Tz,
+}
+
+impl CronSchedule {
+    /// The first time the schedule fires strictly after `after`, if it ever
+    /// does again.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.schedule
+            .after(&after.with_timezone(&self.timezone))
+            .next()
+            .map(|next| next.with_timezone(&Utc))
+    }
+}
+
+/// Parses a standard five field cron expression (minute, hour, day of
+/// month, month, day of week) in an IANA timezone.
+pub fn parse_schedule(expression: &str, timezone: &str) -> Result<CronSchedule, String> {
+    let timezone = timezone
+        .parse::<Tz>()
+        .map_err(|_| format!("Unknown timezone: {timezone}"))?;
+
+    if expression.split_whitespace().count() != 5 {
+        return Err(format!(
+            "Invalid cron expression {expression}: expected five fields"
+        ));
+    }
+    // The cron crate wants seconds too, schedules fire at the top of the minute.
+    let schedule = format!("0 {expression}")
+        .parse::<cron::Schedule>()
+        .map_err(|e| format!("Invalid cron expression {expression}: {e}"))?;
+
+    Ok(CronSchedule { schedule, timezone })
+}
+
+pub async fn read_template(
+    db: &impl GenericClient,
+    schedule_id: &Uuid,
+) -> Result<JobTemplate, tokio_postgres::Error> {
+    let row = db
+        .query_one(
+            "SELECT template FROM schedules WHERE id = $1",
+            &[schedule_id],
+        )
+        .await?;
+
+    Ok(row.get::<_, Json<JobTemplate>>("template").0)
+}
+
+pub async fn write_template(
+    db: &impl GenericClient,
+    schedule_id: &Uuid,
+    template: &JobTemplate,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE schedules SET template = $1 WHERE id = $2",
+        &[&Json(template), schedule_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Counts the schedules launching any of `image_version_ids`.
+pub async fn schedule_count(
+    db: &impl GenericClient,
+    image_version_ids: &[Uuid],
+) -> Result<i64, tokio_postgres::Error> {
+    let row = db
+        .query_one(
+            "SELECT COUNT(*) FROM schedules WHERE image_version_id = ANY($1)",
+            &[&image_version_ids],
+        )
+        .await?;
+
+    Ok(row.get(0))
+}
+
+/// Lists the schedules due at `now`, without claiming them.
+async fn due_schedules(
+    db: &impl GenericClient,
+    now: DateTime<Utc>,
+) -> Result<Vec<Uuid>, tokio_postgres::Error> {
+    let rows = db
+        .query(
+            "SELECT id FROM schedules
+             WHERE enabled AND next_run_at <= $1
+             ORDER BY next_run_at",
+            &[&now],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get("id")).collect())
+}
+
+/// Locks the schedule if it's due and no other instance holds it, it stays
+/// claimed until `db`'s transaction ends.
+async fn claim_due(
+    db: &impl GenericClient,
+    schedule_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<Option<Schedule>, Box<dyn std::error::Error>> {
+    let row = db
+        .query_opt(
+            "SELECT id FROM schedules
+             WHERE enabled AND next_run_at <= $1 AND id = $2
+             FOR UPDATE SKIP LOCKED",
+            &[&now, schedule_id],
+        )
+        .await?;
+
+    match row {
+        Some(row) => Ok(Some(Schedule::read(db, &row.get("id")).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Submits the schedule's template the way `POST /jobs` does.
+async fn launch(
+    db: &impl GenericClient,
+    schedule: &Schedule,
+) -> Result<Job, Box<dyn std::error::Error>> {
+    let image_version = ImageVersion::read(db, &schedule.image_version_id).await?;
+    let image = Image::read(db, &image_version.image_id).await?;
+    if image.user_id != schedule.user_id {
+        return Err(format!("Unknown image version {}", image_version.id).into());
+    }
+    let template = read_template(db, &schedule.id).await?;
+
+    launch_job(
+        db,
+        &schedule.user_id,
+        &image,
+        &image_version,
+        &template.spec,
+        &template.input,
+    )
+    .await
+}
+
+/// Launches the claimed schedule's job, records the run and moves the
+/// schedule on to its next time after `now`. Runs missed while no instance
+/// was up are folded into this one rather than fired one after another. The
+/// launch runs in a savepoint, so one that fails part way is rolled back and
+/// recorded as the run's error.
+async fn fire(
+    db: &mut Transaction<'_>,
+    schedule: &Schedule,
+    now: DateTime<Utc>,
+) -> Result<ScheduleRun, Box<dyn std::error::Error>> {
+    let scheduled_for = schedule.next_run_at.unwrap_or(now);
+
+    let savepoint = db.savepoint("launch").await?;
+    let launched = launch(&savepoint, schedule)
+        .await
+        .map_err(|e| e.to_string());
+    match &launched {
+        Ok(_) => savepoint.commit().await?,
+        Err(_) => savepoint.rollback().await?,
+    }
+    let job_id = launched.as_ref().ok().map(|job| job.id);
+    let error = launched.err();
+    if let Some(message) = &error {
+        tracing::warn!("Schedule {} could not launch a job: {message}", schedule.id);
+    }
+    let run = ScheduleRun::create(&*db, schedule.id, scheduled_for, job_id, error).await?;
+
+    let next_run_at = parse_schedule(&schedule.cron, &schedule.timezone)
+        .ok()
+        .and_then(|cron| cron.next_after(now));
+    db.execute(
+        "UPDATE schedules SET next_run_at = $1 WHERE id = $2",
+        &[&next_run_at, &schedule.id],
+    )
+    .await?;
+
+    Ok(run)
+}
+
+/// Fires the schedule if it's due. The claim and the run are committed
+/// together, so two instances can't both fire the same time.
+pub async fn fire_schedule(
+    db: &Pool,
+    schedule_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<Option<ScheduleRun>, Box<dyn std::error::Error>> {
+    let mut client = db.get().await?;
+    let mut transaction = client.transaction().await?;
+
+    let Some(schedule) = claim_due(&transaction, schedule_id, now).await? else {
+        return Ok(None);
+    };
+    let run = fire(&mut transaction, &schedule, now).await?;
+    transaction.commit().await?;
+
+    Ok(Some(run))
+}
+
+/// Fires every schedule that's due, returning the runs. Each is fired in its
+/// own transaction, one that errors is logged and the rest still fire.
+pub async fn fire_due_schedules(
+    db: &Pool,
+    clock: &impl Clock,
+) -> Result<Vec<ScheduleRun>, Box<dyn std::error::Error>> {
+    let now = clock.now();
+    let mut runs = vec![];
+    for schedule_id in due_schedules(&db.get().await?, now).await? {
+        match fire_schedule(db, &schedule_id, now).await {
+            Ok(run) => runs.extend(run),
+            Err(e) => {
+                tracing::error!("An error occurred while firing schedule {schedule_id}: {e:#?}")
+            }
+        }
+    }
+
+    Ok(runs)
+}
+
+/// Runs the scheduler until the process exits.
+pub async fn run(db: Pool) {
+    let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = fire_due_schedules(&db, &SystemClock).await {
+            tracing::error!("An error occurred while firing schedules: {e:#?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{JobStatus, User},
+        services::{clock::FakeClock, jobs::read_job_input},
+    };
+    use chrono::TimeZone;
+
+    #[test]
+    fn parse_schedule_should_read_expressions_in_their_timezone() {
+        let schedule = parse_schedule("0 2 * * *", "America/New_York").unwrap();
+
+        assert_eq!(
+            schedule.next_after(Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap()),
+            Some(Utc.with_ymd_and_hms(2024, 6, 1, 6, 0, 0).unwrap()),
+            "It should follow daylight saving time"
+        );
+        assert_eq!(
+            schedule.next_after(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()),
+            Some(Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap())
+        );
+        assert_eq!(
+            schedule.next_after(Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap()),
+            Some(Utc.with_ymd_and_hms(2024, 1, 2, 7, 0, 0).unwrap()),
+            "It should only fire after the given time"
+        );
+    }
+
+    #[test]
+    fn parse_schedule_should_reject_invalid_expressions() {
+        assert!(parse_schedule("*/15 * * * *", "UTC").is_ok());
+        assert!(parse_schedule("0 9 * * MON-FRI", "Europe/Berlin").is_ok());
+        assert!(parse_schedule("0 2 * * *", "Mars/Olympus_Mons").is_err());
+        assert!(parse_schedule("0 25 * * *", "UTC").is_err());
+        assert!(parse_schedule("* * * * * *", "UTC").is_err());
+        assert!(parse_schedule("daily", "UTC").is_err());
+    }
+
+    async fn create_schedule(db: &Pool, next_run_at: DateTime<Utc>) -> Schedule {
+        let db = db.get().await.unwrap();
+        let user = User::create(
+            &db,
+            format!("{}@example.com", Uuid::new_v4()),
+            "".to_string(),
+        )
+        .await
+        .unwrap();
+        let image = Image::create(
+            &db,
+            user.id,
+            "scheduled".to_string(),
+            "docker.io/library/alpine".to_string(),
+        )
+        .await
+        .unwrap();
+        let image_version = ImageVersion::create(
+            &db,
+            image.id,
+            "sha256:abc".to_string(),
+            "latest".to_string(),
+        )
+        .await
+        .unwrap();
+        let schedule = Schedule::create(
+            &db,
+            user.id,
+            "nightly".to_string(),
+            "0 2 * * *".to_string(),
+            DEFAULT_TIMEZONE.to_string(),
+            image_version.id,
+            true,
+            Some(next_run_at),
+        )
+        .await
+        .unwrap();
+        write_template(
+            &db,
+            &schedule.id,
+            &JobTemplate {
+                spec: JobSpec {
+                    preset: Some("cpu-small".to_string()),
+                    ..Default::default()
+                },
+                input: JobInput {
+                    params: Some(serde_json::json!({ "day": "today" })),
+                    ..Default::default()
+                },
+            },
+        )
+        .await
+        .unwrap();
+
+        schedule
+    }
+
+    #[tokio::test]
+    async fn fire_schedule_should_launch_the_template_once_per_time() {
+        let db = crate::app::generate_default_server_config().await.db;
+        let clock = FakeClock::new(Utc.with_ymd_and_hms(2024, 6, 1, 1, 59, 0).unwrap());
+        let schedule =
+            create_schedule(&db, Utc.with_ymd_and_hms(2024, 6, 1, 2, 0, 0).unwrap()).await;
+
+        assert!(
+            fire_schedule(&db, &schedule.id, clock.now())
+                .await
+                .unwrap()
+                .is_none(),
+            "It should wait until the schedule is due"
+        );
+
+        clock.advance(chrono::Duration::minutes(1));
+        let run = fire_schedule(&db, &schedule.id, clock.now())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.scheduled_for, schedule.next_run_at.unwrap());
+        assert!(run.error.is_none());
+
+        let client = db.get().await.unwrap();
+        let job = Job::read(&client, &run.job_id.unwrap()).await.unwrap();
+        assert_eq!(job.user_id, schedule.user_id);
+        assert!(matches!(job.status, JobStatus::Queued));
+        assert_eq!(
+            read_job_input(&client, &job.id).await.unwrap().params,
+            Some(serde_json::json!({ "day": "today" }))
+        );
+        assert_eq!(
+            Schedule::read(&client, &schedule.id)
+                .await
+                .unwrap()
+                .next_run_at,
+            Some(Utc.with_ymd_and_hms(2024, 6, 2, 2, 0, 0).unwrap())
+        );
+        assert!(fire_schedule(&db, &schedule.id, clock.now())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn fire_schedule_should_not_fire_twice_across_instances() {
+        let db = crate::app::generate_default_server_config().await.db;
+        let now = Utc::now();
+        let schedule = create_schedule(&db, now).await;
+
+        let (first, second) = tokio::join!(
+            fire_schedule(&db, &schedule.id, now),
+            fire_schedule(&db, &schedule.id, now)
+        );
+        let fired = [first.unwrap(), second.unwrap()]
+            .into_iter()
+            .flatten()
+            .count();
+
+        assert_eq!(fired, 1);
+        assert_eq!(
+            ScheduleRun::read_where_schedule_id(&db.get().await.unwrap(), &schedule.id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn fire_due_schedules_should_record_failed_launches_and_keep_going() {
+        let db = crate::app::generate_default_server_config().await.db;
+        let clock = FakeClock::new(Utc::now());
+        let broken = create_schedule(&db, clock.now()).await;
+        let working = create_schedule(&db, clock.now()).await;
+        db.get()
+            .await
+            .unwrap()
+            .execute(
+                "UPDATE schedules SET image_version_id = $1 WHERE id = $2",
+                &[&Uuid::new_v4(), &broken.id],
+            )
+            .await
+            .unwrap();
+
+        let runs = fire_due_schedules(&db, &clock).await.unwrap();
+
+        let broken_run = runs
+            .iter()
+            .find(|run| run.schedule_id == broken.id)
+            .expect("It should record the failed run");
+        assert!(broken_run.job_id.is_none());
+        assert!(broken_run.error.is_some());
+        let working_run = runs
+            .iter()
+            .find(|run| run.schedule_id == working.id)
+            .expect("It should still fire the other schedules");
+        assert!(working_run.job_id.is_some());
+        assert!(
+            Schedule::read(&db.get().await.unwrap(), &broken.id)
+                .await
+                .unwrap()
+                .next_run_at
+                > broken.next_run_at,
+            "It should move the failed schedule on"
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+
+use deadpool_postgres::GenericClient;
+use uuid::Uuid;
+
+use crate::services::crypto::{CryptoError, Encryptor};
+
+#[derive(Debug)]
+pub enum SecretError {
+    Missing(Vec<String>),
+    Database(tokio_postgres::Error),
+    Crypto(CryptoError),
+}
+
+impl std::fmt::Display for SecretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretError::Missing(names) => write!(f, "unknown secrets: {}", names.join(", ")),
+            SecretError::Database(e) => write!(f, "could not load secrets: {e}"),
+            SecretError::Crypto(e) => write!(f, "could not decrypt secret: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+impl From<tokio_postgres::Error> for SecretError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        SecretError::Database(e)
+    }
+}
+
+impl From<CryptoError> for SecretError {
+    fn from(e: CryptoError) -> Self {
+        SecretError::Crypto(e)
+    }
+}
+
+/// The names in `names` that `user_id` has no secret for.
+pub async fn missing_secrets(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    names: &[String],
+) -> Result<Vec<String>, tokio_postgres::Error> {
+    let rows = db
+        .query(
+            "SELECT name FROM secrets WHERE user_id = $1 AND name = ANY($2)",
+            &[user_id, &names],
+        )
+        .await?;
+    let found = rows
+        .iter()
+        .map(|row| row.get::<_, String>("name"))
+        .collect::<Vec<_>>();
+
+    Ok(names
+        .iter()
+        .filter(|name| !found.contains(name))
+        .cloned()
+        .collect())
+}
+
+/// Decrypts the secrets `names` refers to, keyed by name. Every name must
+/// belong to `user_id`.
+pub async fn resolve_secrets(
+    db: &impl GenericClient,
+    encryptor: &Encryptor,
+    user_id: &Uuid,
+    names: &[String],
+) -> Result<BTreeMap<String, String>, SecretError> {
+    if names.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let rows = db
+        .query(
+            "SELECT name, encrypted_value FROM secrets WHERE user_id = $1 AND name = ANY($2)",
+            &[user_id, &names],
+        )
+        .await?;
+
+    let mut secrets = BTreeMap::new();
+    for row in rows {
+        secrets.insert(
+            row.get::<_, String>("name"),
+            encryptor.decrypt_str(row.get("encrypted_value"))?,
+        );
+    }
+
+    let missing = names
+        .iter()
+        .filter(|name| !secrets.contains_key(*name))
+        .cloned()
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(SecretError::Missing(missing));
+    }
+
+    Ok(secrets)
+}
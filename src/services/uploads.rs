@@ -0,0 +1,227 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::services::blob_store::{BlobStore, BlobStoreError, BlobWriter};
+
+/// Uploads larger than this are rejected as they stream in, override with
+/// `UPLOAD_MAX_BYTES`.
+const DEFAULT_MAX_UPLOAD_BYTES: u64 = 512 * 1024 * 1024;
+/// How long machines have to download a job's files once it is created.
+pub const DOWNLOAD_EXPIRY_SECONDS: u64 = 24 * 60 * 60;
+/// Where the download script and its manifest are written in the machine.
+pub const FETCH_SCRIPT_PATH: &str = "/lim/fetch-files.sh";
+pub const FETCH_MANIFEST_PATH: &str = "/lim/files.tsv";
+
+/// Downloads every file in the manifest (`sha256<TAB>path<TAB>url` per line)
+/// with whatever the image has, checks it, then runs the job's command.
+/// Images without `sha256sum` fail rather than run on unchecked files.
+pub const FETCH_SCRIPT: &str = r#"#!/bin/sh
+set -e
+if ! command -v sha256sum >/dev/null 2>&1; then
+  echo "sha256sum is needed to check the job's files" >&2
+  exit 1
+fi
+tab="$(printf '\t')"
+while IFS="$tab" read -r sha256 path url; do
+  mkdir -p "$(dirname "$path")"
+  if command -v curl >/dev/null 2>&1; then
+    curl -fsSL -o "$path" "$url"
+  else
+    wget -qO "$path" "$url"
+  fi
+  echo "$sha256  $path" | sha256sum -c - >/dev/null
+done < /lim/files.tsv
+exec "$@"
+"#;
+
+pub fn max_upload_bytes() -> u64 {
+    std::env::var("UPLOAD_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES)
+}
+
+pub fn upload_key(user_id: &Uuid) -> String {
+    format!("files/{user_id}/{}", Uuid::new_v4())
+}
+
+/// Places an uploaded file in a job's machine before its command runs.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct JobFile {
+    pub upload_id: Uuid,
+    pub path: String,
+}
+
+impl JobFile {
+    pub fn validate(&self) -> Result<(), String> {
+        // Tabs and newlines would split the path across manifest fields.
+        if !self.path.starts_with('/')
+            || self.path.ends_with('/')
+            || self.path.chars().any(|c| c.is_control())
+        {
+            return Err(format!("Invalid file path: {}", self.path));
+        }
+
+        Ok(())
+    }
+}
+
+/// A file the machine fetches on boot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileDownload {
+    pub path: String,
+    pub url: String,
+    pub sha256: String,
+}
+
+pub fn fetch_manifest(downloads: &[FileDownload]) -> String {
+    downloads
+        .iter()
+        .map(|d| format!("{}\t{}\t{}\n", d.sha256, d.path, d.url))
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    TooLarge(u64),
+    ChecksumMismatch,
+    Invalid(String),
+    Storage(BlobStoreError),
+}
+
+impl std::fmt::Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::TooLarge(limit) => write!(f, "uploads are limited to {limit} bytes"),
+            UploadError::ChecksumMismatch => {
+                write!(f, "upload does not match the provided sha256")
+            }
+            UploadError::Invalid(message) => write!(f, "{message}"),
+            UploadError::Storage(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+impl From<BlobStoreError> for UploadError {
+    fn from(e: BlobStoreError) -> Self {
+        UploadError::Storage(e)
+    }
+}
+
+/// Writes an upload to storage as it streams in, hashing it and enforcing
+/// the size limit chunk by chunk so oversized uploads are cut off early.
+pub struct UploadWriter {
+    writer: BlobWriter,
+    hasher: Sha256,
+    limit: u64,
+}
+
+impl UploadWriter {
+    pub async fn new(store: &BlobStore, key: &str, limit: u64) -> Result<Self, UploadError> {
+        Ok(UploadWriter {
+            writer: store.writer(key).await?,
+            hasher: Sha256::new(),
+            limit,
+        })
+    }
+
+    pub async fn push(&mut self, chunk: &[u8]) -> Result<(), UploadError> {
+        if self.writer.size() + chunk.len() as u64 > self.limit {
+            return Err(UploadError::TooLarge(self.limit));
+        }
+        self.hasher.update(chunk);
+        self.writer.write(chunk).await?;
+
+        Ok(())
+    }
+
+    /// Stores the upload and returns its size and hex sha256, unless it
+    /// doesn't match `expected_sha256` when the client sent one.
+    pub async fn finish(
+        self,
+        store: &BlobStore,
+        expected_sha256: Option<&str>,
+    ) -> Result<(u64, String), UploadError> {
+        let sha256 = format!("{:x}", self.hasher.finalize());
+        match expected_sha256 {
+            Some(expected) if !expected.eq_ignore_ascii_case(&sha256) => {
+                self.writer.abort(store).await?;
+                Err(UploadError::ChecksumMismatch)
+            }
+            _ => Ok((self.writer.finish().await?, sha256)),
+        }
+    }
+
+    pub async fn abort(self, store: &BlobStore) -> Result<(), UploadError> {
+        Ok(self.writer.abort(store).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_store() -> BlobStore {
+        BlobStore::Filesystem(std::env::temp_dir().join(format!("lim-uploads-{}", Uuid::new_v4())))
+    }
+
+    #[tokio::test]
+    async fn upload_writer_should_enforce_the_limit_and_checksum() {
+        let store = test_store();
+
+        let mut upload = UploadWriter::new(&store, "files/a", 8).await.unwrap();
+        upload.push(b"hello").await.unwrap();
+        assert!(matches!(
+            upload.push(b"world").await,
+            Err(UploadError::TooLarge(8))
+        ));
+        upload.abort(&store).await.unwrap();
+        assert_eq!(store.get("files/a").await.unwrap(), None);
+
+        let mut upload = UploadWriter::new(&store, "files/b", 8).await.unwrap();
+        upload.push(b"hello").await.unwrap();
+        let sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert_eq!(
+            upload.finish(&store, Some(sha256)).await.unwrap(),
+            (5, sha256.to_string())
+        );
+        assert_eq!(store.get("files/b").await.unwrap(), Some(b"hello".to_vec()));
+
+        let mut upload = UploadWriter::new(&store, "files/c", 8).await.unwrap();
+        upload.push(b"hello").await.unwrap();
+        assert!(matches!(
+            upload.finish(&store, Some("00")).await,
+            Err(UploadError::ChecksumMismatch)
+        ));
+        assert_eq!(store.get("files/c").await.unwrap(), None);
+    }
+
+    #[test]
+    fn job_file_should_reject_paths_that_break_the_manifest() {
+        for path in ["/data/a\tb", "/data/a\nb", "data/a", "/data/"] {
+            let file = JobFile {
+                upload_id: Uuid::new_v4(),
+                path: path.to_string(),
+            };
+            assert!(file.validate().is_err(), "{path:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn fetch_manifest_should_list_one_file_per_line() {
+        let manifest = fetch_manifest(&[FileDownload {
+            path: "/data/train.csv".to_string(),
+            url: "https://example.com/train.csv?sig=1".to_string(),
+            sha256: "abc".to_string(),
+        }]);
+
+        assert_eq!(
+            manifest,
+            "abc\t/data/train.csv\thttps://example.com/train.csv?sig=1\n"
+        );
+        assert!(FETCH_SCRIPT.contains(FETCH_MANIFEST_PATH));
+    }
+}
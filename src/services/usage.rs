@@ -0,0 +1,207 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::GenericClient;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::types::Json;
+use uuid::Uuid;
+
+use crate::services::{compute::MachineTimes, credits::debit_usage, presets::MachineResources};
+
+/// Keeps the guest an attempt's machine was created with, its usage is
+/// recorded against it once the attempt finishes.
+pub async fn write_attempt_resources(
+    db: &impl GenericClient,
+    attempt_id: &Uuid,
+    resources: &MachineResources,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE attempts SET resources = $1 WHERE id = $2",
+        &[&Json(resources), attempt_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Records when an attempt's machine started and stopped according to its
+/// events, times that aren't known are left as they were.
+pub async fn record_machine_times(
+    db: &impl GenericClient,
+    attempt_id: &Uuid,
+    times: MachineTimes,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "UPDATE attempts
+         SET machine_started_at = COALESCE($1, machine_started_at),
+             machine_stopped_at = COALESCE($2, machine_stopped_at)
+         WHERE id = $3",
+        &[&times.started_at, &times.stopped_at, attempt_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Writes the usage record of a finished attempt. Without machine events the
+/// attempt's own start and finish are used. Attempts that never got a
+/// machine have nothing to record, and recording twice keeps the first.
+/// New records are charged to the user's credit account if they have one.
+pub async fn record_usage(
+    db: &impl GenericClient,
+    attempt_id: &Uuid,
+    now: DateTime<Utc>,
+) -> Result<(), tokio_postgres::Error> {
+    let row = db
+        .query_one(
+            "SELECT a.job_id, j.user_id, a.resources,
+                    COALESCE(a.machine_started_at, a.created_at) AS started_at,
+                    COALESCE(a.machine_stopped_at, a.finished_at, $2) AS stopped_at
+             FROM attempts a JOIN jobs j ON j.id = a.job_id
+             WHERE a.id = $1",
+            &[attempt_id, &now],
+        )
+        .await?;
+    let Some(Json(resources)) = row.get::<_, Option<Json<MachineResources>>>("resources") else {
+        return Ok(());
+    };
+    let started_at: DateTime<Utc> = row.get("started_at");
+    let stopped_at: DateTime<Utc> = row.get("stopped_at");
+    let seconds = (stopped_at - started_at).num_seconds().max(0);
+
+    let inserted = db
+        .query_opt(
+            "INSERT INTO usage_records
+           (user_id, job_id, attempt_id, cpu_kind, cpus, memory_mb, gpu_kind, gpus,
+            started_at, stopped_at, seconds)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+         ON CONFLICT (attempt_id) DO NOTHING
+         RETURNING id",
+            &[
+                &row.get::<_, Uuid>("user_id"),
+                &row.get::<_, Uuid>("job_id"),
+                attempt_id,
+                &resources.cpu_kind,
+                &resources.cpus,
+                &resources.memory_mb,
+                &resources.gpu_kind,
+                &resources.gpus,
+                &started_at,
+                &stopped_at,
+                &seconds,
+            ],
+        )
+        .await?;
+    if let Some(row) = inserted {
+        debit_usage(db, &row.get("id")).await?;
+    }
+
+    Ok(())
+}
+
+/// What usage is summed up by.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageGroup {
+    Day,
+    Month,
+    Job,
+    User,
+    /// The GPU kind, or the CPU kind for machines without GPUs.
+    Kind,
+}
+
+impl UsageGroup {
+    fn column(&self) -> &'static str {
+        match self {
+            UsageGroup::Day => "to_char(stopped_at AT TIME ZONE 'UTC', 'YYYY-MM-DD')",
+            UsageGroup::Month => "to_char(stopped_at AT TIME ZONE 'UTC', 'YYYY-MM')",
+            UsageGroup::Job => "job_id::TEXT",
+            UsageGroup::User => "user_id::TEXT",
+            UsageGroup::Kind => "COALESCE(gpu_kind, cpu_kind)",
+        }
+    }
+}
+
+impl FromStr for UsageGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(UsageGroup::Day),
+            "month" => Ok(UsageGroup::Month),
+            "job" => Ok(UsageGroup::Job),
+            "user" => Ok(UsageGroup::User),
+            "kind" => Ok(UsageGroup::Kind),
+            _ => Err(format!(
+                "group_by must be one of day, month, job, user or kind, not {s}"
+            )),
+        }
+    }
+}
+
+/// Usage summed up over one group.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct UsageSummary {
+    pub group: String,
+    pub jobs: i64,
+    /// How long machines ran.
+    pub seconds: i64,
+    /// Machine time multiplied by the CPUs, GPUs and memory they had.
+    pub cpu_seconds: i64,
+    pub gpu_seconds: i64,
+    pub memory_gb_seconds: f64,
+}
+
+/// Sums up the usage of `user_ids`' machines that stopped between `from`
+/// and `to`.
+pub async fn summarize_usage(
+    db: &impl GenericClient,
+    user_ids: &[Uuid],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    group_by: UsageGroup,
+) -> Result<Vec<UsageSummary>, tokio_postgres::Error> {
+    let rows = db
+        .query(
+            &format!(
+                "SELECT {} AS grp,
+                        COUNT(DISTINCT job_id) AS jobs,
+                        SUM(seconds)::BIGINT AS seconds,
+                        SUM(seconds * cpus)::BIGINT AS cpu_seconds,
+                        SUM(seconds * gpus)::BIGINT AS gpu_seconds,
+                        SUM(seconds * memory_mb)::DOUBLE PRECISION / 1024 AS memory_gb_seconds
+                 FROM usage_records
+                 WHERE user_id = ANY($1) AND stopped_at >= $2 AND stopped_at < $3
+                 GROUP BY grp
+                 ORDER BY grp",
+                group_by.column()
+            ),
+            &[&user_ids, &from, &to],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| UsageSummary {
+            group: row.get("grp"),
+            jobs: row.get("jobs"),
+            seconds: row.get("seconds"),
+            cpu_seconds: row.get("cpu_seconds"),
+            gpu_seconds: row.get("gpu_seconds"),
+            memory_gb_seconds: row.get("memory_gb_seconds"),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_group_should_parse_known_groups() {
+        assert_eq!("kind".parse(), Ok(UsageGroup::Kind));
+        assert_eq!("month".parse(), Ok(UsageGroup::Month));
+        assert!("region".parse::<UsageGroup>().is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use deadpool_postgres::GenericClient;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::models::{JobStatus, Volume};
+
+/// Volumes can't shrink, and fly.io caps them at 500GB.
+pub const MAX_VOLUME_SIZE_GB: i32 = 500;
+
+/// Attaches one of the user's volumes to a job.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct VolumeMount {
+    pub volume_id: Uuid,
+    /// Where the volume is mounted inside the machine.
+    pub path: String,
+}
+
+impl VolumeMount {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.path.starts_with('/') || self.path == "/" {
+            return Err(format!("Invalid mount path: {}", self.path));
+        }
+
+        Ok(())
+    }
+}
+
+/// Volume names are passed on to fly.io, which only allows lowercase letters,
+/// digits and underscores, up to 30 characters.
+pub fn is_valid_volume_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 30
+        && name
+            .chars()
+            .all(|c| c == '_' || c.is_ascii_lowercase() || c.is_ascii_digit())
+}
+
+/// A job asked for a volume another job still holds.
+#[derive(Debug)]
+pub struct VolumeInUseError(pub String);
+
+impl std::fmt::Display for VolumeInUseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for VolumeInUseError {}
+
+/// The volumes a user manages: their own and those shared with the
+/// organizations they administer.
+pub async fn manageable_volumes(
+    db: &impl GenericClient,
+    user_id: &Uuid,
+    organization_ids: &[Uuid],
+) -> Result<Vec<Volume>, Box<dyn std::error::Error>> {
+    let rows = db
+        .query(
+            "SELECT id FROM volumes WHERE user_id = $1 OR organization_id = ANY($2)
+              ORDER BY created_at",
+            &[user_id, &organization_ids],
+        )
+        .await?;
+
+    let mut volumes = vec![];
+    for row in rows {
+        volumes.push(Volume::read(db, &row.get("id")).await?);
+    }
+
+    Ok(volumes)
+}
+
+/// Locks the volume's row until the transaction ends, so two jobs submitted
+/// at once can't both find it free.
+pub async fn lock_volume(
+    db: &impl GenericClient,
+    volume_id: &Uuid,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "SELECT 1 FROM volumes WHERE id = $1 FOR UPDATE",
+        &[volume_id],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Whether a job that may still hold a machine has `volume_id` mounted. A
+/// volume can only be attached to one machine at a time.
+pub async fn volume_in_use(
+    db: &impl GenericClient,
+    volume_id: &Uuid,
+) -> Result<bool, tokio_postgres::Error> {
+    let row = db
+        .query_one(
+            "SELECT EXISTS (
+               SELECT 1 FROM job_volumes jv JOIN jobs j ON j.id = jv.job_id
+                WHERE jv.volume_id = $1 AND j.status = ANY($2)
+             )",
+            &[volume_id, &JobStatus::active()],
+        )
+        .await?;
+
+    Ok(row.get(0))
+}
+
+pub async fn attach_volume(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    mount: &VolumeMount,
+) -> Result<(), tokio_postgres::Error> {
+    db.execute(
+        "INSERT INTO job_volumes (job_id, volume_id, path) VALUES ($1, $2, $3)",
+        &[job_id, &mount.volume_id, &mount.path],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume_names_should_match_what_fly_accepts() {
+        assert!(is_valid_volume_name("datasets_2024"));
+        assert!(!is_valid_volume_name("Datasets"));
+        assert!(!is_valid_volume_name("data-sets"));
+        assert!(!is_valid_volume_name(&"a".repeat(31)));
+        assert!(!is_valid_volume_name(""));
+    }
+
+    #[test]
+    fn mount_paths_should_be_absolute() {
+        let mount = |path: &str| VolumeMount {
+            volume_id: Uuid::new_v4(),
+            path: path.to_string(),
+        };
+
+        assert!(mount("/data").validate().is_ok());
+        assert!(mount("data").validate().is_err());
+        assert!(mount("/").validate().is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use deadpool_postgres::{GenericClient, Pool};
+use fly::apis::configuration::Configuration as FlyClient;
+use uuid::Uuid;
+
+use crate::{
+    models::{FailureClass, JobStatus},
+    services::{
+        clock::{Clock, SystemClock},
+        compute::ComputeBackend,
+        jobs::{terminate_job, ActiveJob},
+    },
+};
+
+/// How often running jobs are checked against their maximum runtime.
+pub const WATCHDOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The active jobs that have run past their `max_runtime_seconds`.
+pub async fn expired_jobs(
+    db: &impl GenericClient,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<ActiveJob>, tokio_postgres::Error> {
+    let rows = db
+        .query(
+            "SELECT id, user_id, machine_id FROM jobs
+             WHERE status = ANY($1)
+               AND started_at + max_runtime_seconds * INTERVAL '1 second' <= $2",
+            &[&JobStatus::active(), &now],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| ActiveJob {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            machine_id: row.get("machine_id"),
+        })
+        .collect())
+}
+
+/// Locks an expired job if no other instance holds it, until `db`'s
+/// transaction ends.
+async fn claim_expired(
+    db: &impl GenericClient,
+    job_id: &Uuid,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<Option<ActiveJob>, tokio_postgres::Error> {
+    let row = db
+        .query_opt(
+            "SELECT id, user_id, machine_id FROM jobs
+             WHERE id = $1
+               AND status = ANY($2)
+               AND started_at + max_runtime_seconds * INTERVAL '1 second' <= $3
+             FOR UPDATE SKIP LOCKED",
+            &[job_id, &JobStatus::active(), &now],
+        )
+        .await?;
+
+    Ok(row.map(|row| ActiveJob {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        machine_id: row.get("machine_id"),
+    }))
+}
+
+/// Destroys the job's machine and marks it `TimedOut`. Returns false if the
+/// machine couldn't be destroyed.
+async fn time_out(
+    db: &impl GenericClient,
+    compute: &impl ComputeBackend,
+    job: &ActiveJob,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<bool, tokio_postgres::Error> {
+    let terminated = terminate_job(
+        db,
+        compute,
+        job,
+        JobStatus::TimedOut,
+        FailureClass::Timeout,
+        now,
+    )
+    .await?;
+    if terminated {
+        tracing::info!("Job {} exceeded its maximum runtime", job.id);
+    }
+
+    Ok(terminated)
+}
+
+/// Stops and destroys the machines of jobs that have run for too long and
+/// marks them `TimedOut`. Jobs whose machine can't be destroyed are left
+/// alone, so the next pass tries again. Each job is claimed in its own
+/// transaction, so instances running at once never handle one twice.
+pub async fn enforce_max_runtime(
+    db: &Pool,
+    clock: &impl Clock,
+    compute: &impl ComputeBackend,
+) -> Result<Vec<Uuid>, Box<dyn std::error::Error>> {
+    let now = clock.now();
+    let mut timed_out = vec![];
+    let mut client = db.get().await?;
+
+    for job in expired_jobs(&client, now).await? {
+        let transaction = client.transaction().await?;
+        let Some(job) = claim_expired(&transaction, &job.id, now).await? else {
+            continue;
+        };
+        if time_out(&transaction, compute, &job, now).await? {
+            transaction.commit().await?;
+            timed_out.push(job.id);
+        }
+    }
+
+    Ok(timed_out)
+}
+
+/// Runs the watchdog until the process exits.
+pub async fn run(db: Pool, fly: FlyClient) {
+    let mut interval = tokio::time::interval(WATCHDOG_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = enforce_max_runtime(&db, &SystemClock, &fly).await {
+            tracing::error!("An error occurred while enforcing job runtimes: {e:#?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::Job,
+        services::{clock::FakeClock, compute::FakeCompute, jobs::record_job_start},
+    };
+    use chrono::Utc;
+
+    #[tokio::test]
+    async fn enforce_max_runtime_should_time_out_expired_jobs() {
+        let pool = crate::app::generate_default_server_config().await.db;
+        let db = pool.get().await.unwrap();
+        let clock = FakeClock::new(Utc::now());
+        let compute = FakeCompute::default();
+        let user_id = Uuid::new_v4();
+        let machine_id = Uuid::new_v4().to_string();
+
+        let job = Job::create(&db, user_id, JobStatus::Pending, Some(Uuid::new_v4()))
+            .await
+            .unwrap();
+        record_job_start(&db, &job.id, Some(&machine_id), 600, clock.now())
+            .await
+            .unwrap();
+
+        clock.advance(chrono::Duration::seconds(599));
+        let timed_out = enforce_max_runtime(&pool, &clock, &compute).await.unwrap();
+        assert!(!timed_out.contains(&job.id));
+        assert!(compute.calls_for(&machine_id).is_empty());
+
+        clock.advance(chrono::Duration::seconds(1));
+        let timed_out = enforce_max_runtime(&pool, &clock, &compute).await.unwrap();
+        assert!(timed_out.contains(&job.id));
+        assert_eq!(compute.calls_for(&machine_id), vec!["stop", "destroy"]);
+        assert!(matches!(
+            Job::read(&db, &job.id).await.unwrap().status,
+            JobStatus::TimedOut
+        ));
+
+        let timed_out = enforce_max_runtime(&pool, &clock, &compute).await.unwrap();
+        assert!(!timed_out.contains(&job.id), "It should only time out once");
+        assert_eq!(compute.calls_for(&machine_id).len(), 2);
+    }
+}
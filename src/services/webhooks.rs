@@ -0,0 +1,242 @@
+use base64::{engine::general_purpose, Engine};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{
+    reference::{is_valid_digest, is_valid_tag},
+    services::crypto::decode_hex,
+};
+
+pub const SIGNATURE_HEADER: &str = "X-Registry-Signature";
+const SIGNATURE_PREFIX: &str = "sha256=";
+
+#[derive(Debug)]
+pub enum WebhookError {
+    InvalidSignature,
+    MalformedPayload,
+}
+
+impl std::fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebhookError::InvalidSignature => write!(f, "signature does not match the payload"),
+            WebhookError::MalformedPayload => write!(f, "payload is not a push notification"),
+        }
+    }
+}
+
+impl std::error::Error for WebhookError {}
+
+/// A tag that the registry reports now points at `digest`.
+#[derive(Debug, PartialEq)]
+pub struct PushedTag {
+    pub tag: String,
+    pub digest: String,
+}
+
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn sign(secret: &str, payload: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+
+    format!("{SIGNATURE_PREFIX}{:x}", mac.finalize().into_bytes())
+}
+
+/// Checks a `sha256=<hex>` signature header against the payload in constant
+/// time.
+pub fn verify_signature(secret: &str, payload: &[u8], signature: &str) -> Result<(), WebhookError> {
+    let expected = signature
+        .strip_prefix(SIGNATURE_PREFIX)
+        .and_then(decode_hex)
+        .ok_or(WebhookError::InvalidSignature)?;
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    mac.verify_slice(&expected)
+        .map_err(|_| WebhookError::InvalidSignature)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Notification {
+    Distribution {
+        events: Vec<DistributionEvent>,
+    },
+    Harbor {
+        #[serde(rename = "type")]
+        event_type: String,
+        event_data: HarborEventData,
+    },
+}
+
+#[derive(Deserialize)]
+struct DistributionEvent {
+    action: String,
+    target: DistributionTarget,
+}
+
+#[derive(Deserialize)]
+struct DistributionTarget {
+    #[serde(rename = "mediaType", default)]
+    media_type: String,
+    digest: Option<String>,
+    tag: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HarborEventData {
+    #[serde(default)]
+    resources: Vec<HarborResource>,
+}
+
+#[derive(Deserialize)]
+struct HarborResource {
+    digest: Option<String>,
+    tag: Option<String>,
+}
+
+/// Extracts the tags pushed in a Docker Registry (distribution) or Harbor
+/// notification. Pulls, deletes, blob pushes and untagged manifests are
+/// ignored.
+pub fn parse_push_notification(payload: &[u8]) -> Result<Vec<PushedTag>, WebhookError> {
+    let notification: Notification =
+        serde_json::from_slice(payload).map_err(|_| WebhookError::MalformedPayload)?;
+
+    let pushed = match notification {
+        Notification::Distribution { events } => events
+            .into_iter()
+            .filter(|event| event.action == "push")
+            .filter(|event| {
+                event.target.media_type.contains("manifest")
+                    || event.target.media_type.contains("image.index")
+            })
+            .filter_map(|event| Some((event.target.tag?, event.target.digest?)))
+            .collect::<Vec<_>>(),
+        Notification::Harbor {
+            event_type,
+            event_data,
+        } => match event_type.as_str() {
+            "PUSH_ARTIFACT" | "pushImage" => event_data
+                .resources
+                .into_iter()
+                .filter_map(|resource| Some((resource.tag?, resource.digest?)))
+                .collect(),
+            _ => vec![],
+        },
+    };
+
+    Ok(pushed
+        .into_iter()
+        .filter(|(tag, digest)| is_valid_tag(tag) && is_valid_digest(digest))
+        .map(|(tag, digest)| PushedTag { tag, digest })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+    #[test]
+    fn verify_signature_should_accept_its_own_signatures() {
+        let signature = sign("secret", b"payload");
+
+        assert!(verify_signature("secret", b"payload", &signature).is_ok());
+    }
+
+    #[test]
+    fn verify_signature_should_reject_other_secrets_and_payloads() {
+        let signature = sign("secret", b"payload");
+
+        assert!(verify_signature("other", b"payload", &signature).is_err());
+        assert!(verify_signature("secret", b"payload!", &signature).is_err());
+        assert!(verify_signature("secret", b"payload", "sha256=zz").is_err());
+        assert!(verify_signature("secret", b"payload", "").is_err());
+    }
+
+    #[test]
+    fn parse_push_notification_should_read_distribution_events() {
+        let payload = serde_json::json!({
+            "events": [
+                {
+                    "action": "push",
+                    "target": {
+                        "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                        "digest": DIGEST,
+                        "repository": "images/test",
+                        "tag": "v1"
+                    }
+                },
+                {
+                    "action": "push",
+                    "target": {
+                        "mediaType": "application/octet-stream",
+                        "digest": DIGEST,
+                        "repository": "images/test"
+                    }
+                },
+                {
+                    "action": "pull",
+                    "target": {
+                        "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                        "digest": DIGEST,
+                        "repository": "images/test",
+                        "tag": "v0"
+                    }
+                }
+            ]
+        });
+
+        assert_eq!(
+            parse_push_notification(payload.to_string().as_bytes()).unwrap(),
+            vec![PushedTag {
+                tag: "v1".to_string(),
+                digest: DIGEST.to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_push_notification_should_read_harbor_events() {
+        let payload = serde_json::json!({
+            "type": "PUSH_ARTIFACT",
+            "occur_at": 1729000000,
+            "operator": "robot",
+            "event_data": {
+                "resources": [
+                    {
+                        "digest": DIGEST,
+                        "tag": "latest",
+                        "resource_url": "harbor.example.com/images/test:latest"
+                    }
+                ],
+                "repository": { "name": "test", "namespace": "images" }
+            }
+        });
+
+        assert_eq!(
+            parse_push_notification(payload.to_string().as_bytes()).unwrap(),
+            vec![PushedTag {
+                tag: "latest".to_string(),
+                digest: DIGEST.to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_push_notification_should_reject_other_payloads() {
+        assert!(parse_push_notification(b"{\"hello\": \"world\"}").is_err());
+        assert!(parse_push_notification(b"not json").is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+/// A request received by a `StubServer`, with lowercased header names.
+#[derive(Clone, Debug)]
+pub(crate) struct StubRequest {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) host: String,
+    pub(crate) headers: HashMap<String, String>,
+    pub(crate) body: Vec<u8>,
+}
+
+pub(crate) struct StubResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl StubResponse {
+    pub(crate) fn new(status: u16) -> Self {
+        StubResponse {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    pub(crate) fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub(crate) fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+}
+
+type Handler = dyn Fn(&StubRequest) -> StubResponse + Send + Sync;
+
+/// A tiny HTTP/1.1 server standing in for third party services (registries,
+/// object stores, payment providers) in tests.
+pub(crate) struct StubServer {
+    host: String,
+    requests: Arc<Mutex<Vec<StubRequest>>>,
+}
+
+impl StubServer {
+    pub(crate) async fn start(
+        handler: impl Fn(&StubRequest) -> StubResponse + Send + Sync + 'static,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Could not bind stub server");
+        let host = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    if let Some(request) = read_request(stream, handler).await {
+                        recorded.lock().unwrap().push(request);
+                    }
+                });
+            }
+        });
+
+        StubServer { host, requests }
+    }
+
+    pub(crate) fn host(&self) -> String {
+        self.host.clone()
+    }
+
+    pub(crate) fn url(&self) -> String {
+        format!("http://{}", self.host)
+    }
+
+    pub(crate) fn requests(&self) -> Vec<StubRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn read_request(stream: TcpStream, handler: Arc<Handler>) -> Option<StubRequest> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await.ok()?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            headers.insert(key.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let mut body = vec![];
+    if headers
+        .get("transfer-encoding")
+        .map_or(false, |v| v.eq_ignore_ascii_case("chunked"))
+    {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size).await.ok()?;
+            let size = usize::from_str_radix(size.trim(), 16).ok()?;
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).await.ok()?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = headers.get("content-length") {
+        body = vec![0; length.parse().ok()?];
+        reader.read_exact(&mut body).await.ok()?;
+    }
+
+    let request = StubRequest {
+        method,
+        path,
+        host: headers.get("host").cloned().unwrap_or_default(),
+        headers,
+        body,
+    };
+
+    let response = handler(&request);
+    let mut raw = format!("HTTP/1.1 {} Stub\r\n", response.status);
+    for (key, value) in &response.headers {
+        raw.push_str(&format!("{key}: {value}\r\n"));
+    }
+    raw.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    ));
+
+    let mut stream = reader.into_inner();
+    stream.write_all(raw.as_bytes()).await.ok()?;
+    if request.method != "HEAD" {
+        stream.write_all(&response.body).await.ok()?;
+    }
+    stream.shutdown().await.ok()?;
+
+    Some(request)
+}
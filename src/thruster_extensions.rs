@@ -1,3 +1,113 @@
+use std::{collections::HashMap, str::FromStr};
+
+use thruster::{Context, ContextState};
+
+use crate::app::{Ctx, RequestId};
+
+pub(crate) trait RequestExt {
+    fn query_params(&self) -> HashMap<String, String>;
+    fn client_ip(&self) -> Option<String>;
+    fn request_id(&self) -> String;
+    async fn take_body(&mut self) -> Result<Vec<u8>, hyper::Error>;
+    fn take_body_stream(&mut self) -> hyper::Body;
+    fn restore_body(&mut self, bytes: Vec<u8>);
+    fn set_body_bytes(&mut self, bytes: Vec<u8>);
+    fn set_body_stream(&mut self, body: hyper::Body);
+}
+
+impl RequestExt for Ctx {
+    fn query_params(&self) -> HashMap<String, String> {
+        self.hyper_request
+            .as_ref()
+            .and_then(|r| r.request.uri().query())
+            .map(|query| {
+                query
+                    .split('&')
+                    .filter(|pair| !pair.is_empty())
+                    .map(|pair| {
+                        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                        (
+                            urlencoding::decode(key).unwrap_or_default().into_owned(),
+                            urlencoding::decode(&value.replace('+', " "))
+                                .unwrap_or_default()
+                                .into_owned(),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn client_ip(&self) -> Option<String> {
+        self.req_header("Fly-Client-IP")
+            .map(|v| v.to_string())
+            .or_else(|| {
+                self.req_header("X-Forwarded-For")
+                    .and_then(|v| v.split(',').next())
+                    .map(|v| v.trim().to_string())
+            })
+            .or_else(|| {
+                self.hyper_request
+                    .as_ref()
+                    .and_then(|r| r.ip)
+                    .map(|ip| ip.to_string())
+            })
+    }
+
+    fn request_id(&self) -> String {
+        let request_id: &RequestId = self.extra.get();
+        request_id.0.clone()
+    }
+
+    /// Reads the raw request body, for endpoints that can't go through
+    /// `json_request` (binary uploads, signed payloads).
+    async fn take_body(&mut self) -> Result<Vec<u8>, hyper::Error> {
+        match self.hyper_request.as_mut() {
+            Some(r) => Ok(hyper::body::to_bytes(std::mem::take(r.request.body_mut()))
+                .await?
+                .to_vec()),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Hands over the request body without buffering it, for uploads that
+    /// are checked as they arrive.
+    fn take_body_stream(&mut self) -> hyper::Body {
+        match self.hyper_request.as_mut() {
+            Some(r) => std::mem::take(r.request.body_mut()),
+            None => hyper::Body::empty(),
+        }
+    }
+
+    /// Puts back a body read with `take_body`, for middleware that needs to
+    /// look at it before the handler does.
+    fn restore_body(&mut self, bytes: Vec<u8>) {
+        if let Some(r) = self.hyper_request.as_mut() {
+            *r.request.body_mut() = hyper::Body::from(bytes);
+        }
+    }
+
+    fn set_body_bytes(&mut self, bytes: Vec<u8>) {
+        self.body = hyper::Body::from(bytes);
+    }
+
+    /// Responds with a body that's still being produced, e.g. read from
+    /// storage as the client takes it.
+    fn set_body_stream(&mut self, body: hyper::Body) {
+        self.body = body;
+    }
+}
+
+pub(crate) fn parse_param<T: FromStr>(
+    params: &HashMap<String, String>,
+    key: &str,
+) -> Result<Option<T>, String> {
+    params
+        .get(key)
+        .map(|v| T::from_str(v).map_err(|_| format!("Invalid value for {key}: {v}")))
+        .transpose()
+}
+
 pub(crate) trait TestResponseExt {
     fn json<T: serde::de::DeserializeOwned>(&self) -> T;
 }